@@ -0,0 +1,92 @@
+use std::cell::{Ref, RefCell, RefMut};
+use std::ops::Index;
+
+/// Pool of heap objects addressed by a stable index.
+///
+/// Freeing an object leaves a tombstone in its slot instead of shifting the
+/// objects after it, so every other handle keeps pointing at the same object.
+/// Tombstoned slots are reused by later allocations.
+pub struct Arena<T> {
+    slots: Vec<Option<RefCell<T>>>,
+    /// Tombstoned slots, reused last freed first
+    free: Vec<usize>,
+}
+
+impl<T> Arena<T> {
+    pub fn new() -> Self {
+        Arena {
+            slots: vec![],
+            free: vec![],
+        }
+    }
+
+    /// Store the object, returning its index
+    pub fn alloc(&mut self, object: T) -> usize {
+        let cell = Some(RefCell::new(object));
+        match self.free.pop() {
+            Some(idx) => {
+                self.slots[idx] = cell;
+                idx
+            }
+            None => {
+                self.slots.push(cell);
+                self.slots.len() - 1
+            }
+        }
+    }
+
+    /// Drop the object and tombstone its slot, returning the object
+    pub fn free(&mut self, idx: usize) -> Option<T> {
+        let object = self.slots.get_mut(idx)?.take()?;
+        self.free.push(idx);
+        return Some(object.into_inner());
+    }
+
+    pub fn is_live(&self, idx: usize) -> bool {
+        return matches!(self.slots.get(idx), Some(Some(_)));
+    }
+
+    /// Indexes of the live objects
+    pub fn live_indexes(&self) -> impl Iterator<Item = usize> + '_ {
+        return self.slots.iter().enumerate()
+            .filter(|(_, slot)| slot.is_some())
+            .map(|(idx, _)| idx);
+    }
+
+    /// Number of live objects
+    pub fn len(&self) -> usize {
+        return self.slots.len() - self.free.len();
+    }
+
+    pub fn is_empty(&self) -> bool {
+        return self.len() == 0;
+    }
+
+    pub fn get(&self, idx: usize) -> Ref<'_, T> {
+        return self[idx].borrow();
+    }
+
+    pub fn get_mut(&self, idx: usize) -> RefMut<'_, T> {
+        return self[idx].borrow_mut();
+    }
+
+    pub fn clear(&mut self) {
+        self.slots.clear();
+        self.free.clear();
+    }
+}
+
+impl<T> Default for Arena<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> Index<usize> for Arena<T> {
+    type Output = RefCell<T>;
+
+    /// Panics when the slot was freed, a handle outliving its object is a GC bug
+    fn index(&self, idx: usize) -> &RefCell<T> {
+        return self.slots[idx].as_ref().expect("Use of a freed heap object.");
+    }
+}
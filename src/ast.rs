@@ -0,0 +1,164 @@
+//! Syntax tree built by the parser and lowered to bytecode by the compiler.
+//!
+//! Nodes keep the tokens they came from, so diagnostics and line numbers can
+//! point back at the source.
+use std::rc::Rc;
+
+use crate::token::Token;
+
+/// Declaration or statement
+pub struct Stmt {
+    pub kind: StmtKind,
+    /// First token of the statement
+    pub start: Token,
+}
+
+pub enum StmtKind {
+    /// `var name = initializer;`
+    Var { name: Token, initializer: Option<Expr> },
+    /// `const name = initializer;`
+    Const { name: Token, initializer: Expr },
+    Fun(Function),
+    Class(Class),
+    /// Expression evaluated for its effect, its value is dropped
+    Expression(Expr),
+    Print(Expr),
+    Block(Vec<Stmt>),
+    If { condition: Expr, then_branch: Box<Stmt>, else_branch: Option<Box<Stmt>> },
+    While { condition: Expr, body: Box<Stmt> },
+    /// `for (initializer; condition; increment) body`, any of the clauses can be left out
+    For { initializer: Option<Box<Stmt>>, condition: Option<Expr>, increment: Option<Expr>, body: Box<Stmt> },
+    /// `for (var variable in iterable) body`
+    ForIn { variable: Token, iterable: Expr, body: Box<Stmt> },
+    /// `try { body } catch (variable) { handler }`
+    Try { body: Vec<Stmt>, variable: Token, handler: Vec<Stmt> },
+    Throw(Expr),
+    Return(Option<Expr>),
+}
+
+/// Function declaration or method
+pub struct Function {
+    pub name: Token,
+    pub params: Vec<Token>,
+    /// `...rest` parameter collecting the arguments past the others
+    pub rest: Option<Token>,
+    pub body: Vec<Stmt>,
+    /// Closing brace of the body, or the semicolon of an abstract method
+    pub end: Token,
+    /// `abstract name(params);` method, declared without a body
+    pub is_abstract: bool,
+}
+
+pub struct Class {
+    pub name: Token,
+    pub superclass: Option<Token>,
+    pub methods: Vec<Function>,
+    /// `const name = value;` declarations
+    pub constants: Vec<(Token, Expr)>,
+    /// Closing brace of the class body
+    pub end: Token,
+}
+
+/// Method or constant of a class body
+pub enum Member<'a> {
+    Method(&'a Function),
+    Constant(&'a Token, &'a Expr),
+}
+
+impl Class {
+    /// Methods and constants in source order
+    pub fn members(&self) -> Vec<Member<'_>> {
+        let mut members: Vec<(&Token, Member)> = self.methods.iter()
+            .map(|method| (&method.name, Member::Method(method)))
+            .chain(self.constants.iter().map(|(name, value)| (name, Member::Constant(name, value))))
+            .collect();
+        members.sort_by_key(|(name, _)| (name.line, name.column));
+        return members.into_iter().map(|(_, member)| member).collect();
+    }
+}
+
+/// Expression with the token its code is attributed to, such as its
+/// operator or the closing parenthesis of a call
+pub struct Expr {
+    pub kind: ExprKind,
+    pub token: Token,
+}
+
+pub enum ExprKind {
+    Number(f64),
+    String(Rc<str>),
+    Bool(bool),
+    Nil,
+    Grouping(Box<Expr>),
+    /// `-operand` or `!operand`
+    Unary(Box<Expr>),
+    /// Arithmetic or comparison of the two operands
+    Binary(Box<Expr>, Box<Expr>),
+    And(Box<Expr>, Box<Expr>),
+    Or(Box<Expr>, Box<Expr>),
+    /// `left ?? right`
+    NilCoalesce(Box<Expr>, Box<Expr>),
+    /// Read of the variable named by the token
+    Variable,
+    /// `name = value`, `name += value` or `name -= value`
+    Assign { name: Token, value: Box<Expr> },
+    /// `name++` or `name--`
+    PostIncrement { name: Token },
+    /// `++name` or `++name.field.field`, `--` alike
+    PreIncrement { target: Token, fields: Vec<Token> },
+    This,
+    /// `super.method`, called when it has arguments
+    Super { method: Token, arguments: Option<Arguments> },
+    /// `object.name`
+    Get { object: Box<Expr>, name: Token },
+    /// `object.name = value`
+    Set { object: Box<Expr>, name: Token, value: Box<Expr> },
+    /// `object.name++` or `object.name--`
+    PropertyIncrement { object: Box<Expr>, name: Token },
+    /// `object.name(arguments)`
+    Invoke { object: Box<Expr>, name: Token, arguments: Arguments },
+    Call { callee: Box<Expr>, arguments: Arguments },
+    List(Vec<Expr>),
+    Map(Vec<(Expr, Expr)>),
+    /// `object[index]`
+    Index { object: Box<Expr>, index: Box<Expr> },
+    /// `object[index] = value`
+    SetIndex { object: Box<Expr>, index: Box<Expr>, value: Box<Expr> },
+    Match { subject: Box<Expr>, arms: Vec<MatchArm> },
+}
+
+/// Argument list of a call
+pub enum Arguments {
+    Positional(Vec<Expr>),
+    /// Arguments marked true are `...list`, expanded in place
+    Spread(Vec<(bool, Expr)>),
+    /// Positional arguments followed by `name: value` pairs
+    Named { positional: Vec<Expr>, named: Vec<(Token, Expr)> },
+}
+
+/// `pattern if guard => body`
+pub struct MatchArm {
+    pub pattern: Pattern,
+    pub guard: Option<Expr>,
+    pub body: Expr,
+}
+
+/// Pattern of a match arm
+pub enum Pattern {
+    /// `_` matches anything
+    Wildcard,
+    /// Number, string, boolean or nil compared with ==, numbers can be negative
+    Literal { token: Token, negative: bool },
+    /// Name bound to the matched value
+    Binding(Token),
+    /// `[a, b, ...rest]`
+    List(Vec<Pattern>, Option<Box<Pattern>>),
+    /// `Point { x, y: 0 }`, the class plus field names and their patterns
+    Instance(Token, Vec<(Token, Pattern)>),
+}
+
+impl Expr {
+    pub fn new(kind: ExprKind, token: Token) -> Self {
+        Expr { kind, token }
+    }
+}
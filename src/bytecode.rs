@@ -0,0 +1,364 @@
+use fnv::FnvHashMap;
+
+use crate::{Chunk, Heap, Object, Opcode, Value};
+use crate::function::Function;
+
+/// Leading bytes of a compiled script
+pub const MAGIC: &[u8; 4] = b"KBC\0";
+
+/// Bumped whenever the opcodes or the layout below change
+const VERSION: u8 = 8;
+
+const TAG_NIL: u8 = 0;
+const TAG_FALSE: u8 = 1;
+const TAG_TRUE: u8 = 2;
+const TAG_NUMBER: u8 = 3;
+const TAG_STRING: u8 = 4;
+const TAG_FUNCTION: u8 = 5;
+
+/// Is the file content a compiled script rather than source?
+pub fn is_bytecode(bytes: &[u8]) -> bool {
+    return bytes.starts_with(MAGIC);
+}
+
+/// Serialize every compiled function on the heap, main first.
+///
+/// Layout, integers little endian:
+/// magic, version, function count (u32), then per function its name,
+/// arity (u32), variadic flag (u8), parameter names, upvalue count (u32),
+/// code, line runs and constants. Strings are a u32 byte length and UTF-8 bytes.
+/// Constants are a tag byte followed by an f64 for numbers, the text for
+/// strings, and the position in this file for functions.
+pub fn serialize(heap: &Heap, main_func_idx: usize) -> Vec<u8> {
+    let mut func_indexes: Vec<usize> = vec![main_func_idx];
+    func_indexes.extend(heap.functions.live_indexes().filter(|idx| *idx != main_func_idx));
+    let positions: FnvHashMap<usize, u32> = func_indexes.iter().enumerate()
+        .map(|(position, idx)| (*idx, position as u32))
+        .collect();
+
+    let mut writer = Writer { bytes: MAGIC.to_vec() };
+    writer.u8(VERSION);
+    writer.u32(func_indexes.len() as u32);
+    for idx in func_indexes {
+        let function = heap.get_function(idx);
+        writer.string(&function.name);
+        writer.u32(function.arity as u32);
+        writer.u8(function.is_variadic as u8);
+        writer.u32(function.param_names.len() as u32);
+        for name in &function.param_names {
+            writer.string(name);
+        }
+        writer.u32(function.upvalue_count as u32);
+
+        let chunk = &function.chunk;
+        writer.u32(chunk.code.len() as u32);
+        writer.bytes.extend_from_slice(&chunk.code);
+        writer.u32(chunk.lines.len() as u32);
+        for (line, count) in &chunk.lines {
+            writer.u32(*line as u32);
+            writer.u32(*count as u32);
+        }
+        writer.u32(chunk.constants.len() as u32);
+        for constant in &chunk.constants {
+            match constant {
+                Value::Nil() => writer.u8(TAG_NIL),
+                Value::Bool(false) => writer.u8(TAG_FALSE),
+                Value::Bool(true) => writer.u8(TAG_TRUE),
+                Value::Number(n) => {
+                    writer.u8(TAG_NUMBER);
+                    writer.bytes.extend_from_slice(&n.to_le_bytes());
+                }
+                Value::Obj(Object::StringHash(hash)) => {
+                    writer.u8(TAG_STRING);
+                    writer.string(heap.get_string(*hash));
+                }
+                Value::Obj(Object::FunctionIndex(func_idx)) => {
+                    writer.u8(TAG_FUNCTION);
+                    writer.u32(positions[func_idx]);
+                }
+                Value::Obj(_) => panic!("Only numbers, strings and functions are compiled as constants."),
+            }
+        }
+    }
+    return writer.bytes;
+}
+
+/// Load a compiled script onto the heap, interning its strings. Damaged
+/// code is an error, see `validate`.
+///
+/// Returns the index of the main function
+pub fn deserialize(bytes: &[u8], heap: &mut Heap) -> Result<usize, String> {
+    let mut reader = Reader { bytes, offset: 0 };
+    if reader.take(MAGIC.len())? != MAGIC {
+        return Err("Not a compiled KScript file.".to_string());
+    }
+    let version = reader.u8()?;
+    if version != VERSION {
+        return Err(format!("Unsupported bytecode version {}, expected {}.", version, VERSION));
+    }
+
+    let func_count = reader.u32()? as usize;
+    let mut functions: Vec<Function> = vec![];
+    for _ in 0..func_count {
+        let mut function = Function::new(reader.string()?, 0);
+        function.arity = reader.u32()? as usize;
+        function.is_variadic = reader.u8()? != 0;
+        let param_count = reader.u32()?;
+        for _ in 0..param_count {
+            function.param_names.push(reader.string()?);
+        }
+        function.upvalue_count = reader.u32()? as usize;
+
+        let mut chunk = Chunk::new();
+        let code_len = reader.u32()? as usize;
+        chunk.code = reader.take(code_len)?.to_vec();
+        let run_count = reader.u32()?;
+        for _ in 0..run_count {
+            chunk.lines.push((reader.u32()? as usize, reader.u32()? as usize));
+        }
+        let constant_count = reader.u32()?;
+        for _ in 0..constant_count {
+            let constant = match reader.u8()? {
+                TAG_NIL => Value::nil(),
+                TAG_FALSE => Value::bool(false),
+                TAG_TRUE => Value::bool(true),
+                TAG_NUMBER => {
+                    let mut number = [0u8; 8];
+                    number.copy_from_slice(reader.take(8)?);
+                    Value::number(f64::from_le_bytes(number))
+                }
+                TAG_STRING => Value::object(Object::string(heap.alloc_string(reader.string()?))),
+                TAG_FUNCTION => {
+                    let position = reader.u32()? as usize;
+                    if position >= func_count {
+                        return Err(format!("Function constant {} out of range.", position));
+                    }
+                    // Patched to the heap index once every function is allocated
+                    Value::object(Object::function(position))
+                }
+                tag => return Err(format!("Unknown constant tag {}.", tag)),
+            };
+            chunk.constants.push(constant);
+        }
+        function.chunk = chunk;
+        functions.push(function);
+    }
+    if reader.offset != bytes.len() {
+        return Err("Trailing bytes after the last function.".to_string());
+    }
+    if functions.is_empty() {
+        return Err("Compiled file has no main function.".to_string());
+    }
+    validate(&functions)?;
+
+    let func_indexes: Vec<usize> = functions.into_iter()
+        .map(|function| heap.alloc_function(function))
+        .collect();
+    for idx in &func_indexes {
+        let mut function = heap.get_mut_function(*idx);
+        for constant in function.chunk.constants.iter_mut() {
+            if let Value::Obj(Object::FunctionIndex(position)) = constant {
+                *position = func_indexes[*position];
+            }
+        }
+    }
+    return Ok(func_indexes[0]);
+}
+
+/// Check that the code of every function only reads its own code, constants
+/// and upvalues and ends in a return, so a damaged file is an error rather
+/// than a crash of the VM. Function constants are still positions in the file
+fn validate(functions: &[Function]) -> Result<(), String> {
+    if functions[0].upvalue_count != 0 {
+        return Err("Main function can't capture upvalues.".to_string());
+    }
+    for function in functions {
+        validate_code(function, functions)
+            .map_err(|error| format!("Invalid code in {}: {}", function.name, error))?;
+    }
+    return Ok(());
+}
+
+fn validate_code(function: &Function, functions: &[Function]) -> Result<(), String> {
+    let code = &function.chunk.code;
+    let constants = &function.chunk.constants;
+    let byte = |at: usize| -> Result<usize, String> {
+        return code.get(at).map(|byte| *byte as usize)
+            .ok_or_else(|| "instruction cut short at the end of the code.".to_string());
+    };
+    let short = |at: usize| -> Result<usize, String> { return Ok((byte(at)? << 8) | byte(at + 1)?); };
+    let constant = |index: usize| -> Result<Value, String> {
+        return constants.get(index).copied().ok_or_else(|| format!("constant {} out of range.", index));
+    };
+    let name = |index: usize| -> Result<(), String> {
+        return match constant(index)? {
+            Value::Obj(Object::StringHash(_)) => Ok(()),
+            _ => Err(format!("constant {} isn't a name.", index)),
+        };
+    };
+    let upvalue = |index: usize| -> Result<(), String> {
+        if index >= function.upvalue_count {
+            return Err(format!("upvalue {} out of range.", index));
+        }
+        return Ok(());
+    };
+
+    let mut starts = vec![false; code.len()];
+    // Offset of each jump and where it lands
+    let mut jumps: Vec<(usize, Option<usize>)> = vec![];
+    let mut offset = 0;
+    let mut wide = None;
+    let mut last = None;
+    while offset < code.len() {
+        starts[offset] = true;
+        let opcode = Opcode::from_byte(code[offset])
+            .ok_or_else(|| format!("unknown opcode {} at {}.", code[offset], offset))?;
+        let high = wide.take().unwrap_or(0);
+        let operands = offset + 1;
+        let next = match opcode {
+            Opcode::Wide => {
+                wide = Some((byte(operands)? << 16) | (byte(operands + 1)? << 8));
+                match Opcode::from_byte(byte(operands + 2)? as u8) {
+                    Some(Opcode::GetGlobal | Opcode::SetGlobal | Opcode::DefineGlobal | Opcode::DefineConstGlobal
+                        | Opcode::DefineDeclaration | Opcode::Class | Opcode::GetProperty | Opcode::SetProperty
+                        | Opcode::Method | Opcode::AbstractMethod | Opcode::ClassConstant | Opcode::GetSuper
+                        | Opcode::Invoke | Opcode::SuperInvoke | Opcode::Closure | Opcode::ClosureLong) => {}
+                    _ => return Err(format!("wide operand at {} before an instruction without a constant.", offset)),
+                }
+                operands + 2
+            }
+            Opcode::Constant => {
+                constant(byte(operands)?)?;
+                operands + 1
+            }
+            Opcode::ConstantLong => {
+                constant((byte(operands)? << 16) | short(operands + 1)?)?;
+                operands + 3
+            }
+            Opcode::GetGlobal | Opcode::SetGlobal | Opcode::DefineGlobal | Opcode::DefineConstGlobal
+            | Opcode::DefineDeclaration | Opcode::Class | Opcode::GetProperty | Opcode::SetProperty
+            | Opcode::Method | Opcode::AbstractMethod | Opcode::ClassConstant | Opcode::GetSuper => {
+                name(high | byte(operands)?)?;
+                operands + 1
+            }
+            Opcode::Invoke | Opcode::SuperInvoke => {
+                name(high | byte(operands)?)?;
+                byte(operands + 1)?;
+                operands + 2
+            }
+            Opcode::Closure | Opcode::ClosureLong => {
+                let index = high | byte(operands)?;
+                let captured = match constant(index)? {
+                    Value::Obj(Object::FunctionIndex(position)) => &functions[position],
+                    _ => return Err(format!("constant {} isn't a function.", index)),
+                };
+                let index_len = if matches!(opcode, Opcode::ClosureLong) { 2 } else { 1 };
+                let mut at = operands + 1;
+                for _ in 0..captured.upvalue_count {
+                    let is_local = byte(at)? == 1;
+                    let index = if index_len == 2 { short(at + 1)? } else { byte(at + 1)? };
+                    if !is_local {
+                        upvalue(index)?;
+                    }
+                    at += 1 + index_len;
+                }
+                at
+            }
+            Opcode::GetUpvalue | Opcode::SetUpvalue => {
+                upvalue(byte(operands)?)?;
+                operands + 1
+            }
+            Opcode::GetUpvalueLong | Opcode::SetUpvalueLong => {
+                upvalue(short(operands)?)?;
+                operands + 2
+            }
+            Opcode::GetLocal | Opcode::SetLocal | Opcode::Call | Opcode::BuildList | Opcode::BuildMap
+            | Opcode::SliceFrom | Opcode::PopN => {
+                byte(operands)?;
+                operands + 1
+            }
+            Opcode::GetLocalLong | Opcode::SetLocalLong | Opcode::CallLong | Opcode::CallNamed | Opcode::MatchList => {
+                byte(operands + 1)?;
+                operands + 2
+            }
+            Opcode::Jump | Opcode::JumpIfFalse | Opcode::JumpIfTrue | Opcode::JumpIfNotNil | Opcode::PushHandler => {
+                let next = operands + 2;
+                jumps.push((offset, Some(next + short(operands)?)));
+                next
+            }
+            Opcode::Loop => {
+                let next = operands + 2;
+                jumps.push((offset, next.checked_sub(short(operands)?)));
+                next
+            }
+            Opcode::ForIter => {
+                let next = operands + 3;
+                jumps.push((offset, Some(next + short(operands + 1)?)));
+                next
+            }
+            _ => operands,
+        };
+        last = Some(opcode);
+        offset = next;
+    }
+    if !matches!(last, Some(Opcode::Return)) {
+        return Err("code doesn't end with a return.".to_string());
+    }
+    for (offset, target) in jumps {
+        if !target.is_some_and(|target| target < code.len() && starts[target]) {
+            return Err(format!("jump at {} doesn't land on an instruction.", offset));
+        }
+    }
+    return Ok(());
+}
+
+struct Writer {
+    bytes: Vec<u8>,
+}
+
+impl Writer {
+    fn u8(&mut self, value: u8) {
+        self.bytes.push(value);
+    }
+
+    fn u32(&mut self, value: u32) {
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn string(&mut self, value: &str) {
+        self.u32(value.len() as u32);
+        self.bytes.extend_from_slice(value.as_bytes());
+    }
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], String> {
+        let end = self.offset.checked_add(len)
+            .filter(|end| *end <= self.bytes.len())
+            .ok_or_else(|| "Unexpected end of bytecode file.".to_string())?;
+        let bytes = &self.bytes[self.offset..end];
+        self.offset = end;
+        return Ok(bytes);
+    }
+
+    fn u8(&mut self) -> Result<u8, String> {
+        return Ok(self.take(1)?[0]);
+    }
+
+    fn u32(&mut self) -> Result<u32, String> {
+        let mut value = [0u8; 4];
+        value.copy_from_slice(self.take(4)?);
+        return Ok(u32::from_le_bytes(value));
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        let len = self.u32()? as usize;
+        return String::from_utf8(self.take(len)?.to_vec())
+            .map_err(|_| "Invalid UTF-8 string in bytecode file.".to_string());
+    }
+}
@@ -17,4 +17,14 @@ impl CallFrame {
             ip: 0,
         }
     }
-}
\ No newline at end of file
+}
+/// Catch block registered by a try statement
+#[derive(Copy, Clone)]
+pub struct Handler {
+    /// Call stack depth of the function containing the try statement
+    pub frame_depth: usize,
+    /// Stack top to restore before pushing the thrown value
+    pub stack_top: usize,
+    /// Instruction pointer of the catch block
+    pub catch_ip: usize,
+}
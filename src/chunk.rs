@@ -1,13 +1,15 @@
 #![allow(dead_code, unused)]
 
 use std::ops::Index;
+use fnv::FnvHashMap;
+use crate::object::Object;
 use crate::value::Value;
 
 /**
  * Enum of op codes
  */
 #[repr(u8)]
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, Debug)]
 pub enum Opcode {
     Constant = 0,
     Nil = 1,
@@ -45,12 +47,87 @@ pub enum Opcode {
     Inherit = 33,
     SuperInvoke = 34,
     Return = 35,
+    GetSuper = 36,
+    BuildList = 37,
+    GetIndex = 38,
+    SetIndex = 39,
+    BuildMap = 40,
+    DefineConstGlobal = 41,
+    ForIter = 42,
+    PushHandler = 43,
+    PopHandler = 44,
+    Throw = 45,
+    Dup = 46,
+    JumpIfNotNil = 47,
+    ListAppend = 48,
+    ListExtend = 49,
+    CallSpread = 50,
+    CallNamed = 51,
+    MatchList = 52,
+    SliceFrom = 53,
+    IsInstance = 54,
+    ConstantLong = 55,
+    GetLocalLong = 56,
+    SetLocalLong = 57,
+    CallLong = 58,
+    PopN = 59,
+    JumpIfTrue = 60,
+    ClosureLong = 61,
+    GetUpvalueLong = 62,
+    SetUpvalueLong = 63,
+    AbstractMethod = 64,
+    ClassConstant = 65,
+    DefineDeclaration = 66,
+    Wide = 67,
 }
 
+/// Every opcode, at the index of its byte
+const OPCODES: [Opcode; 68] = [
+    Opcode::Constant, Opcode::Nil, Opcode::True, Opcode::False, Opcode::Pop, Opcode::GetLocal, Opcode::GetGlobal,
+    Opcode::DefineGlobal, Opcode::SetLocal, Opcode::SetGlobal, Opcode::Equal, Opcode::GetUpvalue,
+    Opcode::SetUpvalue, Opcode::Greater, Opcode::Less, Opcode::Add, Opcode::Subtract, Opcode::Multiply,
+    Opcode::Divide, Opcode::Not, Opcode::Negate, Opcode::Print, Opcode::JumpIfFalse, Opcode::Jump, Opcode::Loop,
+    Opcode::Call, Opcode::Closure, Opcode::CloseValue, Opcode::Class, Opcode::SetProperty, Opcode::GetProperty,
+    Opcode::Method, Opcode::Invoke, Opcode::Inherit, Opcode::SuperInvoke, Opcode::Return, Opcode::GetSuper,
+    Opcode::BuildList, Opcode::GetIndex, Opcode::SetIndex, Opcode::BuildMap, Opcode::DefineConstGlobal,
+    Opcode::ForIter, Opcode::PushHandler, Opcode::PopHandler, Opcode::Throw, Opcode::Dup, Opcode::JumpIfNotNil,
+    Opcode::ListAppend, Opcode::ListExtend, Opcode::CallSpread, Opcode::CallNamed, Opcode::MatchList,
+    Opcode::SliceFrom, Opcode::IsInstance, Opcode::ConstantLong, Opcode::GetLocalLong, Opcode::SetLocalLong,
+    Opcode::CallLong, Opcode::PopN, Opcode::JumpIfTrue, Opcode::ClosureLong, Opcode::GetUpvalueLong,
+    Opcode::SetUpvalueLong, Opcode::AbstractMethod, Opcode::ClassConstant, Opcode::DefineDeclaration,
+    Opcode::Wide,
+];
+
 impl Opcode {
     pub fn byte(&self) -> u8 {
         return *self as u8
     }
+
+    /// Opcode with the byte, None when no opcode has it
+    pub fn from_byte(byte: u8) -> Option<Opcode> {
+        return OPCODES.get(byte as usize).copied();
+    }
+}
+
+/// Hashable stand in for a constant, numbers are told apart by their bits
+/// so 0 and -0 stay separate constants
+#[derive(Copy, Clone, PartialEq, Eq, Hash)]
+enum ConstantKey {
+    Number(u64),
+    Bool(bool),
+    Obj(Object),
+    Nil,
+}
+
+impl ConstantKey {
+    fn from(value: Value) -> Self {
+        return match value {
+            Value::Number(n) => ConstantKey::Number(n.to_bits()),
+            Value::Bool(b) => ConstantKey::Bool(b),
+            Value::Obj(object) => ConstantKey::Obj(object),
+            Value::Nil() => ConstantKey::Nil,
+        };
+    }
 }
 
 /// Represent a chunk of machine code
@@ -59,7 +136,17 @@ impl Opcode {
 pub struct Chunk {
     pub code: Vec<u8>,
     pub constants: Vec<Value>,
-    pub lines: Vec<usize>
+    /// Source lines run length encoded, each entry is a line and how many
+    /// consecutive bytes of code came from it
+    pub lines: Vec<(usize, usize)>,
+    /// Index of each constant added so far, so adding one again reuses it
+    constant_indexes: FnvHashMap<ConstantKey, usize>,
+}
+
+impl Default for Chunk {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Chunk {
@@ -67,27 +154,67 @@ impl Chunk {
         Chunk {
             code: vec![],
             constants: vec![],
-            lines: vec![]
+            lines: vec![],
+            constant_indexes: FnvHashMap::default(),
         }
     }
 
     /// Append bytecode
     pub fn code(&mut self, byte: u8, line: usize) -> &mut Chunk {
         self.code.push(byte);
-        self.lines.push(line);
+        #[cfg(feature = "line-tracking")]
+        match self.lines.last_mut() {
+            Some((last_line, count)) if *last_line == line => *count += 1,
+            _ => self.lines.push((line, 1)),
+        }
+        #[cfg(not(feature = "line-tracking"))]
+        let _ = line;
         return self;
     }
 
+    /// Drop the code from the given offset on
+    pub fn truncate(&mut self, len: usize) {
+        self.code.truncate(len);
+        let mut remaining = len;
+        let mut runs = 0;
+        for (_, count) in self.lines.iter_mut() {
+            if remaining == 0 {
+                break;
+            }
+            *count = (*count).min(remaining);
+            remaining -= *count;
+            runs += 1;
+        }
+        self.lines.truncate(runs);
+    }
+
+    /// Source line of the byte at the given offset.
+    /// Returns 0 when line tracking is compiled out.
+    pub fn line_for_offset(&self, offset: usize) -> usize {
+        let mut start = 0;
+        for (line, count) in &self.lines {
+            start += count;
+            if offset < start {
+                return *line;
+            }
+        }
+        return 0;
+    }
+
+    /// Use the constants of the other chunk, for a rewritten copy of its code
+    pub fn share_constants(&mut self, other: &Chunk) {
+        self.constants = other.constants.clone();
+        self.constant_indexes = other.constant_indexes.clone();
+    }
+
     /// Add constant
     /// Return index number pointing to the constant
-    pub fn add_constants(&mut self, val: Value) -> u8 {
-        let existing_index = self.constants.iter().position(|&r| r == val );
-        if existing_index.is_some()  {
-            return existing_index.unwrap() as u8;
-        }
-        let index = self.constants.len() as u8; // fixme: might overflow!
-        self.constants.push(val);
-        return index;
+    pub fn add_constants(&mut self, val: Value) -> usize {
+        let constants = &mut self.constants;
+        return *self.constant_indexes.entry(ConstantKey::from(val)).or_insert_with(|| {
+            constants.push(val);
+            constants.len() - 1
+        });
     }
 }
 
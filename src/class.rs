@@ -1,22 +1,46 @@
 use fnv::FnvHashMap;
 use crate::Value;
+use crate::shape::{Shapes, MAX_SLOTS};
 
+#[derive(Clone)]
 pub struct Class {
     pub name: String,
-    pub methods: FnvHashMap<u32, Value>
+    pub methods: FnvHashMap<u32, Value>,
+    /// Methods declared abstract that no method has been defined for yet, in
+    /// declaration order. The class can't be instantiated until it's empty
+    pub abstract_methods: Vec<u32>,
+    /// `const` declarations of the class body, read as `Class.NAME`
+    pub constants: FnvHashMap<u32, Value>,
+    /// Class it inherits from, whose methods and constants it has copies of
+    pub superclass: Option<usize>,
 }
 
 impl Class {
     pub fn new(name: String) ->Self {
         Class {
             name,
-            methods: Default::default()
+            methods: Default::default(),
+            abstract_methods: vec![],
+            constants: Default::default(),
+            superclass: None,
         }
     }
 }
 
+/// Error for instantiating a class whose abstract methods are missing, the
+/// compiler reports the same one when it can tell
+pub fn abstract_class_message(class_name: &str, missing: &[&str]) -> String {
+    return format!("Cannot instantiate abstract class {}, missing methods: {}.", class_name, missing.join(", "));
+}
+
+#[derive(Clone)]
 pub struct Instance {
     pub class_idx: usize,
+    /// Layout of the fields kept in slots
+    pub shape: usize,
+    /// Field values at the slots their shape gives them
+    pub slots: Vec<Value>,
+    /// Fields added once the slots are full
     pub fields: FnvHashMap<u32, Value>,
 }
 
@@ -24,7 +48,54 @@ impl Instance {
     pub fn new(class_idx: usize) ->Self {
         Instance {
             class_idx,
+            shape: 0,
+            slots: vec![],
             fields: FnvHashMap::default()
         }
     }
+
+    pub fn get_field(&self, shapes: &Shapes, name: u32) -> Option<Value> {
+        if let Some(slot) = shapes.slot(self.shape, name) {
+            return Some(self.slots[slot]);
+        }
+        return self.fields.get(&name).copied();
+    }
+
+    pub fn set_field(&mut self, shapes: &mut Shapes, name: u32, value: Value) {
+        if let Some(slot) = shapes.slot(self.shape, name) {
+            self.slots[slot] = value;
+        } else if self.slots.len() < MAX_SLOTS {
+            self.shape = shapes.add_field(self.shape, name);
+            self.slots.push(value);
+        } else {
+            self.fields.insert(name, value);
+        }
+    }
+
+    /// Names of the fields in the order they were added, those past the
+    /// slots come last in no particular order
+    pub fn field_names(&self, shapes: &Shapes) -> Vec<u32> {
+        let mut names = shapes.get(self.shape).names.clone();
+        names.extend(self.fields.keys());
+        return names;
+    }
+
+    pub fn field_values(&self) -> impl Iterator<Item = Value> + '_ {
+        return self.slots.iter().chain(self.fields.values()).copied();
+    }
+}
+
+/// A method closure paired with the instance it was accessed from
+pub struct BoundMethod {
+    pub receiver: Value,
+    pub closure_idx: usize,
+}
+
+impl BoundMethod {
+    pub fn new(receiver: Value, closure_idx: usize) ->Self {
+        BoundMethod {
+            receiver,
+            closure_idx
+        }
+    }
 }
@@ -76,12 +76,10 @@ impl ObjUpvalue {
 
 
     pub fn resolve_value(&mut self, vm: &VM) -> Value {
-        return if self.closed.is_some() {
-            self.closed.unwrap()
-        } else if self.location.is_some() {
-            vm.stack[self.location.unwrap()]
-        } else {
-            panic!("Unreachable code");
+        return match (self.closed, self.location) {
+            (Some(closed), _) => closed,
+            (None, Some(location)) => vm.stack[location],
+            (None, None) => panic!("Unreachable code"),
         }
     }
 }
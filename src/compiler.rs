@@ -1,16 +1,43 @@
-use std::collections::HashMap;
 use std::{fmt, mem};
-use std::borrow::BorrowMut;
 use std::cell::{RefCell, RefMut};
+use std::collections::VecDeque;
+use std::rc::Rc;
 
+use fnv::{FnvHashMap, FnvHashSet};
+use tracing::{debug, info_span};
+
+use crate::ast::Pattern;
 use crate::function::{Function};
 use crate::{Heap, Object, Opcode, Value};
+use crate::class::abstract_class_message;
 use crate::closure::Upvalue;
 use crate::token::{Token, TokenType};
+use crate::scanner::source_snippet;
 use crate::debug::disassemble_chunk;
-
-static DEBUG_MACHINE_CODE: bool = true;
-static MAX_UPVALUE_COUNT: usize = 256;
+use crate::diagnostic::{Diagnostic, Severity};
+use crate::optimizer::optimize;
+
+mod ast_parser;
+mod codegen;
+
+/// Upvalues addressable by the 16 bit operand of GetUpvalueLong
+static MAX_UPVALUE_COUNT: usize = 1 << 16;
+/// Constants addressable by the 24 bit index of ConstantLong or of a Wide
+/// instruction and the one after it
+static MAX_LONG_CONSTANTS: usize = 1 << 24;
+/// Locals, parameters and call arguments addressable by a 16 bit operand
+static MAX_LOCALS: usize = 1 << 16;
+/// Expressions and statements that can be parsed inside each other
+static MAX_NESTING: usize = 200;
+
+/// Which compiled functions to print the bytecode of
+#[derive(Clone, PartialEq)]
+pub enum Disassemble {
+    None,
+    All,
+    /// Only the functions with this name, the top level script is "main"
+    Function(String),
+}
 
 #[derive(Copy, Clone)]
 pub enum FunctionType {
@@ -38,27 +65,73 @@ impl fmt::Display for FunctionType {
     }
 }
 
+/// Line number of a "[line N] ..." diagnostic
+fn diagnostic_line(diagnostic: &str) -> usize {
+    return diagnostic.strip_prefix("[line ")
+        .and_then(|rest| rest.split(']').next())
+        .and_then(|line| line.parse().ok())
+        .unwrap_or(0);
+}
+
+/// What a name was declared as, for naming it in unused warnings
+#[derive(Copy, Clone, PartialEq)]
+enum DeclarationKind {
+    Variable,
+    Parameter,
+    Function,
+    Class,
+}
+
+impl fmt::Display for DeclarationKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DeclarationKind::Variable => write!(f, "variable"),
+            DeclarationKind::Parameter => write!(f, "parameter"),
+            DeclarationKind::Function => write!(f, "function"),
+            DeclarationKind::Class => write!(f, "class"),
+        }
+    }
+}
+
 /// Data structure for Local variable
 #[repr(C)]
 struct Local {
-    name: String,
+    name: Rc<str>,
     depth: isize,
     pub is_captured: bool,
+    pub is_const: bool,
+    /// Read somewhere after its declaration
+    pub is_read: bool,
+    pub kind: DeclarationKind,
+    /// Line and column of the declaration
+    pub line: usize,
+    pub column: usize,
 }
 
 impl Local {
-    pub fn from(name: String, depth: isize) -> Self {
+    pub fn from(name: Rc<str>, depth: isize) -> Self {
         Local {
             name,
             depth,
             is_captured: false,
+            is_const: false,
+            is_read: false,
+            kind: DeclarationKind::Variable,
+            line: 0,
+            column: 0,
         }
     }
 }
 
 impl Clone for Local {
     fn clone(&self) -> Self {
-        return Local::from(self.name.to_string(), self.depth);
+        let mut local = Local::from(Rc::clone(&self.name), self.depth);
+        local.is_const = self.is_const;
+        local.is_read = self.is_read;
+        local.kind = self.kind;
+        local.line = self.line;
+        local.column = self.column;
+        return local;
     }
 }
 
@@ -88,9 +161,9 @@ impl Compiler {
                function_type: FunctionType) -> Self {
 
         let local = match function_type {
-            FunctionType::Method => Local::from("this".to_string(),0),
-            FunctionType::Initializer => Local::from("this".to_string(),0),
-            _ => Local::from("".to_string(),0)
+            FunctionType::Method => Local::from("this".into(),0),
+            FunctionType::Initializer => Local::from("this".into(),0),
+            _ => Local::from("".into(),0)
         };
         Compiler {
             enclosing,
@@ -102,8 +175,11 @@ impl Compiler {
         }
     }
 
-    pub fn add_local(&mut self, name: String, depth: isize) {
-        self.locals.push(Local::from(name, depth));
+    pub fn add_local(&mut self, name: Rc<str>, depth: isize, declared_at: &Token) {
+        let mut local = Local::from(name, depth);
+        local.line = declared_at.line;
+        local.column = declared_at.column;
+        self.locals.push(local);
     }
 
     pub fn add_upvalues(&mut self, index: usize, is_local: bool) {
@@ -115,19 +191,34 @@ impl Compiler {
 
 struct ClassCompiler {
     pub enclosing: Option<Box<RefCell<ClassCompiler>>>,
-    pub has_superclass: bool
+    pub has_superclass: bool,
+    /// The init method is being compiled
+    pub in_init: bool,
+    /// The init method calls super.init
+    pub init_calls_super: bool,
 }
 
 impl ClassCompiler {
     pub fn new(enclosing: Option<Box<RefCell<ClassCompiler>>>) -> Self {
         ClassCompiler{
             enclosing,
-            has_superclass: false
+            has_superclass: false,
+            in_init: false,
+            init_calls_super: false,
         }
     }
 
 }
 
+/// What the compiler knows about a global class declared earlier in the source
+#[derive(Clone, Default)]
+struct ClassInfo {
+    /// Abstract methods it has left, inherited or its own
+    abstract_methods: Vec<Rc<str>>,
+    /// Parameters of its initializer, inherited when it defines none
+    init_arity: Option<usize>,
+}
+
 #[derive(Copy, Clone)]
 #[derive(PartialEq, PartialOrd)]
 #[repr(u8)]
@@ -144,6 +235,24 @@ enum Precedence {
     Call
 }
 
+impl Precedence {
+    /// Next tighter binding level, the right operand of a left associative
+    /// binary operator is parsed at it
+    fn higher(self) -> Precedence {
+        return match self {
+            Precedence::None => Precedence::Assignment,
+            Precedence::Assignment => Precedence::Or,
+            Precedence::Or => Precedence::And,
+            Precedence::And => Precedence::Equality,
+            Precedence::Equality => Precedence::Comparison,
+            Precedence::Comparison => Precedence::Term,
+            Precedence::Term => Precedence::Factor,
+            Precedence::Factor => Precedence::Unary,
+            Precedence::Unary | Precedence::Call => Precedence::Call,
+        };
+    }
+}
+
 #[derive(Copy, Clone, Debug)]
 enum ParseFn {
     None,
@@ -157,9 +266,31 @@ enum ParseFn {
     Literal,
     And,
     Or,
+    NilCoalesce,
     Dot,
     This,
     Super,
+    List,
+    Map,
+    PreIncrement,
+    Match,
+    Index,
+}
+
+/// Step from the match subject to the value a nested pattern looks at
+#[derive(Copy, Clone)]
+enum PathStep {
+    Index(usize),
+    Rest(usize),
+    Field(usize),
+}
+
+/// Shape of a call's argument list
+#[derive(Copy, Clone, PartialEq)]
+enum ArgumentForm {
+    Positional,
+    Spread,
+    Named,
 }
 
 #[derive(Copy, Clone)]
@@ -170,7 +301,7 @@ struct ParseRule {
 }
 
 impl ParseRule {
-    pub fn from(prefix: ParseFn,
+    pub const fn from(prefix: ParseFn,
                 infix: ParseFn,
                 precedence: Precedence) -> Self {
         ParseRule {
@@ -181,6 +312,82 @@ impl ParseRule {
     }
 }
 
+/// Parse rules for precedence based on Pratt algorithm, indexed by token type.
+/// Tokens without an entry neither start nor continue an expression
+const PARSE_RULES: [ParseRule; TokenType::Eof as usize + 1] = {
+    let mut rules = [ParseRule::from(ParseFn::None, ParseFn::None, Precedence::None); TokenType::Eof as usize + 1];
+    rules[TokenType::LeftParen as usize] = ParseRule::from(ParseFn::Grouping, ParseFn::Call, Precedence::Call);
+    rules[TokenType::Dot as usize] = ParseRule::from(ParseFn::None, ParseFn::Dot, Precedence::Call);
+    rules[TokenType::LeftBracket as usize] = ParseRule::from(ParseFn::List, ParseFn::Index, Precedence::Call);
+    rules[TokenType::LeftBrace as usize] = ParseRule::from(ParseFn::Map, ParseFn::None, Precedence::None);
+    rules[TokenType::Match as usize] = ParseRule::from(ParseFn::Match, ParseFn::None, Precedence::None);
+    rules[TokenType::Minus as usize] = ParseRule::from(ParseFn::Unary, ParseFn::Binary, Precedence::Term);
+    rules[TokenType::Plus as usize] = ParseRule::from(ParseFn::None, ParseFn::Binary, Precedence::Term);
+    rules[TokenType::PlusPlus as usize] = ParseRule::from(ParseFn::PreIncrement, ParseFn::None, Precedence::None);
+    rules[TokenType::MinusMinus as usize] = ParseRule::from(ParseFn::PreIncrement, ParseFn::None, Precedence::None);
+    rules[TokenType::Slash as usize] = ParseRule::from(ParseFn::None, ParseFn::Binary, Precedence::Factor);
+    rules[TokenType::Star as usize] = ParseRule::from(ParseFn::None, ParseFn::Binary, Precedence::Factor);
+    rules[TokenType::Bang as usize] = ParseRule::from(ParseFn::Unary, ParseFn::None, Precedence::None);
+    rules[TokenType::EqualEqual as usize] = ParseRule::from(ParseFn::None, ParseFn::Binary, Precedence::Equality);
+    rules[TokenType::BangEqual as usize] = ParseRule::from(ParseFn::None, ParseFn::Binary, Precedence::Equality);
+    rules[TokenType::Greater as usize] = ParseRule::from(ParseFn::None, ParseFn::Binary, Precedence::Comparison);
+    rules[TokenType::GreaterEqual as usize] = ParseRule::from(ParseFn::None, ParseFn::Binary, Precedence::Comparison);
+    rules[TokenType::Less as usize] = ParseRule::from(ParseFn::None, ParseFn::Binary, Precedence::Comparison);
+    rules[TokenType::LessEqual as usize] = ParseRule::from(ParseFn::None, ParseFn::Binary, Precedence::Comparison);
+    rules[TokenType::Identifier as usize] = ParseRule::from(ParseFn::Variable, ParseFn::None, Precedence::None);
+    rules[TokenType::String as usize] = ParseRule::from(ParseFn::String, ParseFn::None, Precedence::None);
+    rules[TokenType::Number as usize] = ParseRule::from(ParseFn::Number, ParseFn::None, Precedence::None);
+    rules[TokenType::And as usize] = ParseRule::from(ParseFn::None, ParseFn::And, Precedence::And);
+    rules[TokenType::Or as usize] = ParseRule::from(ParseFn::None, ParseFn::Or, Precedence::Or);
+    rules[TokenType::QuestionQuestion as usize] = ParseRule::from(ParseFn::None, ParseFn::NilCoalesce, Precedence::Or);
+    rules[TokenType::False as usize] = ParseRule::from(ParseFn::Literal, ParseFn::None, Precedence::None);
+    rules[TokenType::Super as usize] = ParseRule::from(ParseFn::Super, ParseFn::None, Precedence::None);
+    rules[TokenType::This as usize] = ParseRule::from(ParseFn::This, ParseFn::None, Precedence::None);
+    rules[TokenType::True as usize] = ParseRule::from(ParseFn::Literal, ParseFn::None, Precedence::None);
+    rules[TokenType::Nil as usize] = ParseRule::from(ParseFn::Literal, ParseFn::None, Precedence::None);
+    rules
+};
+
+fn parse_rule(token_type: TokenType) -> &'static ParseRule {
+    return &PARSE_RULES[token_type as usize];
+}
+
+/// Tokens the parser has looked at, pulled from the scanner as it reads
+/// ahead and dropped once it has moved past them
+struct TokenWindow {
+    source: Box<dyn Iterator<Item = Token>>,
+    tokens: VecDeque<Token>,
+    /// Index in the whole token stream of the first token kept
+    start: usize,
+    /// Error tokens read so far, waiting to be reported
+    scan_errors: Vec<Token>,
+}
+
+impl TokenWindow {
+    /// Token at the index in the whole stream, the last one (Eof) past the end
+    fn get(&mut self, index: usize) -> Token {
+        while self.start + self.tokens.len() <= index {
+            match self.source.next() {
+                Some(token) if token.token_type == TokenType::Error => {
+                    self.scan_errors.push(token);
+                }
+                Some(token) => self.tokens.push_back(token),
+                None => break,
+            }
+        }
+        let offset = index - self.start;
+        return self.tokens.get(offset).or(self.tokens.back()).unwrap().clone();
+    }
+
+    /// Forget the tokens before the index
+    fn drop_before(&mut self, index: usize) {
+        while self.start < index && self.tokens.len() > 1 {
+            self.tokens.pop_front();
+            self.start += 1;
+        }
+    }
+}
+
 /// Represent a parser that transform scanned tokens into
 /// virtual machine code
 pub struct Parser {
@@ -188,60 +395,107 @@ pub struct Parser {
     curr_token_index: usize,
     panic_mode: bool,
     pub had_error: bool,
+    /// Messages of the errors reported so far
+    pub errors: Vec<String>,
+    /// Messages of the warnings reported so far, they don't stop compilation
+    pub warnings: Vec<String>,
+    /// Errors and warnings with their position, alongside the messages
+    pub diagnostics: Vec<Diagnostic>,
+    /// Turn warnings into errors
+    pub deny_warnings: bool,
+    /// Warn about global functions and classes the source never refers to.
+    /// Off for the REPL, where later inputs can still use them
+    pub warn_unused_globals: bool,
+    /// Global functions and classes with the token that declares them
+    global_declarations: Vec<(DeclarationKind, Token)>,
+    /// Global names the source refers to
+    referenced_globals: FnvHashSet<Rc<str>>,
+    /// Warn about globals the source uses but never defines, such as a
+    /// misspelled function name
+    pub warn_undefined_globals: bool,
+    /// Globals defined before this source runs, natives and earlier scripts
+    pub predefined_globals: FnvHashSet<String>,
+    /// Globals the source defines
+    defined_globals: FnvHashSet<Rc<str>>,
+    /// Tokens that read or assign a global, in source order
+    global_references: Vec<Token>,
+    /// Global classes declared so far, to check how they're instantiated and
+    /// extended
+    global_classes: FnvHashMap<Rc<str>, ClassInfo>,
+    /// Print the errors and warnings to stderr once compilation finishes
+    pub print_errors: bool,
+    /// Source text the tokens come from, to quote in diagnostics. Diagnostics
+    /// have no snippet when it's empty
+    pub source: Rc<str>,
     /// List of compilers
     compilers: Vec<Compiler>,
-    /// List of tokens
-    tokens: Vec<Token>,
+    /// Tokens from the previous one on
+    tokens: RefCell<TokenWindow>,
     /// Argument length of function
     function_arity: usize,
     /// Index to the compiler instances inside compilers
     curr_compiler_index: usize,
     current_class: Option<Box<RefCell<ClassCompiler>>>,
+    /// The last statement compiled always returns or throws, so the rest of
+    /// its block can't run
+    exited: bool,
     /// For memory management using Rust Box construct
     pub heap: Heap,
-    /// Parse rules for precedence based on Pratt algorithm
-    parse_rules: HashMap<TokenType, ParseRule>,
+    /// Print the bytecode of functions as they finish compiling
+    pub disassemble: Disassemble,
+    /// Run the peephole optimizer over functions as they finish compiling
+    pub optimize: bool,
+    /// Emit code straight from the tokens instead of building a syntax tree
+    /// first. Kept to compare the two front ends
+    pub single_pass: bool,
+    /// Token of the syntax tree node being lowered, it stands in for the
+    /// previous token when emitting code and reporting errors
+    node_token: Option<Token>,
+    /// Expressions and statements being parsed inside each other
+    nesting: usize,
 }
 
 impl Parser {
-    pub fn new(heap: Heap,
-               tokens: Vec<Token>) -> Self {
+    /// Parser over a token list or a Scanner, which is then read as the
+    /// parser goes
+    pub fn new<T>(heap: Heap, tokens: T) -> Self
+        where T: IntoIterator<Item = Token>, T::IntoIter: 'static {
+        let tokens = RefCell::new(TokenWindow {
+            source: Box::new(tokens.into_iter()),
+            tokens: VecDeque::new(),
+            start: 0,
+            scan_errors: vec![],
+        });
         Parser {
             curr_token_index: 0,
             panic_mode: false,
             had_error: false,
+            errors: vec![],
+            warnings: vec![],
+            diagnostics: vec![],
+            deny_warnings: false,
+            warn_unused_globals: true,
+            global_declarations: vec![],
+            referenced_globals: FnvHashSet::default(),
+            warn_undefined_globals: false,
+            predefined_globals: FnvHashSet::default(),
+            defined_globals: FnvHashSet::default(),
+            global_references: vec![],
+            global_classes: FnvHashMap::default(),
+            print_errors: true,
+            source: "".into(),
             compilers: vec![],
             tokens,
             function_arity: 0,
             curr_compiler_index: usize::MAX, // MAX means null
             current_class: None,
+            exited: false,
             heap,
-            parse_rules: HashMap::from([
-                (TokenType::LeftParen, ParseRule::from(ParseFn::Grouping, ParseFn::Call, Precedence::Call)),
-                (TokenType::Dot, ParseRule::from(ParseFn::None, ParseFn::Dot, Precedence::Call)),
-                (TokenType::Minus, ParseRule::from(ParseFn::Unary, ParseFn::Binary, Precedence::Term)),
-                (TokenType::Plus, ParseRule::from(ParseFn::None, ParseFn::Binary, Precedence::Term)),
-                (TokenType::Slash, ParseRule::from(ParseFn::None, ParseFn::Binary, Precedence::Factor)),
-                (TokenType::Star, ParseRule::from(ParseFn::None, ParseFn::Binary, Precedence::Factor)),
-                (TokenType::Bang, ParseRule::from(ParseFn::Unary, ParseFn::None, Precedence::None)),
-                (TokenType::EqualEqual, ParseRule::from(ParseFn::None, ParseFn::Binary, Precedence::Equality)),
-                (TokenType::BangEqual, ParseRule::from(ParseFn::None, ParseFn::Binary, Precedence::Equality)),
-                (TokenType::Equal, ParseRule::from(ParseFn::None, ParseFn::Binary, Precedence::Equality)),
-                (TokenType::Greater, ParseRule::from(ParseFn::None, ParseFn::Binary, Precedence::Comparison)),
-                (TokenType::GreaterEqual, ParseRule::from(ParseFn::None, ParseFn::Binary, Precedence::Comparison)),
-                (TokenType::Less, ParseRule::from(ParseFn::None, ParseFn::Binary, Precedence::Comparison)),
-                (TokenType::LessEqual, ParseRule::from(ParseFn::None, ParseFn::Binary, Precedence::Comparison)),
-                (TokenType::Identifier, ParseRule::from(ParseFn::Variable, ParseFn::None, Precedence::None)),
-                (TokenType::String, ParseRule::from(ParseFn::String, ParseFn::None, Precedence::None)),
-                (TokenType::Number, ParseRule::from(ParseFn::Number, ParseFn::None, Precedence::None)),
-                (TokenType::And, ParseRule::from(ParseFn::None, ParseFn::And, Precedence::And)),
-                (TokenType::Or, ParseRule::from(ParseFn::None, ParseFn::Or, Precedence::Or)),
-                (TokenType::False, ParseRule::from(ParseFn::Literal, ParseFn::None, Precedence::None)),
-                (TokenType::Super, ParseRule::from(ParseFn::Super, ParseFn::None, Precedence::None)),
-                (TokenType::This, ParseRule::from(ParseFn::This, ParseFn::None, Precedence::None)),
-                (TokenType::True, ParseRule::from(ParseFn::Literal, ParseFn::None, Precedence::None)),
-                (TokenType::Nil, ParseRule::from(ParseFn::Literal, ParseFn::None, Precedence::None))
-            ]),
+            disassemble: Disassemble::None,
+            optimize: true,
+            single_pass: false,
+            node_token: None,
+            nesting: 0,
         }
     }
 
@@ -249,6 +503,8 @@ impl Parser {
     ///
     /// Returns the function pointer to main
     pub fn compile(&mut self) -> usize {
+        let _span = info_span!("compile", single_pass = self.single_pass).entered();
+        let statements = if self.single_pass { vec![] } else { self.parse() };
 
         let function_name = "main".to_string();
         let function = Function::new(function_name, self.function_arity);
@@ -259,22 +515,33 @@ impl Parser {
         self.curr_compiler_index = self.compilers.len();
         self.compilers.push(compiler);
 
-        while !self.is_at_end() {
-            self.declaration()
+        if self.single_pass {
+            self.declarations_until(TokenType::Eof);
+        } else if !self.had_error {
+            // Syntax errors leave gaps in the tree, so it isn't lowered
+            self.lower_declarations(&statements);
         }
 
-        return self.end_compiler();
+        let main_func_idx = self.end_compiler();
+        if self.warn_unused_globals {
+            self.warn_unused_globals();
+        }
+        if self.warn_undefined_globals {
+            self.warn_undefined_globals();
+        }
+        self.report_diagnostics();
+        return main_func_idx;
     }
 
     /// Begin a new scope
     fn begin_scope(&mut self) {
-        let index = self.curr_compiler_index as usize;
+        let index = self.curr_compiler_index;
         self.compilers[index].scope_depth += 1;
     }
 
     /// End the current scope
     fn end_scope(&mut self) {
-        let index = self.curr_compiler_index as usize;
+        let index = self.curr_compiler_index;
         self.compilers[index].scope_depth -= 1;
         let mut curr_local_count = self.current_compiler().locals.len();
 
@@ -288,7 +555,8 @@ impl Parser {
                 self.emit_byte(Opcode::Pop.byte());
             }
             // Pop the current local variable
-            self.compilers[self.curr_compiler_index as usize].locals.pop();
+            let local = self.compilers[self.curr_compiler_index].locals.pop().unwrap();
+            self.warn_if_unused(&local);
 
             curr_local_count = self.current_compiler().locals.len();
         }
@@ -298,21 +566,72 @@ impl Parser {
     fn end_compiler(&mut self) -> usize {
         self.emit_return();
 
-        let func_index = self.compilers[self.curr_compiler_index as usize].function_idx;
-        let chunk = self.heap.get_mut_function(func_index).chunk.clone();
+        // Locals of the function body go away with the frame, without an end_scope
+        let locals = self.compilers[self.curr_compiler_index].locals.clone();
+        for local in &locals {
+            self.warn_if_unused(local);
+        }
+
+        let func_index = self.compilers[self.curr_compiler_index].function_idx;
+        let mut chunk = self.heap.get_mut_function(func_index).chunk.clone();
+
+        if self.optimize && !self.had_error {
+            chunk = optimize(&chunk, &self.heap);
+            self.heap.get_mut_function(func_index).chunk = chunk.clone();
+        }
 
         if !self.had_error {
-            if DEBUG_MACHINE_CODE {
-                disassemble_chunk(&chunk, &self.heap, &self.current_function().name);
+            let name = self.current_function().name.to_string();
+            let selected = match &self.disassemble {
+                Disassemble::None => false,
+                Disassemble::All => true,
+                Disassemble::Function(function_name) => *function_name == name,
+            };
+            if selected {
+                disassemble_chunk(&chunk, &self.heap, &name);
             }
         }
 
-        let enclosing = self.compilers[self.curr_compiler_index as usize].enclosing;
+        let enclosing = self.compilers[self.curr_compiler_index].enclosing;
         self.curr_compiler_index = enclosing;
 
         return func_index;
     }
 
+    /// Compile the tokens as a single expression whose value main returns,
+    /// a trailing semicolon is allowed.
+    ///
+    /// Returns the function pointer to main
+    pub fn compile_expression(&mut self) -> usize {
+        let _span = info_span!("compile_expression", single_pass = self.single_pass).entered();
+        let expression = if self.single_pass { None } else { Some(self.parse_expression()) };
+
+        let function = Function::new("main".to_string(), 0);
+        let main_func_idx = self.heap.alloc_function(function);
+
+        let compiler = Compiler::new(usize::MAX, main_func_idx, FunctionType::Main);
+        self.curr_compiler_index = self.compilers.len();
+        self.compilers.push(compiler);
+
+        if self.single_pass {
+            self.expression();
+        }
+        self.match_token_type(TokenType::Semicolon);
+        if !self.is_at_end() {
+            self.error_at_current("Expect end of expression.");
+        }
+        self.take_scan_errors();
+        match expression {
+            Some(expression) if !self.had_error => self.lower_expression(&expression),
+            _ => {}
+        }
+        self.emit_byte(Opcode::Return.byte());
+
+        let main_func_idx = self.end_compiler();
+        self.report_diagnostics();
+        return main_func_idx;
+    }
+
     /// Check if the current token match the given token type
     /// Note: this call does not consume the token
     fn check(&self, token_type: TokenType) -> bool {
@@ -325,7 +644,12 @@ impl Parser {
 
     /// Peek the current token
     fn peek(&self) -> Token {
-        return self.tokens.get(self.curr_token_index).unwrap().clone();
+        return self.token_at(self.curr_token_index);
+    }
+
+    /// Token at the index in the whole stream, reading ahead as needed
+    fn token_at(&self, index: usize) -> Token {
+        return self.tokens.borrow_mut().get(index);
     }
 
     /// Are we at EOF yet?
@@ -337,13 +661,29 @@ impl Parser {
     fn advance(&mut self) -> Token {
         if !self.is_at_end() {
             self.curr_token_index += 1;
+            self.tokens.borrow_mut().drop_before(self.curr_token_index - 1);
         }
+        self.take_scan_errors();
         return self.previous();
     }
 
-    /// Retrieve the previous token
+    /// Retrieve the previous token, or the first one before anything is consumed.
+    /// While lowering the syntax tree it is the token of the node being lowered
     fn previous(&self) -> Token {
-        self.tokens.get(self.curr_token_index - 1).unwrap().clone()
+        if let Some(token) = &self.node_token {
+            return token.clone();
+        }
+        return self.token_at(self.curr_token_index.saturating_sub(1));
+    }
+
+    /// Report the errors the scanner ran into while the parser read ahead
+    fn take_scan_errors(&mut self) {
+        let scan_errors = mem::take(&mut self.tokens.borrow_mut().scan_errors);
+        for token in scan_errors {
+            self.errors.push(token.literal.to_string());
+            self.diagnostics.push(Diagnostic::new(Severity::Error, token.line, token.column, &token.lexeme));
+            self.had_error = true;
+        }
     }
 
     /// Eat the current token
@@ -355,9 +695,25 @@ impl Parser {
         self.error_at_current(message);
     }
 
+    /// Count one more level of nesting, reporting an error when there are
+    /// too many for the parser's recursion to stay within the stack. The
+    /// caller leaves the level whether or not it could enter it
+    fn enter_nesting(&mut self) -> bool {
+        self.nesting += 1;
+        if self.nesting > MAX_NESTING {
+            self.error_at_current("Too much nesting.");
+            return false;
+        }
+        return true;
+    }
+
     /// Report error at current token
     fn error_at_current(&mut self, message: &str) {
-        self.error_at(self.peek(), message);
+        let token = match &self.node_token {
+            Some(token) => token.clone(),
+            None => self.peek(),
+        };
+        self.error_at(token, message);
     }
 
     /// Report error at previous token
@@ -371,21 +727,125 @@ impl Parser {
             return;
         }
         self.panic_mode = true;
-        eprint!("[line {}] Error ", token.line);
-        if token.token_type == TokenType::Eof {
-            eprintln!("at end ");
-        } else if token.token_type == TokenType::Error {
-            // do nothing
-        } else {
-            eprintln!("at {}", token.literal)
-        }
-        eprintln!("{}", message);
+        let location = match token.token_type {
+            TokenType::Eof => " at end".to_string(),
+            TokenType::Error => "".to_string(),
+            _ => format!(" at '{}'", token.lexeme),
+        };
+        let snippet = self.snippet(token.line, token.column, token.lexeme.chars().count());
+        self.errors.push(format!("[line {}] Error{}: {}{}", token.line, location, message, snippet));
+        self.diagnostics.push(Diagnostic::new(Severity::Error, token.line, token.column, message));
         self.had_error = true;
     }
 
+    /// Report a warning at the current token
+    fn warning_at_current(&mut self, message: &str) {
+        let token = self.peek();
+        self.warning_at(token.line, token.column, &token.lexeme, message);
+    }
+
+    fn warning_at(&mut self, line: usize, column: usize, lexeme: &str, message: &str) {
+        let snippet = self.snippet(line, column, lexeme.chars().count());
+        self.warnings.push(format!("[line {}] Warning at '{}': {}{}", line, lexeme, message, snippet));
+        self.diagnostics.push(Diagnostic::new(Severity::Warning, line, column, message));
+    }
+
+    /// Source line to show under a diagnostic
+    fn snippet(&self, line: usize, column: usize, width: usize) -> String {
+        if self.source.is_empty() {
+            return "".to_string();
+        }
+        return source_snippet(&self.source, line, column, width);
+    }
+
+    /// Warn about a local that goes out of scope without ever being read.
+    /// Parameters, hidden locals and names starting with an underscore are left alone
+    fn warn_if_unused(&mut self, local: &Local) {
+        let ignored = local.is_read || local.kind == DeclarationKind::Parameter
+            || local.name.is_empty() || local.name.starts_with('_') || local.name.starts_with(' ')
+            || &*local.name == "this" || &*local.name == "super";
+        if !ignored {
+            self.warning_at(local.line, local.column, &local.name, &format!("Unused {} '{}'.", local.kind, local.name));
+        }
+    }
+
+    fn warn_unused_globals(&mut self) {
+        let declarations = mem::take(&mut self.global_declarations);
+        for (kind, token) in declarations {
+            let name = &token.lexeme;
+            if !self.referenced_globals.contains(name) && !name.starts_with('_') {
+                self.warning_at(token.line, token.column, name, &format!("Unused {} '{}'.", kind, name));
+            }
+        }
+    }
+
+    /// Warn once per name about globals that are neither defined by the
+    /// source nor beforehand
+    fn warn_undefined_globals(&mut self) {
+        let references = mem::take(&mut self.global_references);
+        let mut reported: FnvHashSet<Rc<str>> = FnvHashSet::default();
+        for token in references {
+            let name = &token.lexeme;
+            if self.defined_globals.contains(name) || self.predefined_globals.contains(&**name)
+                || !reported.insert(Rc::clone(name)) {
+                continue;
+            }
+            self.warning_at(token.line, token.column, name, &format!("Undefined variable '{}'.", name));
+        }
+    }
+
+    /// Record that the name is read, marking the innermost local of that name
+    /// in this or an enclosing function, or else the global
+    fn mark_read(&mut self, name: &Rc<str>) {
+        let mut compiler_idx = self.curr_compiler_index;
+        while compiler_idx != usize::MAX {
+            let compiler = &mut self.compilers[compiler_idx];
+            if let Some(local) = compiler.locals.iter_mut().rev().find(|local| local.name == *name) {
+                local.is_read = true;
+                return;
+            }
+            compiler_idx = compiler.enclosing;
+        }
+        self.referenced_globals.insert(Rc::clone(name));
+    }
+
+    /// Record what the name just declared is, globals are checked for use at the end
+    fn declared_as(&mut self, kind: DeclarationKind) {
+        let token = self.previous();
+        if self.current_scope_depth() > 0 {
+            let index = self.curr_compiler_index;
+            self.compilers[index].locals.last_mut().unwrap().kind = kind;
+        } else if kind != DeclarationKind::Variable {
+            self.global_declarations.push((kind, token));
+        }
+    }
+
+    /// Turn the warnings into errors when they are denied, then print every
+    /// warning and error found while compiling
+    fn report_diagnostics(&mut self) {
+        self.take_scan_errors();
+        // Unused locals are found when their scope ends, list everything in source order
+        self.warnings.sort_by_key(|warning| diagnostic_line(warning));
+        self.diagnostics.sort_by_key(|diagnostic| (diagnostic.line, diagnostic.column));
+        if self.deny_warnings && !self.warnings.is_empty() {
+            self.errors.append(&mut self.warnings);
+            for diagnostic in self.diagnostics.iter_mut() {
+                diagnostic.severity = Severity::Error;
+            }
+            self.had_error = true;
+        }
+        debug!(errors = self.errors.len(), warnings = self.warnings.len(), "compiled");
+        if !self.print_errors {
+            return;
+        }
+        for diagnostic in self.warnings.iter().chain(self.errors.iter()) {
+            eprintln!("{}", diagnostic);
+        }
+    }
+
     /// Helper method to retrieve current function as mutable
-    fn current_function(&self) -> RefMut<Function> {
-        let fn_hash = &self.compilers[self.curr_compiler_index as usize].function_idx;
+    fn current_function(&self) -> RefMut<'_, Function> {
+        let fn_hash = &self.compilers[self.curr_compiler_index].function_idx;
         self.heap.functions[*fn_hash].borrow_mut()
     }
 
@@ -414,17 +874,38 @@ impl Parser {
     }
 
     /// Shortcut for writing a jump instruction to function chunk
-    fn emit_jump(&mut self, instruction: u8) -> u8 {
+    fn emit_jump(&mut self, instruction: u8) -> usize {
         self.emit_byte(instruction);
         self.emit_byte(0xff);
         self.emit_byte(0xff);
-        return (self.current_function().chunk.code.len() - 2) as u8;
+        return self.current_function().chunk.code.len() - 2;
     }
 
     /// Shortcut for writing constant to function chunk
+    /// Past the first 256 constants the index is written as 24 bits with ConstantLong
     fn emit_constant(&mut self, value: Value) {
-        let constant = self.make_constant(value);
-        self.emit_bytes(Opcode::Constant as u8, constant);
+        let constant = self.current_function().chunk.add_constants(value);
+        if constant <= u8::MAX as usize {
+            self.emit_bytes(Opcode::Constant as u8, constant as u8);
+        } else if constant < MAX_LONG_CONSTANTS {
+            self.emit_byte(Opcode::ConstantLong.byte());
+            self.emit_byte(((constant >> 16) & 0xff) as u8);
+            self.emit_byte(((constant >> 8) & 0xff) as u8);
+            self.emit_byte((constant & 0xff) as u8);
+        } else {
+            self.error_at_current("Too many constants in one chunk");
+        }
+    }
+
+    /// Emit an instruction whose operand byte indexes a constant. Past the
+    /// first 256 constants a Wide instruction before it carries the upper 16
+    /// bits of the index
+    fn emit_constant_op(&mut self, op: u8, constant: usize) {
+        if constant > u8::MAX as usize {
+            self.emit_byte(Opcode::Wide.byte());
+            self.emit_short(constant >> 8);
+        }
+        self.emit_bytes(op, (constant & 0xff) as u8);
     }
 
     /// Shortcut for writing loop statement to function chunk
@@ -440,8 +921,8 @@ impl Parser {
 
     /// Short cut for patching current jump location to the given offset
     fn patch_jump(&mut self, offset: usize) {
-        let jump = (self.current_function().chunk.code.len() - offset - 2) as u16;
-        if jump >= 65535 {
+        let jump = self.current_function().chunk.code.len() - offset - 2;
+        if jump > u16::MAX as usize {
             self.error("Too much code to jump over");
         }
         self.current_function().chunk.code[offset] = ((jump >> 8) & 0xff) as u8;
@@ -461,6 +942,8 @@ impl Parser {
             self.fun_declaration();
         } else if self.match_token_type(TokenType::Var) {
             self.var_declaration();
+        } else if self.match_token_type(TokenType::Const) {
+            self.const_declaration();
         } else if self.match_token_type(TokenType::Class) {
            self.class_declaration();
         } else {
@@ -473,12 +956,13 @@ impl Parser {
 
     fn fun_declaration(&mut self) {
         let global = self.parse_variable("Expect a function name");
+        self.declared_as(DeclarationKind::Function);
         self.mark_initialized();
         self.function(FunctionType::Function);
-        self.define_variable(global);
+        self.define_declaration(global);
     }
 
-    fn function(&mut self, function_type: FunctionType) {
+    fn function(&mut self, function_type: FunctionType) -> usize {
 
         let function_name = match function_type {
             FunctionType::Main => "Main".to_string(),
@@ -499,12 +983,26 @@ impl Parser {
         self.consume(TokenType::LeftParen, "Expect '(' after function name");
         if !self.check(TokenType::RightParen) {
             loop {
+                if self.match_token_type(TokenType::Ellipsis) {
+                    // Rest parameter, not counted in arity
+                    self.current_function().is_variadic = true;
+                    let constant = self.parse_variable("Expect a rest parameter name");
+                    self.declared_as(DeclarationKind::Parameter);
+                    self.define_variable(constant);
+                    if self.check(TokenType::Comma) {
+                        self.error_at_current("Rest parameter must be the last parameter");
+                    }
+                    break;
+                }
                 self.current_function().arity += 1;
-                if self.current_function().arity >= 255 {
-                    self.error_at_current("Can't have more than 255 parameters");
+                if self.current_function().arity >= MAX_LOCALS - 1 {
+                    self.error_at_current("Can't have more than 65534 parameters");
                 }
                 let constant = self.parse_variable("Expect a parameter name");
+                self.declared_as(DeclarationKind::Parameter);
                 self.define_variable(constant);
+                let param_name = self.previous().lexeme.to_string();
+                self.current_function().param_names.push(param_name);
                 if !self.match_token_type(TokenType::Comma) {
                     break;
                 }
@@ -512,27 +1010,42 @@ impl Parser {
         }
         self.consume(TokenType::RightParen, "Expect ')' after parameters");
         self.consume(TokenType::LeftBrace, "Expect '{' before function body");
-        self.block();
+        if self.enter_nesting() {
+            self.block();
+        }
+        self.nesting -= 1;
+        // Returning from the body doesn't make the code after the declaration unreachable
+        self.exited = false;
 
         self.end_compiler();
+        self.emit_closure(func_idx, compiler_idx);
+        return func_idx;
+    }
 
+    /// Emit the closure for a function compiled by the given compiler along
+    /// with how to capture each of its upvalues. ClosureLong gives every index
+    /// 16 bits when one of them doesn't fit in a byte
+    fn emit_closure(&mut self, func_idx: usize, compiler_idx: usize) {
         let constant = self.make_constant(Value::Obj(Object::FunctionIndex(func_idx)));
-        // self.emit_bytes(Opcode::Constant.byte(), constant );
-        self.emit_bytes(Opcode::Closure.byte(), constant);
+        let upvalue_count = self.heap.functions[func_idx].borrow().upvalue_count;
+        let is_wide = self.compilers[compiler_idx].upvalues[..upvalue_count].iter()
+            .any(|upvalue| upvalue.index > u8::MAX as usize);
+        let opcode = if is_wide { Opcode::ClosureLong } else { Opcode::Closure };
+        self.emit_constant_op(opcode.byte(), constant);
 
-        let mut upvalue_count = 0;
-        if self.heap.functions[func_idx].borrow().upvalue_count > 0 {
-            upvalue_count = self.heap.functions[func_idx].borrow().upvalue_count;
-        }
         for i in 0..upvalue_count {
             let is_local = self.compilers[compiler_idx].upvalues[i].is_local;
-            let upvalue_index_byte = self.compilers[compiler_idx].upvalues[i].index as u8;
+            let index = self.compilers[compiler_idx].upvalues[i].index;
             if is_local {
                 self.emit_byte(1u8);
             } else {
                 self.emit_byte(0u8);
             }
-            self.emit_byte(upvalue_index_byte);
+            if is_wide {
+                self.emit_short(index);
+            } else {
+                self.emit_byte(index as u8);
+            }
         }
     }
 
@@ -547,16 +1060,40 @@ impl Parser {
         self.define_variable(global);
     }
 
-    fn define_variable(&mut self, global: u8) {
+    fn const_declaration(&mut self) {
+        let global = self.parse_variable("Expect a constant name.");
+        self.consume(TokenType::Equal, "Expect '=' after constant name.");
+        self.expression();
+        self.consume(TokenType::Semicolon, "Expect ';' after constant declaration.");
+        if self.current_scope_depth() > 0 {
+            let index = self.curr_compiler_index;
+            self.compilers[index].locals.last_mut().unwrap().is_const = true;
+            self.mark_initialized();
+            return;
+        }
+        self.emit_constant_op(Opcode::DefineConstGlobal.byte(), global)
+    }
+
+    fn define_variable(&mut self, global: usize) {
+        if self.current_scope_depth() > 0 {
+            self.mark_initialized();
+            return;
+        }
+        self.emit_constant_op(Opcode::DefineGlobal.byte(), global)
+    }
+
+    /// Define the global a function or class declaration names. Unlike a
+    /// variable, hot redefinition updates the object it already holds
+    fn define_declaration(&mut self, global: usize) {
         if self.current_scope_depth() > 0 {
             self.mark_initialized();
             return;
         }
-        self.emit_bytes(Opcode::DefineGlobal as u8, global)
+        self.emit_constant_op(Opcode::DefineDeclaration.byte(), global)
     }
 
     fn mark_initialized(&mut self) {
-        let index = self.curr_compiler_index as usize;
+        let index = self.curr_compiler_index;
         let locals_len = self.compilers[index].locals.len();
         if locals_len > 0 {
             self.compilers[index].locals[locals_len-1].depth = self.current_scope_depth();
@@ -564,11 +1101,17 @@ impl Parser {
     }
 
     fn current_scope_depth(&mut self) -> isize {
-        self.compilers[self.curr_compiler_index as usize].scope_depth
+        self.compilers[self.curr_compiler_index].scope_depth
     }
 
-    fn parse_variable(&mut self, error_message: &str) -> u8 {
+    fn parse_variable(&mut self, error_message: &str) -> usize {
         self.consume(TokenType::Identifier, error_message);
+        return self.declare_name();
+    }
+
+    /// Declare the variable named by the previous token, returning the
+    /// constant of its name when it is a global
+    fn declare_name(&mut self) -> usize {
         self.declare_variable();
         if self.current_scope_depth() > 0 {
             return 0;
@@ -578,6 +1121,8 @@ impl Parser {
 
     fn declare_variable(&mut self) {
         if self.current_scope_depth() == 0 {
+            let name = Rc::clone(&self.previous().lexeme);
+            self.defined_globals.insert(name);
             return;
         }
         let name = &self.previous().lexeme;
@@ -592,36 +1137,42 @@ impl Parser {
                 self.error("Already a variable of this name in this scope");
             }
         }
-        self.compilers[self.curr_compiler_index as usize].add_local(name.to_string(), -1);
+        if self.current_compiler().locals.len() >= MAX_LOCALS {
+            self.error("Too many local variables in function.");
+        }
+        let declared_at = self.previous();
+        self.compilers[self.curr_compiler_index].add_local(Rc::clone(name), -1, &declared_at);
     }
 
     fn current_compiler(&mut self) -> &Compiler {
-        return &self.compilers[self.curr_compiler_index as usize];
+        return &self.compilers[self.curr_compiler_index];
     }
 
-    fn identifier_constant(&mut self, token_name: &str) -> u8 {
+    fn identifier_constant(&mut self, token_name: &str) -> usize {
         let string_hash = self.heap.alloc_string(token_name.to_string());
         return self.make_constant( Value::object(Object::string(string_hash)));
     }
 
-    fn make_constant(&mut self, value: Value) -> u8 {
+    /// Constant for instructions naming a global, property or function, see emit_constant_op
+    fn make_constant(&mut self, value: Value) -> usize {
         let constant_index = self.current_function().chunk.add_constants(value);
-        if constant_index >= 255 {
+        if constant_index >= MAX_LONG_CONSTANTS {
             self.error_at_current("Too many constants in one chunk");
         }
         return constant_index;
     }
 
+    /// Skip tokens up to the start of the next statement
     fn synchronize(&mut self) {
         self.panic_mode = false;
-        self.advance();
         while !self.is_at_end() {
             if matches!(self.previous().token_type, TokenType::Semicolon) {
                 return;
             }
             match self.peek().token_type {
                 TokenType::Class | TokenType::For    | TokenType::Fun | TokenType::If |
-                TokenType::Print | TokenType::Return | TokenType::Var |
+                TokenType::Print | TokenType::Return | TokenType::Var | TokenType::Const |
+                TokenType::Try | TokenType::Throw |
                 TokenType::While  => { return; }
                 _ => {
                     self.advance();
@@ -630,40 +1181,37 @@ impl Parser {
         }
     }
 
-    // fixme: This can go into infinite loop when there is an error with parsing inside this function
     fn parse_precedence(&mut self, precedence: Precedence) {
-        self.advance();
+        if self.enter_nesting() {
+            self.nested_parse_precedence(precedence);
+        }
+        self.nesting -= 1;
+    }
 
-        let prefix_rule_option = self.parse_rules.get(&self.previous().token_type);
-        let mut prefix_rule: Option<ParseFn> = Some(ParseFn::None);
-        if prefix_rule_option.is_some() {
-            prefix_rule = Some(prefix_rule_option.unwrap().prefix);
+    fn nested_parse_precedence(&mut self, precedence: Precedence) {
+        if self.is_at_end() {
+            // Advancing would hand back the previous token and parse it again
+            self.error_at_current("Expect expression");
+            return;
         }
+        self.advance();
 
+        let prefix_rule = parse_rule(self.previous().token_type).prefix;
         let can_assign = precedence <= Precedence::Assignment;
 
-        if self.call_rule_function(&mut prefix_rule, can_assign) == false {
-            panic!("");
+        if !self.call_rule_function(prefix_rule, can_assign) {
+            // Reported as a compile error, the declaration loop resynchronizes
+            return;
         }
 
         loop {
-            let parse_rule_option = self.parse_rules.get(&self.peek().token_type);
-            let mut precedence_rule: Option<Precedence> = Some(Precedence::None);
-            if parse_rule_option.is_some() {
-                precedence_rule = Some(parse_rule_option.unwrap().precedence);
-            }
-
-            if precedence > precedence_rule.unwrap() {
+            if precedence > parse_rule(self.peek().token_type).precedence {
                 break;
             }
 
             self.advance();
-            let infix_rule_option = self.parse_rules.get(&self.previous().token_type);
-            let mut infix_rule: Option<ParseFn> = Some(ParseFn::None);
-            if infix_rule_option.is_some() {
-                infix_rule = Some(infix_rule_option.unwrap().infix);
-            }
-            if self.call_rule_function(&mut infix_rule, can_assign) == false {
+            let infix_rule = parse_rule(self.previous().token_type).infix;
+            if !self.call_rule_function(infix_rule, can_assign) {
                 return;
             }
         }
@@ -673,8 +1221,8 @@ impl Parser {
         }
     }
 
-    fn call_rule_function(&mut self, prefix_rule: &mut Option<ParseFn>, can_assign: bool) -> bool {
-        match prefix_rule.unwrap() {
+    fn call_rule_function(&mut self, rule: ParseFn, can_assign: bool) -> bool {
+        match rule {
             ParseFn::None => {
                 self.error("Expect expression");
                 return false;
@@ -689,9 +1237,15 @@ impl Parser {
             ParseFn::Literal => self.literal(),
             ParseFn::And => self.and(),
             ParseFn::Or => self.or(),
+            ParseFn::NilCoalesce => self.nil_coalesce(),
             ParseFn::Dot => self.dot(can_assign),
             ParseFn::This => self.this(),
-            ParseFn::Super => self.super_()
+            ParseFn::Super => self.super_(),
+            ParseFn::List => self.list(),
+            ParseFn::Map => self.map(),
+            ParseFn::PreIncrement => self.pre_increment(),
+            ParseFn::Match => self.match_expression(),
+            ParseFn::Index => self.index(can_assign),
         }
         return true;
     }
@@ -701,7 +1255,7 @@ impl Parser {
     }
 
     fn string(&mut self) {
-        let string_hash = self.heap.alloc_string(self.previous().literal);
+        let string_hash = self.heap.alloc_string(self.previous().literal.to_string());
         self.emit_constant(Value::object(Object::StringHash(string_hash)));
     }
 
@@ -722,10 +1276,26 @@ impl Parser {
     }
 
     fn variable(&mut self, can_assign: bool) {
-        self.named_variable(&self.previous(), can_assign);
+        let token = self.previous();
+        if !(can_assign && self.check(TokenType::Equal)) {
+            self.mark_read(&token.lexeme);
+        }
+        if self.check(TokenType::LeftParen) {
+            if let Some(message) = self.abstract_instantiation(&token.lexeme) {
+                self.error(&message);
+            }
+        }
+        self.named_variable(&token, can_assign);
     }
 
     fn statement(&mut self) {
+        if self.enter_nesting() {
+            self.nested_statement();
+        }
+        self.nesting -= 1;
+    }
+
+    fn nested_statement(&mut self) {
         if self.match_token_type(TokenType::Print) {
             self.print_statement();
         } else if self.match_token_type(TokenType::For) {
@@ -734,15 +1304,26 @@ impl Parser {
             self.if_statement();
         } else if self.match_token_type(TokenType::Return) {
             self.return_statement();
+            self.exited = true;
+            return;
         } else if self.match_token_type(TokenType::While) {
             self.while_statement();
+        } else if self.match_token_type(TokenType::Try) {
+            self.try_statement();
+        } else if self.match_token_type(TokenType::Throw) {
+            self.throw_statement();
+            self.exited = true;
+            return;
         } else if self.match_token_type(TokenType::LeftBrace) {
+            // A block exits when one of its statements does
             self.begin_scope();
             self.block();
             self.end_scope();
+            return;
         } else {
             self.expression_statement();
         }
+        self.exited = false;
     }
 
     fn while_statement(&mut self) {
@@ -754,10 +1335,44 @@ impl Parser {
         self.emit_byte(Opcode::Pop.byte());
         self.statement();
         self.emit_loop(loop_start);
-        self.patch_jump(exit_jump as usize);
+        self.patch_jump(exit_jump);
         self.emit_byte(Opcode::Pop.byte());
     }
 
+    /// The try body runs with a handler registered; a throw or runtime error
+    /// inside it unwinds to the catch block with the thrown value in the
+    /// catch variable.
+    fn try_statement(&mut self) {
+        let handler_jump = self.emit_jump(Opcode::PushHandler.byte());
+        self.consume(TokenType::LeftBrace, "Expect '{' after try.");
+        self.begin_scope();
+        self.block();
+        self.end_scope();
+        self.emit_byte(Opcode::PopHandler.byte());
+        let end_jump = self.emit_jump(Opcode::Jump.byte());
+
+        self.patch_jump(handler_jump);
+        self.consume(TokenType::Catch, "Expect 'catch' after try block.");
+        self.consume(TokenType::LeftParen, "Expect '(' after catch.");
+        self.begin_scope();
+        self.consume(TokenType::Identifier, "Expect a catch variable name.");
+        let name = Rc::clone(&self.previous().lexeme);
+        let depth = self.current_scope_depth();
+        let declared_at = self.previous();
+        self.compilers[self.curr_compiler_index].add_local(name, depth, &declared_at);
+        self.consume(TokenType::RightParen, "Expect ')' after catch variable.");
+        self.consume(TokenType::LeftBrace, "Expect '{' before catch body.");
+        self.block();
+        self.end_scope();
+        self.patch_jump(end_jump);
+    }
+
+    fn throw_statement(&mut self) {
+        self.expression();
+        self.consume(TokenType::Semicolon, "Expect ';' after thrown value.");
+        self.emit_byte(Opcode::Throw.byte());
+    }
+
     fn if_statement(&mut self) {
         self.consume(TokenType::LeftParen, "Expect '(' after if.");
         self.expression();
@@ -768,24 +1383,35 @@ impl Parser {
         self.statement();
 
         let else_jump = self.emit_jump(Opcode::Jump.byte());
-        self.patch_jump(then_jump as usize);
+        self.patch_jump(then_jump);
         self.emit_byte(Opcode::Pop.byte());
 
         if self.match_token_type(TokenType::Else) {
             self.statement();
         }
 
-        self.patch_jump(else_jump as usize);
+        self.patch_jump(else_jump);
     }
 
     fn for_statement(&mut self) {
         self.begin_scope();
         self.consume(TokenType::LeftParen, "Expect '(' after 'for'.");
 
+        if self.is_for_in() {
+            self.for_in_statement();
+            self.end_scope();
+            return;
+        }
+
+        let mut loop_variable = None;
         if self.match_token_type(TokenType::Semicolon) {
             // No initializer
         } else if self.match_token_type(TokenType::Var) {
             self.var_declaration();
+            loop_variable = Some(self.current_compiler().locals.len() - 1);
+        } else if self.match_token_type(TokenType::Const) {
+            self.const_declaration();
+            loop_variable = Some(self.current_compiler().locals.len() - 1);
         } else {
             self.expression_statement();
         }
@@ -811,10 +1437,13 @@ impl Parser {
 
             self.emit_loop(loop_start);
             loop_start = increment_start;
-            self.patch_jump(body_jump as usize);
+            self.patch_jump(body_jump);
         }
 
-        self.statement();
+        match loop_variable {
+            Some(slot) => self.loop_body_with_fresh_variable(slot, true, |parser| parser.statement()),
+            None => self.statement()
+        }
 
         self.emit_loop(loop_start);
 
@@ -826,6 +1455,94 @@ impl Parser {
         self.end_scope();
     }
 
+    /// Is the for clause of the form `var x in` or `x in`? `in` is only a
+    /// keyword here so it stays usable as a name elsewhere.
+    fn is_for_in(&self) -> bool {
+        let offset = if self.check(TokenType::Var) { 1 } else { 0 };
+        let is_identifier = |ahead: usize| self.token_at(self.curr_token_index + ahead).token_type == TokenType::Identifier;
+        let next = self.token_at(self.curr_token_index + offset + 1);
+        let is_in = next.token_type == TokenType::Identifier && &*next.lexeme == "in";
+        return is_identifier(offset) && is_in;
+    }
+
+    /// `for (var x in collection) body` keeps the collection and the position in
+    /// hidden locals; ForIter loads the next list item or map key into x, or
+    /// jumps past the loop once the position reaches the length.
+    fn for_in_statement(&mut self) {
+        self.match_token_type(TokenType::Var);
+        self.consume(TokenType::Identifier, "Expect a loop variable name.");
+        let variable = self.previous();
+        self.consume(TokenType::Identifier, "Expect 'in' after loop variable.");
+
+        self.expression();
+        let seq_slot = self.add_hidden_local(" seq");
+        self.emit_constant(Value::number(0.0));
+        self.add_hidden_local(" index");
+        self.emit_byte(Opcode::Nil.byte());
+        let depth = self.current_scope_depth();
+        self.compilers[self.curr_compiler_index].add_local(Rc::clone(&variable.lexeme), depth, &variable);
+        self.consume(TokenType::RightParen, "Expect ')' after for clauses.");
+
+        let exit_jump = self.begin_for_iter(seq_slot);
+        let variable_slot = self.current_compiler().locals.len() - 1;
+        self.loop_body_with_fresh_variable(variable_slot, false, |parser| parser.statement());
+        self.end_for_iter(exit_jump);
+    }
+
+    /// Emit the ForIter starting a for in loop, returning the offset of its jump
+    fn begin_for_iter(&mut self, seq_slot: u8) -> usize {
+        self.emit_bytes(Opcode::ForIter.byte(), seq_slot);
+        self.emit_byte(0xff);
+        self.emit_byte(0xff);
+        return self.current_function().chunk.code.len() - 2;
+    }
+
+    /// Loop back to the ForIter after the body, whose jump leaves the loop
+    fn end_for_iter(&mut self, exit_jump: usize) {
+        self.emit_loop(exit_jump - 2);
+        self.patch_jump(exit_jump);
+    }
+
+    /// Compile a loop body that sees its own copy of the loop variable, so
+    /// closures created in different iterations don't share one slot. With
+    /// copy_back the body's changes are written back for the increment clause.
+    fn loop_body_with_fresh_variable(&mut self, slot: usize, copy_back: bool, body: impl FnOnce(&mut Self)) {
+        self.begin_scope();
+        let index = self.curr_compiler_index;
+        let mut local = self.compilers[index].locals[slot].clone();
+        local.depth = self.current_scope_depth();
+        self.emit_variable_op(Opcode::GetLocal.byte(), slot);
+        self.compilers[index].locals.push(local);
+        let copy_slot = self.compilers[index].locals.len() - 1;
+
+        body(self);
+
+        // Only the loop variable itself is reported when the body never reads it
+        let copy_read = self.compilers[index].locals[copy_slot].is_read;
+        self.compilers[index].locals[slot].is_read |= copy_read;
+        self.compilers[index].locals[copy_slot].is_read = true;
+
+        if copy_back {
+            self.emit_variable_op(Opcode::GetLocal.byte(), copy_slot);
+            self.emit_variable_op(Opcode::SetLocal.byte(), slot);
+            self.emit_byte(Opcode::Pop.byte());
+        }
+        self.end_scope();
+    }
+
+    /// Reserve a local slot that user code can't name, returning the slot
+    fn add_hidden_local(&mut self, name: &str) -> u8 {
+        let depth = self.current_scope_depth();
+        let index = self.curr_compiler_index;
+        let declared_at = self.previous();
+        self.compilers[index].add_local(name.into(), depth, &declared_at);
+        let slot = self.compilers[index].locals.len() - 1;
+        if slot > u8::MAX as usize {
+            self.error("Too many local variables before this statement.");
+        }
+        return slot as u8;
+    }
+
     fn expression_statement(&mut self) {
         self.expression();
         self.consume(TokenType::Semicolon, "Expect ';' after expression.");
@@ -839,29 +1556,23 @@ impl Parser {
     }
 
     fn binary(&mut self) {
-        let prefix_rule_option = self.parse_rules.get(&self.previous().token_type);
-        if prefix_rule_option.is_some() {
-            let prev = self.previous();
-            let prec = prefix_rule_option.unwrap().precedence as u8;
-            let next_prec: Precedence = unsafe { mem::transmute(prec + 1u8) };
-            self.parse_precedence(next_prec);
-            match prev.token_type {
-                TokenType::Plus => self.emit_byte(Opcode::Add.byte()),
-                TokenType::Star => self.emit_byte(Opcode::Multiply.byte()),
-                TokenType::Slash => self.emit_byte(Opcode::Divide.byte()),
-                TokenType::Minus => self.emit_byte(Opcode::Subtract.byte()),
-                TokenType::BangEqual => self.emit_bytes(Opcode::Equal.byte(), Opcode::Not.byte()),
-                TokenType::EqualEqual => self.emit_byte(Opcode::Equal.byte()),
-                TokenType::Less => self.emit_byte(Opcode::Less.byte()),
-                TokenType::LessEqual => self.emit_bytes(Opcode::Greater.byte(), Opcode::Not.byte()),
-                TokenType::Greater => self.emit_byte(Opcode::Greater.byte()),
-                TokenType::GreaterEqual => self.emit_bytes(Opcode::Less.byte(), Opcode::Not.byte()),
-                _ => {
-                    panic!("Unreachable code");
-                }
+        let prev = self.previous();
+        let next_prec = parse_rule(prev.token_type).precedence.higher();
+        self.parse_precedence(next_prec);
+        match prev.token_type {
+            TokenType::Plus => self.emit_byte(Opcode::Add.byte()),
+            TokenType::Star => self.emit_byte(Opcode::Multiply.byte()),
+            TokenType::Slash => self.emit_byte(Opcode::Divide.byte()),
+            TokenType::Minus => self.emit_byte(Opcode::Subtract.byte()),
+            TokenType::BangEqual => self.emit_bytes(Opcode::Equal.byte(), Opcode::Not.byte()),
+            TokenType::EqualEqual => self.emit_byte(Opcode::Equal.byte()),
+            TokenType::Less => self.emit_byte(Opcode::Less.byte()),
+            TokenType::LessEqual => self.emit_bytes(Opcode::Greater.byte(), Opcode::Not.byte()),
+            TokenType::Greater => self.emit_byte(Opcode::Greater.byte()),
+            TokenType::GreaterEqual => self.emit_bytes(Opcode::Less.byte(), Opcode::Not.byte()),
+            _ => {
+                panic!("Unreachable code");
             }
-        } else {
-            panic!("Unreachable code");
         }
     }
 
@@ -887,40 +1598,407 @@ impl Parser {
         let name = self.identifier_constant(&self.previous().lexeme);
         if can_assign && self.match_token_type(TokenType::Equal) {
             self.expression();
-            self.emit_bytes(Opcode::SetProperty.byte(), name);
+            self.emit_constant_op(Opcode::SetProperty.byte(), name);
         } else if self.match_token_type(TokenType::LeftParen) {
-            let arg_count = self.argument_list();
-            self.emit_bytes(Opcode::Invoke.byte(), name);
+            let form = self.argument_form();
+            if form != ArgumentForm::Positional {
+                self.emit_constant_op(Opcode::GetProperty.byte(), name);
+                self.dynamic_call(form);
+                return;
+            }
+            let arg_count = self.method_argument_list();
+            self.emit_constant_op(Opcode::Invoke.byte(), name);
             self.emit_byte(arg_count);
+        } else if self.match_token_type(TokenType::PlusPlus) || self.match_token_type(TokenType::MinusMinus) {
+            let (step, undo) = Self::increment_ops(self.previous().token_type);
+            self.emit_byte(Opcode::Dup.byte());
+            self.emit_constant_op(Opcode::GetProperty.byte(), name);
+            self.emit_constant(Value::number(1.0));
+            self.emit_byte(step);
+            self.emit_constant_op(Opcode::SetProperty.byte(), name);
+            self.emit_constant(Value::number(1.0));
+            self.emit_byte(undo);
         }
         else {
-            self.emit_bytes(Opcode::GetProperty.byte(), name);
+            self.emit_constant_op(Opcode::GetProperty.byte(), name);
+        }
+    }
+
+    fn list(&mut self) {
+        let mut item_count: u8 = 0;
+        if !self.check(TokenType::RightBracket) {
+            loop {
+                self.expression();
+                if item_count == 255 {
+                    self.error("Can't have more than 255 items in a list literal.");
+                }
+                item_count = item_count.wrapping_add(1);
+                if !self.match_token_type(TokenType::Comma) { break; }
+            }
+        }
+        self.consume(TokenType::RightBracket, "Expect ']' after list items.");
+        self.emit_bytes(Opcode::BuildList.byte(), item_count);
+    }
+
+    fn map(&mut self) {
+        let mut entry_count: u8 = 0;
+        if !self.check(TokenType::RightBrace) {
+            loop {
+                self.expression();
+                self.consume(TokenType::Colon, "Expect ':' after map key.");
+                self.expression();
+                if entry_count == 255 {
+                    self.error("Can't have more than 255 entries in a map literal.");
+                }
+                entry_count = entry_count.wrapping_add(1);
+                if !self.match_token_type(TokenType::Comma) { break; }
+            }
+        }
+        self.consume(TokenType::RightBrace, "Expect '}' after map entries.");
+        self.emit_bytes(Opcode::BuildMap.byte(), entry_count);
+    }
+
+    /// `match (subject) { pattern if guard => value, ... }`
+    ///
+    /// The arms are compiled into their own function, called straight away, so
+    /// that bindings can live in local slots even when the match sits in the
+    /// middle of an expression. Each arm runs its tests against the subject
+    /// first, then binds names, checks the guard and returns its value.
+    fn match_expression(&mut self) {
+        let func_idx = self.heap.alloc_function(Function::new("match".to_string(), 0));
+        let compiler = Compiler::new(self.curr_compiler_index, func_idx, FunctionType::Function);
+        self.curr_compiler_index = self.compilers.len();
+        self.compilers.push(compiler);
+        let compiler_idx = self.compilers.len() - 1;
+        self.begin_scope();
+
+        self.consume(TokenType::LeftParen, "Expect '(' after match.");
+        self.expression();
+        self.consume(TokenType::RightParen, "Expect ')' after match subject.");
+        let subject_slot = self.add_hidden_local(" subject");
+        self.consume(TokenType::LeftBrace, "Expect '{' before match arms.");
+
+        while !self.check(TokenType::RightBrace) && !self.is_at_end() {
+            self.match_arm(subject_slot);
+            if !self.match_token_type(TokenType::Comma) { break; }
         }
+        self.consume(TokenType::RightBrace, "Expect '}' after match arms.");
+
+        let message = self.heap.alloc_string("No match arm for value.".to_string());
+        self.emit_constant(Value::object(Object::string(message)));
+        self.emit_byte(Opcode::Throw.byte());
+
+        self.end_compiler();
+        self.emit_closure(func_idx, compiler_idx);
+        self.emit_bytes(Opcode::Call.byte(), 0);
     }
 
+    fn match_arm(&mut self, subject_slot: u8) {
+        let pattern = self.pattern();
+        let (first_binding, fail_jumps) = self.begin_match_arm(&pattern, subject_slot);
+
+        let mut guard_jump = None;
+        if self.match_token_type(TokenType::If) {
+            self.expression();
+            guard_jump = Some(self.emit_jump(Opcode::JumpIfFalse.byte()));
+            self.emit_byte(Opcode::Pop.byte());
+        }
+        self.consume(TokenType::FatArrow, "Expect '=>' after match pattern.");
+        self.expression();
+        self.end_match_arm(first_binding, guard_jump, fail_jumps);
+    }
+
+    /// Emit the tests of an arm's pattern, then bind its names. Returns the
+    /// slot of the first binding and the jumps taken when a test fails
+    fn begin_match_arm(&mut self, pattern: &Pattern, subject_slot: u8) -> (usize, Vec<usize>) {
+        // Tests, each leaves a boolean that a failure jumps with
+        let mut fail_jumps = vec![];
+        self.emit_pattern_tests(pattern, subject_slot, &mut vec![], &mut fail_jumps);
+
+        // Bindings
+        self.begin_scope();
+        let mut bindings = vec![];
+        self.collect_bindings(pattern, &mut vec![], &mut bindings);
+        let first_binding = self.current_compiler().locals.len();
+        for (name, path) in bindings {
+            let is_duplicate = self.current_compiler().locals[first_binding..].iter()
+                .any(|local| local.name == name.lexeme);
+            if is_duplicate {
+                self.error_at(name.clone(), "Already a variable of this name in this pattern");
+            }
+            self.emit_path(subject_slot, &path);
+            let depth = self.current_scope_depth();
+            self.compilers[self.curr_compiler_index].add_local(Rc::clone(&name.lexeme), depth, &name);
+        }
+        return (first_binding, fail_jumps);
+    }
+
+    /// Return the value of the arm, which is on the stack. Then emit the code
+    /// a failed test or guard jumps to, which moves on to the next arm
+    fn end_match_arm(&mut self, first_binding: usize, guard_jump: Option<usize>, fail_jumps: Vec<usize>) {
+        self.emit_byte(Opcode::Return.byte());
+
+        // Forget the bindings, the code below only runs when the arm didn't match
+        let index = self.curr_compiler_index;
+        let arm_locals = self.compilers[index].locals.split_off(first_binding);
+        self.compilers[index].scope_depth -= 1;
+
+        let mut next_jump = None;
+        if let Some(guard_jump) = guard_jump {
+            self.patch_jump(guard_jump);
+            self.emit_byte(Opcode::Pop.byte());
+            for local in arm_locals.iter().rev() {
+                if local.is_captured {
+                    self.emit_byte(Opcode::CloseValue.byte());
+                } else {
+                    self.emit_byte(Opcode::Pop.byte());
+                }
+            }
+            next_jump = Some(self.emit_jump(Opcode::Jump.byte()));
+        }
+        if !fail_jumps.is_empty() {
+            for fail_jump in fail_jumps {
+                self.patch_jump(fail_jump);
+            }
+            self.emit_byte(Opcode::Pop.byte());
+        }
+        if let Some(next_jump) = next_jump {
+            self.patch_jump(next_jump);
+        }
+    }
+
+    fn pattern(&mut self) -> Pattern {
+        let pattern = if self.enter_nesting() { self.nested_pattern() } else { Pattern::Wildcard };
+        self.nesting -= 1;
+        return pattern;
+    }
+
+    fn nested_pattern(&mut self) -> Pattern {
+        if self.match_token_type(TokenType::LeftBracket) {
+            let mut items = vec![];
+            let mut rest = None;
+            if !self.check(TokenType::RightBracket) {
+                loop {
+                    if self.match_token_type(TokenType::Ellipsis) {
+                        self.consume(TokenType::Identifier, "Expect a name after '...'.");
+                        rest = Some(Box::new(self.binding_pattern()));
+                        break;
+                    }
+                    items.push(self.pattern());
+                    if !self.match_token_type(TokenType::Comma) { break; }
+                }
+            }
+            self.consume(TokenType::RightBracket, "Expect ']' after list pattern.");
+            if items.len() > 255 {
+                self.error("Can't have more than 255 items in a list pattern.");
+            }
+            return Pattern::List(items, rest);
+        }
+        if self.match_token_type(TokenType::Identifier) {
+            if !self.check(TokenType::LeftBrace) {
+                return self.binding_pattern();
+            }
+            let class_token = self.previous();
+            self.advance();
+            let mut fields = vec![];
+            if !self.check(TokenType::RightBrace) {
+                loop {
+                    self.consume(TokenType::Identifier, "Expect a field name.");
+                    let field_token = self.previous();
+                    let pattern = if self.match_token_type(TokenType::Colon) {
+                        self.pattern()
+                    } else {
+                        Pattern::Binding(field_token.clone())
+                    };
+                    fields.push((field_token, pattern));
+                    if !self.match_token_type(TokenType::Comma) { break; }
+                }
+            }
+            self.consume(TokenType::RightBrace, "Expect '}' after instance pattern.");
+            return Pattern::Instance(class_token, fields);
+        }
+        let negative = self.match_token_type(TokenType::Minus);
+        self.advance();
+        let token = self.previous();
+        let is_literal = match token.token_type {
+            TokenType::Number => true,
+            TokenType::String | TokenType::True | TokenType::False | TokenType::Nil => !negative,
+            _ => false,
+        };
+        if !is_literal {
+            self.error("Expect a pattern.");
+        }
+        return Pattern::Literal { token, negative };
+    }
+
+    /// Pattern for the identifier just consumed
+    fn binding_pattern(&self) -> Pattern {
+        let name = self.previous();
+        return if &*name.lexeme == "_" { Pattern::Wildcard } else { Pattern::Binding(name) };
+    }
+
+    /// Value a literal pattern compares with
+    fn pattern_value(&mut self, token: &Token, negative: bool) -> Value {
+        return match token.token_type {
+            TokenType::Number => {
+                let number: f64 = token.lexeme.parse().unwrap();
+                Value::number(if negative { -number } else { number })
+            }
+            TokenType::String => {
+                let hash = self.heap.alloc_string(token.literal.to_string());
+                Value::object(Object::string(hash))
+            }
+            TokenType::True => Value::bool(true),
+            TokenType::False => Value::bool(false),
+            _ => Value::nil(),
+        };
+    }
+
+    fn emit_pattern_tests(&mut self, pattern: &Pattern, subject_slot: u8,
+                          path: &mut Vec<PathStep>, fail_jumps: &mut Vec<usize>) {
+        match pattern {
+            Pattern::Wildcard | Pattern::Binding(_) => {}
+            Pattern::Literal { token, negative } => {
+                self.emit_path(subject_slot, path);
+                let value = self.pattern_value(token, *negative);
+                self.emit_constant(value);
+                self.emit_byte(Opcode::Equal.byte());
+                self.emit_pattern_check(fail_jumps);
+            }
+            Pattern::List(items, rest) => {
+                self.emit_path(subject_slot, path);
+                self.emit_bytes(Opcode::MatchList.byte(), items.len() as u8);
+                self.emit_byte(rest.is_some() as u8);
+                self.emit_pattern_check(fail_jumps);
+                for (i, item) in items.iter().enumerate() {
+                    path.push(PathStep::Index(i));
+                    self.emit_pattern_tests(item, subject_slot, path, fail_jumps);
+                    path.pop();
+                }
+            }
+            Pattern::Instance(class_token, fields) => {
+                self.emit_path(subject_slot, path);
+                self.get_variable(class_token);
+                self.emit_byte(Opcode::IsInstance.byte());
+                self.emit_pattern_check(fail_jumps);
+                for (field, field_pattern) in fields {
+                    let field = self.identifier_constant(&field.lexeme);
+                    path.push(PathStep::Field(field));
+                    self.emit_pattern_tests(field_pattern, subject_slot, path, fail_jumps);
+                    path.pop();
+                }
+            }
+        }
+    }
+
+    /// Jump to the arm's failure code when the test on top of the stack is false
+    fn emit_pattern_check(&mut self, fail_jumps: &mut Vec<usize>) {
+        fail_jumps.push(self.emit_jump(Opcode::JumpIfFalse.byte()));
+        self.emit_byte(Opcode::Pop.byte());
+    }
+
+    fn collect_bindings(&mut self, pattern: &Pattern, path: &mut Vec<PathStep>, bindings: &mut Vec<(Token, Vec<PathStep>)>) {
+        match pattern {
+            Pattern::Wildcard | Pattern::Literal { .. } => {}
+            Pattern::Binding(name) => bindings.push((name.clone(), path.clone())),
+            Pattern::List(items, rest) => {
+                for (i, item) in items.iter().enumerate() {
+                    path.push(PathStep::Index(i));
+                    self.collect_bindings(item, path, bindings);
+                    path.pop();
+                }
+                if let Some(rest) = rest {
+                    path.push(PathStep::Rest(items.len()));
+                    self.collect_bindings(rest, path, bindings);
+                    path.pop();
+                }
+            }
+            Pattern::Instance(_, fields) => {
+                for (field, field_pattern) in fields {
+                    // The tests already added the field name constants
+                    let field = self.identifier_constant(&field.lexeme);
+                    path.push(PathStep::Field(field));
+                    self.collect_bindings(field_pattern, path, bindings);
+                    path.pop();
+                }
+            }
+        }
+    }
+
+    /// Push the part of the subject the path leads to
+    fn emit_path(&mut self, subject_slot: u8, path: &[PathStep]) {
+        self.emit_bytes(Opcode::GetLocal.byte(), subject_slot);
+        for step in path {
+            match *step {
+                PathStep::Index(i) => {
+                    self.emit_constant(Value::number(i as f64));
+                    self.emit_byte(Opcode::GetIndex.byte());
+                }
+                PathStep::Rest(start) => self.emit_bytes(Opcode::SliceFrom.byte(), start as u8),
+                PathStep::Field(name) => self.emit_constant_op(Opcode::GetProperty.byte(), name),
+            }
+        }
+    }
+
+    fn index(&mut self, can_assign: bool) {
+        self.expression();
+        self.consume(TokenType::RightBracket, "Expect ']' after index.");
+        if can_assign && self.match_token_type(TokenType::Equal) {
+            self.expression();
+            self.emit_byte(Opcode::SetIndex.byte());
+        } else {
+            self.emit_byte(Opcode::GetIndex.byte());
+        }
+    }
 
     fn and(&mut self) {
         let end_jump = self.emit_jump(Opcode::JumpIfFalse.byte());
         self.emit_byte(Opcode::Pop.byte());
         self.parse_precedence(Precedence::And);
-        self.patch_jump(end_jump as usize);
+        self.patch_jump(end_jump);
     }
 
     fn or(&mut self) {
-        let else_jump = self.emit_jump(Opcode::JumpIfFalse.byte());
-        let end_jump = self.emit_jump(Opcode::Jump.byte());
-        self.patch_jump(else_jump as usize);
+        let end_jump = self.emit_jump(Opcode::JumpIfTrue.byte());
         self.emit_byte(Opcode::Pop.byte());
         self.parse_precedence(Precedence::Or);
-        self.patch_jump(end_jump as usize);
+        self.patch_jump(end_jump);
+    }
+
+    /// `a ?? b` keeps a unless it is nil
+    fn nil_coalesce(&mut self) {
+        let end_jump = self.emit_jump(Opcode::JumpIfNotNil.byte());
+        self.emit_byte(Opcode::Pop.byte());
+        self.parse_precedence(Precedence::Or);
+        self.patch_jump(end_jump);
     }
 
     fn block(&mut self) {
-        while !self.check(TokenType::RightBrace) &&
-            !self.check(TokenType::Eof) {
+        self.declarations_until(TokenType::RightBrace);
+        self.consume(TokenType::RightBrace, "Expect '}' after block.");
+    }
+
+    /// Compile declarations up to the end token. Declarations after one that
+    /// always returns or throws are still checked, but their code is dropped
+    fn declarations_until(&mut self, end: TokenType) {
+        self.exited = false;
+        let mut dead_code_start = None;
+        while !self.check(end) && !self.is_at_end() {
+            if self.exited && dead_code_start.is_none() {
+                self.warning_at_current("Unreachable code.");
+                dead_code_start = Some(self.current_function().chunk.code.len());
+            }
+            let start = self.curr_token_index;
             self.declaration();
+            if self.curr_token_index == start {
+                // Recovering from an error has to move on, or it would be reported forever
+                self.advance();
+            }
+        }
+        if let Some(start) = dead_code_start {
+            self.current_function().chunk.truncate(start);
+            self.exited = true;
         }
-        self.consume(TokenType::RightBrace, "Expect '}' after block.");
     }
 
     fn return_statement(&mut self) {
@@ -931,9 +2009,8 @@ impl Parser {
             if self.match_token_type(TokenType::Semicolon) {
                 self.emit_return();
             } else {
-                match self.current_compiler().function_type {
-                    FunctionType::Initializer => {self.error("Can't return value from an initializer.")}
-                    _ => {}
+                if let FunctionType::Initializer = self.current_compiler().function_type {
+                    self.error("Can't return value from an initializer.");
                 }
                 self.expression();
                 self.consume(TokenType::Semicolon, "Expect ';' after return value.");
@@ -943,18 +2020,118 @@ impl Parser {
     }
 
     fn call(&mut self) {
-        // fixme: implement me
+        let form = self.argument_form();
+        if form != ArgumentForm::Positional {
+            self.dynamic_call(form);
+            return;
+        }
         let arg_count = self.argument_list();
-        self.emit_bytes(Opcode::Call.byte(), arg_count);
+        if arg_count <= u8::MAX as usize {
+            self.emit_bytes(Opcode::Call.byte(), arg_count as u8);
+        } else {
+            self.emit_byte(Opcode::CallLong.byte());
+            self.emit_short(arg_count);
+        }
+    }
+
+    /// Does the argument list starting at the current token spread a list or
+    /// name its arguments?
+    fn argument_form(&self) -> ArgumentForm {
+        let mut depth = 0;
+        let mut form = ArgumentForm::Positional;
+        for i in self.curr_token_index.. {
+            let token = self.token_at(i);
+            match token.token_type {
+                TokenType::LeftParen | TokenType::LeftBracket | TokenType::LeftBrace => depth += 1,
+                TokenType::RightParen | TokenType::RightBracket | TokenType::RightBrace => {
+                    if depth == 0 { break; }
+                    depth -= 1;
+                }
+                TokenType::Ellipsis if depth == 0 => form = ArgumentForm::Spread,
+                TokenType::Identifier if depth == 0 && self.token_at(i + 1).token_type == TokenType::Colon => {
+                    return ArgumentForm::Named;
+                }
+                TokenType::Semicolon | TokenType::Eof => break,
+                _ => {}
+            }
+        }
+        return form;
     }
 
-    fn argument_list(&mut self)->u8 {
-        let mut arg_count:u8 = 0;
+    /// Call the value on top of the stack with spread or named arguments
+    fn dynamic_call(&mut self, form: ArgumentForm) {
+        if form == ArgumentForm::Spread {
+            self.spread_argument_list();
+            self.emit_byte(Opcode::CallSpread.byte());
+        } else {
+            let (positional, named) = self.named_argument_list();
+            self.emit_bytes(Opcode::CallNamed.byte(), positional);
+            self.emit_byte(named);
+        }
+    }
+
+    /// Positional arguments followed by `name: value` pairs, each name pushed
+    /// as a string before its value
+    fn named_argument_list(&mut self) -> (u8, u8) {
+        let mut positional: u8 = 0;
+        let mut named: u8 = 0;
+        loop {
+            if self.match_token_type(TokenType::Ellipsis) {
+                self.error("Can't mix spread and named arguments.");
+            }
+            let is_named = self.check(TokenType::Identifier)
+                && self.token_at(self.curr_token_index + 1).token_type == TokenType::Colon;
+            if is_named {
+                self.advance();
+                let name = self.heap.alloc_string(self.previous().lexeme.to_string());
+                self.emit_constant(Value::object(Object::string(name)));
+                self.advance(); // ':'
+                self.expression();
+                if named == 255 {
+                    self.error("Can't have more than 255 named arguments.");
+                }
+                named = named.wrapping_add(1);
+            } else {
+                if named > 0 {
+                    self.error("Positional arguments must come before named arguments.");
+                }
+                self.expression();
+                if positional == 255 {
+                    self.error("Can't have more than 255 arguments.");
+                }
+                positional = positional.wrapping_add(1);
+            }
+            if !self.match_token_type(TokenType::Comma) { break; }
+        }
+        self.consume(TokenType::RightParen, "Expect ')' after arguments");
+        return (positional, named);
+    }
+
+    /// Collect the arguments into one list, expanding `...list` arguments in place
+    fn spread_argument_list(&mut self) {
+        self.emit_bytes(Opcode::BuildList.byte(), 0);
+        if !self.check(TokenType::RightParen) {
+            loop {
+                if self.match_token_type(TokenType::Ellipsis) {
+                    self.expression();
+                    self.emit_byte(Opcode::ListExtend.byte());
+                } else {
+                    self.expression();
+                    self.emit_byte(Opcode::ListAppend.byte());
+                }
+                if !self.match_token_type(TokenType::Comma) { break; }
+            }
+        }
+        self.consume(TokenType::RightParen, "Expect ')' after arguments");
+    }
+
+    fn argument_list(&mut self)->usize {
+        let mut arg_count: usize = 0;
         if !self.check(TokenType::RightParen) {
             loop {
                 self.expression();
-                if arg_count == 255 {
-                    self.error("Can't have more than 255 arguments.");
+                if arg_count == MAX_LOCALS - 1 {
+                    self.error("Can't have more than 65535 arguments.");
                 }
                 arg_count += 1;
                 if !self.match_token_type(TokenType::Comma) { break; }
@@ -964,43 +2141,150 @@ impl Parser {
         return arg_count;
     }
 
-    fn named_variable(&mut self, token: &Token, can_assign: bool) {
-
-        let mut set_op: u8 = Opcode::SetGlobal.byte();
-        let mut get_op: u8 = Opcode::GetGlobal.byte();
+    /// Arguments of a method invocation, whose count is a single byte operand
+    fn method_argument_list(&mut self) -> u8 {
+        let arg_count = self.argument_list();
+        if arg_count > u8::MAX as usize {
+            self.error("Can't pass more than 255 arguments to a method.");
+        }
+        return arg_count as u8;
+    }
 
-        let current_compiler_index = self.curr_compiler_index as usize;
+    /// Get and set opcodes plus operand for a variable name, resolved as a
+    /// local, an upvalue or else a global
+    fn resolve_variable(&mut self, token: &Token) -> (u8, u8, usize) {
+        let current_compiler_index = self.curr_compiler_index;
 
-        let mut arg = self.resolve_local(current_compiler_index, token);
+        let arg = self.resolve_local(current_compiler_index, token);
         if arg != usize::MAX {
-            set_op = Opcode::SetLocal.byte();
-            get_op = Opcode::GetLocal.byte();
-        } else {
-            arg = self.resolve_upvalue(current_compiler_index, token);
-            if arg != usize::MAX {
-                set_op = Opcode::SetUpvalue.byte();
-                get_op = Opcode::GetUpvalue.byte();
-            }
-            else {
-                arg = self.identifier_constant(&token.lexeme) as usize;
-            }
+            return (Opcode::GetLocal.byte(), Opcode::SetLocal.byte(), arg);
+        }
+        let arg = self.resolve_upvalue(current_compiler_index, token);
+        if arg != usize::MAX {
+            return (Opcode::GetUpvalue.byte(), Opcode::SetUpvalue.byte(), arg);
+        }
+        if self.warn_undefined_globals {
+            self.global_references.push(token.clone());
+        }
+        let arg = self.identifier_constant(&token.lexeme);
+        return (Opcode::GetGlobal.byte(), Opcode::SetGlobal.byte(), arg);
+    }
+
+    /// Emit a read of the variable, whatever tokens follow it
+    fn get_variable(&mut self, token: &Token) {
+        let (get_op, _, arg) = self.resolve_variable(token);
+        self.emit_variable_op(get_op, arg);
+    }
+
+    fn named_variable(&mut self, token: &Token, can_assign: bool) {
+        let current_compiler_index = self.curr_compiler_index;
+        let (get_op, set_op, arg) = self.resolve_variable(token);
+
+        let is_assignment = (can_assign && (self.check(TokenType::Equal)
+            || self.check(TokenType::PlusEqual)
+            || self.check(TokenType::MinusEqual)))
+            || self.check(TokenType::PlusPlus)
+            || self.check(TokenType::MinusMinus);
+        if is_assignment && self.is_const_local(current_compiler_index, token) {
+            self.error("Can't assign to a constant.");
         }
 
         if can_assign && self.match_token_type(TokenType::Equal) {
             self.expression();
-            self.emit_bytes(set_op, arg as u8);
+            self.emit_variable_op(set_op, arg);
         } else if can_assign && self.match_token_type(TokenType::PlusEqual) {
-            self.emit_bytes(get_op, arg as u8);
+            self.emit_variable_op(get_op, arg);
             self.expression();
             self.emit_byte(Opcode::Add.byte());
-            self.emit_bytes(set_op, arg as u8);
+            self.emit_variable_op(set_op, arg);
         } else if can_assign && self.match_token_type(TokenType::MinusEqual) {
-            self.emit_bytes(get_op, arg as u8);
+            self.emit_variable_op(get_op, arg);
             self.expression();
             self.emit_byte(Opcode::Subtract.byte());
-            self.emit_bytes(set_op, arg as u8);
+            self.emit_variable_op(set_op, arg);
+        } else if self.match_token_type(TokenType::PlusPlus) || self.match_token_type(TokenType::MinusMinus) {
+            // Postfix: store the new value, then undo the step on the copy left behind
+            let (step, undo) = Self::increment_ops(self.previous().token_type);
+            self.emit_variable_op(get_op, arg);
+            self.emit_constant(Value::number(1.0));
+            self.emit_byte(step);
+            self.emit_variable_op(set_op, arg);
+            self.emit_constant(Value::number(1.0));
+            self.emit_byte(undo);
+        } else {
+            self.emit_variable_op(get_op, arg);
+        }
+    }
+
+    /// Emit a variable access, switching locals and upvalues past 255 to the 16 bit form
+    fn emit_variable_op(&mut self, op: u8, arg: usize) {
+        if arg <= u8::MAX as usize {
+            self.emit_bytes(op, arg as u8);
+            return;
+        }
+        let long_op = if op == Opcode::GetLocal.byte() {
+            Opcode::GetLocalLong
+        } else if op == Opcode::SetLocal.byte() {
+            Opcode::SetLocalLong
+        } else if op == Opcode::GetUpvalue.byte() {
+            Opcode::GetUpvalueLong
+        } else if op == Opcode::SetUpvalue.byte() {
+            Opcode::SetUpvalueLong
+        } else {
+            // Globals index their name constant
+            self.emit_constant_op(op, arg);
+            return;
+        };
+        self.emit_byte(long_op.byte());
+        self.emit_short(arg);
+    }
+
+    /// Write a 16 bit operand, high byte first
+    fn emit_short(&mut self, value: usize) {
+        self.emit_byte(((value >> 8) & 0xff) as u8);
+        self.emit_byte((value & 0xff) as u8);
+    }
+
+    /// Opcodes applying and reverting `++` or `--`
+    fn increment_ops(token_type: TokenType) -> (u8, u8) {
+        return if token_type == TokenType::PlusPlus {
+            (Opcode::Add.byte(), Opcode::Subtract.byte())
         } else {
-            self.emit_bytes(get_op, arg as u8);
+            (Opcode::Subtract.byte(), Opcode::Add.byte())
+        };
+    }
+
+    /// Prefix `++` or `--` on a variable or a field chain such as `++a.b.c`
+    fn pre_increment(&mut self) {
+        let (step, _) = Self::increment_ops(self.previous().token_type);
+        if !self.match_token_type(TokenType::This) {
+            self.consume(TokenType::Identifier, "Expect a variable or field after increment operator.");
+        }
+        let token = self.previous();
+        let (get_op, set_op, arg) = self.resolve_variable(&token);
+        if !self.check(TokenType::Dot) {
+            if self.is_const_local(self.curr_compiler_index, &token) {
+                self.error("Can't assign to a constant.");
+            }
+            self.emit_variable_op(get_op, arg);
+            self.emit_constant(Value::number(1.0));
+            self.emit_byte(step);
+            self.emit_variable_op(set_op, arg);
+            return;
+        }
+        self.emit_variable_op(get_op, arg);
+        while self.match_token_type(TokenType::Dot) {
+            self.consume(TokenType::Identifier, "Expect field name after '.'.");
+            let name = self.identifier_constant(&self.previous().lexeme);
+            if self.check(TokenType::Dot) {
+                self.emit_constant_op(Opcode::GetProperty.byte(), name);
+                continue;
+            }
+            self.emit_byte(Opcode::Dup.byte());
+            self.emit_constant_op(Opcode::GetProperty.byte(), name);
+            self.emit_constant(Value::number(1.0));
+            self.emit_byte(step);
+            self.emit_constant_op(Opcode::SetProperty.byte(), name);
         }
     }
 
@@ -1018,6 +2302,20 @@ impl Parser {
         return usize::MAX;
     }
 
+    /// Does the name resolve to a const local of this or an enclosing function?
+    /// Const globals are checked by the VM.
+    fn is_const_local(&self, compiler_idx: usize, token: &Token) -> bool {
+        let mut compiler_idx = compiler_idx;
+        while compiler_idx != usize::MAX {
+            let compiler = &self.compilers[compiler_idx];
+            if let Some(local) = compiler.locals.iter().rev().find(|local| token.lexeme == local.name) {
+                return local.is_const;
+            }
+            compiler_idx = compiler.enclosing;
+        }
+        return false;
+    }
+
     fn add_upvalue(&mut self, compiler_idx:usize, index: usize, is_local: bool)->usize {
         let function_idx = self.compilers[compiler_idx].function_idx;
         let upvalue_count = self.heap.get_mut_function(function_idx).upvalue_count;
@@ -1037,21 +2335,48 @@ impl Parser {
         return upvalue_count;
     }
 
+    /// Is the name a local of the current function or one enclosing it?
+    fn is_local_name(&self, name: &str) -> bool {
+        let mut compiler_idx = self.curr_compiler_index;
+        while compiler_idx != usize::MAX {
+            let compiler = &self.compilers[compiler_idx];
+            if compiler.locals.iter().any(|local| &*local.name == name) {
+                return true;
+            }
+            compiler_idx = compiler.enclosing;
+        }
+        return false;
+    }
+
+    /// Error for calling the name when it is a global class known to have
+    /// abstract methods left
+    fn abstract_instantiation(&self, class_name: &str) -> Option<String> {
+        if self.is_local_name(class_name) {
+            return None;
+        }
+        let missing = &self.global_classes.get(class_name)?.abstract_methods;
+        if missing.is_empty() {
+            return None;
+        }
+        let missing: Vec<&str> = missing.iter().map(|name| &**name).collect();
+        return Some(abstract_class_message(class_name, &missing));
+    }
+
     fn resolve_upvalue(&mut self, compiler_idx: usize,  name: &Token)->usize {
         let enclosing_idx = self.compilers[compiler_idx].enclosing;
         if enclosing_idx == usize::MAX {
             return usize::MAX;
         }
 
-        let local = self.resolve_local(enclosing_idx as usize, name);
+        let local = self.resolve_local(enclosing_idx, name);
         if local != usize::MAX {
-            self.compilers[enclosing_idx as usize].locals[local as usize].is_captured = true;
-            return self.add_upvalue(compiler_idx, local as usize, true);
+            self.compilers[enclosing_idx].locals[local].is_captured = true;
+            return self.add_upvalue(compiler_idx, local, true);
         }
 
-        let upvalue = self.resolve_upvalue(enclosing_idx as usize, name);
+        let upvalue = self.resolve_upvalue(enclosing_idx, name);
         if upvalue != usize::MAX {
-            return self.add_upvalue(compiler_idx, upvalue as usize, false);
+            return self.add_upvalue(compiler_idx, upvalue, false);
         }
 
         return usize::MAX;
@@ -1063,15 +2388,24 @@ impl Parser {
         let class_name = self.previous();
         let name_constant = self.identifier_constant(&self.previous().lexeme);
         self.declare_variable();
+        self.declared_as(DeclarationKind::Class);
 
-        self.emit_bytes(Opcode::Class.byte(), name_constant);
-        self.define_variable(name_constant);
+        self.emit_constant_op(Opcode::Class.byte(), name_constant);
+        self.define_declaration(name_constant);
 
-        let mut class_compiler = Some(Box::new(RefCell::new(ClassCompiler::new(self.current_class.take()))));
+        let class_compiler = Some(Box::new(RefCell::new(ClassCompiler::new(self.current_class.take()))));
         self.current_class = class_compiler;
 
+        let mut superclass = None;
         if self.match_token_type(TokenType::Extend) {
             self.consume(TokenType::Identifier, "Expect parent class name.");
+            let superclass_name = self.previous();
+            let superclass_info = if self.is_local_name(&superclass_name.lexeme) {
+                None
+            } else {
+                self.global_classes.get(&superclass_name.lexeme).cloned()
+            };
+            superclass = Some((superclass_name, superclass_info));
             self.variable(false);
             if self.identifier_equals(&class_name, &self.previous()) {
                 self.error("Class cannot inherit from itself");
@@ -1079,7 +2413,8 @@ impl Parser {
 
             self.begin_scope();
             let current_scope_depth = self.current_scope_depth();
-            self.compilers[self.curr_compiler_index as usize].add_local("super".to_string(), current_scope_depth);
+            let declared_at = self.previous();
+            self.compilers[self.curr_compiler_index].add_local("super".into(), current_scope_depth, &declared_at);
             self.define_variable(0);
 
             self.named_variable(&class_name, false);
@@ -1093,9 +2428,30 @@ impl Parser {
 
         self.named_variable(&class_name, false);
 
+        // Abstract methods and initializer of the class, starting from what its superclass has
+        let mut info = superclass.as_ref().and_then(|(_, info)| info.clone()).unwrap_or_default();
         self.consume(TokenType::LeftBrace, "Expect '{' before class body");
-        while !self.check(TokenType::RightBrace) && !self.check(TokenType::Eof) {
-            self.method();
+        while !self.check(TokenType::RightBrace) && !self.is_at_end() {
+            let start = self.curr_token_index;
+            if self.match_token_type(TokenType::Const) {
+                self.class_constant();
+            } else if self.match_token_type(TokenType::Abstract) {
+                let name = self.abstract_method();
+                info.abstract_methods.retain(|method| *method != name);
+                info.abstract_methods.push(name);
+            } else {
+                let name = self.peek();
+                let arity = self.method();
+                info.abstract_methods.retain(|method| *method != name.lexeme);
+                if &*name.lexeme == "init" {
+                    info.init_arity = Some(arity);
+                    self.check_super_init(&name, superclass.as_ref());
+                }
+            }
+            if self.curr_token_index == start {
+                // Recovering from an error has to move on, or it would be reported forever
+                self.advance();
+            }
         }
         self.consume(TokenType::RightBrace, "Expect '}' after class body.");
         self.emit_byte(Opcode::Pop.byte()); // pop class name
@@ -1105,7 +2461,24 @@ impl Parser {
         }
 
 
-        self.current_class = self.enclosing_class()
+        self.current_class = self.enclosing_class();
+        if self.current_scope_depth() == 0 {
+            self.global_classes.insert(class_name.lexeme.clone(), info);
+        }
+    }
+
+    /// Warn about an init that never calls super.init when the init of the
+    /// superclass takes arguments
+    fn check_super_init(&mut self, name: &Token, superclass: Option<&(Token, Option<ClassInfo>)>) {
+        let calls_super = self.current_class.as_ref().unwrap().borrow().init_calls_super;
+        let Some((superclass, Some(ClassInfo { init_arity: Some(arity), .. }))) = superclass else {
+            return;
+        };
+        if !calls_super && *arity > 0 {
+            let message = format!("init never calls super.init, the init of {} takes {} argument(s).",
+                                  superclass.lexeme, arity);
+            self.warning_at(name.line, name.column, &name.lexeme, &message);
+        }
     }
 
     fn identifier_equals(&self, token1: &Token, token2: &Token ) -> bool {
@@ -1114,26 +2487,64 @@ impl Parser {
 
     fn enclosing_class(&mut self) -> Option<Box<RefCell<ClassCompiler>>> {
         match self.current_class.take() {
-            Some(mut it) => {
-                match it.into_inner().enclosing {
-                    Some(it2) => { Some(Box::new(*it2)) }
-                    None => None
-                }
+            Some(it) => {
+                it.into_inner().enclosing.map(|it2| Box::new(*it2))
             },
             None => None,
         }
     }
 
-    fn method(&mut self) {
+    /// Compile a method, returning its arity
+    fn method(&mut self) -> usize {
         self.consume(TokenType::Identifier, "Expect a method name.");
         let constant = self.identifier_constant(&self.previous().lexeme);
-        let func_type = if self.previous().lexeme == "init" {
+        let func_type = if &*self.previous().lexeme == "init" {
             FunctionType::Initializer
         } else {
             FunctionType::Method
         };
-        self.function(func_type);
-        self.emit_bytes(Opcode::Method.byte(), constant);
+        let is_init = matches!(func_type, FunctionType::Initializer);
+        self.current_class.as_ref().unwrap().borrow_mut().in_init = is_init;
+        let func_idx = self.function(func_type);
+        self.current_class.as_ref().unwrap().borrow_mut().in_init = false;
+        self.emit_constant_op(Opcode::Method.byte(), constant);
+        return self.heap.functions[func_idx].borrow().arity;
+    }
+
+    /// `const name = value;` in a class body, with the class on the stack
+    fn class_constant(&mut self) {
+        self.consume(TokenType::Identifier, "Expect a constant name.");
+        let name = self.previous();
+        self.consume(TokenType::Equal, "Expect '=' after constant name.");
+        self.expression();
+        self.consume(TokenType::Semicolon, "Expect ';' after constant declaration.");
+        let constant = self.identifier_constant(&name.lexeme);
+        self.emit_constant_op(Opcode::ClassConstant.byte(), constant);
+    }
+
+    /// `abstract name(params);`, the parameters only document it. Returns
+    /// the name
+    fn abstract_method(&mut self) -> Rc<str> {
+        self.consume(TokenType::Identifier, "Expect a method name.");
+        let name = self.previous().lexeme;
+        if &*self.previous().lexeme == "init" {
+            self.error("An initializer can't be abstract.");
+        }
+        let constant = self.identifier_constant(&self.previous().lexeme);
+        self.consume(TokenType::LeftParen, "Expect '(' after function name");
+        if !self.check(TokenType::RightParen) {
+            loop {
+                self.match_token_type(TokenType::Ellipsis);
+                self.consume(TokenType::Identifier, "Expect a parameter name");
+                if !self.match_token_type(TokenType::Comma) {
+                    break;
+                }
+            }
+        }
+        self.consume(TokenType::RightParen, "Expect ')' after parameters");
+        self.consume(TokenType::Semicolon, "Expect ';' after abstract method.");
+        self.emit_constant_op(Opcode::AbstractMethod.byte(), constant);
+        return name;
     }
 
     fn this(&mut self) {
@@ -1153,27 +2564,45 @@ impl Parser {
 
         self.consume(TokenType::Dot, "Expect '.' after super.");
         self.consume(TokenType::Identifier, "Expect superclass method name");
+        if &*self.previous().lexeme == "init" {
+            if let Some(class_compiler) = &self.current_class {
+                let mut class_compiler = class_compiler.borrow_mut();
+                if class_compiler.in_init {
+                    class_compiler.init_calls_super = true;
+                }
+            }
+        }
         let name = self.identifier_constant(&self.previous().lexeme);
 
 
         let this_token = self.synthetic_this_token();
         self.named_variable(&this_token, false);
 
-        if self.match_token_type(TokenType::LeftParen) {
-            let arg_count = self.argument_list();
+        if self.match_token_type(TokenType::LeftParen) && self.argument_form() != ArgumentForm::Positional {
+            let form = self.argument_form();
+            let super_token = self.synthetic_super_token();
+            self.named_variable(&super_token, false);
+            self.emit_constant_op(Opcode::GetSuper.byte(), name);
+            self.dynamic_call(form);
+        } else if self.previous().token_type == TokenType::LeftParen {
+            let arg_count = self.method_argument_list();
             let super_token = self.synthetic_super_token();
             self.named_variable(&super_token, false);
-            self.emit_bytes(Opcode::SuperInvoke.byte(), name);
+            self.emit_constant_op(Opcode::SuperInvoke.byte(), name);
             self.emit_byte(arg_count);
+        } else {
+            let super_token = self.synthetic_super_token();
+            self.named_variable(&super_token, false);
+            self.emit_constant_op(Opcode::GetSuper.byte(), name);
         }
     }
 
     fn synthetic_super_token(&mut self) -> Token {
-        return Token::new(TokenType::Super, "super".to_string(), "super".to_string(), 0);
+        return Token::new(TokenType::Super, "super".into(), "super".into(), 0, 0);
     }
 
     fn synthetic_this_token(&mut self) -> Token {
-        return Token::new(TokenType::This, "this".to_string(), "this".to_string(), 0);
+        return Token::new(TokenType::This, "this".into(), "this".into(), 0, 0);
     }
 }
 
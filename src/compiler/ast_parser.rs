@@ -0,0 +1,587 @@
+
+use crate::ast::{Arguments, Class, Expr, ExprKind, Function, MatchArm, Stmt, StmtKind};
+use crate::token::{Token, TokenType};
+
+use super::{parse_rule, ArgumentForm, ParseFn, Parser, Precedence, MAX_LOCALS};
+
+/// Parsing into the syntax tree. The grammar and the syntax errors are those
+/// of the single pass compiler, checks that need scopes wait for the lowering
+impl Parser {
+    /// Parse the tokens into a syntax tree, the errors found are in `errors`
+    pub fn parse(&mut self) -> Vec<Stmt> {
+        let statements = self.parse_declarations_until(TokenType::Eof);
+        self.take_scan_errors();
+        return statements;
+    }
+
+    /// Parse a single expression, such as the input of `compile_expression`
+    pub fn parse_expression(&mut self) -> Expr {
+        return self.parse_precedence_node(Precedence::Assignment);
+    }
+
+    fn parse_declarations_until(&mut self, end: TokenType) -> Vec<Stmt> {
+        let mut statements = vec![];
+        while !self.check(end) && !self.is_at_end() {
+            let start = self.curr_token_index;
+            statements.push(self.parse_declaration());
+            if self.curr_token_index == start {
+                // Recovering from an error has to move on, or it would be reported forever
+                self.advance();
+            }
+        }
+        return statements;
+    }
+
+    fn parse_declaration(&mut self) -> Stmt {
+        let start = self.peek();
+        let statement = if self.match_token_type(TokenType::Fun) {
+            self.consume(TokenType::Identifier, "Expect a function name");
+            let name = self.previous();
+            Stmt { kind: StmtKind::Fun(self.parse_function(name)), start }
+        } else if self.match_token_type(TokenType::Var) {
+            Stmt { kind: self.parse_var_declaration(), start }
+        } else if self.match_token_type(TokenType::Const) {
+            Stmt { kind: self.parse_const_declaration(), start }
+        } else if self.match_token_type(TokenType::Class) {
+            Stmt { kind: self.parse_class_declaration(), start }
+        } else {
+            self.parse_statement()
+        };
+        if self.panic_mode {
+            self.synchronize();
+        }
+        return statement;
+    }
+
+    /// Parameters and body of the function whose name was just consumed
+    fn parse_function(&mut self, name: Token) -> Function {
+        let (params, rest) = self.parse_parameters();
+        self.consume(TokenType::LeftBrace, "Expect '{' before function body");
+        let body = if self.enter_nesting() { self.parse_block() } else { vec![] };
+        self.nesting -= 1;
+        return Function { name, params, rest, body, end: self.previous(), is_abstract: false };
+    }
+
+    /// `abstract name(params);` in a class body
+    fn parse_abstract_method(&mut self) -> Function {
+        self.consume(TokenType::Identifier, "Expect a method name.");
+        let name = self.previous();
+        if &*name.lexeme == "init" {
+            self.error("An initializer can't be abstract.");
+        }
+        let (params, rest) = self.parse_parameters();
+        self.consume(TokenType::Semicolon, "Expect ';' after abstract method.");
+        return Function { name, params, rest, body: vec![], end: self.previous(), is_abstract: true };
+    }
+
+    /// Parenthesized parameter list, with the `...rest` parameter if there is one
+    fn parse_parameters(&mut self) -> (Vec<Token>, Option<Token>) {
+        let mut params = vec![];
+        let mut rest = None;
+        self.consume(TokenType::LeftParen, "Expect '(' after function name");
+        if !self.check(TokenType::RightParen) {
+            loop {
+                if self.match_token_type(TokenType::Ellipsis) {
+                    // Rest parameter, not counted in arity
+                    self.consume(TokenType::Identifier, "Expect a rest parameter name");
+                    rest = Some(self.previous());
+                    if self.check(TokenType::Comma) {
+                        self.error_at_current("Rest parameter must be the last parameter");
+                    }
+                    break;
+                }
+                if params.len() + 1 >= MAX_LOCALS - 1 {
+                    self.error_at_current("Can't have more than 65534 parameters");
+                }
+                self.consume(TokenType::Identifier, "Expect a parameter name");
+                params.push(self.previous());
+                if !self.match_token_type(TokenType::Comma) {
+                    break;
+                }
+            }
+        }
+        self.consume(TokenType::RightParen, "Expect ')' after parameters");
+        return (params, rest);
+    }
+
+    fn parse_var_declaration(&mut self) -> StmtKind {
+        self.consume(TokenType::Identifier, "Expect a variable name.");
+        let name = self.previous();
+        let initializer = if self.match_token_type(TokenType::Equal) {
+            Some(self.parse_expression())
+        } else {
+            None
+        };
+        self.consume(TokenType::Semicolon, "Expect ';' after variable declaration.");
+        return StmtKind::Var { name, initializer };
+    }
+
+    fn parse_const_declaration(&mut self) -> StmtKind {
+        self.consume(TokenType::Identifier, "Expect a constant name.");
+        let name = self.previous();
+        self.consume(TokenType::Equal, "Expect '=' after constant name.");
+        let initializer = self.parse_expression();
+        self.consume(TokenType::Semicolon, "Expect ';' after constant declaration.");
+        return StmtKind::Const { name, initializer };
+    }
+
+    fn parse_class_declaration(&mut self) -> StmtKind {
+        self.consume(TokenType::Identifier, "Expect a class name.");
+        let name = self.previous();
+        let mut superclass = None;
+        if self.match_token_type(TokenType::Extend) {
+            self.consume(TokenType::Identifier, "Expect parent class name.");
+            if self.identifier_equals(&name, &self.previous()) {
+                self.error("Class cannot inherit from itself");
+            }
+            superclass = Some(self.previous());
+        }
+        self.consume(TokenType::LeftBrace, "Expect '{' before class body");
+        let mut methods = vec![];
+        let mut constants = vec![];
+        while !self.check(TokenType::RightBrace) && !self.is_at_end() {
+            if self.match_token_type(TokenType::Const) {
+                self.consume(TokenType::Identifier, "Expect a constant name.");
+                let constant_name = self.previous();
+                self.consume(TokenType::Equal, "Expect '=' after constant name.");
+                constants.push((constant_name, self.parse_expression()));
+                self.consume(TokenType::Semicolon, "Expect ';' after constant declaration.");
+                continue;
+            }
+            if self.match_token_type(TokenType::Abstract) {
+                methods.push(self.parse_abstract_method());
+                continue;
+            }
+            let start = self.curr_token_index;
+            self.consume(TokenType::Identifier, "Expect a method name.");
+            let method_name = self.previous();
+            methods.push(self.parse_function(method_name));
+            if self.curr_token_index == start {
+                // Recovering from an error has to move on, or it would be reported forever
+                self.advance();
+            }
+        }
+        self.consume(TokenType::RightBrace, "Expect '}' after class body.");
+        return StmtKind::Class(Class { name, superclass, methods, constants, end: self.previous() });
+    }
+
+    fn parse_statement(&mut self) -> Stmt {
+        let statement = if self.enter_nesting() {
+            self.parse_nested_statement()
+        } else {
+            Stmt { kind: StmtKind::Expression(Expr::new(ExprKind::Nil, self.peek())), start: self.peek() }
+        };
+        self.nesting -= 1;
+        return statement;
+    }
+
+    fn parse_nested_statement(&mut self) -> Stmt {
+        let start = self.peek();
+        let kind = if self.match_token_type(TokenType::Print) {
+            let value = self.parse_expression();
+            self.consume(TokenType::Semicolon, "Expect ';' after value.");
+            StmtKind::Print(value)
+        } else if self.match_token_type(TokenType::For) {
+            self.parse_for_statement()
+        } else if self.match_token_type(TokenType::If) {
+            self.parse_if_statement()
+        } else if self.match_token_type(TokenType::Return) {
+            self.parse_return_statement()
+        } else if self.match_token_type(TokenType::While) {
+            self.parse_while_statement()
+        } else if self.match_token_type(TokenType::Try) {
+            self.parse_try_statement()
+        } else if self.match_token_type(TokenType::Throw) {
+            let value = self.parse_expression();
+            self.consume(TokenType::Semicolon, "Expect ';' after thrown value.");
+            StmtKind::Throw(value)
+        } else if self.match_token_type(TokenType::LeftBrace) {
+            StmtKind::Block(self.parse_block())
+        } else {
+            self.parse_expression_statement()
+        };
+        return Stmt { kind, start };
+    }
+
+    fn parse_block(&mut self) -> Vec<Stmt> {
+        let statements = self.parse_declarations_until(TokenType::RightBrace);
+        self.consume(TokenType::RightBrace, "Expect '}' after block.");
+        return statements;
+    }
+
+    fn parse_expression_statement(&mut self) -> StmtKind {
+        let expression = self.parse_expression();
+        self.consume(TokenType::Semicolon, "Expect ';' after expression.");
+        return StmtKind::Expression(expression);
+    }
+
+    fn parse_while_statement(&mut self) -> StmtKind {
+        self.consume(TokenType::LeftParen, "Expect '(' after while.");
+        let condition = self.parse_expression();
+        self.consume(TokenType::RightParen, "Expect ')' after condition.");
+        let body = Box::new(self.parse_statement());
+        return StmtKind::While { condition, body };
+    }
+
+    fn parse_try_statement(&mut self) -> StmtKind {
+        self.consume(TokenType::LeftBrace, "Expect '{' after try.");
+        let body = self.parse_block();
+        self.consume(TokenType::Catch, "Expect 'catch' after try block.");
+        self.consume(TokenType::LeftParen, "Expect '(' after catch.");
+        self.consume(TokenType::Identifier, "Expect a catch variable name.");
+        let variable = self.previous();
+        self.consume(TokenType::RightParen, "Expect ')' after catch variable.");
+        self.consume(TokenType::LeftBrace, "Expect '{' before catch body.");
+        let handler = self.parse_block();
+        return StmtKind::Try { body, variable, handler };
+    }
+
+    fn parse_if_statement(&mut self) -> StmtKind {
+        self.consume(TokenType::LeftParen, "Expect '(' after if.");
+        let condition = self.parse_expression();
+        self.consume(TokenType::RightParen, "Expect ')' after condition.");
+        let then_branch = Box::new(self.parse_statement());
+        let else_branch = if self.match_token_type(TokenType::Else) {
+            Some(Box::new(self.parse_statement()))
+        } else {
+            None
+        };
+        return StmtKind::If { condition, then_branch, else_branch };
+    }
+
+    fn parse_for_statement(&mut self) -> StmtKind {
+        self.consume(TokenType::LeftParen, "Expect '(' after 'for'.");
+        if self.is_for_in() {
+            self.match_token_type(TokenType::Var);
+            self.consume(TokenType::Identifier, "Expect a loop variable name.");
+            let variable = self.previous();
+            self.consume(TokenType::Identifier, "Expect 'in' after loop variable.");
+            let iterable = self.parse_expression();
+            self.consume(TokenType::RightParen, "Expect ')' after for clauses.");
+            let body = Box::new(self.parse_statement());
+            return StmtKind::ForIn { variable, iterable, body };
+        }
+
+        let start = self.peek();
+        let initializer = if self.match_token_type(TokenType::Semicolon) {
+            None
+        } else if self.match_token_type(TokenType::Var) {
+            Some(Box::new(Stmt { kind: self.parse_var_declaration(), start }))
+        } else if self.match_token_type(TokenType::Const) {
+            Some(Box::new(Stmt { kind: self.parse_const_declaration(), start }))
+        } else {
+            Some(Box::new(Stmt { kind: self.parse_expression_statement(), start }))
+        };
+
+        let mut condition = None;
+        if !self.match_token_type(TokenType::Semicolon) {
+            condition = Some(self.parse_expression());
+            self.consume(TokenType::Semicolon, "Expect ';' after loop condition");
+        }
+        let mut increment = None;
+        if !self.match_token_type(TokenType::RightParen) {
+            increment = Some(self.parse_expression());
+            self.consume(TokenType::RightParen, "Expect ')' after for clauses.");
+        }
+        let body = Box::new(self.parse_statement());
+        return StmtKind::For { initializer, condition, increment, body };
+    }
+
+    fn parse_return_statement(&mut self) -> StmtKind {
+        if self.match_token_type(TokenType::Semicolon) {
+            return StmtKind::Return(None);
+        }
+        let value = self.parse_expression();
+        self.consume(TokenType::Semicolon, "Expect ';' after return value.");
+        return StmtKind::Return(Some(value));
+    }
+
+    fn parse_precedence_node(&mut self, precedence: Precedence) -> Expr {
+        let expression = if self.enter_nesting() {
+            self.parse_nested_precedence_node(precedence)
+        } else {
+            Expr::new(ExprKind::Nil, self.peek())
+        };
+        self.nesting -= 1;
+        return expression;
+    }
+
+    fn parse_nested_precedence_node(&mut self, precedence: Precedence) -> Expr {
+        if self.is_at_end() {
+            // Advancing would hand back the previous token and parse it again
+            self.error_at_current("Expect expression");
+            return Expr::new(ExprKind::Nil, self.peek());
+        }
+        self.advance();
+
+        let prefix_rule = parse_rule(self.previous().token_type).prefix;
+        let can_assign = precedence <= Precedence::Assignment;
+
+        if let ParseFn::None = prefix_rule {
+            // Reported as a compile error, the declaration loop resynchronizes
+            self.error("Expect expression");
+            return Expr::new(ExprKind::Nil, self.previous());
+        }
+        let mut expression = self.parse_prefix(prefix_rule, can_assign);
+
+        loop {
+            if precedence > parse_rule(self.peek().token_type).precedence {
+                break;
+            }
+
+            self.advance();
+            let infix_rule = parse_rule(self.previous().token_type).infix;
+            if let ParseFn::None = infix_rule {
+                self.error("Expect expression");
+                return expression;
+            }
+            expression = self.parse_infix(infix_rule, expression, can_assign);
+        }
+
+        if can_assign && self.match_token_type(TokenType::Equal) {
+            self.error("Invalid assignment target.");
+        }
+        return expression;
+    }
+
+    /// Expression starting with the token just consumed
+    fn parse_prefix(&mut self, rule: ParseFn, can_assign: bool) -> Expr {
+        let token = self.previous();
+        let kind = match rule {
+            ParseFn::Grouping => {
+                let inner = self.parse_expression();
+                self.consume(TokenType::RightParen, "Expect ')' after expression.");
+                ExprKind::Grouping(Box::new(inner))
+            }
+            ParseFn::Unary => ExprKind::Unary(Box::new(self.parse_precedence_node(Precedence::Unary))),
+            ParseFn::Variable => return self.parse_variable_node(token, can_assign),
+            ParseFn::String => ExprKind::String(token.literal.clone()),
+            ParseFn::Number => ExprKind::Number(token.lexeme.parse().unwrap()),
+            ParseFn::Literal => match token.token_type {
+                TokenType::True => ExprKind::Bool(true),
+                TokenType::False => ExprKind::Bool(false),
+                _ => ExprKind::Nil,
+            },
+            ParseFn::This => ExprKind::This,
+            ParseFn::Super => {
+                self.consume(TokenType::Dot, "Expect '.' after super.");
+                self.consume(TokenType::Identifier, "Expect superclass method name");
+                let method = self.previous();
+                let arguments = if self.match_token_type(TokenType::LeftParen) {
+                    Some(self.parse_arguments(true))
+                } else {
+                    None
+                };
+                ExprKind::Super { method, arguments }
+            }
+            ParseFn::List => {
+                let mut items = vec![];
+                if !self.check(TokenType::RightBracket) {
+                    loop {
+                        items.push(self.parse_expression());
+                        if items.len() > 255 {
+                            self.error("Can't have more than 255 items in a list literal.");
+                        }
+                        if !self.match_token_type(TokenType::Comma) { break; }
+                    }
+                }
+                self.consume(TokenType::RightBracket, "Expect ']' after list items.");
+                ExprKind::List(items)
+            }
+            ParseFn::Map => {
+                let mut entries = vec![];
+                if !self.check(TokenType::RightBrace) {
+                    loop {
+                        let key = self.parse_expression();
+                        self.consume(TokenType::Colon, "Expect ':' after map key.");
+                        entries.push((key, self.parse_expression()));
+                        if entries.len() > 255 {
+                            self.error("Can't have more than 255 entries in a map literal.");
+                        }
+                        if !self.match_token_type(TokenType::Comma) { break; }
+                    }
+                }
+                self.consume(TokenType::RightBrace, "Expect '}' after map entries.");
+                ExprKind::Map(entries)
+            }
+            ParseFn::PreIncrement => {
+                if !self.match_token_type(TokenType::This) {
+                    self.consume(TokenType::Identifier, "Expect a variable or field after increment operator.");
+                }
+                let target = self.previous();
+                let mut fields = vec![];
+                while self.match_token_type(TokenType::Dot) {
+                    self.consume(TokenType::Identifier, "Expect field name after '.'.");
+                    fields.push(self.previous());
+                }
+                ExprKind::PreIncrement { target, fields }
+            }
+            ParseFn::Match => self.parse_match(),
+            _ => {
+                // Only infix rules are left, which never start an expression
+                self.error("Expect expression");
+                ExprKind::Nil
+            }
+        };
+        return Expr::new(kind, token);
+    }
+
+    /// Read, assignment or postfix increment of the variable just consumed
+    fn parse_variable_node(&mut self, name: Token, can_assign: bool) -> Expr {
+        let is_assignment = can_assign && (self.match_token_type(TokenType::Equal)
+            || self.match_token_type(TokenType::PlusEqual)
+            || self.match_token_type(TokenType::MinusEqual));
+        if is_assignment {
+            let operator = self.previous();
+            let value = Box::new(self.parse_expression());
+            return Expr::new(ExprKind::Assign { name, value }, operator);
+        }
+        if self.match_token_type(TokenType::PlusPlus) || self.match_token_type(TokenType::MinusMinus) {
+            return Expr::new(ExprKind::PostIncrement { name }, self.previous());
+        }
+        return Expr::new(ExprKind::Variable, name);
+    }
+
+    /// Expression continuing the left one with the operator just consumed
+    fn parse_infix(&mut self, rule: ParseFn, left: Expr, can_assign: bool) -> Expr {
+        let token = self.previous();
+        let left = Box::new(left);
+        let kind = match rule {
+            ParseFn::Binary => {
+                let next_prec = parse_rule(token.token_type).precedence.higher();
+                ExprKind::Binary(left, Box::new(self.parse_precedence_node(next_prec)))
+            }
+            ParseFn::And => ExprKind::And(left, Box::new(self.parse_precedence_node(Precedence::And))),
+            ParseFn::Or => ExprKind::Or(left, Box::new(self.parse_precedence_node(Precedence::Or))),
+            ParseFn::NilCoalesce => ExprKind::NilCoalesce(left, Box::new(self.parse_precedence_node(Precedence::Or))),
+            ParseFn::Call => {
+                let arguments = self.parse_arguments(false);
+                // Calls are attributed to their closing parenthesis
+                return Expr::new(ExprKind::Call { callee: left, arguments }, self.previous());
+            }
+            ParseFn::Dot => {
+                self.consume(TokenType::Identifier, "Expect field name after '.'.");
+                let name = self.previous();
+                if can_assign && self.match_token_type(TokenType::Equal) {
+                    let value = Box::new(self.parse_expression());
+                    return Expr::new(ExprKind::Set { object: left, name, value }, token);
+                } else if self.match_token_type(TokenType::LeftParen) {
+                    let arguments = self.parse_arguments(true);
+                    return Expr::new(ExprKind::Invoke { object: left, name, arguments }, self.previous());
+                } else if self.match_token_type(TokenType::PlusPlus) || self.match_token_type(TokenType::MinusMinus) {
+                    return Expr::new(ExprKind::PropertyIncrement { object: left, name }, self.previous());
+                }
+                return Expr::new(ExprKind::Get { object: left, name: name.clone() }, name);
+            }
+            ParseFn::Index => {
+                let index = Box::new(self.parse_expression());
+                self.consume(TokenType::RightBracket, "Expect ']' after index.");
+                if can_assign && self.match_token_type(TokenType::Equal) {
+                    let value = Box::new(self.parse_expression());
+                    ExprKind::SetIndex { object: left, index, value }
+                } else {
+                    ExprKind::Index { object: left, index }
+                }
+            }
+            _ => {
+                // Only prefix rules are left, which never continue an expression
+                self.error("Expect expression");
+                return *left;
+            }
+        };
+        return Expr::new(kind, token);
+    }
+
+    /// Arguments after the '(' just consumed, up to the closing ')'. Methods
+    /// take at most 255 positional arguments
+    fn parse_arguments(&mut self, is_method: bool) -> Arguments {
+        let arguments = match self.argument_form() {
+            ArgumentForm::Positional => {
+                let mut arguments = vec![];
+                if !self.check(TokenType::RightParen) {
+                    loop {
+                        let argument = self.parse_expression();
+                        if arguments.len() == MAX_LOCALS - 1 {
+                            self.error("Can't have more than 65535 arguments.");
+                        }
+                        arguments.push(argument);
+                        if !self.match_token_type(TokenType::Comma) { break; }
+                    }
+                }
+                Arguments::Positional(arguments)
+            }
+            ArgumentForm::Spread => {
+                let mut arguments = vec![];
+                if !self.check(TokenType::RightParen) {
+                    loop {
+                        let is_spread = self.match_token_type(TokenType::Ellipsis);
+                        arguments.push((is_spread, self.parse_expression()));
+                        if !self.match_token_type(TokenType::Comma) { break; }
+                    }
+                }
+                Arguments::Spread(arguments)
+            }
+            ArgumentForm::Named => {
+                let mut positional = vec![];
+                let mut named = vec![];
+                loop {
+                    if self.match_token_type(TokenType::Ellipsis) {
+                        self.error("Can't mix spread and named arguments.");
+                    }
+                    let is_named = self.check(TokenType::Identifier)
+                        && self.token_at(self.curr_token_index + 1).token_type == TokenType::Colon;
+                    if is_named {
+                        let name = self.advance();
+                        self.advance(); // ':'
+                        let value = self.parse_expression();
+                        if named.len() == 255 {
+                            self.error("Can't have more than 255 named arguments.");
+                        }
+                        named.push((name, value));
+                    } else {
+                        if !named.is_empty() {
+                            self.error("Positional arguments must come before named arguments.");
+                        }
+                        let value = self.parse_expression();
+                        if positional.len() == 255 {
+                            self.error("Can't have more than 255 arguments.");
+                        }
+                        positional.push(value);
+                    }
+                    if !self.match_token_type(TokenType::Comma) { break; }
+                }
+                Arguments::Named { positional, named }
+            }
+        };
+        self.consume(TokenType::RightParen, "Expect ')' after arguments");
+        if let Arguments::Positional(positional) = &arguments {
+            if is_method && positional.len() > u8::MAX as usize {
+                self.error("Can't pass more than 255 arguments to a method.");
+            }
+        }
+        return arguments;
+    }
+
+    /// `match (subject) { pattern if guard => value, ... }` after the `match`
+    fn parse_match(&mut self) -> ExprKind {
+        self.consume(TokenType::LeftParen, "Expect '(' after match.");
+        let subject = Box::new(self.parse_expression());
+        self.consume(TokenType::RightParen, "Expect ')' after match subject.");
+        self.consume(TokenType::LeftBrace, "Expect '{' before match arms.");
+        let mut arms = vec![];
+        while !self.check(TokenType::RightBrace) && !self.is_at_end() {
+            let pattern = self.pattern();
+            let guard = if self.match_token_type(TokenType::If) {
+                Some(self.parse_expression())
+            } else {
+                None
+            };
+            self.consume(TokenType::FatArrow, "Expect '=>' after match pattern.");
+            let body = self.parse_expression();
+            arms.push(MatchArm { pattern, guard, body });
+            if !self.match_token_type(TokenType::Comma) { break; }
+        }
+        self.consume(TokenType::RightBrace, "Expect '}' after match arms.");
+        return ExprKind::Match { subject, arms };
+    }
+}
@@ -0,0 +1,742 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use crate::ast::{Arguments, Class, Expr, ExprKind, Function as FunctionNode, MatchArm, Member, Stmt, StmtKind};
+use crate::function::Function;
+use crate::token::{Token, TokenType};
+use crate::{Object, Opcode, Value};
+
+use super::{ClassCompiler, ClassInfo, Compiler, DeclarationKind, FunctionType, Parser};
+
+/// Lowering of the syntax tree to chunks. It emits the same code as the
+/// single pass compiler and reports the errors that need scopes, such as
+/// reading a local in its own initializer
+impl Parser {
+    /// Point the code emitted and the errors reported from here on at the token
+    fn at(&mut self, token: &Token) {
+        self.node_token = Some(token.clone());
+    }
+
+    /// Lower the declarations of the script or a block. Declarations after
+    /// one that always returns or throws are still checked, but their code is dropped
+    pub(super) fn lower_declarations(&mut self, statements: &[Stmt]) {
+        self.exited = false;
+        let mut dead_code_start = None;
+        for statement in statements {
+            if self.exited && dead_code_start.is_none() {
+                let start = &statement.start;
+                self.warning_at(start.line, start.column, &start.lexeme, "Unreachable code.");
+                dead_code_start = Some(self.current_function().chunk.code.len());
+            }
+            self.lower_declaration(statement);
+        }
+        if let Some(start) = dead_code_start {
+            self.current_function().chunk.truncate(start);
+            self.exited = true;
+        }
+    }
+
+    fn lower_declaration(&mut self, statement: &Stmt) {
+        match &statement.kind {
+            StmtKind::Fun(function) => {
+                self.at(&function.name);
+                let global = self.declare_name();
+                self.declared_as(DeclarationKind::Function);
+                self.mark_initialized();
+                self.lower_function(function, FunctionType::Function);
+                self.define_declaration(global);
+            }
+            StmtKind::Var { name, initializer } => self.lower_var_declaration(name, initializer.as_ref()),
+            StmtKind::Const { name, initializer } => self.lower_const_declaration(name, initializer),
+            StmtKind::Class(class) => self.lower_class_declaration(class),
+            _ => self.lower_statement(statement),
+        }
+        // Each declaration reports its first error, like the parser does
+        self.panic_mode = false;
+    }
+
+    fn lower_function(&mut self, node: &FunctionNode, function_type: FunctionType) {
+        self.at(&node.name);
+        let function = Function::new(node.name.lexeme.to_string(), self.function_arity);
+        let func_idx = self.heap.alloc_function(function);
+
+        let compiler = Compiler::new(self.curr_compiler_index, func_idx, function_type);
+        self.curr_compiler_index = self.compilers.len();
+        self.compilers.push(compiler);
+        let compiler_idx = self.compilers.len() - 1;
+
+        self.begin_scope();
+        for param in &node.params {
+            self.current_function().arity += 1;
+            self.at(param);
+            let constant = self.declare_name();
+            self.declared_as(DeclarationKind::Parameter);
+            self.define_variable(constant);
+            self.current_function().param_names.push(param.lexeme.to_string());
+        }
+        if let Some(rest) = &node.rest {
+            self.current_function().is_variadic = true;
+            self.at(rest);
+            let constant = self.declare_name();
+            self.declared_as(DeclarationKind::Parameter);
+            self.define_variable(constant);
+        }
+        self.lower_declarations(&node.body);
+        // Returning from the body doesn't make the code after the declaration unreachable
+        self.exited = false;
+
+        self.at(&node.end);
+        self.end_compiler();
+        self.emit_closure(func_idx, compiler_idx);
+    }
+
+    fn lower_var_declaration(&mut self, name: &Token, initializer: Option<&Expr>) {
+        self.at(name);
+        let global = self.declare_name();
+        match initializer {
+            Some(initializer) => self.lower_expression(initializer),
+            None => self.emit_byte(Opcode::Nil.byte()),
+        }
+        self.at(name);
+        self.define_variable(global);
+    }
+
+    fn lower_const_declaration(&mut self, name: &Token, initializer: &Expr) {
+        self.at(name);
+        let global = self.declare_name();
+        self.lower_expression(initializer);
+        self.at(name);
+        if self.current_scope_depth() > 0 {
+            let index = self.curr_compiler_index;
+            self.compilers[index].locals.last_mut().unwrap().is_const = true;
+            self.mark_initialized();
+            return;
+        }
+        self.emit_constant_op(Opcode::DefineConstGlobal.byte(), global);
+    }
+
+    fn lower_class_declaration(&mut self, class: &Class) {
+        let class_name = &class.name;
+        self.at(class_name);
+        let name_constant = self.identifier_constant(&class_name.lexeme);
+        self.declare_variable();
+        self.declared_as(DeclarationKind::Class);
+
+        self.emit_constant_op(Opcode::Class.byte(), name_constant);
+        self.define_declaration(name_constant);
+
+        self.current_class = Some(Box::new(RefCell::new(ClassCompiler::new(self.current_class.take()))));
+
+        let superclass_info = class.superclass.as_ref()
+            .filter(|superclass| !self.is_local_name(&superclass.lexeme))
+            .and_then(|superclass| self.global_classes.get(&superclass.lexeme).cloned());
+        if let Some(superclass) = &class.superclass {
+            self.at(superclass);
+            self.mark_read(&superclass.lexeme);
+            self.get_variable(superclass);
+
+            self.begin_scope();
+            let current_scope_depth = self.current_scope_depth();
+            self.compilers[self.curr_compiler_index].add_local("super".into(), current_scope_depth, superclass);
+            self.define_variable(0);
+
+            self.get_variable(class_name);
+            self.emit_byte(Opcode::Inherit.byte());
+            self.current_class.as_ref().unwrap().borrow_mut().has_superclass = true;
+        }
+
+        self.at(class_name);
+        self.get_variable(class_name);
+
+        for member in class.members() {
+            let method = match member {
+                Member::Method(method) => method,
+                Member::Constant(name, value) => {
+                    self.lower_expression(value);
+                    self.at(name);
+                    let constant = self.identifier_constant(&name.lexeme);
+                    self.emit_constant_op(Opcode::ClassConstant.byte(), constant);
+                    continue;
+                }
+            };
+            self.at(&method.name);
+            let constant = self.identifier_constant(&method.name.lexeme);
+            if method.is_abstract {
+                self.at(&method.end);
+                self.emit_constant_op(Opcode::AbstractMethod.byte(), constant);
+                continue;
+            }
+            if &*method.name.lexeme == "init" {
+                self.current_class.as_ref().unwrap().borrow_mut().in_init = true;
+                self.lower_function(method, FunctionType::Initializer);
+                let class_compiler = self.current_class.as_ref().unwrap();
+                class_compiler.borrow_mut().in_init = false;
+                let calls_super = class_compiler.borrow().init_calls_super;
+                let superclass_arity = superclass_info.as_ref().and_then(|info| info.init_arity).unwrap_or(0);
+                if !calls_super && superclass_arity > 0 {
+                    let superclass = class.superclass.as_ref().unwrap();
+                    let message = format!("init never calls super.init, the init of {} takes {} argument(s).",
+                                          superclass.lexeme, superclass_arity);
+                    self.warning_at(method.name.line, method.name.column, &method.name.lexeme, &message);
+                }
+            } else {
+                self.lower_function(method, FunctionType::Method);
+            }
+            self.emit_constant_op(Opcode::Method.byte(), constant);
+        }
+        self.at(&class.end);
+        self.emit_byte(Opcode::Pop.byte()); // pop class name
+
+        if self.current_class.as_ref().unwrap().borrow().has_superclass {
+            self.end_scope();
+        }
+        self.current_class = self.enclosing_class();
+        if self.current_scope_depth() == 0 {
+            let info = Self::class_info(class, superclass_info);
+            self.global_classes.insert(class.name.lexeme.clone(), info);
+        }
+    }
+
+    /// Abstract methods and initializer of the class, starting from what its
+    /// superclass has
+    fn class_info(class: &Class, superclass_info: Option<ClassInfo>) -> ClassInfo {
+        let mut info = superclass_info.unwrap_or_default();
+        for method in &class.methods {
+            info.abstract_methods.retain(|name| *name != method.name.lexeme);
+            if method.is_abstract {
+                info.abstract_methods.push(method.name.lexeme.clone());
+            } else if &*method.name.lexeme == "init" {
+                info.init_arity = Some(method.params.len());
+            }
+        }
+        return info;
+    }
+
+    /// Report a call to a global class known to have abstract methods left
+    fn check_instantiation(&mut self, callee: &Expr) {
+        if !matches!(callee.kind, ExprKind::Variable) {
+            return;
+        }
+        if let Some(message) = self.abstract_instantiation(&callee.token.lexeme) {
+            self.at(&callee.token);
+            self.error(&message);
+        }
+    }
+
+    fn lower_statement(&mut self, statement: &Stmt) {
+        self.at(&statement.start);
+        match &statement.kind {
+            StmtKind::Print(value) => {
+                self.lower_expression(value);
+                self.at(&statement.start);
+                self.emit_byte(Opcode::Print.byte());
+            }
+            StmtKind::Expression(expression) => {
+                self.lower_expression(expression);
+                self.emit_byte(Opcode::Pop.byte());
+            }
+            StmtKind::For { initializer, condition, increment, body } => {
+                self.lower_for_statement(initializer.as_deref(), condition.as_ref(), increment.as_ref(), body);
+            }
+            StmtKind::ForIn { variable, iterable, body } => self.lower_for_in_statement(variable, iterable, body),
+            StmtKind::If { condition, then_branch, else_branch } => {
+                self.lower_expression(condition);
+                let then_jump = self.emit_jump(Opcode::JumpIfFalse.byte());
+                self.emit_byte(Opcode::Pop.byte());
+                self.lower_statement(then_branch);
+
+                let else_jump = self.emit_jump(Opcode::Jump.byte());
+                self.patch_jump(then_jump);
+                self.emit_byte(Opcode::Pop.byte());
+                if let Some(else_branch) = else_branch {
+                    self.lower_statement(else_branch);
+                }
+                self.patch_jump(else_jump);
+            }
+            StmtKind::Return(value) => {
+                self.lower_return_statement(value.as_ref());
+                self.exited = true;
+                return;
+            }
+            StmtKind::While { condition, body } => {
+                let loop_start = self.current_function().chunk.code.len();
+                self.lower_expression(condition);
+                let exit_jump = self.emit_jump(Opcode::JumpIfFalse.byte());
+                self.emit_byte(Opcode::Pop.byte());
+                self.lower_statement(body);
+                self.emit_loop(loop_start);
+                self.patch_jump(exit_jump);
+                self.emit_byte(Opcode::Pop.byte());
+            }
+            StmtKind::Try { body, variable, handler } => {
+                let handler_jump = self.emit_jump(Opcode::PushHandler.byte());
+                self.begin_scope();
+                self.lower_declarations(body);
+                self.end_scope();
+                self.emit_byte(Opcode::PopHandler.byte());
+                let end_jump = self.emit_jump(Opcode::Jump.byte());
+
+                self.patch_jump(handler_jump);
+                self.begin_scope();
+                let depth = self.current_scope_depth();
+                self.compilers[self.curr_compiler_index].add_local(Rc::clone(&variable.lexeme), depth, variable);
+                self.lower_declarations(handler);
+                self.end_scope();
+                self.patch_jump(end_jump);
+            }
+            StmtKind::Throw(value) => {
+                self.lower_expression(value);
+                self.at(&statement.start);
+                self.emit_byte(Opcode::Throw.byte());
+                self.exited = true;
+                return;
+            }
+            StmtKind::Block(statements) => {
+                // A block exits when one of its statements does
+                self.begin_scope();
+                self.lower_declarations(statements);
+                self.end_scope();
+                return;
+            }
+            StmtKind::Var { .. } | StmtKind::Const { .. } | StmtKind::Fun(_) | StmtKind::Class(_) => {
+                // The parser only puts declarations where they are allowed
+                self.lower_declaration(statement);
+            }
+        }
+        self.exited = false;
+    }
+
+    fn lower_for_statement(&mut self, initializer: Option<&Stmt>, condition: Option<&Expr>,
+                           increment: Option<&Expr>, body: &Stmt) {
+        self.begin_scope();
+        let mut loop_variable = None;
+        if let Some(initializer) = initializer {
+            match &initializer.kind {
+                StmtKind::Var { name, initializer } => {
+                    self.lower_var_declaration(name, initializer.as_ref());
+                    loop_variable = Some(self.current_compiler().locals.len() - 1);
+                }
+                StmtKind::Const { name, initializer } => {
+                    self.lower_const_declaration(name, initializer);
+                    loop_variable = Some(self.current_compiler().locals.len() - 1);
+                }
+                _ => {
+                    self.lower_statement(initializer);
+                }
+            }
+        }
+
+        let mut loop_start = self.current_function().chunk.code.len();
+        let mut exit_jump = None;
+        if let Some(condition) = condition {
+            self.lower_expression(condition);
+            // Jump out of the loop if condition is false
+            exit_jump = Some(self.emit_jump(Opcode::JumpIfFalse.byte()));
+            self.emit_byte(Opcode::Pop.byte());
+        }
+
+        if let Some(increment) = increment {
+            let body_jump = self.emit_jump(Opcode::Jump.byte());
+            let increment_start = self.current_function().chunk.code.len();
+            self.lower_expression(increment);
+            self.emit_byte(Opcode::Pop.byte());
+
+            self.emit_loop(loop_start);
+            loop_start = increment_start;
+            self.patch_jump(body_jump);
+        }
+
+        match loop_variable {
+            Some(slot) => self.loop_body_with_fresh_variable(slot, true, |parser| parser.lower_statement(body)),
+            None => self.lower_statement(body),
+        }
+
+        self.emit_loop(loop_start);
+
+        if let Some(exit_jump) = exit_jump {
+            self.patch_jump(exit_jump);
+            self.emit_byte(Opcode::Pop.byte());
+        }
+
+        self.end_scope();
+    }
+
+    fn lower_for_in_statement(&mut self, variable: &Token, iterable: &Expr, body: &Stmt) {
+        self.begin_scope();
+        self.lower_expression(iterable);
+        self.at(variable);
+        let seq_slot = self.add_hidden_local(" seq");
+        self.emit_constant(Value::number(0.0));
+        self.add_hidden_local(" index");
+        self.emit_byte(Opcode::Nil.byte());
+        let depth = self.current_scope_depth();
+        self.compilers[self.curr_compiler_index].add_local(Rc::clone(&variable.lexeme), depth, variable);
+
+        let exit_jump = self.begin_for_iter(seq_slot);
+        let variable_slot = self.current_compiler().locals.len() - 1;
+        self.loop_body_with_fresh_variable(variable_slot, false, |parser| parser.lower_statement(body));
+        self.end_for_iter(exit_jump);
+        self.end_scope();
+    }
+
+    fn lower_return_statement(&mut self, value: Option<&Expr>) {
+        if self.current_function().name == "main" {
+            self.error("Can't return from main.");
+            return;
+        }
+        match value {
+            None => self.emit_return(),
+            Some(value) => {
+                if let FunctionType::Initializer = self.current_compiler().function_type {
+                    self.error("Can't return value from an initializer.");
+                }
+                self.lower_expression(value);
+                self.emit_byte(Opcode::Return.byte());
+            }
+        }
+    }
+
+    pub(super) fn lower_expression(&mut self, expression: &Expr) {
+        let token = &expression.token;
+        self.at(token);
+        match &expression.kind {
+            ExprKind::Number(value) => self.emit_constant(Value::number(*value)),
+            ExprKind::String(value) => {
+                let string_hash = self.heap.alloc_string(value.to_string());
+                self.emit_constant(Value::object(Object::StringHash(string_hash)));
+            }
+            ExprKind::Bool(true) => self.emit_byte(Opcode::True.byte()),
+            ExprKind::Bool(false) => self.emit_byte(Opcode::False.byte()),
+            ExprKind::Nil => self.emit_byte(Opcode::Nil.byte()),
+            ExprKind::Grouping(inner) => self.lower_expression(inner),
+            ExprKind::Unary(operand) => {
+                self.lower_expression(operand);
+                self.at(token);
+                match token.token_type {
+                    TokenType::Minus => self.emit_byte(Opcode::Negate.byte()),
+                    _ => self.emit_byte(Opcode::Not.byte()),
+                }
+            }
+            ExprKind::Binary(left, right) => {
+                self.lower_expression(left);
+                self.lower_expression(right);
+                self.at(token);
+                match token.token_type {
+                    TokenType::Plus => self.emit_byte(Opcode::Add.byte()),
+                    TokenType::Star => self.emit_byte(Opcode::Multiply.byte()),
+                    TokenType::Slash => self.emit_byte(Opcode::Divide.byte()),
+                    TokenType::Minus => self.emit_byte(Opcode::Subtract.byte()),
+                    TokenType::BangEqual => self.emit_bytes(Opcode::Equal.byte(), Opcode::Not.byte()),
+                    TokenType::EqualEqual => self.emit_byte(Opcode::Equal.byte()),
+                    TokenType::Less => self.emit_byte(Opcode::Less.byte()),
+                    TokenType::LessEqual => self.emit_bytes(Opcode::Greater.byte(), Opcode::Not.byte()),
+                    TokenType::Greater => self.emit_byte(Opcode::Greater.byte()),
+                    TokenType::GreaterEqual => self.emit_bytes(Opcode::Less.byte(), Opcode::Not.byte()),
+                    _ => {
+                        panic!("Unreachable code");
+                    }
+                }
+            }
+            ExprKind::And(left, right) => self.lower_short_circuit(left, token, right, Opcode::JumpIfFalse),
+            ExprKind::Or(left, right) => self.lower_short_circuit(left, token, right, Opcode::JumpIfTrue),
+            ExprKind::NilCoalesce(left, right) => self.lower_short_circuit(left, token, right, Opcode::JumpIfNotNil),
+            ExprKind::Variable => {
+                self.mark_read(&token.lexeme);
+                self.get_variable(token);
+            }
+            ExprKind::Assign { name, value } => self.lower_assignment(name, token, value),
+            ExprKind::PostIncrement { name } => {
+                self.at(name);
+                self.mark_read(&name.lexeme);
+                let (get_op, set_op, arg) = self.resolve_variable(name);
+                if self.is_const_local(self.curr_compiler_index, name) {
+                    self.error("Can't assign to a constant.");
+                }
+                // Store the new value, then undo the step on the copy left behind
+                let (step, undo) = Self::increment_ops(token.token_type);
+                self.emit_variable_op(get_op, arg);
+                self.emit_constant(Value::number(1.0));
+                self.emit_byte(step);
+                self.emit_variable_op(set_op, arg);
+                self.emit_constant(Value::number(1.0));
+                self.emit_byte(undo);
+            }
+            ExprKind::PreIncrement { target, fields } => self.lower_pre_increment(token, target, fields),
+            ExprKind::This => {
+                if self.current_class.is_none() {
+                    self.error("Can't use 'this' outside of class");
+                    return;
+                }
+                self.mark_read(&token.lexeme);
+                self.get_variable(token);
+            }
+            ExprKind::Super { method, arguments } => self.lower_super(token, method, arguments.as_ref()),
+            ExprKind::Get { object, name } => {
+                self.lower_expression(object);
+                self.at(name);
+                let name = self.identifier_constant(&name.lexeme);
+                self.emit_constant_op(Opcode::GetProperty.byte(), name);
+            }
+            ExprKind::Set { object, name, value } => {
+                self.lower_expression(object);
+                self.at(name);
+                let name = self.identifier_constant(&name.lexeme);
+                self.lower_expression(value);
+                self.at(token);
+                self.emit_constant_op(Opcode::SetProperty.byte(), name);
+            }
+            ExprKind::PropertyIncrement { object, name } => {
+                self.lower_expression(object);
+                self.at(name);
+                let name = self.identifier_constant(&name.lexeme);
+                let (step, undo) = Self::increment_ops(token.token_type);
+                self.emit_byte(Opcode::Dup.byte());
+                self.emit_constant_op(Opcode::GetProperty.byte(), name);
+                self.emit_constant(Value::number(1.0));
+                self.emit_byte(step);
+                self.emit_constant_op(Opcode::SetProperty.byte(), name);
+                self.emit_constant(Value::number(1.0));
+                self.emit_byte(undo);
+            }
+            ExprKind::Invoke { object, name, arguments } => {
+                self.lower_expression(object);
+                self.at(name);
+                let name = self.identifier_constant(&name.lexeme);
+                match arguments {
+                    Arguments::Positional(arguments) => {
+                        self.lower_expressions(arguments);
+                        self.at(token);
+                        self.emit_constant_op(Opcode::Invoke.byte(), name);
+                        self.emit_byte(arguments.len() as u8);
+                    }
+                    _ => {
+                        self.emit_constant_op(Opcode::GetProperty.byte(), name);
+                        self.lower_dynamic_call(arguments, token);
+                    }
+                }
+            }
+            ExprKind::Call { callee, arguments } => {
+                self.check_instantiation(callee);
+                self.lower_expression(callee);
+                match arguments {
+                    Arguments::Positional(arguments) => {
+                        self.lower_expressions(arguments);
+                        self.at(token);
+                        let arg_count = arguments.len();
+                        if arg_count <= u8::MAX as usize {
+                            self.emit_bytes(Opcode::Call.byte(), arg_count as u8);
+                        } else {
+                            self.emit_byte(Opcode::CallLong.byte());
+                            self.emit_short(arg_count);
+                        }
+                    }
+                    _ => self.lower_dynamic_call(arguments, token),
+                }
+            }
+            ExprKind::List(items) => {
+                self.lower_expressions(items);
+                self.at(token);
+                self.emit_bytes(Opcode::BuildList.byte(), items.len() as u8);
+            }
+            ExprKind::Map(entries) => {
+                for (key, value) in entries {
+                    self.lower_expression(key);
+                    self.lower_expression(value);
+                }
+                self.at(token);
+                self.emit_bytes(Opcode::BuildMap.byte(), entries.len() as u8);
+            }
+            ExprKind::Index { object, index } => {
+                self.lower_expression(object);
+                self.lower_expression(index);
+                self.at(token);
+                self.emit_byte(Opcode::GetIndex.byte());
+            }
+            ExprKind::SetIndex { object, index, value } => {
+                self.lower_expression(object);
+                self.lower_expression(index);
+                self.lower_expression(value);
+                self.at(token);
+                self.emit_byte(Opcode::SetIndex.byte());
+            }
+            ExprKind::Match { subject, arms } => self.lower_match(token, subject, arms),
+        }
+    }
+
+    fn lower_expressions(&mut self, expressions: &[Expr]) {
+        for expression in expressions {
+            self.lower_expression(expression);
+        }
+    }
+
+    /// `and`, `or` and `??` skip the right operand when the jump keeps the left one
+    fn lower_short_circuit(&mut self, left: &Expr, operator: &Token, right: &Expr, jump: Opcode) {
+        self.lower_expression(left);
+        self.at(operator);
+        let end_jump = self.emit_jump(jump.byte());
+        self.emit_byte(Opcode::Pop.byte());
+        self.lower_expression(right);
+        self.patch_jump(end_jump);
+    }
+
+    /// `name = value`, `name += value` or `name -= value`
+    fn lower_assignment(&mut self, name: &Token, operator: &Token, value: &Expr) {
+        self.at(name);
+        if operator.token_type != TokenType::Equal {
+            self.mark_read(&name.lexeme);
+        }
+        let (get_op, set_op, arg) = self.resolve_variable(name);
+        if self.is_const_local(self.curr_compiler_index, name) {
+            self.error("Can't assign to a constant.");
+        }
+        match operator.token_type {
+            TokenType::PlusEqual | TokenType::MinusEqual => {
+                self.emit_variable_op(get_op, arg);
+                self.lower_expression(value);
+                self.at(operator);
+                let op = if operator.token_type == TokenType::PlusEqual { Opcode::Add } else { Opcode::Subtract };
+                self.emit_byte(op.byte());
+            }
+            _ => {
+                self.lower_expression(value);
+                self.at(operator);
+            }
+        }
+        self.emit_variable_op(set_op, arg);
+    }
+
+    /// Prefix `++` or `--` on a variable or a field chain such as `++a.b.c`
+    fn lower_pre_increment(&mut self, operator: &Token, target: &Token, fields: &[Token]) {
+        let (step, _) = Self::increment_ops(operator.token_type);
+        self.at(target);
+        let (get_op, set_op, arg) = self.resolve_variable(target);
+        if fields.is_empty() {
+            if self.is_const_local(self.curr_compiler_index, target) {
+                self.error("Can't assign to a constant.");
+            }
+            self.emit_variable_op(get_op, arg);
+            self.emit_constant(Value::number(1.0));
+            self.emit_byte(step);
+            self.emit_variable_op(set_op, arg);
+            return;
+        }
+        self.emit_variable_op(get_op, arg);
+        for (i, field) in fields.iter().enumerate() {
+            self.at(field);
+            let name = self.identifier_constant(&field.lexeme);
+            if i + 1 < fields.len() {
+                self.emit_constant_op(Opcode::GetProperty.byte(), name);
+                continue;
+            }
+            self.emit_byte(Opcode::Dup.byte());
+            self.emit_constant_op(Opcode::GetProperty.byte(), name);
+            self.emit_constant(Value::number(1.0));
+            self.emit_byte(step);
+            self.emit_constant_op(Opcode::SetProperty.byte(), name);
+        }
+    }
+
+    fn lower_super(&mut self, keyword: &Token, method: &Token, arguments: Option<&Arguments>) {
+        if self.current_class.is_none() {
+            self.error("Can't use 'super' outside of a class.");
+        } else if !self.current_class.as_ref().unwrap().borrow().has_superclass {
+            self.error("Can't use 'super' in a class with no parent class");
+        } else if &*method.lexeme == "init" {
+            let mut class_compiler = self.current_class.as_ref().unwrap().borrow_mut();
+            if class_compiler.in_init {
+                class_compiler.init_calls_super = true;
+            }
+        }
+        self.at(method);
+        let name = self.identifier_constant(&method.lexeme);
+
+        let this_token = self.synthetic_this_token();
+        self.get_variable(&this_token);
+        let super_token = self.synthetic_super_token();
+        match arguments {
+            Some(Arguments::Positional(arguments)) => {
+                self.lower_expressions(arguments);
+                self.at(keyword);
+                self.get_variable(&super_token);
+                self.emit_constant_op(Opcode::SuperInvoke.byte(), name);
+                self.emit_byte(arguments.len() as u8);
+            }
+            Some(arguments) => {
+                self.get_variable(&super_token);
+                self.emit_constant_op(Opcode::GetSuper.byte(), name);
+                self.lower_dynamic_call(arguments, keyword);
+            }
+            None => {
+                self.get_variable(&super_token);
+                self.emit_constant_op(Opcode::GetSuper.byte(), name);
+            }
+        }
+    }
+
+    /// Call the value on top of the stack with spread or named arguments
+    fn lower_dynamic_call(&mut self, arguments: &Arguments, token: &Token) {
+        match arguments {
+            Arguments::Spread(arguments) => {
+                // Collect the arguments into one list, expanding `...list` arguments in place
+                self.emit_bytes(Opcode::BuildList.byte(), 0);
+                for (is_spread, argument) in arguments {
+                    self.lower_expression(argument);
+                    let opcode = if *is_spread { Opcode::ListExtend } else { Opcode::ListAppend };
+                    self.emit_byte(opcode.byte());
+                }
+                self.at(token);
+                self.emit_byte(Opcode::CallSpread.byte());
+            }
+            Arguments::Named { positional, named } => {
+                self.lower_expressions(positional);
+                // Each name is pushed as a string before its value
+                for (name, value) in named {
+                    self.at(name);
+                    let name = self.heap.alloc_string(name.lexeme.to_string());
+                    self.emit_constant(Value::object(Object::string(name)));
+                    self.lower_expression(value);
+                }
+                self.at(token);
+                self.emit_bytes(Opcode::CallNamed.byte(), positional.len() as u8);
+                self.emit_byte(named.len() as u8);
+            }
+            Arguments::Positional(_) => panic!("Unreachable code"),
+        }
+    }
+
+    /// The arms are compiled into their own function, called straight away,
+    /// see `match_expression`
+    fn lower_match(&mut self, keyword: &Token, subject: &Expr, arms: &[MatchArm]) {
+        let func_idx = self.heap.alloc_function(Function::new("match".to_string(), 0));
+        let compiler = Compiler::new(self.curr_compiler_index, func_idx, FunctionType::Function);
+        self.curr_compiler_index = self.compilers.len();
+        self.compilers.push(compiler);
+        let compiler_idx = self.compilers.len() - 1;
+        self.begin_scope();
+
+        self.lower_expression(subject);
+        self.at(keyword);
+        let subject_slot = self.add_hidden_local(" subject");
+
+        for arm in arms {
+            let (first_binding, fail_jumps) = self.begin_match_arm(&arm.pattern, subject_slot);
+            let mut guard_jump = None;
+            if let Some(guard) = &arm.guard {
+                self.lower_expression(guard);
+                guard_jump = Some(self.emit_jump(Opcode::JumpIfFalse.byte()));
+                self.emit_byte(Opcode::Pop.byte());
+            }
+            self.lower_expression(&arm.body);
+            self.end_match_arm(first_binding, guard_jump, fail_jumps);
+        }
+
+        self.at(keyword);
+        let message = self.heap.alloc_string("No match arm for value.".to_string());
+        self.emit_constant(Value::object(Object::string(message)));
+        self.emit_byte(Opcode::Throw.byte());
+
+        self.end_compiler();
+        self.emit_closure(func_idx, compiler_idx);
+        self.emit_bytes(Opcode::Call.byte(), 0);
+    }
+}
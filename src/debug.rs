@@ -1,216 +1,399 @@
+use std::fmt::Write;
+
 use crate::{Chunk, Heap, Object, Opcode, Value};
+use crate::token::Token;
 
 
-fn simple_instruction(name: &str, offset: usize) ->usize {
-    println!("{}", name);
+fn simple_instruction(out: &mut String, name: &str, offset: usize) ->usize {
+    writeln!(out, "{}", name).unwrap();
     return offset + 1;
 }
 
-fn constant_instruction(name: &str, chunk: &Chunk, heap: &Heap, offset: usize) ->usize {
-    let constant = chunk.code.get(offset + 1).unwrap();
-    print!("{: <20} | {: >6} | ", name, constant);
-    let value = chunk.constants.get(*constant as usize).unwrap();
-    match value {
+/// Instruction with a constant operand, `wide` holds the upper bits of the
+/// index when a Wide instruction came before
+fn constant_instruction(out: &mut String, name: &str, chunk: &Chunk, heap: &Heap, offset: usize, wide: usize) ->usize {
+    let constant = wide | *chunk.code.get(offset + 1).unwrap() as usize;
+    print_constant(out, name, chunk, heap, constant);
+    return offset + 2;
+}
+
+fn constant_long_instruction(out: &mut String, name: &str, chunk: &Chunk, heap: &Heap, offset: usize) ->usize {
+    let constant = (chunk.code[offset + 1] as usize) << 16
+        | (chunk.code[offset + 2] as usize) << 8
+        | chunk.code[offset + 3] as usize;
+    print_constant(out, name, chunk, heap, constant);
+    return offset + 4;
+}
+
+fn print_constant(out: &mut String, name: &str, chunk: &Chunk, heap: &Heap, constant: usize) {
+    let value = chunk.constants.get(constant).unwrap();
+    writeln!(out, "{: <20} | {: >6} | {: <20}", name, constant, constant_text(*value, heap)).unwrap();
+}
+
+/// Constant as the disassembly shows it, objects by name rather than heap index
+pub(crate) fn constant_text(value: Value, heap: &Heap) -> String {
+    return match value {
         Value::Obj(object) => {
             match object {
-                Object::StringHash(str_hash) => {
-                    let str = heap.get_string(*str_hash);
-                    println!("{: <20}", str);
-                }
-                Object::FunctionIndex(idx) => {
-                    let fun = heap.get_mut_function(*idx);
-                    println!("{: <20}", format!("<fn {}>",fun.name));
-                }
-                Object::NativeFnIndex(_) => {
-                    println!("{: <20}","<nativefn>");
-                }
+                Object::StringHash(str_hash) => heap.get_string(str_hash).to_string(),
+                Object::FunctionIndex(idx) => format!("<fn {}>", heap.get_function(idx).name),
+                Object::NativeFnIndex(_) => "<nativefn>".to_string(),
                 Object::ClosureIndex(idx) => {
-                    let closure = heap.get_closure(*idx);
-                    let func_idx = closure.func_idx as usize;
-                    let func = heap.get_function(func_idx);
-                    println!("{: <20}", format!("<fn {}>", func.name));
-                }
-                Object::ClassIndex(idx) => {
-                    let class = heap.get_class(*idx);
-                    println!("{: <20}", format!("<Class {}>", class.name));
+                    let closure = heap.get_closure(idx);
+                    let func_idx = closure.func_idx;
+                    format!("<fn {}>", heap.get_function(func_idx).name)
                 }
+                Object::ClassIndex(idx) => format!("<Class {}>", heap.get_class(idx).name),
                 Object::InstanceIndex(idx) => {
-                    let instance = heap.get_instance(*idx);
+                    let instance = heap.get_instance(idx);
                     let class_idx = instance.class_idx;
-                    let class = heap.get_class(class_idx);
-                    println!("{: <20}", format!("<Instance {}>", class.name));
+                    format!("<Instance {}>", heap.get_class(class_idx).name)
+                }
+                Object::BoundMethodIndex(_) => "<bound method>".to_string(),
+                Object::ListIndex(_) => "<list>".to_string(),
+                Object::MapIndex(_) => "<map>".to_string(),
+                Object::UserDataIndex(idx) => {
+                    let type_idx = heap.get_user_data(idx).type_idx;
+                    format!("<UserData {}>", heap.user_types[type_idx].name)
                 }
             }
         }
-        _ => {
-            println!("{: <20}", chunk.constants.get(*constant as usize).unwrap());
-        }
-    }
-    return offset + 2;
+        _ => value.to_string(),
+    };
 }
 
-fn  byte_instruction(name: &str, chunk: &Chunk, offset: usize)->usize {
+fn  byte_instruction(out: &mut String, name: &str, chunk: &Chunk, offset: usize)->usize {
     let slot = chunk.code.get(offset + 1).unwrap();
-    println!("{: <20} | {: >6} | ", name, slot);
+    writeln!(out, "{: <20} | {: >6} | ", name, slot).unwrap();
     return offset + 2;
 }
 
-fn invoke_instruction(name: &str, chunk: &Chunk, offset: usize)->usize {
-    let constant = chunk.code[offset + 1];
+fn short_instruction(out: &mut String, name: &str, chunk: &Chunk, offset: usize)->usize {
+    let operand = ((chunk.code[offset + 1] as usize) << 8) | chunk.code[offset + 2] as usize;
+    writeln!(out, "{: <20} | {: >6} | ", name, operand).unwrap();
+    return offset + 3;
+}
+
+fn invoke_instruction(out: &mut String, name: &str, chunk: &Chunk, heap: &Heap, offset: usize, wide: usize)->usize {
+    let constant = wide | chunk.code[offset + 1] as usize;
     let arg_count = chunk.code[offset + 2];
-    println!("{: <20} | {: >6} | {: >4}", name, arg_count, constant);
-    println!("{}", chunk.constants[constant as usize]);
+    let method = constant_text(chunk.constants[constant], heap);
+    writeln!(out, "{: <20} | {: >6} | {: >4} {}", name, arg_count, constant, method).unwrap();
     return offset + 3;
 }
 
 #[allow(arithmetic_overflow)]
-fn  jump_instruction(name: &str, sign: isize, chunk: &Chunk, offset: usize)->usize {
+fn  jump_instruction(out: &mut String, name: &str, sign: isize, chunk: &Chunk, offset: usize)->usize {
     let mut jump:u32 = (chunk.code[offset + 1] as u32) << 8;
     jump |= (chunk.code[offset+2]) as u32;
     // printf("%-16s | %04d -> %04d\n", name, offset, offset + 3 + sign * jump);
-    println!("{: <20} | {} => {}", name, offset, offset as isize + 3 + sign * jump as isize);
+    writeln!(out, "{: <20} | {} => {}", name, offset, offset as isize + 3 + sign * jump as isize).unwrap();
     return offset + 3;
 }
 
 pub fn disassemble_chunk(chunk: &Chunk, heap: &Heap, name: &str) {
-    println!("{}", name);
-    println!("Loc  | Line  | Instruction          | Const  | Values");
+    print!("{}", chunk_listing(chunk, heap, name));
+}
+
+/// Disassembly of the chunk, headed by the name of its function
+pub fn chunk_listing(chunk: &Chunk, heap: &Heap, name: &str) -> String {
+    let mut out = String::new();
+    writeln!(out, "{}", name).unwrap();
+    writeln!(out, "Loc  | Line  | Instruction          | Const  | Values").unwrap();
     let mut offset = 0;
+    let mut wide = 0;
     loop {
         if offset >= chunk.code.len() { break };
-        offset = disassemble_instruction(chunk, heap, offset);
+        let next = disassemble_instruction(&mut out, chunk, heap, offset, wide);
+        wide = if chunk.code[offset] == Opcode::Wide.byte() {
+            ((chunk.code[offset + 1] as usize) << 16) | (chunk.code[offset + 2] as usize) << 8
+        } else {
+            0
+        };
+        offset = next;
+    }
+    // Columns are padded, the padding at line ends would only get in the way of comparisons
+    return out.lines().map(|line| format!("{}\n", line.trim_end())).collect();
+}
+
+/// Disassembly of the function followed by the functions it creates, each
+/// after a blank line, depth first in the order of its constants. It only
+/// depends on the source and the compiler, so tests can compare it with an
+/// expected listing
+pub fn function_listing(heap: &Heap, function_idx: usize) -> String {
+    let function = heap.get_function(function_idx);
+    let mut out = chunk_listing(&function.chunk, heap, &function.name);
+    for constant in &function.chunk.constants {
+        if let Value::Obj(Object::FunctionIndex(idx)) = constant {
+            out.push('\n');
+            out.push_str(&function_listing(heap, *idx));
+        }
+    }
+    return out;
+}
+
+/// Print the tokens as a table, one per line
+pub fn print_tokens(tokens: impl Iterator<Item = Token>) {
+    println!("Line  | Col  | Type             | Lexeme");
+    for token in tokens {
+        println!("{}", token_row(&token));
     }
 }
 
-fn disassemble_instruction(chunk: &Chunk, heap: &Heap, mut offset: usize) -> usize {
-    print!("{: >4} | {: >5 } | ", offset, chunk.lines[offset]);
-    let inst = chunk.code.get(offset).unwrap().clone();
-    let opcode: Opcode = unsafe { std::mem::transmute(inst) };
+/// Row of the token table, the lexeme is quoted so whitespace in it shows.
+/// The lexeme of an Error token is its message
+pub fn token_row(token: &Token) -> String {
+    return format!("{: >5} | {: >4} | {: <16} | {:?}", token.line, token.column, token.token_type.to_string(), &*token.lexeme);
+}
+
+fn for_iter_instruction(out: &mut String, name: &str, chunk: &Chunk, offset: usize)->usize {
+    let slot = chunk.code[offset + 1];
+    let jump = ((chunk.code[offset + 2] as usize) << 8) | chunk.code[offset + 3] as usize;
+    writeln!(out, "{: <20} | {: >6} | {} => {}", name, slot, offset, offset + 4 + jump).unwrap();
+    return offset + 4;
+}
+
+fn disassemble_instruction(out: &mut String, chunk: &Chunk, heap: &Heap, mut offset: usize, wide: usize) -> usize {
+    write!(out, "{: >4} | {: >5 } | ", offset, chunk.line_for_offset(offset)).unwrap();
+    let inst = *chunk.code.get(offset).unwrap();
+    let opcode = match Opcode::from_byte(inst) {
+        Some(opcode) => opcode,
+        None => {
+            writeln!(out, "unknown opcode {}", inst).unwrap();
+            return offset + 1;
+        }
+    };
     match opcode {
         Opcode::Constant => {
-            return constant_instruction( "op_constant", chunk, heap, offset);
+            return constant_instruction(out,  "op_constant", chunk, heap, offset, wide);
+        }
+        Opcode::Wide => {
+            return short_instruction(out, "op_wide", chunk, offset);
+        }
+        Opcode::ConstantLong => {
+            return constant_long_instruction(out,  "op_constant_long", chunk, heap, offset);
         }
         Opcode::Nil => {
-            return simple_instruction("op_nil", offset);
+            return simple_instruction(out, "op_nil", offset);
         }
         Opcode::True => {
-            return simple_instruction("op_true", offset);
+            return simple_instruction(out, "op_true", offset);
         }
         Opcode::False => {
-            return simple_instruction("op_false", offset);
+            return simple_instruction(out, "op_false", offset);
         }
         Opcode::Pop => {
-            return simple_instruction("op_pop", offset);
+            return simple_instruction(out, "op_pop", offset);
+        }
+        Opcode::PopN => {
+            return byte_instruction(out, "op_pop_n", chunk, offset);
         }
         Opcode::GetLocal => {
-            return byte_instruction("op_get_local", chunk,  offset);
+            return byte_instruction(out, "op_get_local", chunk,  offset);
+        }
+        Opcode::GetLocalLong => {
+            return short_instruction(out, "op_get_local_long", chunk, offset);
+        }
+        Opcode::SetLocalLong => {
+            return short_instruction(out, "op_set_local_long", chunk, offset);
         }
         Opcode::GetGlobal => {
-            return constant_instruction("op_get_global", chunk, heap, offset);
+            return constant_instruction(out, "op_get_global", chunk, heap, offset, wide);
         }
         Opcode::DefineGlobal => {
-            return constant_instruction("op_define_global", chunk, heap, offset);
+            return constant_instruction(out, "op_define_global", chunk, heap, offset, wide);
+        }
+        Opcode::DefineDeclaration => {
+            return constant_instruction(out, "op_define_declaration", chunk, heap, offset, wide);
+        }
+        Opcode::DefineConstGlobal => {
+            return constant_instruction(out, "op_define_const_global", chunk, heap, offset, wide);
         }
         Opcode::SetLocal => {
-            return byte_instruction("op_set_local", chunk, offset);
+            return byte_instruction(out, "op_set_local", chunk, offset);
         }
         Opcode::SetGlobal => {
-            return constant_instruction("op_set_global", chunk, heap, offset);
+            return constant_instruction(out, "op_set_global", chunk, heap, offset, wide);
         }
         Opcode::GetUpvalue => {
-            return byte_instruction("op_get_upvalue", chunk, offset);
+            return byte_instruction(out, "op_get_upvalue", chunk, offset);
         }
         Opcode::SetUpvalue => {
-            return byte_instruction("op_set_upvalue", chunk, offset);
+            return byte_instruction(out, "op_set_upvalue", chunk, offset);
+        }
+        Opcode::GetUpvalueLong => {
+            return short_instruction(out, "op_get_upvalue_long", chunk, offset);
+        }
+        Opcode::SetUpvalueLong => {
+            return short_instruction(out, "op_set_upvalue_long", chunk, offset);
         }
         Opcode::Equal => {
-            return simple_instruction("op_equal", offset);
+            return simple_instruction(out, "op_equal", offset);
         }
         Opcode::Greater => {
-            return simple_instruction("op_greater", offset);
+            return simple_instruction(out, "op_greater", offset);
         }
         Opcode::Less => {
-            return simple_instruction("op_less", offset);
+            return simple_instruction(out, "op_less", offset);
         }
         Opcode::Add => {
-            return simple_instruction("op_add", offset);
+            return simple_instruction(out, "op_add", offset);
         }
         Opcode::Subtract => {
-            return simple_instruction("op_subtract", offset);
+            return simple_instruction(out, "op_subtract", offset);
         }
         Opcode::Multiply => {
-            return simple_instruction("op_mul", offset);
+            return simple_instruction(out, "op_mul", offset);
         }
         Opcode::Divide => {
-            return simple_instruction("op_divide", offset);
+            return simple_instruction(out, "op_divide", offset);
         }
         Opcode::Not => {
-            return simple_instruction("op_not", offset);
+            return simple_instruction(out, "op_not", offset);
         }
         Opcode::Negate => {
-            return simple_instruction("op_negate", offset);
+            return simple_instruction(out, "op_negate", offset);
         }
         Opcode::Print => {
-            return simple_instruction("op_print", offset);
+            return simple_instruction(out, "op_print", offset);
         }
         Opcode::JumpIfFalse => {
-            return jump_instruction("op_jump_if_false", 1, chunk, offset);
+            return jump_instruction(out, "op_jump_if_false", 1, chunk, offset);
+        }
+        Opcode::JumpIfTrue => {
+            return jump_instruction(out, "op_jump_if_true", 1, chunk, offset);
+        }
+        Opcode::JumpIfNotNil => {
+            return jump_instruction(out, "op_jump_if_not_nil", 1, chunk, offset);
         }
         Opcode::Jump => {
-            return jump_instruction("op_jump", 1, chunk, offset);
+            return jump_instruction(out, "op_jump", 1, chunk, offset);
         }
         Opcode::Loop => {
-            return jump_instruction("op_loop", -1, chunk, offset);
+            return jump_instruction(out, "op_loop", -1, chunk, offset);
+        }
+        Opcode::PushHandler => {
+            return jump_instruction(out, "op_push_handler", 1, chunk, offset);
+        }
+        Opcode::PopHandler => {
+            return simple_instruction(out, "op_pop_handler", offset);
+        }
+        Opcode::ListAppend => {
+            return simple_instruction(out, "op_list_append", offset);
+        }
+        Opcode::ListExtend => {
+            return simple_instruction(out, "op_list_extend", offset);
+        }
+        Opcode::CallSpread => {
+            return simple_instruction(out, "op_call_spread", offset);
+        }
+        Opcode::CallNamed => {
+            let positional = chunk.code[offset + 1];
+            let named = chunk.code[offset + 2];
+            writeln!(out, "{: <20} | {: >6} | {: >4}", "op_call_named", positional, named).unwrap();
+            return offset + 3;
+        }
+        Opcode::MatchList => {
+            let count = chunk.code[offset + 1];
+            let has_rest = chunk.code[offset + 2];
+            writeln!(out, "{: <20} | {: >6} | {: >4}", "op_match_list", count, has_rest).unwrap();
+            return offset + 3;
+        }
+        Opcode::SliceFrom => {
+            return byte_instruction(out, "op_slice_from", chunk, offset);
+        }
+        Opcode::IsInstance => {
+            return simple_instruction(out, "op_is_instance", offset);
+        }
+        Opcode::Dup => {
+            return simple_instruction(out, "op_dup", offset);
+        }
+        Opcode::Throw => {
+            return simple_instruction(out, "op_throw", offset);
+        }
+        Opcode::ForIter => {
+            return for_iter_instruction(out, "op_for_iter", chunk, offset);
         }
         Opcode::Call => {
-            return byte_instruction("op_call", chunk, offset);
+            return byte_instruction(out, "op_call", chunk, offset);
+        }
+        Opcode::CallLong => {
+            return short_instruction(out, "op_call_long", chunk, offset);
         }
-        Opcode::Closure => {
+        Opcode::Closure | Opcode::ClosureLong => {
+            let is_wide = matches!(opcode, Opcode::ClosureLong);
+            let name = if is_wide { "op_closure_long" } else { "op_closure" };
             offset += 1;
-            let constant = chunk.code[offset] as usize;
+            let constant = wide | chunk.code[offset] as usize;
             offset += 1;
             let value = chunk.constants[constant];
-            print!("{:>4} {:>5 }", "op_closure" , constant);
-            println!("  {:>10}", value);
+            write!(out, "{:>4} {:>5 }", name, constant).unwrap();
+            writeln!(out, "  {:>10}", constant_text(value, heap)).unwrap();
             let func_index = value.as_function_index();
-            let function = heap.get_mut_function(func_index);
+            let function = heap.get_function(func_index);
             for _ in 0..function.upvalue_count {
+                let start = offset;
                 let is_local = chunk.code[offset];
                 offset+=1;
-                let index = chunk.code[offset];
+                let mut index = chunk.code[offset] as usize;
                 offset+=1;
+                if is_wide {
+                    index = index << 8 | chunk.code[offset] as usize;
+                    offset+=1;
+                }
                 let local_str = if is_local == 1u8 {"local"} else {"upvalue"};
-                println!("{:>4}           | {:>4}{:>2 }", offset - 2, local_str , index)
+                writeln!(out, "{:>4}           | {:>4}{:>2 }", start, local_str , index).unwrap()
             }
             return offset;
         }
         Opcode::CloseValue => {
-            return simple_instruction("op_close_upvalue", offset);
+            return simple_instruction(out, "op_close_upvalue", offset);
         }
         Opcode::Class => {
-            return constant_instruction("op_class", chunk, heap, offset);
+            return constant_instruction(out, "op_class", chunk, heap, offset, wide);
         }
         Opcode::Return => {
-            return simple_instruction("op_return", offset);
+            return simple_instruction(out, "op_return", offset);
         }
         Opcode::SetProperty => {
-            return constant_instruction("op_set_property", chunk, heap, offset);
+            return constant_instruction(out, "op_set_property", chunk, heap, offset, wide);
 
         }
         Opcode::GetProperty => {
-            return constant_instruction("op_get_property", chunk, heap, offset);
+            return constant_instruction(out, "op_get_property", chunk, heap, offset, wide);
         }
         Opcode::Method => {
-            return constant_instruction("op_method", chunk, heap, offset);
+            return constant_instruction(out, "op_method", chunk, heap, offset, wide);
+        }
+        Opcode::AbstractMethod => {
+            return constant_instruction(out, "op_abstract_method", chunk, heap, offset, wide);
+        }
+        Opcode::ClassConstant => {
+            return constant_instruction(out, "op_class_constant", chunk, heap, offset, wide);
         }
         Opcode::Invoke => {
-            return invoke_instruction("op_invoke", chunk, offset);
+            return invoke_instruction(out, "op_invoke", chunk, heap, offset, wide);
         }
         Opcode::Inherit => {
-            return simple_instruction("op_inherit", offset);
+            return simple_instruction(out, "op_inherit", offset);
         }
         Opcode::SuperInvoke => {
-            return invoke_instruction("op_super_invoke", chunk, offset);
+            return invoke_instruction(out, "op_super_invoke", chunk, heap, offset, wide);
+        }
+        Opcode::GetSuper => {
+            return constant_instruction(out, "op_get_super", chunk, heap, offset, wide);
+        }
+        Opcode::BuildList => {
+            return byte_instruction(out, "op_build_list", chunk, offset);
+        }
+        Opcode::BuildMap => {
+            return byte_instruction(out, "op_build_map", chunk, offset);
+        }
+        Opcode::GetIndex => {
+            return simple_instruction(out, "op_get_index", offset);
+        }
+        Opcode::SetIndex => {
+            return simple_instruction(out, "op_set_index", offset);
         }
     }
 }
@@ -0,0 +1,60 @@
+use std::fmt::Write;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+impl Severity {
+    pub fn name(&self) -> &'static str {
+        return match self {
+            Severity::Error => "error",
+            Severity::Warning => "warning",
+        };
+    }
+}
+
+/// Error or warning found in the source, for tools that want more than the
+/// printed message
+#[derive(Clone, Debug, PartialEq)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    /// Line of the token it is about, counted from 1 as in printed messages
+    pub line: usize,
+    /// Characters between the start of the line and the token
+    pub column: usize,
+    /// Message without the location or source snippet
+    pub message: String,
+}
+
+impl Diagnostic {
+    pub fn new(severity: Severity, line: usize, column: usize, message: &str) -> Self {
+        Diagnostic { severity, line, column, message: message.to_string() }
+    }
+
+    /// JSON object with the file the diagnostic is in
+    pub fn to_json(&self, file: &str) -> String {
+        return format!("{{\"file\": {}, \"line\": {}, \"column\": {}, \"severity\": \"{}\", \"message\": {}}}",
+                       json_string(file), self.line, self.column, self.severity.name(), json_string(&self.message));
+    }
+}
+
+/// Quote the text as a JSON string
+pub(crate) fn json_string(text: &str) -> String {
+    let mut quoted = String::with_capacity(text.len() + 2);
+    quoted.push('"');
+    for c in text.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            c if (c as u32) < 0x20 => { let _ = write!(quoted, "\\u{:04x}", c as u32); }
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    return quoted;
+}
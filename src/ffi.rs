@@ -0,0 +1,219 @@
+//! Calling C functions in shared libraries from scripts.
+//!
+//! `loadLibrary(path)` returns a handle for `ffiCall(lib, symbol, signature, args)`,
+//! where the signature is written like a C prototype without names, such as
+//! "double(double, int)". Supported types are void (return only), int, long,
+//! double, string (a NUL terminated char*) and pointer. Variadic functions
+//! such as printf can't be called.
+use std::fmt;
+#[cfg(feature = "ffi")]
+use std::ffi::{c_char, c_void, CStr, CString};
+#[cfg(feature = "ffi")]
+use std::sync::Mutex;
+
+use crate::heap::Heap;
+use crate::object::Object;
+use crate::nativefn::{check_arity, copy_value, string_argument, NativeError, NativeResult, NativeValue};
+
+/// C type in an ffiCall signature
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum CType {
+    Void,
+    Int,
+    Long,
+    Double,
+    String,
+    Pointer,
+}
+
+impl fmt::Display for CType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CType::Void => write!(f, "void"),
+            CType::Int => write!(f, "int"),
+            CType::Long => write!(f, "long"),
+            CType::Double => write!(f, "double"),
+            CType::String => write!(f, "string"),
+            CType::Pointer => write!(f, "pointer"),
+        }
+    }
+}
+
+/// Return and parameter types of a C function
+#[derive(PartialEq, Debug)]
+pub struct Signature {
+    pub ret: CType,
+    pub params: Vec<CType>,
+}
+
+/// Most integer and double arguments a call can take, those passed in registers
+const MAX_INT_ARGS: usize = 6;
+const MAX_DOUBLE_ARGS: usize = 8;
+
+/// Libraries loaded so far, scripts refer to them by index. They stay loaded
+/// for the life of the process so no symbol outlives its library
+#[cfg(feature = "ffi")]
+static LIBRARIES: Mutex<Vec<libloading::Library>> = Mutex::new(vec![]);
+
+fn parse_type(name: &str) -> Result<CType, String> {
+    return match name.trim() {
+        "void" => Ok(CType::Void),
+        "int" => Ok(CType::Int),
+        "long" => Ok(CType::Long),
+        "double" => Ok(CType::Double),
+        "string" => Ok(CType::String),
+        "pointer" => Ok(CType::Pointer),
+        other => Err(format!("Unknown type '{}' in ffiCall signature.", other)),
+    };
+}
+
+/// Parse a signature such as "long(string)", "()" and "(void)" take no arguments
+pub fn parse_signature(text: &str) -> Result<Signature, String> {
+    let invalid = || format!("Invalid ffiCall signature '{}', expected a form like double(double, int).", text);
+    let open = text.find('(').ok_or_else(invalid)?;
+    if !text.trim_end().ends_with(')') {
+        return Err(invalid());
+    }
+    let ret = parse_type(&text[..open])?;
+    let inner = text.trim_end().strip_suffix(')').unwrap()[open + 1..].trim();
+    let mut params = vec![];
+    if !inner.is_empty() && inner != "void" {
+        for name in inner.split(',') {
+            let param = parse_type(name)?;
+            if param == CType::Void {
+                return Err(invalid());
+            }
+            params.push(param);
+        }
+    }
+    let doubles = params.iter().filter(|param| **param == CType::Double).count();
+    if params.len() - doubles > MAX_INT_ARGS || doubles > MAX_DOUBLE_ARGS {
+        return Err(format!("ffiCall supports up to {} integer and {} double arguments.", MAX_INT_ARGS, MAX_DOUBLE_ARGS));
+    }
+    return Ok(Signature { ret, params });
+}
+
+/// Load the shared library at the path, returning its handle for ffiCall
+pub fn load_library_native(_heap: &Heap, _arg_count: usize, arguments: Vec<NativeValue>) -> NativeResult {
+    check_arity("loadLibrary", 1, &arguments)?;
+    let path = string_argument("loadLibrary", "path", &arguments, 0)?;
+    return load_library(path);
+}
+
+/// Call the named function of a library from loadLibrary with the list of
+/// arguments, converted to and from C as the signature says
+pub fn ffi_call_native(heap: &Heap, _arg_count: usize, arguments: Vec<NativeValue>) -> NativeResult {
+    check_arity("ffiCall", 4, &arguments)?;
+    let library = match arguments[0] {
+        NativeValue::Number(handle) if handle >= 0.0 && handle.fract() == 0.0 => handle as usize,
+        _ => return Err(NativeError::new("Invalid type for ffiCall lib, library from loadLibrary expected.")),
+    };
+    let symbol = string_argument("ffiCall", "symbol", &arguments, 1)?;
+    let signature = parse_signature(string_argument("ffiCall", "signature", &arguments, 2)?)
+        .map_err(|message| NativeError::new(&message))?;
+    let args: Vec<NativeValue> = match &arguments[3] {
+        NativeValue::Object(Object::ListIndex(idx)) => heap.get_list(*idx).items.iter().map(|item| copy_value(heap, *item)).collect(),
+        _ => return Err(NativeError::new("Invalid type for ffiCall args, list expected.")),
+    };
+    if args.len() != signature.params.len() {
+        return Err(NativeError::new(&format!("ffiCall signature takes {} argument(s) but got {}.", signature.params.len(), args.len())));
+    }
+    return call_symbol(library, symbol, &signature, &args);
+}
+
+#[cfg(feature = "ffi")]
+fn load_library(path: &str) -> NativeResult {
+    // Running the library's initializers is what loading it is for
+    let library = unsafe { libloading::Library::new(path) }
+        .map_err(|error| NativeError::new(&format!("Unable to load library {}: {}", path, error)))?;
+    let mut libraries = LIBRARIES.lock().unwrap();
+    libraries.push(library);
+    return Ok(NativeValue::Number((libraries.len() - 1) as f64));
+}
+
+#[cfg(not(feature = "ffi"))]
+fn load_library(_path: &str) -> NativeResult {
+    return Err(NativeError::new("Calling C functions needs kscript to be built with the ffi feature."));
+}
+
+/// Integer register value for an argument of a non double type. Strings are
+/// copied into `strings`, which must outlive the call
+#[cfg(feature = "ffi")]
+fn integer_argument(param: CType, value: &NativeValue, strings: &mut Vec<CString>) -> Result<i64, NativeError> {
+    return match (param, value) {
+        (CType::String, NativeValue::String(text)) => {
+            let text = CString::new(text.as_str())
+                .map_err(|_| NativeError::new("ffiCall string arguments can't contain a NUL character."))?;
+            strings.push(text);
+            Ok(strings.last().unwrap().as_ptr() as i64)
+        }
+        (CType::Pointer, NativeValue::Nil()) => Ok(0),
+        (CType::Int | CType::Long | CType::Pointer, NativeValue::Number(n)) if n.fract() == 0.0 => Ok(*n as i64),
+        (CType::Int | CType::Long | CType::Pointer, NativeValue::Boolean(b)) => Ok(*b as i64),
+        _ => Err(NativeError::new(&format!("Invalid argument for ffiCall, {} expected.", param))),
+    };
+}
+
+/// Integer arguments go in the integer registers and doubles in the floating
+/// point ones, each in order of their own kind, so every signature can be
+/// called through one with all the registers of both. Unused ones are ignored
+/// by the callee
+#[cfg(all(feature = "ffi", unix, any(target_arch = "x86_64", target_arch = "aarch64")))]
+fn call_symbol(library: usize, symbol: &str, signature: &Signature, args: &[NativeValue]) -> NativeResult {
+    type IntegerFn = unsafe extern "C" fn(i64, i64, i64, i64, i64, i64, f64, f64, f64, f64, f64, f64, f64, f64) -> i64;
+    type DoubleFn = unsafe extern "C" fn(i64, i64, i64, i64, i64, i64, f64, f64, f64, f64, f64, f64, f64, f64) -> f64;
+
+    let libraries = LIBRARIES.lock().unwrap();
+    let library = libraries.get(library)
+        .ok_or_else(|| NativeError::new("Invalid type for ffiCall lib, library from loadLibrary expected."))?;
+    let address = unsafe { library.get::<*mut c_void>(symbol.as_bytes()) }
+        .map(|address| *address)
+        .map_err(|error| NativeError::new(&format!("Unable to find {}: {}", symbol, error)))?;
+
+    let mut ints = [0i64; MAX_INT_ARGS];
+    let mut doubles = [0f64; MAX_DOUBLE_ARGS];
+    let (mut int_count, mut double_count) = (0, 0);
+    let mut strings = vec![];
+    for (param, value) in signature.params.iter().zip(args) {
+        if *param == CType::Double {
+            doubles[double_count] = match value {
+                NativeValue::Number(n) => *n,
+                _ => return Err(NativeError::new("Invalid argument for ffiCall, double expected.")),
+            };
+            double_count += 1;
+        } else {
+            ints[int_count] = integer_argument(*param, value, &mut strings)?;
+            int_count += 1;
+        }
+    }
+
+    let [i0, i1, i2, i3, i4, i5] = ints;
+    let [d0, d1, d2, d3, d4, d5, d6, d7] = doubles;
+    // The script vouches for the signature, a wrong one is undefined behaviour
+    // just as it would be in C
+    unsafe {
+        if signature.ret == CType::Double {
+            let function: DoubleFn = std::mem::transmute(address);
+            return Ok(NativeValue::Number(function(i0, i1, i2, i3, i4, i5, d0, d1, d2, d3, d4, d5, d6, d7)));
+        }
+        let function: IntegerFn = std::mem::transmute(address);
+        let result = function(i0, i1, i2, i3, i4, i5, d0, d1, d2, d3, d4, d5, d6, d7);
+        return Ok(match signature.ret {
+            CType::Void => NativeValue::Nil(),
+            CType::Int => NativeValue::Number(result as i32 as f64),
+            CType::String if result == 0 => NativeValue::Nil(),
+            CType::String => NativeValue::String(CStr::from_ptr(result as *const c_char).to_string_lossy().into_owned()),
+            _ => NativeValue::Number(result as f64),
+        });
+    }
+}
+
+#[cfg(all(feature = "ffi", not(all(unix, any(target_arch = "x86_64", target_arch = "aarch64")))))]
+fn call_symbol(_library: usize, _symbol: &str, _signature: &Signature, _args: &[NativeValue]) -> NativeResult {
+    return Err(NativeError::new("ffiCall isn't supported on this platform."));
+}
+
+#[cfg(not(feature = "ffi"))]
+fn call_symbol(_library: usize, _symbol: &str, _signature: &Signature, _args: &[NativeValue]) -> NativeResult {
+    return Err(NativeError::new("Calling C functions needs kscript to be built with the ffi feature."));
+}
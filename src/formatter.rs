@@ -0,0 +1,747 @@
+//! Formatter reprinting a script from its syntax tree with canonical layout.
+//!
+//! Statements go one per line, indented by four spaces, with opening braces
+//! on the line of their statement and single spaces around binary operators.
+//! Comments are kept, as is a single blank line wherever the source had
+//! blank lines between statements. Lists, maps and match arms stay on one
+//! line unless their first item starts on a new line in the source, then
+//! each item gets its own line. Long lines aren't wrapped.
+use fnv::FnvHashMap;
+
+use crate::ast::{Arguments, Class, Expr, ExprKind, Function, MatchArm, Member, Pattern, Stmt, StmtKind};
+use crate::heap::Heap;
+use crate::token::{Token, TokenType};
+use crate::{Parser, Scanner};
+
+const INDENT: &str = "    ";
+
+/// Format the source, or return the syntax errors that stop it being parsed
+pub fn format_source(source: &str) -> Result<String, Vec<String>> {
+    let mut scanner = Scanner::new(&source.to_string());
+    scanner.print_errors = false;
+    scanner.keep_comments = true;
+    let tokens = scanner.scan_tokens();
+    if scanner.had_error {
+        return Err(scanner.errors);
+    }
+
+    let mut parser = Parser::new(Heap::new(), tokens.clone());
+    parser.print_errors = false;
+    parser.source = source.into();
+    let statements = parser.parse();
+    if parser.had_error {
+        return Err(parser.errors);
+    }
+
+    let mut printer = Printer::new(tokens, scanner.comments);
+    printer.program(&statements);
+    return Ok(printer.out);
+}
+
+/// Unified diff turning the old text into the new one, with three lines of
+/// context around each change. Empty when they are the same
+pub fn unified_diff(old: &str, new: &str, old_name: &str, new_name: &str) -> String {
+    let old_lines: Vec<&str> = old.lines().collect();
+    let new_lines: Vec<&str> = new.lines().collect();
+    let edits = line_edits(&old_lines, &new_lines);
+    if edits.iter().all(|(kind, _)| *kind == ' ') {
+        return "".to_string();
+    }
+
+    let mut diff = format!("--- {}\n+++ {}\n", old_name, new_name);
+    const CONTEXT: usize = 3;
+    let mut start = 0;
+    while start < edits.len() {
+        let first_change = match edits[start..].iter().position(|(kind, _)| *kind != ' ') {
+            Some(offset) => start + offset,
+            None => break,
+        };
+        // The hunk runs until a stretch of unchanged lines long enough to split it
+        let mut end = first_change;
+        let mut unchanged = 0;
+        for (index, (kind, _)) in edits.iter().enumerate().skip(first_change) {
+            if *kind == ' ' {
+                unchanged += 1;
+                if unchanged > 2 * CONTEXT {
+                    break;
+                }
+            } else {
+                unchanged = 0;
+                end = index + 1;
+            }
+        }
+        let hunk_start = first_change.saturating_sub(CONTEXT).max(start);
+        let hunk_end = (end + CONTEXT).min(edits.len());
+
+        // Lines before the hunk in each text, for the header
+        let old_before = edits[..hunk_start].iter().filter(|(kind, _)| *kind != '+').count();
+        let new_before = edits[..hunk_start].iter().filter(|(kind, _)| *kind != '-').count();
+        let hunk = &edits[hunk_start..hunk_end];
+        let old_count = hunk.iter().filter(|(kind, _)| *kind != '+').count();
+        let new_count = hunk.iter().filter(|(kind, _)| *kind != '-').count();
+        diff.push_str(&format!("@@ -{},{} +{},{} @@\n", old_before + 1, old_count, new_before + 1, new_count));
+        for (kind, line) in hunk {
+            diff.push(*kind);
+            diff.push_str(line);
+            diff.push('\n');
+        }
+        start = hunk_end;
+    }
+    return diff;
+}
+
+/// Lines of both texts marked ' ' when kept, '-' when removed or '+' when
+/// added, from their longest common subsequence
+fn line_edits<'a>(old: &[&'a str], new: &[&'a str]) -> Vec<(char, &'a str)> {
+    let prefix = old.iter().zip(new).take_while(|(a, b)| a == b).count();
+    let suffix = old[prefix..].iter().rev().zip(new[prefix..].iter().rev())
+        .take_while(|(a, b)| a == b).count();
+    let old_middle = &old[prefix..old.len() - suffix];
+    let new_middle = &new[prefix..new.len() - suffix];
+
+    let mut edits: Vec<(char, &str)> = old[..prefix].iter().map(|line| (' ', *line)).collect();
+    // The table grows with the product of the lengths, very different texts
+    // are shown as the old lines replaced by the new ones
+    if old_middle.len() * new_middle.len() > 4_000_000 {
+        edits.extend(old_middle.iter().map(|line| ('-', *line)));
+        edits.extend(new_middle.iter().map(|line| ('+', *line)));
+    } else {
+        // common[i][j] is the longest common subsequence of old_middle[i..] and new_middle[j..]
+        let width = new_middle.len() + 1;
+        let mut common = vec![0u32; (old_middle.len() + 1) * width];
+        for i in (0..old_middle.len()).rev() {
+            for j in (0..new_middle.len()).rev() {
+                common[i * width + j] = if old_middle[i] == new_middle[j] {
+                    common[(i + 1) * width + j + 1] + 1
+                } else {
+                    common[(i + 1) * width + j].max(common[i * width + j + 1])
+                };
+            }
+        }
+        let (mut i, mut j) = (0, 0);
+        while i < old_middle.len() && j < new_middle.len() {
+            if old_middle[i] == new_middle[j] {
+                edits.push((' ', old_middle[i]));
+                i += 1;
+                j += 1;
+            } else if common[(i + 1) * width + j] >= common[i * width + j + 1] {
+                edits.push(('-', old_middle[i]));
+                i += 1;
+            } else {
+                edits.push(('+', new_middle[j]));
+                j += 1;
+            }
+        }
+        edits.extend(old_middle[i..].iter().map(|line| ('-', *line)));
+        edits.extend(new_middle[j..].iter().map(|line| ('+', *line)));
+    }
+    edits.extend(old[old.len() - suffix..].iter().map(|line| (' ', *line)));
+    return edits;
+}
+
+/// Writes the formatted source. The syntax tree doesn't keep comments,
+/// brackets or line breaks, those are looked up in the tokens
+struct Printer {
+    out: String,
+    indent: usize,
+    tokens: Vec<Token>,
+    /// Index in tokens of the token at each line and column
+    positions: FnvHashMap<(usize, usize), usize>,
+    /// Column of the first token on each line, comments after it are trailing
+    line_starts: FnvHashMap<usize, usize>,
+    comments: Vec<Token>,
+    /// Index of the first comment not printed yet
+    next_comment: usize,
+    /// Nothing has been printed in the current block yet, it gets no blank line
+    block_start: bool,
+}
+
+impl Printer {
+    fn new(tokens: Vec<Token>, comments: Vec<Token>) -> Self {
+        let mut positions = FnvHashMap::default();
+        let mut line_starts = FnvHashMap::default();
+        for (index, token) in tokens.iter().enumerate() {
+            positions.entry((token.line, token.column)).or_insert(index);
+            line_starts.entry(token.line).or_insert(token.column);
+        }
+        Printer {
+            out: String::new(),
+            indent: 0,
+            tokens,
+            positions,
+            line_starts,
+            comments,
+            next_comment: 0,
+            block_start: true,
+        }
+    }
+
+    fn program(&mut self, statements: &[Stmt]) {
+        for statement in statements {
+            self.line_start(&statement.start, true);
+            self.statement(statement);
+            self.out.push('\n');
+        }
+        let eof = self.tokens.last().unwrap().clone();
+        self.comments_before(&eof, false);
+    }
+
+    fn index_of(&self, token: &Token) -> usize {
+        return self.positions[&(token.line, token.column)];
+    }
+
+    /// Index of the bracket closing the one at the index
+    fn matching(&self, open: usize) -> usize {
+        let mut depth = 0;
+        for (index, token) in self.tokens.iter().enumerate().skip(open) {
+            match token.token_type {
+                TokenType::LeftParen | TokenType::LeftBracket | TokenType::LeftBrace => depth += 1,
+                TokenType::RightParen | TokenType::RightBracket | TokenType::RightBrace => {
+                    depth -= 1;
+                    if depth == 0 {
+                        return index;
+                    }
+                }
+                _ => {}
+            }
+        }
+        return self.tokens.len() - 1;
+    }
+
+    /// Print the comments before the token, each on its own line unless code
+    /// precedes it on its source line. A blank line is kept above comments
+    /// and, with `keep_blank_line`, above the token where the source had one
+    fn comments_before(&mut self, token: &Token, keep_blank_line: bool) {
+        // Line of the code or comment printed last, none at the start of the source
+        let index = self.index_of(token);
+        let mut last_line = if index == 0 { None } else { Some(self.tokens[index - 1].line) };
+        while let Some(comment) = self.comments.get(self.next_comment) {
+            if (comment.line, comment.column) >= (token.line, token.column) {
+                break;
+            }
+            let comment = comment.clone();
+            self.next_comment += 1;
+            let is_trailing = self.line_starts.get(&comment.line).is_some_and(|column| *column < comment.column);
+            if is_trailing && self.out.ends_with('\n') {
+                self.out.pop();
+                self.out.push(' ');
+            } else {
+                if last_line.is_some_and(|line| comment.line > line + 1) {
+                    self.blank_line();
+                }
+                self.write_indent();
+                self.block_start = false;
+            }
+            self.out.push_str(&comment.lexeme);
+            self.out.push('\n');
+            let end_line = comment.line + comment.lexeme.matches('\n').count();
+            last_line = Some(last_line.map_or(end_line, |line| line.max(end_line)));
+        }
+        if keep_blank_line && last_line.is_some_and(|line| token.line > line + 1) {
+            self.blank_line();
+        }
+    }
+
+    /// Begin the line of the statement or item starting with the token
+    fn line_start(&mut self, token: &Token, keep_blank_line: bool) {
+        self.comments_before(token, keep_blank_line);
+        self.block_start = false;
+        self.write_indent();
+    }
+
+    fn blank_line(&mut self) {
+        if !self.block_start && !self.out.is_empty() && !self.out.ends_with("\n\n") {
+            self.out.push('\n');
+        }
+    }
+
+    fn write_indent(&mut self) {
+        for _ in 0..self.indent {
+            self.out.push_str(INDENT);
+        }
+    }
+
+    fn has_comment_before(&self, token: &Token) -> bool {
+        return self.comments.get(self.next_comment)
+            .is_some_and(|comment| (comment.line, comment.column) < (token.line, token.column));
+    }
+
+    /// Braced block ending at the token, `{}` when there is nothing in it
+    fn block(&mut self, statements: &[Stmt], close: &Token) {
+        if statements.is_empty() && !self.has_comment_before(close) {
+            self.out.push_str("{}");
+            return;
+        }
+        self.out.push_str("{\n");
+        self.indent += 1;
+        self.block_start = true;
+        for statement in statements {
+            self.line_start(&statement.start, true);
+            self.statement(statement);
+            self.out.push('\n');
+        }
+        self.comments_before(close, false);
+        self.indent -= 1;
+        self.write_indent();
+        self.out.push('}');
+    }
+
+    /// Statement without its indentation or line break
+    fn statement(&mut self, statement: &Stmt) {
+        match &statement.kind {
+            StmtKind::Var { name, initializer } => {
+                self.out.push_str("var ");
+                self.out.push_str(&name.lexeme);
+                if let Some(initializer) = initializer {
+                    self.out.push_str(" = ");
+                    self.expression(initializer);
+                }
+                self.out.push(';');
+            }
+            StmtKind::Const { name, initializer } => {
+                self.out.push_str("const ");
+                self.out.push_str(&name.lexeme);
+                self.out.push_str(" = ");
+                self.expression(initializer);
+                self.out.push(';');
+            }
+            StmtKind::Fun(function) => {
+                self.out.push_str("fun ");
+                self.function(function);
+            }
+            StmtKind::Class(class) => self.class(class),
+            StmtKind::Expression(expression) => {
+                self.expression(expression);
+                self.out.push(';');
+            }
+            StmtKind::Print(expression) => {
+                self.out.push_str("print ");
+                self.expression(expression);
+                self.out.push(';');
+            }
+            StmtKind::Block(statements) => {
+                let close = self.tokens[self.matching(self.index_of(&statement.start))].clone();
+                self.block(statements, &close);
+            }
+            StmtKind::If { condition, then_branch, else_branch } => {
+                self.out.push_str("if (");
+                self.expression(condition);
+                self.out.push(')');
+                self.body(then_branch);
+                if let Some(else_branch) = else_branch {
+                    if let StmtKind::Block(_) = then_branch.kind {
+                        self.out.push(' ');
+                    } else {
+                        self.out.push('\n');
+                        self.write_indent();
+                    }
+                    self.out.push_str("else");
+                    self.body(else_branch);
+                }
+            }
+            StmtKind::While { condition, body } => {
+                self.out.push_str("while (");
+                self.expression(condition);
+                self.out.push(')');
+                self.body(body);
+            }
+            StmtKind::For { initializer, condition, increment, body } => {
+                self.out.push_str("for (");
+                match initializer {
+                    Some(initializer) => self.statement(initializer),
+                    None => self.out.push(';'),
+                }
+                if let Some(condition) = condition {
+                    self.out.push(' ');
+                    self.expression(condition);
+                }
+                self.out.push(';');
+                if let Some(increment) = increment {
+                    self.out.push(' ');
+                    self.expression(increment);
+                }
+                self.out.push(')');
+                self.body(body);
+            }
+            StmtKind::ForIn { variable, iterable, body } => {
+                self.out.push_str("for (var ");
+                self.out.push_str(&variable.lexeme);
+                self.out.push_str(" in ");
+                self.expression(iterable);
+                self.out.push(')');
+                self.body(body);
+            }
+            StmtKind::Try { body, variable, handler } => {
+                // The body opens right after `try`, the handler after `catch (variable)`
+                let body_close = self.tokens[self.matching(self.index_of(&statement.start) + 1)].clone();
+                let handler_close = self.tokens[self.matching(self.index_of(variable) + 2)].clone();
+                self.out.push_str("try ");
+                self.block(body, &body_close);
+                self.out.push_str(" catch (");
+                self.out.push_str(&variable.lexeme);
+                self.out.push_str(") ");
+                self.block(handler, &handler_close);
+            }
+            StmtKind::Throw(value) => {
+                self.out.push_str("throw ");
+                self.expression(value);
+                self.out.push(';');
+            }
+            StmtKind::Return(value) => {
+                self.out.push_str("return");
+                if let Some(value) = value {
+                    self.out.push(' ');
+                    self.expression(value);
+                }
+                self.out.push(';');
+            }
+        }
+    }
+
+    /// Body of an if, else or loop after a space, a block or a statement on the same line
+    fn body(&mut self, body: &Stmt) {
+        self.out.push(' ');
+        self.statement(body);
+    }
+
+    /// Name, parameters and body of a function or method
+    fn function(&mut self, function: &Function) {
+        if function.is_abstract {
+            self.out.push_str("abstract ");
+        }
+        self.out.push_str(&function.name.lexeme);
+        self.out.push('(');
+        let mut params: Vec<String> = function.params.iter().map(|param| param.lexeme.to_string()).collect();
+        if let Some(rest) = &function.rest {
+            params.push(format!("...{}", rest.lexeme));
+        }
+        self.out.push_str(&params.join(", "));
+        if function.is_abstract {
+            self.out.push_str(");");
+            return;
+        }
+        self.out.push_str(") ");
+        self.block(&function.body, &function.end);
+    }
+
+    fn class(&mut self, class: &Class) {
+        self.out.push_str("class ");
+        self.out.push_str(&class.name.lexeme);
+        if let Some(superclass) = &class.superclass {
+            self.out.push_str(" extend ");
+            self.out.push_str(&superclass.lexeme);
+        }
+        if class.methods.is_empty() && class.constants.is_empty() && !self.has_comment_before(&class.end) {
+            self.out.push_str(" {}");
+            return;
+        }
+        self.out.push_str(" {\n");
+        self.indent += 1;
+        self.block_start = true;
+        for member in class.members() {
+            match member {
+                Member::Method(method) => {
+                    let start = if method.is_abstract {
+                        self.tokens[self.index_of(&method.name) - 1].clone()
+                    } else {
+                        method.name.clone()
+                    };
+                    self.line_start(&start, true);
+                    self.function(method);
+                }
+                Member::Constant(name, value) => {
+                    let start = self.tokens[self.index_of(name) - 1].clone();
+                    self.line_start(&start, true);
+                    self.out.push_str("const ");
+                    self.out.push_str(&name.lexeme);
+                    self.out.push_str(" = ");
+                    self.expression(value);
+                    self.out.push(';');
+                }
+            }
+            self.out.push('\n');
+        }
+        self.comments_before(&class.end, false);
+        self.indent -= 1;
+        self.write_indent();
+        self.out.push('}');
+    }
+
+    fn expression(&mut self, expression: &Expr) {
+        let token = &expression.token;
+        match &expression.kind {
+            ExprKind::Number(_) | ExprKind::String(_) | ExprKind::Bool(_) | ExprKind::Nil
+            | ExprKind::Variable | ExprKind::This => self.out.push_str(&token.lexeme),
+            ExprKind::Grouping(inner) => {
+                self.out.push('(');
+                self.expression(inner);
+                self.out.push(')');
+            }
+            ExprKind::Unary(operand) => {
+                self.out.push_str(&token.lexeme);
+                let start = self.out.len();
+                self.expression(operand);
+                // `- -x` must not turn into `--x`
+                if &*token.lexeme == "-" && self.out[start..].starts_with('-') {
+                    self.out.insert(start, ' ');
+                }
+            }
+            ExprKind::Binary(left, right) | ExprKind::And(left, right)
+            | ExprKind::Or(left, right) | ExprKind::NilCoalesce(left, right) => {
+                self.expression(left);
+                self.out.push(' ');
+                self.out.push_str(&token.lexeme);
+                self.out.push(' ');
+                self.expression(right);
+            }
+            ExprKind::Assign { name, value } => {
+                self.out.push_str(&name.lexeme);
+                self.out.push(' ');
+                self.out.push_str(&token.lexeme);
+                self.out.push(' ');
+                self.expression(value);
+            }
+            ExprKind::PostIncrement { name } => {
+                self.out.push_str(&name.lexeme);
+                self.out.push_str(&token.lexeme);
+            }
+            ExprKind::PreIncrement { target, fields } => {
+                self.out.push_str(&token.lexeme);
+                self.out.push_str(&target.lexeme);
+                for field in fields {
+                    self.out.push('.');
+                    self.out.push_str(&field.lexeme);
+                }
+            }
+            ExprKind::Super { method, arguments } => {
+                self.out.push_str("super.");
+                self.out.push_str(&method.lexeme);
+                if let Some(arguments) = arguments {
+                    self.arguments(arguments);
+                }
+            }
+            ExprKind::Get { object, name } => {
+                self.expression(object);
+                self.out.push('.');
+                self.out.push_str(&name.lexeme);
+            }
+            ExprKind::Set { object, name, value } => {
+                self.expression(object);
+                self.out.push('.');
+                self.out.push_str(&name.lexeme);
+                self.out.push_str(" = ");
+                self.expression(value);
+            }
+            ExprKind::PropertyIncrement { object, name } => {
+                self.expression(object);
+                self.out.push('.');
+                self.out.push_str(&name.lexeme);
+                self.out.push_str(&token.lexeme);
+            }
+            ExprKind::Invoke { object, name, arguments } => {
+                self.expression(object);
+                self.out.push('.');
+                self.out.push_str(&name.lexeme);
+                self.arguments(arguments);
+            }
+            ExprKind::Call { callee, arguments } => {
+                self.expression(callee);
+                self.arguments(arguments);
+            }
+            ExprKind::List(items) => {
+                let open = self.index_of(token);
+                self.items(open, items, ("[", "]"), false, |printer, item| printer.expression(item));
+            }
+            ExprKind::Map(entries) => {
+                let open = self.index_of(token);
+                self.items(open, entries, ("{", "}"), false, |printer, (key, value)| {
+                    printer.expression(key);
+                    printer.out.push_str(": ");
+                    printer.expression(value);
+                });
+            }
+            ExprKind::Index { object, index } => {
+                self.expression(object);
+                self.out.push('[');
+                self.expression(index);
+                self.out.push(']');
+            }
+            ExprKind::SetIndex { object, index, value } => {
+                self.expression(object);
+                self.out.push('[');
+                self.expression(index);
+                self.out.push_str("] = ");
+                self.expression(value);
+            }
+            ExprKind::Match { subject, arms } => {
+                self.out.push_str("match (");
+                self.expression(subject);
+                self.out.push_str(") ");
+                // The arms open after the parenthesis closing the subject
+                let open = self.matching(self.index_of(token) + 1) + 1;
+                self.items(open, arms, ("{", "}"), true, Self::match_arm);
+            }
+        }
+    }
+
+    /// Comma separated items between the bracket at the index and its match.
+    /// Each gets a line of its own when the first one starts on a new line
+    fn items<T>(&mut self, open: usize, items: &[T], (open_text, close_text): (&str, &str),
+                padded: bool, print: fn(&mut Self, &T)) {
+        let close = self.tokens[self.matching(open)].clone();
+        if items.is_empty() {
+            self.out.push_str(open_text);
+            self.out.push_str(close_text);
+            return;
+        }
+        if self.tokens[open + 1].line == self.tokens[open].line {
+            self.out.push_str(open_text);
+            if padded {
+                self.out.push(' ');
+            }
+            for (index, item) in items.iter().enumerate() {
+                if index > 0 {
+                    self.out.push_str(", ");
+                }
+                print(self, item);
+            }
+            if padded {
+                self.out.push(' ');
+            }
+            self.out.push_str(close_text);
+            return;
+        }
+
+        // Items start after the bracket and after each comma outside nested brackets
+        let mut starts = vec![self.tokens[open + 1].clone()];
+        let mut depth = 0;
+        for token in &self.tokens[open + 1..self.index_of(&close)] {
+            match token.token_type {
+                TokenType::LeftParen | TokenType::LeftBracket | TokenType::LeftBrace => depth += 1,
+                TokenType::RightParen | TokenType::RightBracket | TokenType::RightBrace => depth -= 1,
+                TokenType::Comma if depth == 0 => starts.push(token.clone()),
+                _ => {}
+            }
+        }
+        self.out.push_str(open_text);
+        self.out.push('\n');
+        self.indent += 1;
+        self.block_start = true;
+        for (index, item) in items.iter().enumerate() {
+            let start = if index == 0 { starts[0].clone() } else { self.tokens[self.index_of(&starts[index]) + 1].clone() };
+            self.line_start(&start, false);
+            print(self, item);
+            if index + 1 < items.len() {
+                self.out.push(',');
+            }
+            self.out.push('\n');
+        }
+        self.comments_before(&close, false);
+        self.indent -= 1;
+        self.write_indent();
+        self.out.push_str(close_text);
+    }
+
+    fn match_arm(&mut self, arm: &MatchArm) {
+        self.pattern(&arm.pattern);
+        if let Some(guard) = &arm.guard {
+            self.out.push_str(" if ");
+            self.expression(guard);
+        }
+        self.out.push_str(" => ");
+        self.expression(&arm.body);
+    }
+
+    fn pattern(&mut self, pattern: &Pattern) {
+        match pattern {
+            Pattern::Wildcard => self.out.push('_'),
+            Pattern::Literal { token, negative } => {
+                if *negative {
+                    self.out.push('-');
+                }
+                self.out.push_str(&token.lexeme);
+            }
+            Pattern::Binding(name) => self.out.push_str(&name.lexeme),
+            Pattern::List(items, rest) => {
+                self.out.push('[');
+                for (index, item) in items.iter().enumerate() {
+                    if index > 0 {
+                        self.out.push_str(", ");
+                    }
+                    self.pattern(item);
+                }
+                if let Some(rest) = rest {
+                    if !items.is_empty() {
+                        self.out.push_str(", ");
+                    }
+                    self.out.push_str("...");
+                    self.pattern(rest);
+                }
+                self.out.push(']');
+            }
+            Pattern::Instance(class, fields) => {
+                self.out.push_str(&class.lexeme);
+                if fields.is_empty() {
+                    self.out.push_str(" {}");
+                    return;
+                }
+                self.out.push_str(" { ");
+                for (index, (field, pattern)) in fields.iter().enumerate() {
+                    if index > 0 {
+                        self.out.push_str(", ");
+                    }
+                    self.out.push_str(&field.lexeme);
+                    // `{ x }` is short for `{ x: x }`
+                    let is_shorthand = matches!(pattern, Pattern::Binding(name) if name.lexeme == field.lexeme);
+                    if !is_shorthand {
+                        self.out.push_str(": ");
+                        self.pattern(pattern);
+                    }
+                }
+                self.out.push_str(" }");
+            }
+        }
+    }
+
+    fn arguments(&mut self, arguments: &Arguments) {
+        self.out.push('(');
+        match arguments {
+            Arguments::Positional(values) => {
+                for (index, value) in values.iter().enumerate() {
+                    if index > 0 {
+                        self.out.push_str(", ");
+                    }
+                    self.expression(value);
+                }
+            }
+            Arguments::Spread(values) => {
+                for (index, (is_spread, value)) in values.iter().enumerate() {
+                    if index > 0 {
+                        self.out.push_str(", ");
+                    }
+                    if *is_spread {
+                        self.out.push_str("...");
+                    }
+                    self.expression(value);
+                }
+            }
+            Arguments::Named { positional, named } => {
+                for (index, value) in positional.iter().enumerate() {
+                    if index > 0 {
+                        self.out.push_str(", ");
+                    }
+                    self.expression(value);
+                }
+                for (index, (name, value)) in named.iter().enumerate() {
+                    if index > 0 || !positional.is_empty() {
+                        self.out.push_str(", ");
+                    }
+                    self.out.push_str(&name.lexeme);
+                    self.out.push_str(": ");
+                    self.expression(value);
+                }
+            }
+        }
+        self.out.push(')');
+    }
+}
@@ -1,10 +1,23 @@
+use std::path::PathBuf;
+
 use crate::Chunk;
+use crate::shape::PropertyCache;
 
 pub struct Function {
     pub name: String,
     pub arity: usize,
+    /// Extra arguments past arity are collected into a list for the rest parameter
+    pub is_variadic: bool,
+    /// Names of the fixed parameters, used to match named arguments
+    pub param_names: Vec<String>,
     pub upvalue_count: usize,
     pub chunk: Chunk,
+    /// Inline caches of the property instructions by code offset, filled
+    /// in by the VM as they run
+    pub property_caches: Vec<PropertyCache>,
+    /// File the script was read from, only set on the main function of
+    /// scripts run from a file. Relative load paths resolve against its directory
+    pub script_path: Option<PathBuf>,
 }
 
 impl Function {
@@ -12,8 +25,12 @@ impl Function {
       Function {
           name,
           arity,
+          is_variadic: false,
+          param_names: vec![],
           upvalue_count: 0,
-          chunk: Chunk::new()
+          chunk: Chunk::new(),
+          property_caches: vec![],
+          script_path: None,
       }
     }
 }
\ No newline at end of file
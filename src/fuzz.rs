@@ -0,0 +1,25 @@
+use std::io;
+
+use crate::nativefn::Capabilities;
+use crate::{Interpreter, KError};
+
+/// Instructions a fuzzed script may run before it is stopped
+const MAX_INSTRUCTIONS: u64 = 200_000;
+/// Heap a fuzzed script may grow to
+const MAX_HEAP_BYTES: usize = 32 * 1024 * 1024;
+
+/// Compile and run arbitrary bytes as a script, for fuzzers. Every failure of
+/// the scanner, compiler or VM comes back as an error, a panic is a bug. The
+/// script runs sandboxed with its instructions and heap bounded and its
+/// output discarded
+pub fn fuzz_compile_and_run(source: &[u8]) -> Result<(), KError> {
+    let source = String::from_utf8_lossy(source);
+    let mut interpreter = Interpreter::new();
+    interpreter.vm.capabilities = Capabilities::none();
+    interpreter.vm.max_instructions = Some(MAX_INSTRUCTIONS);
+    interpreter.vm.heap.max_bytes = MAX_HEAP_BYTES;
+    interpreter.vm.output = Box::new(io::sink());
+    let func_main_idx = interpreter.compile(&source)?;
+    interpreter.run(func_main_idx)?;
+    return Ok(());
+}
@@ -1,21 +1,45 @@
 use std::borrow::{Borrow};
-use std::cell::{Ref, RefCell, RefMut};
+use std::cell::{Ref, RefMut};
 use std::cmp;
-use std::collections::{HashMap, HashSet};
 use std::mem;
 
-use colored::Colorize;
+use fnv::{FnvHashMap, FnvHashSet};
+use tracing::{debug, trace};
 
-use crate::{Value};
-use crate::class::{Class, Instance};
+use crate::{Object, Value};
+use crate::arena::Arena;
+use crate::class::{BoundMethod, Class, Instance};
 use crate::function::Function;
-use crate::nativefn::NativeFn;
+use crate::metrics::Allocations;
+use crate::nativefn::Native;
 use crate::closure::Closure;
+use crate::list::List;
+use crate::map::Map;
+use crate::shape::Shapes;
+use crate::userdata::{UserData, UserType};
 use crate::utils::hash_string;
 
 const GC_FACTOR: usize = 2;
 const INITIAL_SIZE: usize = 1024 * 1024;
 
+/// Snapshot of the heap's memory use and live objects per pool
+#[derive(Copy, Clone, Default)]
+pub struct HeapStats {
+    pub bytes_allocated: usize,
+    /// Bytes allocated at which the next collection starts
+    pub next_gc: usize,
+    pub strings: usize,
+    pub functions: usize,
+    pub native_fns: usize,
+    pub closures: usize,
+    pub classes: usize,
+    pub instances: usize,
+    pub bound_methods: usize,
+    pub lists: usize,
+    pub maps: usize,
+    pub user_data: usize,
+}
+
 /// Heap is an object responsible for managing the lifecycle of all the
 /// resources that needs to be stored on the heap. The resources are
 /// owned by the heap.
@@ -28,60 +52,110 @@ pub struct Heap {
     pub bytes_allocated: usize,
     /// Next gc point in terms of memory size in bytes
     pub next_gc: usize,
-    /// Storage for strings.
-    pub strings: HashMap<u32, Box<String>>,
+    /// Upper bound for bytes_allocated, usize::MAX means unlimited
+    pub max_bytes: usize,
+    /// Number of objects allocated so far by kind
+    pub allocations: Allocations,
+    /// Collect after every allocating instruction to flush out missing roots
+    pub stress: bool,
+    /// Storage for strings by key. The key is the string's hash unless
+    /// another string already took it
+    pub strings: FnvHashMap<u32, Box<String>>,
+    /// Keys of the strings stored, by hash
+    string_keys: FnvHashMap<u32, Vec<u32>>,
     /// Storage for functions. Function is mutable, hence the use of RefCell
-    pub functions: Vec<RefCell<Function>>, // fixme: Should be boxed
+    pub functions: Arena<Function>,
     /// Storage for native functions
-    pub native_fns: Vec<Box<NativeFn>>,
+    pub native_fns: Vec<Box<Native>>,
     /// Storage for closures
-    pub closures: Vec<RefCell<Closure>>,   // fixme: should be boxed
+    pub closures: Arena<Closure>,
     /// Storage for classes
-    pub classes: Vec<RefCell<Class>>,      // fixme: should be boxed
+    pub classes: Arena<Class>,
     /// Storage for class instances
-    pub instances: Vec<RefCell<Instance>>, // fixme: this should be a hash map with unique identifier for each instance and boxed.
+    pub instances: Arena<Instance>,
+    /// Field layouts of the instances, shared between them
+    pub shapes: Shapes,
+    /// Storage for bound methods
+    pub bound_methods: Arena<BoundMethod>,
+    /// Storage for lists
+    pub lists: Arena<List>,
+    /// Storage for maps
+    pub maps: Arena<Map>,
+    /// Host types registered by the embedder, they outlive clear()
+    pub user_types: Vec<UserType>,
+    /// Storage for host values
+    pub user_data: Arena<UserData>,
 }
 
 
+impl Default for Heap {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Heap {
     pub fn new() ->Self {
         Heap {
             bytes_allocated: 0,
             next_gc: INITIAL_SIZE,
+            max_bytes: usize::MAX,
+            allocations: Allocations::default(),
+            stress: false,
             strings: Default::default(),
-            functions: vec![],
+            string_keys: Default::default(),
+            functions: Arena::new(),
             native_fns: vec![],
-            closures: vec![],
-            classes: vec![],
-            instances: vec![],
+            closures: Arena::new(),
+            classes: Arena::new(),
+            instances: Arena::new(),
+            shapes: Shapes::new(),
+            bound_methods: Arena::new(),
+            lists: Arena::new(),
+            maps: Arena::new(),
+            user_types: vec![],
+            user_data: Arena::new(),
         }
     }
 
-    /// Allocate string object
+    /// Allocate string object, or find the one with the same contents
     pub fn alloc_string(&mut self, string: String) -> u32 {
+        if let Some(key) = self.string_key(&string) {
+            return key;
+        }
         let hash = hash_string(&string);
-        let size = mem::size_of_val(&string);
-        if !self.strings.contains_key(&hash) {
-            self.bytes_allocated += size;
-            self.strings.insert(hash, Box::new(string));
+        // Strings whose hashes collide take the next free key
+        let mut key = hash;
+        while self.strings.contains_key(&key) {
+            key = key.wrapping_add(1);
         }
-        return hash;
+        self.bytes_allocated += Self::string_size(&string);
+        self.allocations.strings += 1;
+        self.strings.insert(key, Box::new(string));
+        self.string_keys.entry(hash).or_default().push(key);
+        return key;
+    }
+
+    /// Key of the stored string with the contents, if there is one
+    pub fn string_key(&self, string: &str) -> Option<u32> {
+        let keys = self.string_keys.get(&hash_string(&string.to_string()))?;
+        return keys.iter().copied().find(|key| self.strings[key].as_str() == string);
     }
 
     /// Allocate function object
     pub fn alloc_function(&mut self, function: Function) -> usize {
         let size = mem::size_of_val(&function);
         self.bytes_allocated += size;
-        let size = self.functions.len();
-        self.functions.push(RefCell::new(function));
-        return size;
+        self.allocations.functions += 1;
+        return self.functions.alloc(function);
     }
 
     /// Allocate native fn
-    pub fn alloc_nativefn(&mut self, function: NativeFn) -> usize {
+    pub fn alloc_nativefn(&mut self, function: Native) -> usize {
         // let hash = hash_string(&function.name);
         let size = mem::size_of_val(&function);
         self.bytes_allocated += size;
+        self.allocations.native_fns += 1;
         let size = self.native_fns.len();
         self.native_fns.push(Box::new(function));
         return size;
@@ -91,58 +165,81 @@ impl Heap {
     pub fn alloc_closure(&mut self, closure: Closure) -> usize {
         let size = mem::size_of_val(&closure);
         self.bytes_allocated += size;
-        let size = self.closures.len();
-        self.closures.push(RefCell::new(closure));
-        return size;
+        self.allocations.closures += 1;
+        return self.closures.alloc(closure);
     }
 
     /// Allocate class
     pub fn alloc_class(&mut self, class: Class) -> usize {
         let size = mem::size_of_val(&class);
         self.bytes_allocated += size;
-        let size = self.classes.len();
-        self.classes.push(RefCell::new(class));
-        return size;
+        self.allocations.classes += 1;
+        return self.classes.alloc(class);
     }
 
     /// Allocate instance
     pub fn alloc_instance(&mut self, instance: Instance) ->usize {
         let size = mem::size_of_val(&instance);
         self.bytes_allocated += size;
-        let size = self.instances.len();
-        self.instances.push(RefCell::new(instance));
-        return size;
+        self.allocations.instances += 1;
+        return self.instances.alloc(instance);
+    }
+
+    /// Allocate bound method
+    pub fn alloc_bound_method(&mut self, bound_method: BoundMethod) ->usize {
+        let size = mem::size_of_val(&bound_method);
+        self.bytes_allocated += size;
+        self.allocations.bound_methods += 1;
+        return self.bound_methods.alloc(bound_method);
+    }
+
+    /// Allocate list
+    pub fn alloc_list(&mut self, list: List) ->usize {
+        let size = mem::size_of_val(&list) + list.items.capacity() * mem::size_of::<Value>();
+        self.bytes_allocated += size;
+        self.allocations.lists += 1;
+        return self.lists.alloc(list);
+    }
+
+    /// Allocate map
+    pub fn alloc_map(&mut self, map: Map) ->usize {
+        let size = mem::size_of_val(&map) + map.entries.capacity() * mem::size_of::<(Value, Value)>();
+        self.bytes_allocated += size;
+        self.allocations.maps += 1;
+        return self.maps.alloc(map);
+    }
+
+    /// Allocate host value
+    pub fn alloc_user_data(&mut self, user_data: UserData) ->usize {
+        let size = mem::size_of_val(&user_data) + mem::size_of_val(user_data.data.as_ref());
+        self.bytes_allocated += size;
+        self.allocations.user_data += 1;
+        return self.user_data.alloc(user_data);
     }
 
     pub fn is_ready_for_garbage_collection(&self) ->bool {
         return self.bytes_allocated > self.next_gc;
     }
 
-    ///
+    /// Free the objects that weren't marked and set when the next collection starts
     pub fn run_gc(&mut self, marked: Vec<Value>) {
         let string_heap_len_before_gc = self.strings.len();
         let closure_heap_len_before_gc = self.closures.len();
         let func_heap_len_before_gc = self.functions.len();
-        let before_gc =  self.bytes_allocated as f32 / 1000000.0;
+        let before_gc = self.bytes_allocated;
 
         self.sweep(marked);
-        let after_gc = self.bytes_allocated as f32 / 1000000.0;
         self.next_gc = cmp::max(self.bytes_allocated * GC_FACTOR, INITIAL_SIZE);
 
-        let next_gc = self.next_gc as f32 / 1000000.0;
-        let string_heap_len_after_gc = self.strings.len();
-        let closure_heap_len_after_gc = self.closures.len();
-        let func_heap_len_after_gc = self.functions.len();
-
-        println!("{} Freed memory from {:.2} MB to {:.2} MB, next GC at {:.2} MB.", "GC".bold().blue(), before_gc, after_gc, next_gc);
-        if string_heap_len_before_gc != string_heap_len_after_gc {
-            println!("{} Reduced string capacity from {} to {}", "GC".bold().blue(), string_heap_len_before_gc, string_heap_len_after_gc);
+        debug!(before = before_gc, after = self.bytes_allocated, next_gc = self.next_gc, "freed memory");
+        if string_heap_len_before_gc != self.strings.len() {
+            trace!(before = string_heap_len_before_gc, after = self.strings.len(), "reduced string capacity");
         }
-        if closure_heap_len_before_gc != closure_heap_len_after_gc {
-            println!("{} Reduced closure capacity from {} to {}", "GC".bold().blue(), closure_heap_len_before_gc, closure_heap_len_after_gc);
+        if closure_heap_len_before_gc != self.closures.len() {
+            trace!(before = closure_heap_len_before_gc, after = self.closures.len(), "reduced closure capacity");
         }
-        if func_heap_len_before_gc != func_heap_len_after_gc {
-            println!("{} Reduced function capacity from {} to {}", "GC".bold().blue(), func_heap_len_before_gc, func_heap_len_after_gc);
+        if func_heap_len_before_gc != self.functions.len() {
+            trace!(before = func_heap_len_before_gc, after = self.functions.len(), "reduced function capacity");
         }
     }
 
@@ -150,104 +247,155 @@ impl Heap {
     /// Sweep orphan objects from the heap after comparing with the marked values
     fn sweep(&mut self, marked: Vec<Value>) {
         self.free_strings(&marked);
-        self.free_closures(&marked);
-        self.free_functions(&marked);
-        self.free_classes(&marked);
-        self.free_instances(&marked);
+        self.free_objects(&marked);
+    }
+
+    /// Free every pooled object that wasn't marked. Pools tombstone freed
+    /// slots, so the handles of the surviving objects stay valid.
+    fn free_objects(&mut self, marked: &[Value]) {
+        let is_alive: FnvHashSet<Object> = marked.iter()
+            .filter_map(|value| match value {
+                Value::Obj(object) => Some(*object),
+                _ => None
+            })
+            .collect();
+        let mut freed = 0;
+        freed += Self::free_unmarked(&mut self.functions, &is_alive, Object::FunctionIndex,
+                                     mem::size_of_val);
+        freed += Self::free_unmarked(&mut self.closures, &is_alive, Object::ClosureIndex,
+                                     mem::size_of_val);
+        freed += Self::free_unmarked(&mut self.classes, &is_alive, Object::ClassIndex,
+                                     mem::size_of_val);
+        freed += Self::free_unmarked(&mut self.instances, &is_alive, Object::InstanceIndex,
+                                     mem::size_of_val);
+        freed += Self::free_unmarked(&mut self.bound_methods, &is_alive, Object::BoundMethodIndex,
+                                     mem::size_of_val);
+        freed += Self::free_unmarked(&mut self.lists, &is_alive, Object::ListIndex,
+                                     |list| mem::size_of_val(list) + list.items.capacity() * mem::size_of::<Value>());
+        freed += Self::free_unmarked(&mut self.maps, &is_alive, Object::MapIndex,
+                                     |map| mem::size_of_val(map) + map.entries.capacity() * mem::size_of::<(Value, Value)>());
+        freed += Self::free_unmarked(&mut self.user_data, &is_alive, Object::UserDataIndex,
+                                     |user_data| mem::size_of_val(user_data) + mem::size_of_val(user_data.data.as_ref()));
+        self.bytes_allocated = self.bytes_allocated.saturating_sub(freed);
+    }
+
+    /// Free the objects of one pool missing from the marked set, returning the bytes released
+    fn free_unmarked<T>(pool: &mut Arena<T>,
+                        is_alive: &FnvHashSet<Object>,
+                        handle: fn(usize) -> Object,
+                        size: fn(&T) -> usize) -> usize {
+        let dead: Vec<usize> = pool.live_indexes()
+            .filter(|idx| !is_alive.contains(&handle(*idx)))
+            .collect();
+        let mut freed = 0;
+        for idx in dead {
+            if let Some(object) = pool.free(idx) {
+                freed += size(&object);
+            }
+        }
+        return freed;
     }
 
     fn free_strings(&mut self, marked: &Vec<Value>) {
-        let mut is_alive: HashSet<u32> = HashSet::new();
+        let mut is_alive: FnvHashSet<u32> = FnvHashSet::default();
         for each in marked {
             if each.is_string_hash() {
                 is_alive.insert(each.as_string_hash());
             }
         }
-        let mut deletions: HashSet<u32> = HashSet::new();
+        let mut deletions: FnvHashSet<u32> = FnvHashSet::default();
         //  deleting strings
         for each in self.strings.keys() {
             if is_alive.contains(each) {
                 continue;
             }
-            let string = self.strings.get(&each).unwrap();
-            let size = mem::size_of_val(&string);
+            let string = self.strings.get(each).unwrap();
+            let size = Self::string_size(string);
             if self.bytes_allocated > size {
                 self.bytes_allocated -= size;
             }
             deletions.insert(*each);
         }
         for each in deletions {
-            self.strings.remove(&each);
-        }
-    }
-
-    fn free_closures(&mut self, marked: &Vec<Value>) {
-        let mut is_alive: HashSet<usize> = HashSet::new();
-        for each in marked {
-            if each.is_closure_index() {
-                is_alive.insert(each.as_closure_index());
-            }
-        }
-        let mut deletions: Vec<usize> = vec![];
-        let mut index = 0;
-        for each in &self.closures {
-            if is_alive.contains(&index) {
-                continue;
-            }
-            let size = mem::size_of_val(&each);
-            if self.bytes_allocated > size {
-                self.bytes_allocated -= size;
+            let string = self.strings.remove(&each).unwrap();
+            let hash = hash_string(&string);
+            let keys = self.string_keys.get_mut(&hash).unwrap();
+            keys.retain(|key| *key != each);
+            if keys.is_empty() {
+                self.string_keys.remove(&hash);
             }
-            deletions.push(index);
-            index += 1;
-        }
-
-        deletions.sort();
-        deletions.reverse();
-
-        for i in 0..deletions.len() {
-            let index = deletions[i];
-            self.closures.remove(index);
         }
     }
 
-    // fixme: this can be a generic function
-    fn free_functions(&mut self, marked: &Vec<Value>) {
-        let mut is_alive: HashSet<usize> = HashSet::new();
-        for each in marked {
-            if each.is_function_index() {
-                is_alive.insert(each.as_function_index());
-            }
-        }
-        let mut deletions: Vec<usize> = vec![];
-        let mut index = 0;
-        for each in &self.functions {
-            if is_alive.contains(&index) {
-                continue;
-            }
-            let size = mem::size_of_val(&each);
-            if self.bytes_allocated > size {
-                self.bytes_allocated -= size;
-            }
-            deletions.push(index);
-            index += 1;
-        }
+    /// Bytes accounted for a string, including its character buffer
+    fn string_size(string: &String) -> usize {
+        return mem::size_of::<String>() + string.capacity();
+    }
 
-        deletions.sort();
-        deletions.reverse();
+    /// Has the heap grown past the configured memory limit?
+    pub fn is_over_limit(&self) -> bool {
+        return self.bytes_allocated > self.max_bytes;
+    }
 
-        for i in 0..deletions.len() {
-            let index = deletions[i];
-            self.functions.remove(index);
-        }
+    /// Current memory use and number of live objects in each pool
+    pub fn stats(&self) -> HeapStats {
+        return HeapStats {
+            bytes_allocated: self.bytes_allocated,
+            next_gc: self.next_gc,
+            strings: self.strings.len(),
+            functions: self.functions.len(),
+            native_fns: self.native_fns.len(),
+            closures: self.closures.len(),
+            classes: self.classes.len(),
+            instances: self.instances.len(),
+            bound_methods: self.bound_methods.len(),
+            lists: self.lists.len(),
+            maps: self.maps.len(),
+            user_data: self.user_data.len(),
+        };
     }
 
-    fn free_classes(&self, marked: &Vec<Value>) {
-        todo!()
+    /// Every object on the heap, strings ordered by hash and the rest by
+    /// pool and index
+    pub fn objects(&self) -> Vec<Object> {
+        let mut string_hashes: Vec<u32> = self.strings.keys().copied().collect();
+        string_hashes.sort();
+        let mut objects: Vec<Object> = string_hashes.into_iter().map(Object::StringHash).collect();
+        objects.extend(self.functions.live_indexes().map(Object::FunctionIndex));
+        objects.extend((0..self.native_fns.len()).map(Object::NativeFnIndex));
+        objects.extend(self.closures.live_indexes().map(Object::ClosureIndex));
+        objects.extend(self.classes.live_indexes().map(Object::ClassIndex));
+        objects.extend(self.instances.live_indexes().map(Object::InstanceIndex));
+        objects.extend(self.bound_methods.live_indexes().map(Object::BoundMethodIndex));
+        objects.extend(self.lists.live_indexes().map(Object::ListIndex));
+        objects.extend(self.maps.live_indexes().map(Object::MapIndex));
+        objects.extend(self.user_data.live_indexes().map(Object::UserDataIndex));
+        return objects;
     }
 
-    fn free_instances(&self, marked: &Vec<Value>) {
-        todo!()
+    /// Bytes the object counts for in bytes_allocated
+    pub fn object_size(&self, object: Object) -> usize {
+        return match object {
+            Object::StringHash(hash) => Self::string_size(self.get_string(hash)),
+            Object::FunctionIndex(idx) => mem::size_of_val(&*self.get_function(idx)),
+            Object::NativeFnIndex(idx) => mem::size_of_val(self.get_nativefn(idx)),
+            Object::ClosureIndex(idx) => mem::size_of_val(&*self.get_closure(idx)),
+            Object::ClassIndex(idx) => mem::size_of_val(&*self.get_class(idx)),
+            Object::InstanceIndex(idx) => mem::size_of_val(&*self.get_instance(idx)),
+            Object::BoundMethodIndex(idx) => mem::size_of_val(&*self.get_bound_method(idx)),
+            Object::ListIndex(idx) => {
+                let list = self.get_list(idx);
+                mem::size_of_val(&*list) + list.items.capacity() * mem::size_of::<Value>()
+            }
+            Object::MapIndex(idx) => {
+                let map = self.get_map(idx);
+                mem::size_of_val(&*map) + map.entries.capacity() * mem::size_of::<(Value, Value)>()
+            }
+            Object::UserDataIndex(idx) => {
+                let user_data = self.get_user_data(idx);
+                mem::size_of_val(&*user_data) + mem::size_of_val(user_data.data.as_ref())
+            }
+        };
     }
 
     /// Access string via hash key
@@ -261,8 +409,8 @@ impl Heap {
     /// NonMutator access function via index number
     pub fn get_function(&self, idx: usize) -> Ref<'_, Function> { self.functions[idx].borrow() }
 
-    ///
-    pub fn get_nativefn(&self, idx: usize)->&NativeFn { self.native_fns[idx].borrow() }
+    /// Access native function via index number
+    pub fn get_nativefn(&self, idx: usize)->&Native { self.native_fns[idx].borrow() }
 
     /// Mutator access closure via index number
     pub fn get_mut_closure(&self, idx: usize) -> RefMut<'_, Closure> { self.closures[idx].borrow_mut() }
@@ -282,13 +430,40 @@ impl Heap {
     /// Non mutator access instance via index number
     pub fn get_instance(&self, idx: usize) -> Ref<'_, Instance> { self.instances[idx].borrow() }
 
+    /// Non mutator access bound method via index number
+    pub fn get_bound_method(&self, idx: usize) -> Ref<'_, BoundMethod> { self.bound_methods[idx].borrow() }
+
+    /// Mutator access list via index number
+    pub fn get_mut_list(&self, idx: usize) -> RefMut<'_, List> { self.lists[idx].borrow_mut() }
+
+    /// Non mutator access list via index number
+    pub fn get_list(&self, idx: usize) -> Ref<'_, List> { self.lists[idx].borrow() }
+
+    /// Mutator access map via index number
+    pub fn get_mut_map(&self, idx: usize) -> RefMut<'_, Map> { self.maps[idx].borrow_mut() }
+
+    /// Non mutator access map via index number
+    pub fn get_map(&self, idx: usize) -> Ref<'_, Map> { self.maps[idx].borrow() }
+
+    /// Mutator access host value via index number
+    pub fn get_mut_user_data(&self, idx: usize) -> RefMut<'_, UserData> { self.user_data[idx].borrow_mut() }
+
+    /// Non mutator access host value via index number
+    pub fn get_user_data(&self, idx: usize) -> Ref<'_, UserData> { self.user_data[idx].borrow() }
+
     /// Clear the heap - for testing only
     pub fn clear(&mut self) {
         self.strings.clear();
+        self.string_keys.clear();
         self.functions.clear();
         self.classes.clear();
         self.closures.clear();
         self.instances.clear();
+        self.shapes = Shapes::new();
+        self.bound_methods.clear();
+        self.lists.clear();
+        self.maps.clear();
+        self.user_data.clear();
         self.bytes_allocated = 0;
         self.next_gc = INITIAL_SIZE;
     }
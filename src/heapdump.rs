@@ -0,0 +1,133 @@
+use std::fmt::Write;
+use std::fs;
+
+use fnv::FnvHashSet;
+
+use crate::diagnostic::json_string;
+use crate::nativefn::NativeError;
+use crate::{Object, Value, VM};
+
+impl VM {
+    /// JSON snapshot of the live heap, for looking into leaks offline.
+    /// Garbage is collected first, so the objects listed are the ones the
+    /// roots still reach. Each has an id, its type, its size in bytes and the
+    /// ids of the objects it refers to. The globals holding objects are
+    /// listed by name
+    pub fn heap_dump(&mut self) -> String {
+        self.collect_garbage();
+        let mut roots = vec![];
+        self.mark_roots(&mut roots);
+        let mut globals: Vec<(&String, Object)> = self.globals.iter()
+            .filter_map(|(name_hash, value)| match value {
+                Value::Obj(object) => Some((self.heap.get_string(*name_hash), *object)),
+                _ => None,
+            })
+            .collect();
+        globals.sort_by(|a, b| a.0.cmp(b.0));
+        let globals: Vec<String> = globals.iter()
+            .map(|(name, object)| format!("{}: \"{}\"", json_string(name), object_id(*object)))
+            .collect();
+
+        let mut out = String::new();
+        let _ = writeln!(out, "{{\"bytesAllocated\": {}, \"globals\": {{{}}}, \"roots\": {}, \"objects\": [",
+                         self.heap.bytes_allocated, globals.join(", "), id_list(&roots));
+        let objects = self.heap.objects();
+        for (i, object) in objects.iter().enumerate() {
+            let mut references = vec![];
+            self.push_references(*object, &mut references);
+            let _ = write!(out, "{{\"id\": \"{}\", \"type\": \"{}\", \"size\": {}{}, \"references\": {}}}",
+                           object_id(*object), object_kind(*object), self.heap.object_size(*object),
+                           self.object_details(*object), id_list(&references));
+            out.push_str(if i + 1 < objects.len() { ",\n" } else { "\n" });
+        }
+        out.push_str("]}\n");
+        return out;
+    }
+
+    /// Extra fields that tell the object apart, such as a function's name
+    fn object_details(&self, object: Object) -> String {
+        let heap = &self.heap;
+        return match object {
+            Object::StringHash(hash) => format!(", \"value\": {}", json_string(heap.get_string(hash))),
+            Object::FunctionIndex(idx) => format!(", \"name\": {}", json_string(&heap.get_function(idx).name)),
+            Object::ClosureIndex(idx) => {
+                let func_idx = heap.get_closure(idx).func_idx;
+                format!(", \"name\": {}", json_string(&heap.get_function(func_idx).name))
+            }
+            Object::ClassIndex(idx) => format!(", \"name\": {}", json_string(&heap.get_class(idx).name)),
+            Object::InstanceIndex(idx) => {
+                let class_idx = heap.get_instance(idx).class_idx;
+                format!(", \"class\": {}", json_string(&heap.get_class(class_idx).name))
+            }
+            Object::BoundMethodIndex(idx) => {
+                let func_idx = heap.get_closure(heap.get_bound_method(idx).closure_idx).func_idx;
+                format!(", \"name\": {}", json_string(&heap.get_function(func_idx).name))
+            }
+            Object::ListIndex(idx) => format!(", \"length\": {}", heap.get_list(idx).items.len()),
+            Object::MapIndex(idx) => format!(", \"length\": {}", heap.get_map(idx).entries.len()),
+            Object::UserDataIndex(idx) => {
+                let type_idx = heap.get_user_data(idx).type_idx;
+                format!(", \"name\": {}", json_string(&heap.user_types[type_idx].name))
+            }
+            Object::NativeFnIndex(_) => "".to_string(),
+        };
+    }
+}
+
+/// heapDump(path), writing VM::heap_dump to the file
+pub fn heap_dump_native(vm: &mut VM, arguments: Vec<Value>) -> Result<Value, NativeError> {
+    if arguments.len() != 1 {
+        return Err(NativeError::new(&format!("heapDump expects 1 argument(s) but got {}.", arguments.len())));
+    }
+    if !arguments[0].is_string_hash() {
+        return Err(NativeError::new("Invalid type for heapDump path, string expected."));
+    }
+    let path = vm.heap.get_string(arguments[0].as_string_hash()).clone();
+    let dump = vm.heap_dump();
+    fs::write(&path, dump)
+        .map_err(|error| NativeError::new(&format!("Unable to write {}: {}", path, error)))?;
+    return Ok(Value::bool(true));
+}
+
+/// Id of the object in the dump, its type and its index or string hash
+fn object_id(object: Object) -> String {
+    return match object {
+        Object::StringHash(hash) => format!("string:{}", hash),
+        Object::FunctionIndex(idx) => format!("function:{}", idx),
+        Object::NativeFnIndex(idx) => format!("native:{}", idx),
+        Object::ClosureIndex(idx) => format!("closure:{}", idx),
+        Object::ClassIndex(idx) => format!("class:{}", idx),
+        Object::InstanceIndex(idx) => format!("instance:{}", idx),
+        Object::BoundMethodIndex(idx) => format!("boundMethod:{}", idx),
+        Object::ListIndex(idx) => format!("list:{}", idx),
+        Object::MapIndex(idx) => format!("map:{}", idx),
+        Object::UserDataIndex(idx) => format!("userData:{}", idx),
+    };
+}
+
+fn object_kind(object: Object) -> &'static str {
+    return match object {
+        Object::StringHash(_) => "string",
+        Object::FunctionIndex(_) => "function",
+        Object::NativeFnIndex(_) => "native",
+        Object::ClosureIndex(_) => "closure",
+        Object::ClassIndex(_) => "class",
+        Object::InstanceIndex(_) => "instance",
+        Object::BoundMethodIndex(_) => "boundMethod",
+        Object::ListIndex(_) => "list",
+        Object::MapIndex(_) => "map",
+        Object::UserDataIndex(_) => "userData",
+    };
+}
+
+/// JSON array of the ids of the objects among the values, each listed once
+fn id_list(values: &[Value]) -> String {
+    let mut seen = FnvHashSet::default();
+    let ids: Vec<String> = values.iter()
+        .filter_map(|value| match value {
+            Value::Obj(object) if seen.insert(*object) => Some(format!("\"{}\"", object_id(*object))),
+            _ => None,
+        })
+        .collect();
+    return format!("[{}]", ids.join(", "));
+}
@@ -0,0 +1,88 @@
+use crate::scanner::Scanner;
+use crate::token::{Token, TokenType};
+
+/// What a highlighted span of source is
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum SemanticKind {
+    Keyword,
+    String,
+    Number,
+    Identifier,
+    Comment,
+}
+
+impl SemanticKind {
+    pub fn name(&self) -> &'static str {
+        return match self {
+            SemanticKind::Keyword => "keyword",
+            SemanticKind::String => "string",
+            SemanticKind::Number => "number",
+            SemanticKind::Identifier => "identifier",
+            SemanticKind::Comment => "comment",
+        };
+    }
+}
+
+/// Span of source on a single line, lines count from 1 and characters
+/// from 0 as diagnostics do
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct SemanticToken {
+    pub line: usize,
+    pub column: usize,
+    pub length: usize,
+    pub kind: SemanticKind,
+}
+
+/// Classified spans of the source in source order, for syntax highlighting.
+/// Punctuation, operators and text the scanner rejects, such as an
+/// unterminated string, aren't included. Strings and comments over several
+/// lines give a span per line, as editors expect
+pub fn semantic_tokens(source: &str) -> Vec<SemanticToken> {
+    let mut scanner = Scanner::new(&source.to_string());
+    scanner.print_errors = false;
+    scanner.keep_comments = true;
+    let tokens: Vec<Token> = scanner.by_ref().collect();
+    let mut comments = scanner.comments.into_iter().peekable();
+    let mut spans = vec![];
+    for token in tokens {
+        let kind = match semantic_kind(token.token_type) {
+            Some(kind) => kind,
+            None => continue,
+        };
+        // A multi line string token is on its closing line
+        let line = token.line - token.lexeme.matches('\n').count();
+        while let Some(comment) = comments.next_if(|comment| (comment.line, comment.column) < (line, token.column)) {
+            add_spans(&mut spans, &comment, comment.line, SemanticKind::Comment);
+        }
+        add_spans(&mut spans, &token, line, kind);
+    }
+    for comment in comments {
+        add_spans(&mut spans, &comment, comment.line, SemanticKind::Comment);
+    }
+    return spans;
+}
+
+fn semantic_kind(token_type: TokenType) -> Option<SemanticKind> {
+    return match token_type {
+        TokenType::String => Some(SemanticKind::String),
+        TokenType::Number => Some(SemanticKind::Number),
+        TokenType::Identifier => Some(SemanticKind::Identifier),
+        TokenType::And | TokenType::Class | TokenType::Else | TokenType::False | TokenType::Fun
+        | TokenType::For | TokenType::If | TokenType::Nil | TokenType::Or | TokenType::Print
+        | TokenType::Return | TokenType::Super | TokenType::This | TokenType::True | TokenType::Var
+        | TokenType::Const | TokenType::Match | TokenType::Try | TokenType::Catch | TokenType::Throw
+        | TokenType::While | TokenType::Extend | TokenType::Abstract => Some(SemanticKind::Keyword),
+        _ => None,
+    };
+}
+
+/// Add a span for each line of the token, which starts at the line given
+fn add_spans(spans: &mut Vec<SemanticToken>, token: &Token, line: usize, kind: SemanticKind) {
+    for (offset, text) in token.lexeme.split('\n').enumerate() {
+        let column = if offset == 0 { token.column } else { 0 };
+        let length = text.trim_end_matches('\r').chars().count();
+        if length > 0 {
+            spans.push(SemanticToken { line: line + offset, column, length, kind });
+        }
+    }
+}
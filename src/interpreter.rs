@@ -0,0 +1,198 @@
+use std::{error, fmt, fs, io, mem};
+use std::path::PathBuf;
+
+use crate::{bytecode, debug, Diagnostic, Heap, Parser, RunResult, Scanner, Value, VM};
+use crate::compiler::Disassemble;
+
+/// Why an evaluation failed
+#[derive(Debug)]
+pub enum KError {
+    /// The script file couldn't be read
+    Io(io::Error),
+    /// The source didn't compile, one message per error
+    Compile(Vec<String>),
+    /// The compiled script file couldn't be loaded
+    Bytecode(String),
+    /// Uncaught runtime error with its message
+    Runtime(String),
+    /// Stopped after executing VM::max_instructions instructions
+    BudgetExceeded,
+    /// Stopped after running longer than VM::timeout
+    Timeout,
+    /// Stopped because VM::interrupt_flag was set
+    Interrupted,
+}
+
+impl fmt::Display for KError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            KError::Io(error) => write!(f, "{}", error),
+            KError::Compile(errors) => write!(f, "{}", errors.join("\n")),
+            KError::Bytecode(message) => write!(f, "{}", message),
+            KError::Runtime(message) => write!(f, "{}", message),
+            KError::BudgetExceeded => write!(f, "Instruction budget exceeded."),
+            KError::Timeout => write!(f, "Execution timed out."),
+            KError::Interrupted => write!(f, "Interrupted."),
+        }
+    }
+}
+
+impl error::Error for KError {}
+
+impl From<io::Error> for KError {
+    fn from(error: io::Error) -> Self {
+        KError::Io(error)
+    }
+}
+
+/// Scans, compiles and runs KScript for a host program.
+///
+/// Globals and heap objects persist between evaluations. Values handed back
+/// refer to objects on the interpreter's heap, the last result stays alive
+/// until the next evaluation.
+pub struct Interpreter {
+    pub vm: VM,
+    /// Print the bytecode of functions as they finish compiling
+    pub disassemble: Disassemble,
+    /// Print compile errors to stderr as well as returning them
+    pub print_errors: bool,
+    /// Fail compilation on warnings, such as unused variables
+    pub deny_warnings: bool,
+    /// Warn about globals the source uses without defining them
+    pub warn_undefined_globals: bool,
+    /// Warn about global functions and classes the source never uses. Off
+    /// for scripts that later ones build on
+    pub warn_unused_globals: bool,
+    /// Compile straight from the tokens without building a syntax tree
+    pub single_pass: bool,
+}
+
+impl Interpreter {
+    pub fn new() -> Self {
+        let mut vm = VM::new();
+        vm.init();
+        Interpreter {
+            vm,
+            disassemble: Disassemble::None,
+            print_errors: false,
+            deny_warnings: false,
+            warn_undefined_globals: false,
+            warn_unused_globals: true,
+            single_pass: false,
+        }
+    }
+
+    /// Evaluate the source, returning the value of a lone expression or nil
+    /// for statements
+    pub fn eval(&mut self, source: &str) -> Result<Value, KError> {
+        let func_main_idx = match self.compile_with(source, true) {
+            Ok(func_main_idx) => func_main_idx,
+            Err(_) => self.compile(source)?,
+        };
+        return self.run(func_main_idx);
+    }
+
+    /// Run a script file, either source or bytecode written by `compile`
+    pub fn run_file(&mut self, path: &str) -> Result<(), KError> {
+        let contents = fs::read(path)?;
+        let func_main_idx = if bytecode::is_bytecode(&contents) {
+            self.load_bytecode(&contents)?
+        } else {
+            let source = String::from_utf8(contents)
+                .map_err(|error| KError::Io(io::Error::new(io::ErrorKind::InvalidData, error)))?;
+            self.compile(&source)?
+        };
+        self.vm.heap.get_mut_function(func_main_idx).script_path = Some(PathBuf::from(path));
+        self.run(func_main_idx)?;
+        return Ok(());
+    }
+
+    /// Compile the source onto the heap.
+    ///
+    /// Returns the index of the main function
+    pub fn compile(&mut self, source: &str) -> Result<usize, KError> {
+        return self.compile_with(source, false);
+    }
+
+    /// Compile the source and return the disassembly of main and the
+    /// functions it creates, as `debug::function_listing` renders it
+    pub fn listing(&mut self, source: &str) -> Result<String, KError> {
+        let func_main_idx = self.compile(source)?;
+        return Ok(debug::function_listing(&self.vm.heap, func_main_idx));
+    }
+
+    /// Load compiled bytecode onto the heap.
+    ///
+    /// Returns the index of the main function
+    pub fn load_bytecode(&mut self, bytes: &[u8]) -> Result<usize, KError> {
+        return bytecode::deserialize(bytes, &mut self.vm.heap).map_err(KError::Bytecode);
+    }
+
+    /// Run a compiled main function, returning the value it returns
+    pub fn run(&mut self, func_main_idx: usize) -> Result<Value, KError> {
+        let result = self.vm.execute_function(func_main_idx);
+        self.vm.reset_stack();
+        return match result {
+            RunResult::Ok => Ok(self.vm.last_result),
+            RunResult::RuntimeError => Err(KError::Runtime(self.vm.last_error.take().unwrap_or_default())),
+            RunResult::BudgetExceeded => Err(KError::BudgetExceeded),
+            RunResult::Timeout => Err(KError::Timeout),
+            RunResult::Interrupted => Err(KError::Interrupted),
+        };
+    }
+
+    /// Scan and parse the source without compiling it, returning the syntax
+    /// errors in source order. Mistakes only the compiler finds, such as
+    /// assigning to a constant, aren't reported
+    pub fn check_syntax(source: &str) -> Vec<Diagnostic> {
+        let mut scanner = Scanner::new(&source.to_string());
+        scanner.print_errors = false;
+        let mut parser = Parser::new(Heap::new(), scanner);
+        parser.print_errors = false;
+        parser.parse();
+        let mut diagnostics = parser.diagnostics;
+        diagnostics.sort_by_key(|diagnostic| (diagnostic.line, diagnostic.column));
+        return diagnostics;
+    }
+
+    /// Text print would show for the value
+    pub fn display(&self, value: Value) -> String {
+        return self.vm.format_value(value);
+    }
+
+    fn compile_with(&mut self, source: &str, expression: bool) -> Result<usize, KError> {
+        // The parser reports scan errors along with its own
+        let mut scanner = Scanner::new(&source.to_string());
+        scanner.print_errors = false;
+
+        let predefined_globals = if self.warn_undefined_globals { self.vm.global_names() } else { vec![] };
+
+        // The parser owns the heap while compiling
+        let mut heap_to_parser = Heap::new();
+        mem::swap(&mut self.vm.heap, &mut heap_to_parser);
+
+        let mut parser = Parser::new(heap_to_parser, scanner);
+        parser.disassemble = self.disassemble.clone();
+        parser.print_errors = self.print_errors && !expression;
+        parser.source = source.into();
+        parser.deny_warnings = self.deny_warnings;
+        parser.warn_undefined_globals = self.warn_undefined_globals;
+        parser.warn_unused_globals = self.warn_unused_globals;
+        parser.single_pass = self.single_pass;
+        parser.predefined_globals = predefined_globals.into_iter().collect();
+        let func_main_idx = if expression { parser.compile_expression() } else { parser.compile() };
+
+        mem::swap(&mut parser.heap, &mut self.vm.heap);
+
+        if parser.had_error {
+            return Err(KError::Compile(parser.errors));
+        }
+        return Ok(func_main_idx);
+    }
+}
+
+impl Default for Interpreter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,91 @@
+use serde_json::{Map as JsonMap, Number};
+
+use crate::heap::Heap;
+use crate::list::List;
+use crate::map::Map;
+use crate::{Object, Value};
+
+impl Value {
+    /// Script value for the JSON, allocating its strings, lists and maps on
+    /// the heap. Objects become maps with string keys in the same order.
+    /// The value is only kept alive once a script or global holds it
+    pub fn from_json(json: &serde_json::Value, heap: &mut Heap) -> Value {
+        return match json {
+            serde_json::Value::Null => Value::nil(),
+            serde_json::Value::Bool(boolean) => Value::bool(*boolean),
+            serde_json::Value::Number(number) => Value::number(number.as_f64().unwrap_or(f64::NAN)),
+            serde_json::Value::String(text) => Value::object(Object::string(heap.alloc_string(text.clone()))),
+            serde_json::Value::Array(items) => {
+                let items = items.iter().map(|item| Value::from_json(item, heap)).collect();
+                Value::object(Object::list(heap.alloc_list(List::new(items))))
+            }
+            serde_json::Value::Object(entries) => {
+                let mut map = Map::new();
+                for (key, value) in entries {
+                    let key = Value::object(Object::string(heap.alloc_string(key.clone())));
+                    map.set(key, Value::from_json(value, heap));
+                }
+                Value::object(Object::map(heap.alloc_map(map)))
+            }
+        };
+    }
+
+    /// JSON for the value. Instances become objects of their fields. Fails on
+    /// functions, classes, userdata, maps with non string keys, numbers JSON
+    /// can't hold and lists, maps or instances that contain themselves
+    pub fn to_json(&self, heap: &Heap) -> Result<serde_json::Value, String> {
+        return to_json(*self, heap, &mut vec![]);
+    }
+}
+
+/// `enclosing` holds the lists, maps and instances being converted around
+/// the value, meeting one of them again means a cycle
+fn to_json(value: Value, heap: &Heap, enclosing: &mut Vec<Object>) -> Result<serde_json::Value, String> {
+    let object = match value {
+        Value::Nil() => return Ok(serde_json::Value::Null),
+        Value::Bool(boolean) => return Ok(serde_json::Value::Bool(boolean)),
+        // Whole numbers are written without a fraction, as scripts print them
+        Value::Number(n) if n.fract() == 0.0 && n.abs() < 9007199254740992.0 => return Ok(serde_json::Value::Number(Number::from(n as i64))),
+        Value::Number(n) => return Number::from_f64(n)
+            .map(serde_json::Value::Number)
+            .ok_or_else(|| format!("{} can't be converted to JSON.", n)),
+        Value::Obj(Object::StringHash(hash)) => return Ok(serde_json::Value::String(heap.get_string(hash).clone())),
+        Value::Obj(object) => object,
+    };
+    if enclosing.contains(&object) {
+        return Err("Value contains itself and can't be converted to JSON.".to_string());
+    }
+    enclosing.push(object);
+    let json = match object {
+        Object::ListIndex(idx) => {
+            let items = heap.get_list(idx).items.clone();
+            let items = items.into_iter()
+                .map(|item| to_json(item, heap, enclosing))
+                .collect::<Result<Vec<_>, _>>()?;
+            serde_json::Value::Array(items)
+        }
+        Object::MapIndex(idx) => {
+            let entries = heap.get_map(idx).entries.clone();
+            let mut json = JsonMap::new();
+            for (key, value) in entries {
+                if !key.is_string_hash() {
+                    return Err("Only maps with string keys can be converted to JSON.".to_string());
+                }
+                json.insert(heap.get_string(key.as_string_hash()).clone(), to_json(value, heap, enclosing)?);
+            }
+            serde_json::Value::Object(json)
+        }
+        Object::InstanceIndex(idx) => {
+            let names = heap.get_instance(idx).field_names(&heap.shapes);
+            let mut json = JsonMap::new();
+            for name in names {
+                let value = heap.get_instance(idx).get_field(&heap.shapes, name).unwrap();
+                json.insert(heap.get_string(name).clone(), to_json(value, heap, enclosing)?);
+            }
+            serde_json::Value::Object(json)
+        }
+        _ => return Err("Functions, classes and userdata can't be converted to JSON.".to_string()),
+    };
+    enclosing.pop();
+    return Ok(json);
+}
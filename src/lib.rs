@@ -0,0 +1,65 @@
+//! KScript scripting language, a bytecode compiler and virtual machine.
+//!
+//! Embed it through [`Interpreter`]:
+//!
+//! ```
+//! let mut interpreter = kscript_rust::Interpreter::new();
+//! interpreter.eval("var answer = 40 + 2;").unwrap();
+//! let value = interpreter.eval("answer").unwrap();
+//! assert_eq!("42", interpreter.display(value));
+//! ```
+extern crate core;
+
+pub use crate::chunk::{Chunk, Opcode};
+pub use crate::compiler::Parser;
+pub use crate::diagnostic::{Diagnostic, Severity};
+pub use crate::heap::Heap;
+pub use crate::highlight::{semantic_tokens, SemanticKind, SemanticToken};
+pub use crate::interpreter::{Interpreter, KError};
+pub use crate::object::Object;
+pub use crate::scanner::Scanner;
+pub use crate::userdata::{UserData, UserMethod};
+pub use crate::value::Value;
+pub use crate::vm::{RunResult, VM};
+
+pub mod value;
+pub mod chunk;
+pub mod object;
+pub mod function;
+pub mod token;
+pub mod vm;
+pub mod callframe;
+pub mod scanner;
+pub mod compiler;
+pub mod ast;
+pub mod diagnostic;
+pub mod formatter;
+pub mod highlight;
+pub mod heap;
+pub mod heapdump;
+pub mod utils;
+pub mod debug;
+pub mod nativefn;
+pub mod ffi;
+pub mod closure;
+pub mod class;
+pub mod shape;
+pub mod list;
+pub mod map;
+pub mod userdata;
+pub mod arena;
+pub mod metrics;
+pub mod repl;
+pub mod bytecode;
+pub mod optimizer;
+pub mod interpreter;
+#[cfg(feature = "json")]
+pub mod json;
+#[cfg(feature = "logging")]
+pub mod logging;
+#[cfg(feature = "fuzzing")]
+pub mod fuzz;
+#[cfg(feature = "wasm")]
+pub mod wasm;
+#[cfg(test)]
+mod tests;
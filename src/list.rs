@@ -0,0 +1,14 @@
+use crate::Value;
+
+/// Growable sequence of values created by a list literal
+pub struct List {
+    pub items: Vec<Value>,
+}
+
+impl List {
+    pub fn new(items: Vec<Value>) ->Self {
+        List {
+            items
+        }
+    }
+}
@@ -0,0 +1,36 @@
+use std::{env, io};
+
+use tracing_subscriber::EnvFilter;
+
+/// Environment variable with the filter for the interpreter's log events,
+/// such as `debug` or `kscript_rust::heap=trace`
+pub const LOG_ENV: &str = "KSCRIPT_LOG";
+
+/// How log events are written
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum LogFormat {
+    /// A human readable line per event
+    Text,
+    /// A JSON object per line, with the event's fields and the spans around it
+    Json,
+}
+
+/// Write log events to stderr, filtered by KSCRIPT_LOG or warnings only when
+/// it isn't set. Compilation and runs are `compile` and `execute` spans,
+/// garbage collections `gc` spans. Fails when KSCRIPT_LOG isn't a valid
+/// filter or the process already has a global subscriber
+pub fn init_logging(format: LogFormat) -> Result<(), String> {
+    let filter = match env::var(LOG_ENV) {
+        Ok(directives) => EnvFilter::try_new(&directives)
+            .map_err(|error| format!("Invalid {} filter '{}': {}", LOG_ENV, directives, error))?,
+        Err(_) => EnvFilter::new("warn"),
+    };
+    let builder = tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(io::stderr);
+    let result = match format {
+        LogFormat::Text => builder.try_init(),
+        LogFormat::Json => builder.json().with_current_span(true).with_span_list(true).try_init(),
+    };
+    return result.map_err(|error| error.to_string());
+}
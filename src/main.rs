@@ -1,131 +1,518 @@
-extern crate core;
-use std::{env, fs, mem};
+use std::{env, fs, io};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::Ordering;
+use std::time::Duration;
 use std::process::exit;
-use std::time::{Instant};
-
-use crate::chunk::{Chunk, Opcode};
-use crate::compiler::Parser;
-use crate::heap::Heap;
-use crate::object::Object;
-use crate::scanner::Scanner;
-use crate::utils::read_line;
-use crate::value::Value;
-use crate::vm::{RunResult, VM};
-
-mod value;
-mod chunk;
-mod object;
-mod function;
-mod token;
-mod vm;
-mod callframe;
-mod scanner;
-mod compiler;
-mod heap;
-mod utils;
-mod debug;
-mod nativefn;
-mod closure;
-mod class;
-mod tests;
 
-/// Main entry point to KScript VM
-fn main() {
-    let args: Vec<String> = env::args().collect();
-    if args.len() == 1 {
-        run_prompt();
-    } else if args.len() == 2 {
-        let filename = args.get(1).unwrap();
-        run_file(filename);
-    }
+use kscript_rust::{bytecode, debug, Interpreter, KError, Scanner, Severity, VM};
+use kscript_rust::compiler::Disassemble;
+use kscript_rust::formatter;
+#[cfg(feature = "logging")]
+use kscript_rust::logging::{init_logging, LogFormat};
+use kscript_rust::nativefn::Capabilities;
+use kscript_rust::repl::Repl;
+
+/// Command line options
+struct Options {
+    /// Script to run, None starts the interactive prompt
+    filename: Option<String>,
+    /// Scripts run after the first one in the same VM, sharing its globals
+    more_scripts: Vec<String>,
+    /// Arguments after the script, passed to it as `args`
+    script_args: Vec<String>,
+    /// Compile the script to bytecode instead of running it
+    compile: bool,
+    /// Where to write the compiled bytecode
+    output: Option<String>,
+    /// Only check that the script compiles
+    compile_only: bool,
+    /// Only scan and parse, printing the syntax errors as JSON
+    check: bool,
+    /// Only scan, printing the tokens
+    tokens: bool,
+    /// Reprint the scripts with canonical layout
+    format: bool,
+    /// Rewrite the formatted scripts in place
+    format_write: bool,
+    /// Print a diff for the scripts that aren't formatted instead
+    format_check: bool,
+    /// Scripts to check or format, which take several
+    files: Vec<String>,
+    /// Print the bytecode of compiled functions
+    disassemble: Disassemble,
+    /// Print the bytecode of the script and the functions in it instead of running it
+    dump_bytecode: bool,
+    /// Heap limit in megabytes
+    max_heap: Option<usize>,
+    /// Value stack limit in slots
+    stack_size: Option<usize>,
+    /// Instructions a run may execute
+    max_instructions: Option<u64>,
+    /// Wall clock limit for a run in milliseconds
+    timeout: Option<u64>,
+    /// Values traced per incremental garbage collection step
+    gc_step: Option<usize>,
+    /// Collect garbage after every allocating instruction
+    gc_stress: bool,
+    /// Print execution metrics after the run
+    metrics: bool,
+    /// Where to write a JSON dump of the live heap after the run
+    heap_dump: Option<String>,
+    /// Run without file, network or process access
+    sandbox: bool,
+    /// Treat compiler warnings as errors
+    deny_warnings: bool,
+    /// Warn about globals used without being defined
+    warn_undefined: bool,
+    /// Compile without building a syntax tree first
+    single_pass: bool,
+    /// Write log events as JSON lines instead of text
+    log_json: bool,
 }
 
-/// EVAL loop mode
-fn run_prompt() {
-    let mut vm = VM::new();
-    vm.init();
-    println!("KScript VM written in RUST :)");
-    loop {
-        println!("> ");
-        let result = read_line();
-        let line  = match result {
-            Ok(line) => line,
-            Err(error) => panic!("Unable to read input {}", error),
+impl Options {
+    /// Parse the command line arguments, exiting on invalid usage
+    fn parse(args: &[String]) -> Self {
+        let mut options = Options {
+            filename: None,
+            more_scripts: vec![],
+            script_args: vec![],
+            compile: false,
+            output: None,
+            compile_only: false,
+            check: false,
+            tokens: false,
+            format: false,
+            format_write: false,
+            format_check: false,
+            files: vec![],
+            disassemble: Disassemble::None,
+            dump_bytecode: false,
+            max_heap: None,
+            stack_size: None,
+            max_instructions: None,
+            timeout: None,
+            gc_step: None,
+            gc_stress: false,
+            metrics: false,
+            heap_dump: None,
+            sandbox: false,
+            deny_warnings: false,
+            warn_undefined: false,
+            single_pass: false,
+            log_json: false,
         };
-        if line.trim() == "" {
-            continue;
+        let mut iter = args.iter().skip(1).peekable();
+        match iter.peek().map(|it| it.as_str()) {
+            Some("compile") => options.compile = true,
+            Some("fmt") => options.format = true,
+            _ => {}
         }
-        else if line.trim() == "exit" {
-            println!("Good bye!\n");
-            break;
+        if options.compile || options.format {
+            iter.next();
+        }
+        while let Some(arg) = iter.next() {
+            match arg.as_str() {
+                "-o" if options.compile => {
+                    if iter.peek().is_none() {
+                        usage("-o expects an output path");
+                    }
+                    options.output = iter.next().cloned();
+                }
+                "--max-heap" => {
+                    let megabytes = iter.next().and_then(|it| it.parse::<usize>().ok());
+                    if megabytes.is_none() {
+                        usage("--max-heap expects a size in megabytes");
+                    }
+                    options.max_heap = megabytes;
+                }
+                "--stack-size" => {
+                    let slots = iter.next().and_then(|it| it.parse::<usize>().ok());
+                    if slots.is_none() {
+                        usage("--stack-size expects a number of value slots");
+                    }
+                    options.stack_size = slots;
+                }
+                "--max-instructions" => {
+                    let count = iter.next().and_then(|it| it.parse::<u64>().ok());
+                    if count.is_none() {
+                        usage("--max-instructions expects a number of instructions");
+                    }
+                    options.max_instructions = count;
+                }
+                "--timeout" => {
+                    let milliseconds = iter.next().and_then(|it| it.parse::<u64>().ok());
+                    if milliseconds.is_none() {
+                        usage("--timeout expects a number of milliseconds");
+                    }
+                    options.timeout = milliseconds;
+                }
+                "--gc-step" => {
+                    let values = iter.next().and_then(|it| it.parse::<usize>().ok()).filter(|it| *it > 0);
+                    if values.is_none() {
+                        usage("--gc-step expects a positive number of values");
+                    }
+                    options.gc_step = values;
+                }
+                "--write" | "-w" if options.format => options.format_write = true,
+                "--check" if options.format => options.format_check = true,
+                "--compile-only" | "-c" => options.compile_only = true,
+                "--check" => options.check = true,
+                "--tokens" => options.tokens = true,
+                "--disassemble" => options.disassemble = Disassemble::All,
+                "--dump-bytecode" => options.dump_bytecode = true,
+                "--disassemble-fn" => {
+                    match iter.next() {
+                        Some(name) => options.disassemble = Disassemble::Function(name.to_string()),
+                        None => usage("--disassemble-fn expects a function name"),
+                    }
+                }
+                "--gc-stress" => options.gc_stress = true,
+                "--metrics" | "--stats" => options.metrics = true,
+                "--heap-dump" => {
+                    if iter.peek().is_none() {
+                        usage("--heap-dump expects an output path");
+                    }
+                    options.heap_dump = iter.next().cloned();
+                }
+                "--sandbox" => options.sandbox = true,
+                "--deny-warnings" => options.deny_warnings = true,
+                "--warn-undefined" => options.warn_undefined = true,
+                "--single-pass" => options.single_pass = true,
+                "--log-json" => options.log_json = true,
+                _ if arg.starts_with("--") => usage(&format!("Unknown option {}", arg)),
+                _ if options.check || options.format => options.files.push(arg.to_string()),
+                _ => {
+                    if options.filename.is_some() {
+                        usage("Only one script can be compiled at a time");
+                    }
+                    options.filename = Some(arg.to_string());
+                    if !options.compile {
+                        // Scripts following the first run after it, until -- or another argument
+                        while let Some(script) = iter.next_if(|it| is_script(it)) {
+                            options.more_scripts.push(script.to_string());
+                        }
+                        iter.next_if(|it| *it == "--");
+                        // Everything after the scripts belongs to them
+                        options.script_args = iter.by_ref().cloned().collect();
+                    }
+                }
+            }
+        }
+        if options.compile && options.filename.is_none() {
+            usage("compile expects a script");
+        }
+        let single_script = options.compile_only || options.dump_bytecode || options.tokens;
+        if single_script && !options.more_scripts.is_empty() {
+            usage("Only one script can be checked or dumped at a time");
+        }
+        if options.compile_only && options.filename.is_none() {
+            usage("--compile-only expects a script");
+        }
+        if options.dump_bytecode && options.filename.is_none() {
+            usage("--dump-bytecode expects a script");
+        }
+        if options.tokens && options.filename.is_none() {
+            usage("--tokens expects a script");
+        }
+        if options.check && options.files.is_empty() {
+            usage("--check expects one or more scripts");
+        }
+        if options.format && options.files.is_empty() {
+            usage("fmt expects one or more scripts");
+        }
+        if options.format_write && options.format_check {
+            usage("fmt takes either --write or --check");
+        }
+        return options;
+    }
+
+    /// Apply the options that configure the VM
+    fn configure(&self, vm: &mut VM) {
+        if let Some(megabytes) = self.max_heap {
+            vm.heap.max_bytes = megabytes * 1024 * 1024;
+        }
+        if let Some(slots) = self.stack_size {
+            vm.max_stack = slots;
+        }
+        vm.max_instructions = self.max_instructions;
+        vm.timeout = self.timeout.map(Duration::from_millis);
+        if let Some(values) = self.gc_step {
+            vm.gc_step_budget = values;
+        }
+        vm.heap.stress = self.gc_stress;
+        if self.sandbox {
+            vm.capabilities = Capabilities::none();
         }
-        vm = run(vm, &line);
-        vm.reset_stack();
     }
 }
 
-/// Execute the VM by loading the KScript from file
-fn run_file(filename: &String) {
+/// Does the argument name a script, source or bytecode, rather than an
+/// argument for the scripts?
+fn is_script(arg: &str) -> bool {
+    return arg.ends_with(".ks") || arg.ends_with(".kbc");
+}
 
-    let source = fs::read_to_string(filename)
-        .expect("Something went wrong reading the file");
+/// Print usage with an error message and exit
+fn usage(message: &str) -> ! {
+    eprintln!("{}", message);
+    eprintln!("Usage: kscript_rust [--max-heap <MB>] [--stack-size <slots>] [--max-instructions <count>] [--timeout <ms>] [--gc-step <values>] [--gc-stress] [--metrics | --stats] [--heap-dump <path>] [--sandbox] [--compile-only | -c]");
+    eprintln!("                   [--deny-warnings] [--warn-undefined] [--single-pass] [--log-json] [--tokens] [--dump-bytecode] [--disassemble | --disassemble-fn <name>] [script | compiled.kbc]... [--] [args...]");
+    eprintln!("       kscript_rust compile <script> [-o <compiled.kbc>]");
+    eprintln!("       kscript_rust --check <script>...");
+    eprintln!("       kscript_rust fmt [--write | -w | --check] <script>...");
+    exit(64);
+}
 
-    let mut vm = VM::new();
-    vm.init();
+/// Main entry point to KScript VM
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    let options = Options::parse(&args);
+    #[cfg(feature = "logging")]
+    {
+        let format = if options.log_json { LogFormat::Json } else { LogFormat::Text };
+        if let Err(error) = init_logging(format) {
+            usage(&error);
+        }
+    }
+    match &options.filename {
+        _ if options.check => check_syntax(&options.files),
+        _ if options.format => format_files(&options),
+        None => run_prompt(&options),
+        Some(filename) if options.compile => compile_file(filename, &options),
+        Some(filename) if options.compile_only => check_file(filename, &options),
+        Some(filename) if options.tokens => print_tokens(filename),
+        Some(filename) if options.dump_bytecode => dump_bytecode(filename, &options),
+        Some(filename) => run_files(filename, &options),
+    }
+}
 
-    let mut scanner = Scanner::new(  &source);
-    let tokens = scanner.scan_tokens();
+/// EVAL loop mode
+fn run_prompt(options: &Options) {
+    let stdin = io::stdin();
+    let mut repl = Repl::new(stdin.lock(), Box::new(io::stdout()));
+    options.configure(&mut repl.vm);
+    repl.print_metrics = options.metrics;
+    repl.disassemble = options.disassemble.clone();
+    install_interrupt_handler(&repl.vm);
+    println!("KScript VM written in RUST :)");
+    if let Err(error) = repl.run() {
+        panic!("Unable to read input {}", error);
+    }
+}
 
-    // transfer heap ownership to parser
-    let mut heap_to_parser = Heap::new();
-    mem::swap(&mut vm.heap, &mut heap_to_parser);
+/// Execute the VM by loading the KScripts, or their compiled bytecode, from
+/// file. The scripts run in order sharing their globals, stopping at the
+/// first that fails
+fn run_files(filename: &String, options: &Options) {
 
-    let mut parser = Parser::new(heap_to_parser, tokens);
-    parser.compile();
+    let mut interpreter = new_interpreter(options);
+    options.configure(&mut interpreter.vm);
+    interpreter.vm.set_args(&options.script_args);
 
-    // transfer heap ownership of back to vm
-    mem::swap(&mut parser.heap, &mut vm.heap,);
+    install_interrupt_handler(&interpreter.vm);
 
-    // Bail out on parser error
-    if parser.had_error {  exit(50);}
+    let filenames: Vec<&String> = [filename].into_iter().chain(&options.more_scripts).collect();
+    let mut result = Ok(());
+    for (i, filename) in filenames.iter().enumerate() {
+        // Later scripts can still use what earlier ones define
+        interpreter.warn_unused_globals = i + 1 == filenames.len();
+        let func_main_idx = load_file(&mut interpreter, filename);
+        // Scripts it loads are found next to it
+        interpreter.vm.heap.get_mut_function(func_main_idx).script_path = Some(PathBuf::from(filename));
+        result = interpreter.run(func_main_idx).map(|_| ());
+        if result.is_err() {
+            break;
+        }
+    }
 
-    let start = Instant::now();
-    let result = vm.execute();
-    let duration = start.elapsed();
+    if options.metrics {
+        println!("{}", interpreter.vm.metrics());
+    }
+    if let Some(path) = &options.heap_dump {
+        if let Err(error) = fs::write(path, interpreter.vm.heap_dump()) {
+            eprintln!("Unable to write {}: {}", path, error);
+        }
+    }
 
     match result {
-        RunResult::RuntimeError => { exit(70)}
-        RunResult::Ok => {
-            println!("Time elapsed interpret is: {:?}", duration);
-            exit(0);
-        }
+        Err(KError::Interrupted) => { exit(130) }
+        Err(_) => { exit(70)}
+        Ok(_) => { exit(0) }
     }
 }
 
+/// Compile or load the bytecode of the KScript file, exiting when it can't be.
 ///
-fn run(mut vm: VM, source: &String) ->VM {
+/// Returns the index of the main function
+fn load_file(interpreter: &mut Interpreter, filename: &String) -> usize {
+    let contents = fs::read(filename)
+        .expect("Something went wrong reading the file");
 
-    let mut scanner = Scanner::new( source);
+    if bytecode::is_bytecode(&contents) {
+        return match interpreter.load_bytecode(&contents) {
+            Ok(func_main_idx) => func_main_idx,
+            Err(error) => {
+                eprintln!("{}", error);
+                exit(65);
+            }
+        };
+    }
+    let source = String::from_utf8(contents)
+        .expect("Something went wrong reading the file");
+    return compile_source(interpreter, &source);
+}
 
-    let tokens = scanner.scan_tokens();
+/// Stop the running script on Ctrl-C instead of killing the process, so the
+/// REPL gets back to its prompt. The VM only sees the flag between
+/// instructions, so a second Ctrl-C while a blocking native such as input or
+/// httpGet still holds the first one exits with 130
+fn install_interrupt_handler(vm: &VM) {
+    let interrupt_flag = vm.interrupt_flag.clone();
+    let result = ctrlc::set_handler(move || {
+        if interrupt_flag.swap(true, Ordering::Relaxed) {
+            exit(130);
+        }
+    });
+    if let Err(error) = result {
+        eprintln!("Unable to install the Ctrl-C handler: {}", error);
+    }
+}
 
-    // transfer heap ownership of heap in VM to heap1
-    let mut heap1 = Heap::new();
-    mem::swap(&mut vm.heap, &mut heap1);
+/// Print the tokens scanned from the KScript file, exiting with 50 if there
+/// was a scan error
+fn print_tokens(filename: &String) {
+    let source = fs::read_to_string(filename)
+        .expect("Something went wrong reading the file");
+
+    let mut scanner = Scanner::new(&source);
+    scanner.print_errors = false;
+    debug::print_tokens(scanner.by_ref());
+    if scanner.had_error {
+        exit(50);
+    }
+}
 
-    let mut parser = Parser::new(heap1, tokens);
-    parser.compile();
+/// Compile the KScript file without running it, the exit status tells whether it compiled
+fn check_file(filename: &String, options: &Options) {
+    let source = fs::read_to_string(filename)
+        .expect("Something went wrong reading the file");
 
-    // transfer heap ownership of back to vm
-    mem::swap(&mut parser.heap, &mut vm.heap,);
+    compile_source(&mut new_interpreter(options), &source);
+}
 
-    if !parser.had_error {
-        vm.execute();
+/// Scan and parse the KScript files, printing their syntax errors as a JSON
+/// array for editors and hooks. Exits with 50 when there is any error
+fn check_syntax(filenames: &[String]) {
+    let mut entries = vec![];
+    let mut had_error = false;
+    for filename in filenames {
+        let source = fs::read_to_string(filename)
+            .expect("Something went wrong reading the file");
+        for diagnostic in Interpreter::check_syntax(&source) {
+            had_error |= diagnostic.severity == Severity::Error;
+            entries.push(diagnostic.to_json(filename));
+        }
+    }
+    if entries.is_empty() {
+        println!("[]");
+    } else {
+        println!("[\n  {}\n]", entries.join(",\n  "));
     }
+    if had_error {
+        exit(50);
+    }
+}
+
+/// Print the KScript files formatted, or rewrite them with --write. With
+/// --check nothing changes, a diff is printed for each file that isn't
+/// formatted and the exit status is 1 if there was any
+fn format_files(options: &Options) {
+    let mut unformatted = false;
+    for filename in &options.files {
+        let source = fs::read_to_string(filename)
+            .expect("Something went wrong reading the file");
+        let formatted = match formatter::format_source(&source) {
+            Ok(formatted) => formatted,
+            Err(errors) => {
+                for error in errors {
+                    eprintln!("{}: {}", filename, error);
+                }
+                exit(50);
+            }
+        };
+        if options.format_check {
+            let diff = formatter::unified_diff(&source, &formatted, filename, &format!("{} (formatted)", filename));
+            if source != formatted {
+                unformatted = true;
+                if diff.is_empty() {
+                    println!("{}: line endings or the final newline differ", filename);
+                }
+                print!("{}", diff);
+            }
+        } else if options.format_write {
+            if source != formatted {
+                if let Err(error) = fs::write(filename, &formatted) {
+                    eprintln!("Unable to write {}: {}", filename, error);
+                    exit(74);
+                }
+            }
+        } else {
+            print!("{}", formatted);
+        }
+    }
+    if unformatted {
+        exit(1);
+    }
+}
+
+/// Compile the KScript file and print the bytecode of main and every function in it
+fn dump_bytecode(filename: &String, options: &Options) {
+    let source = fs::read_to_string(filename)
+        .expect("Something went wrong reading the file");
 
-    return vm;
+    let mut interpreter = new_interpreter(options);
+    match interpreter.listing(&source) {
+        Ok(listing) => print!("{}", listing),
+        Err(_) => exit(50),
+    }
 }
 
+/// Compile the KScript file and write its bytecode next to it, or to -o
+fn compile_file(filename: &String, options: &Options) {
+    let source = fs::read_to_string(filename)
+        .expect("Something went wrong reading the file");
+
+    let mut interpreter = new_interpreter(options);
+    let func_main_idx = compile_source(&mut interpreter, &source);
 
+    let output = match &options.output {
+        Some(output) => output.to_string(),
+        None => Path::new(filename).with_extension("kbc").to_string_lossy().to_string(),
+    };
+    if let Err(error) = fs::write(&output, bytecode::serialize(&interpreter.vm.heap, func_main_idx)) {
+        eprintln!("Unable to write {}: {}", output, error);
+        exit(74);
+    }
+}
 
+/// Interpreter reporting compile errors as they are found
+fn new_interpreter(options: &Options) -> Interpreter {
+    let mut interpreter = Interpreter::new();
+    interpreter.disassemble = options.disassemble.clone();
+    interpreter.print_errors = true;
+    interpreter.deny_warnings = options.deny_warnings;
+    interpreter.warn_undefined_globals = options.warn_undefined;
+    interpreter.single_pass = options.single_pass;
+    return interpreter;
+}
 
+/// Compile the source, exiting on a compile error.
+///
+/// Returns the index of the main function
+fn compile_source(interpreter: &mut Interpreter, source: &str) -> usize {
+    return match interpreter.compile(source) {
+        Ok(func_main_idx) => func_main_idx,
+        Err(_) => exit(50),
+    };
+}
@@ -0,0 +1,101 @@
+use fnv::FnvHashMap;
+use crate::{Object, Value};
+
+/// Hashable identity of a value used as a map key.
+/// Strings are interned by hash, so equal strings share the same key.
+#[derive(Copy, Clone, PartialEq, Eq, Hash)]
+pub enum MapKey {
+    Number(u64),
+    Bool(bool),
+    Nil,
+    Obj(Object),
+}
+
+impl MapKey {
+    pub fn from(value: Value) -> Self {
+        match value {
+            Value::Number(n) => MapKey::Number(number_bits(n)),
+            Value::Bool(b) => MapKey::Bool(b),
+            Value::Nil() => MapKey::Nil,
+            Value::Obj(object) => MapKey::Obj(object),
+        }
+    }
+}
+
+/// Bits of a number for hashing
+pub fn number_bits(n: f64) -> u64 {
+    // -0.0 and 0.0 compare equal, so they must hash the same
+    return if n == 0.0 { 0u64 } else { n.to_bits() };
+}
+
+/// Where a key is in a map
+pub enum KeyPosition {
+    /// Index of the entry holding the key
+    Entry(usize),
+    /// Not in the map, with the hash from the key's hash method if it has one
+    Vacant(Option<u64>),
+}
+
+/// Associative array created by a map literal. Entries keep insertion order.
+#[derive(Clone)]
+pub struct Map {
+    pub entries: Vec<(Value, Value)>,
+    index: FnvHashMap<MapKey, usize>,
+    /// Entries of instance keys hashed by their hash method, the VM compares
+    /// the keys sharing a hash with ==
+    hashed: FnvHashMap<u64, Vec<usize>>,
+}
+
+impl Map {
+    pub fn new() ->Self {
+        Map {
+            entries: vec![],
+            index: FnvHashMap::default(),
+            hashed: FnvHashMap::default(),
+        }
+    }
+
+    /// Look up the value stored under key
+    pub fn get(&self, key: Value) -> Option<Value> {
+        return self.index.get(&MapKey::from(key)).map(|position| self.entries[*position].1);
+    }
+
+    /// Position of the entry stored under key, for keys without a hash method
+    pub fn position(&self, key: Value) -> Option<usize> {
+        return self.index.get(&MapKey::from(key)).copied();
+    }
+
+    /// Positions of the entries whose key's hash method returned the hash
+    pub fn hashed_positions(&self, hash: u64) -> Vec<usize> {
+        return self.hashed.get(&hash).cloned().unwrap_or_default();
+    }
+
+    /// Store the value at the position found for its key
+    pub fn set_at(&mut self, position: KeyPosition, key: Value, value: Value) {
+        match position {
+            KeyPosition::Entry(position) => self.entries[position].1 = value,
+            KeyPosition::Vacant(None) => self.set(key, value),
+            KeyPosition::Vacant(Some(hash)) => {
+                self.hashed.entry(hash).or_default().push(self.entries.len());
+                self.entries.push((key, value));
+            }
+        }
+    }
+
+    /// Insert or replace the value stored under key
+    pub fn set(&mut self, key: Value, value: Value) {
+        match self.index.get(&MapKey::from(key)) {
+            Some(position) => self.entries[*position].1 = value,
+            None => {
+                self.index.insert(MapKey::from(key), self.entries.len());
+                self.entries.push((key, value));
+            }
+        }
+    }
+}
+
+impl Default for Map {
+    fn default() -> Self {
+        Self::new()
+    }
+}
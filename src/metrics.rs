@@ -0,0 +1,83 @@
+use std::fmt;
+use std::time::Duration;
+
+/// Number of objects allocated on the heap by kind
+#[derive(Copy, Clone, Default)]
+pub struct Allocations {
+    pub strings: usize,
+    pub functions: usize,
+    pub native_fns: usize,
+    pub closures: usize,
+    pub classes: usize,
+    pub instances: usize,
+    pub bound_methods: usize,
+    pub lists: usize,
+    pub maps: usize,
+    pub user_data: usize,
+}
+
+impl Allocations {
+    /// Objects allocated of every kind
+    pub fn total(&self) -> usize {
+        return self.strings + self.functions + self.native_fns + self.closures + self.classes
+            + self.instances + self.bound_methods + self.lists + self.maps + self.user_data;
+    }
+}
+
+/// Lightweight counters collected while the VM runs
+#[derive(Copy, Clone, Default)]
+pub struct Metrics {
+    /// Wall clock time spent interpreting
+    pub elapsed: Duration,
+    /// Number of instructions dispatched
+    pub instructions: u64,
+    /// Number of closures, methods and natives called
+    pub calls: u64,
+    /// Highest number of values on the stack
+    pub peak_stack_depth: usize,
+    /// Highest number of call frames
+    pub peak_call_depth: usize,
+    /// Objects allocated, copied from the heap at the end of the run
+    pub allocations: Allocations,
+    /// Number of garbage collections
+    pub gc_cycles: u64,
+    /// Total time spent in garbage collection
+    pub gc_pause: Duration,
+}
+
+impl Metrics {
+    /// Dispatch rate over the interpreted time, 0 before anything ran
+    pub fn instructions_per_second(&self) -> f64 {
+        let seconds = self.elapsed.as_secs_f64();
+        if seconds == 0.0 {
+            return 0.0;
+        }
+        return self.instructions as f64 / seconds;
+    }
+}
+
+impl fmt::Display for Metrics {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let allocations = &self.allocations;
+        writeln!(f, "{: <20} : {:?}", "Elapsed time", self.elapsed)?;
+        writeln!(f, "{: <20} : {}", "Instructions", self.instructions)?;
+        writeln!(f, "{: <20} : {:.0}", "Instructions/sec", self.instructions_per_second())?;
+        writeln!(f, "{: <20} : {}", "Calls", self.calls)?;
+        writeln!(f, "{: <20} : {}", "Peak stack depth", self.peak_stack_depth)?;
+        writeln!(f, "{: <20} : {}", "Peak call depth", self.peak_call_depth)?;
+        writeln!(f, "{: <20} : strings {}, functions {}, natives {}, closures {}, classes {}, instances {}, bound methods {}, lists {}, maps {}, user data {}",
+                 "Allocations",
+                 allocations.strings,
+                 allocations.functions,
+                 allocations.native_fns,
+                 allocations.closures,
+                 allocations.classes,
+                 allocations.instances,
+                 allocations.bound_methods,
+                 allocations.lists,
+                 allocations.maps,
+                 allocations.user_data)?;
+        writeln!(f, "{: <20} : {}", "GC cycles", self.gc_cycles)?;
+        write!(f, "{: <20} : {:?}", "GC pause time", self.gc_pause)
+    }
+}
@@ -1,90 +1,788 @@
+use std::fmt;
+use std::fs;
 use std::fs::{File, OpenOptions};
-use std::io::Write;
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::io;
+use std::io::{BufRead, Write};
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
 
-pub type NativeFn = fn(usize, Vec<NativeValue>) -> NativeValue;
+use fnv::FnvHashMap;
+
+use crate::heap::Heap;
+use crate::list::List;
+use crate::object::Object;
+use crate::value::Value;
+use crate::utils::{Instant, SystemTime, UNIX_EPOCH};
+use crate::vm::VM;
+
+/// Natives get read access to the heap alongside their converted arguments
+pub type NativeFn = fn(&Heap, usize, Vec<NativeValue>) -> NativeResult;
+
+/// Natives that take the arguments as they are in the VM, for those that
+/// allocate objects or need to keep their identity
+pub type RawNativeFn = fn(&mut Heap, Vec<Value>) -> Result<Value, NativeError>;
+
+/// Native taking the whole VM, for the few that look at its stack or run a
+/// collection
+pub type VmNativeFn = fn(&mut VM, Vec<Value>) -> Result<Value, NativeError>;
+
+/// Native function as stored in the heap
+#[derive(Copy, Clone)]
+pub enum Native {
+    Converted(NativeFn),
+    Raw(RawNativeFn),
+    Vm(VmNativeFn),
+}
 
 pub enum NativeValue {
     String(String),
     Number(f64),
     Boolean(bool),
     Nil(),
+    List(Vec<NativeValue>),
+    Map(Vec<(NativeValue, NativeValue)>),
+    /// Function, class, instance or other heap object, passed by handle so it
+    /// can be inspected through the heap and returned as is
+    Object(Object),
 }
 
-// fixme: Replace NativeValue with Result<NativeValue,Error>
+/// Host access a native needs, checked before the native is made available
+#[derive(Copy, Clone, PartialEq)]
+pub enum Capability {
+    Fs,
+    Net,
+    Exec,
+    Ffi,
+}
+
+/// Host access granted to scripts, everything is allowed by default
+#[derive(Copy, Clone)]
+pub struct Capabilities {
+    /// Reading and writing files
+    pub allow_fs: bool,
+    /// Network requests
+    pub allow_net: bool,
+    /// Starting processes
+    pub allow_exec: bool,
+    /// Loading shared libraries and calling their functions
+    pub allow_ffi: bool,
+}
 
-///
-pub fn str_native(arg_count: usize, arguments: Vec<NativeValue>) -> NativeValue {
-    return match arguments.get(0).unwrap() {
-        NativeValue::String(s) => NativeValue::String(s.to_string()),
-        NativeValue::Number(n) => NativeValue::String(n.to_string()),
-        NativeValue::Boolean(b) => NativeValue::String(b.to_string()),
-        NativeValue::Nil() => NativeValue::String("nil".to_string())
+impl Capabilities {
+    /// Sandbox for untrusted scripts, no host access at all
+    pub fn none() -> Self {
+        Capabilities {
+            allow_fs: false,
+            allow_net: false,
+            allow_exec: false,
+            allow_ffi: false,
+        }
+    }
+
+    pub fn allows(&self, capability: Capability) -> bool {
+        return match capability {
+            Capability::Fs => self.allow_fs,
+            Capability::Net => self.allow_net,
+            Capability::Exec => self.allow_exec,
+            Capability::Ffi => self.allow_ffi,
+        };
+    }
+}
+
+impl Default for Capabilities {
+    fn default() -> Self {
+        Capabilities {
+            allow_fs: true,
+            allow_net: true,
+            allow_exec: true,
+            allow_ffi: true,
+        }
+    }
+}
+
+impl fmt::Display for Capability {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Capability::Fs => write!(f, "file system"),
+            Capability::Net => write!(f, "network"),
+            Capability::Exec => write!(f, "process"),
+            Capability::Ffi => write!(f, "foreign function"),
+        }
+    }
+}
+
+/// Error raised by a native, reported to the script as a runtime error
+#[derive(Debug, PartialEq)]
+pub struct NativeError {
+    pub message: String,
+    /// Append the line of the failing call to the message
+    pub with_line: bool,
+}
+
+impl NativeError {
+    pub fn new(message: &str) -> Self {
+        NativeError { message: message.to_string(), with_line: false }
+    }
+
+    /// Error whose message ends with the line the native was called from
+    pub fn at_line(message: &str) -> Self {
+        NativeError { message: message.to_string(), with_line: true }
+    }
+}
+
+pub type NativeResult = Result<NativeValue, NativeError>;
+
+/// Fail unless the native was called with exactly `arity` arguments
+pub(crate) fn check_arity(name: &str, arity: usize, arguments: &[NativeValue]) -> Result<(), NativeError> {
+    if arguments.len() != arity {
+        return Err(NativeError::new(&format!("{} expects {} argument(s) but got {}.", name, arity, arguments.len())));
+    }
+    return Ok(());
+}
+
+/// String argument at the index, named in the error when it is something else
+pub(crate) fn string_argument<'a>(name: &str, parameter: &str, arguments: &'a [NativeValue], idx: usize) -> Result<&'a String, NativeError> {
+    return match arguments.get(idx) {
+        Some(NativeValue::String(str)) => Ok(str),
+        _ => Err(NativeError::new(&format!("Invalid type for {} {}, string expected.", name, parameter))),
     };
 }
 
-///
-pub fn clock_native(arg_count: usize, arguments: Vec<NativeValue>) -> NativeValue {
-    let start = SystemTime::now();
-    let since_the_epoch = start.duration_since(UNIX_EPOCH);
-    return NativeValue::Number(since_the_epoch.unwrap().as_secs_f64())
+/// Text of the value as print shows it
+pub fn str_native(heap: &Heap, _arg_count: usize, arguments: Vec<NativeValue>) -> NativeResult {
+    check_arity("str", 1, &arguments)?;
+    return Ok(NativeValue::String(to_string(heap, &arguments[0])));
+}
+
+/// Native value of an argument. Lists and maps are passed by handle rather
+/// than copied, see `copy_value` for natives that need their items
+pub fn to_native(heap: &Heap, value: Value) -> NativeValue {
+    return match value {
+        Value::Number(n) => NativeValue::Number(n),
+        Value::Bool(b) => NativeValue::Boolean(b),
+        Value::Nil() => NativeValue::Nil(),
+        Value::Obj(Object::StringHash(hash)) => NativeValue::String(heap.get_string(hash).to_string()),
+        Value::Obj(object) => NativeValue::Object(object),
+    };
+}
+
+/// Native copy of the value, lists and maps with all their items. A list or
+/// map inside itself is kept as the handle
+pub fn copy_value(heap: &Heap, value: Value) -> NativeValue {
+    return copy_nested(heap, value, &mut vec![]);
+}
+
+/// `enclosing` holds the lists and maps being copied around the value
+fn copy_nested(heap: &Heap, value: Value, enclosing: &mut Vec<Object>) -> NativeValue {
+    return match value {
+        Value::Obj(object @ Object::ListIndex(idx)) if !enclosing.contains(&object) => {
+            enclosing.push(object);
+            let items = heap.get_list(idx).items.iter().map(|item| copy_nested(heap, *item, enclosing)).collect();
+            enclosing.pop();
+            NativeValue::List(items)
+        }
+        Value::Obj(object @ Object::MapIndex(idx)) if !enclosing.contains(&object) => {
+            enclosing.push(object);
+            let entries = heap.get_map(idx).entries.iter()
+                .map(|(key, value)| (copy_nested(heap, *key, enclosing), copy_nested(heap, *value, enclosing)))
+                .collect();
+            enclosing.pop();
+            NativeValue::Map(entries)
+        }
+        _ => to_native(heap, value),
+    };
 }
 
-///
-pub fn write_file_native(arg_count: usize, arguments: Vec<NativeValue>) -> NativeValue {
+/// Text for a native value, strings inside lists and maps are quoted
+fn to_string(heap: &Heap, value: &NativeValue) -> String {
+    return match value {
+        NativeValue::Object(object @ (Object::ListIndex(_) | Object::MapIndex(_))) => {
+            copied_to_string(heap, &copy_value(heap, Value::Obj(*object)))
+        }
+        _ => copied_to_string(heap, value),
+    };
+}
 
-    //fixme: check vec is equal to arg count
+/// Text for a value from copy_value, where a list or map handle is one
+/// inside itself
+fn copied_to_string(heap: &Heap, value: &NativeValue) -> String {
+    return match value {
+        NativeValue::String(s) => s.to_string(),
+        NativeValue::Number(n) => n.to_string(),
+        NativeValue::Boolean(b) => b.to_string(),
+        NativeValue::Nil() => "nil".to_string(),
+        NativeValue::List(items) => {
+            let items: Vec<String> = items.iter().map(|item| to_item_string(heap, item)).collect();
+            format!("[{}]", items.join(", "))
+        }
+        NativeValue::Map(entries) => {
+            let entries: Vec<String> = entries.iter()
+                .map(|(key, value)| format!("{}: {}", to_item_string(heap, key), to_item_string(heap, value)))
+                .collect();
+            format!("{{{}}}", entries.join(", "))
+        }
+        // A list or map inside itself
+        NativeValue::Object(Object::ListIndex(_)) => "[...]".to_string(),
+        NativeValue::Object(Object::MapIndex(_)) => "{...}".to_string(),
+        NativeValue::Object(object) => format!("<{}>", object_type_name(heap, object)),
+    };
+}
 
-    let path = match arguments.get(0).unwrap() {
-        NativeValue::String(str) => { str }
-        _ => { panic!("Invalid type for path, string expected.") } // replace with error
+fn to_item_string(heap: &Heap, value: &NativeValue) -> String {
+    return match value {
+        NativeValue::String(s) => format!("\"{}\"", s),
+        _ => copied_to_string(heap, value)
     };
+}
 
-    let content = match arguments.get(1).unwrap() {
-        NativeValue::String(str) => { str }
-        _ => {
-            panic!("Invalid type for content, string expected"); // replace with error
+/// Class name for instances, "class" or "function" for the rest
+fn object_type_name(heap: &Heap, object: &Object) -> String {
+    return match object {
+        Object::InstanceIndex(idx) => {
+            let class_idx = heap.get_instance(*idx).class_idx;
+            heap.get_class(class_idx).name.clone()
+        }
+        Object::ClassIndex(_) => "class".to_string(),
+        Object::ListIndex(_) => "list".to_string(),
+        Object::MapIndex(_) => "map".to_string(),
+        Object::UserDataIndex(idx) => {
+            let type_idx = heap.get_user_data(*idx).type_idx;
+            heap.user_types[type_idx].name.clone()
         }
+        _ => "function".to_string(),
     };
+}
 
-    write_file(path, content);
+/// Fail with the message unless the condition is truthy
+pub fn assert_native(heap: &Heap, _arg_count: usize, arguments: Vec<NativeValue>) -> NativeResult {
+    if arguments.is_empty() || arguments.len() > 2 {
+        return Err(NativeError::new(&format!("assert expects 1 or 2 argument(s) but got {}.", arguments.len())));
+    }
+    if let NativeValue::Nil() | NativeValue::Boolean(false) = arguments[0] {
+        return match arguments.get(1) {
+            Some(message) => Err(NativeError::at_line(&format!("Assertion failed: {}", to_string(heap, message)))),
+            None => Err(NativeError::at_line("Assertion failed")),
+        };
+    }
+    return Ok(NativeValue::Nil());
+}
 
-    return NativeValue::Boolean(true);
+/// Template with the remaining arguments filled into its placeholders in
+/// order. `{}` shows a value like str does, `{:.N}` a number with N decimals
+/// and `{{` `}}` are literal braces
+pub fn format_native(heap: &Heap, _arg_count: usize, arguments: Vec<NativeValue>) -> NativeResult {
+    if arguments.is_empty() {
+        return Err(NativeError::new("format expects at least 1 argument(s) but got 0."));
+    }
+    let template = string_argument("format", "template", &arguments, 0)?;
+    let mut values = arguments[1..].iter();
+    let mut result = String::new();
+    let mut chars = template.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '{' if chars.peek() == Some(&'{') => {
+                chars.next();
+                result.push('{');
+            }
+            '}' if chars.peek() == Some(&'}') => {
+                chars.next();
+                result.push('}');
+            }
+            '{' => {
+                let mut spec = String::new();
+                loop {
+                    match chars.next() {
+                        Some('}') => break,
+                        Some(c) => spec.push(c),
+                        None => return Err(NativeError::new("Unclosed placeholder in format template.")),
+                    }
+                }
+                let value = values.next()
+                    .ok_or_else(|| NativeError::new("Too few arguments for the format template."))?;
+                result.push_str(&format_placeholder(heap, &spec, value)?);
+            }
+            '}' => return Err(NativeError::new("Unmatched } in format template, use }} for a literal brace.")),
+            _ => result.push(c),
+        }
+    }
+    if values.next().is_some() {
+        return Err(NativeError::new("Too many arguments for the format template."));
+    }
+    return Ok(NativeValue::String(result));
 }
 
-pub fn append_file_native(arg_count: usize, arguments: Vec<NativeValue>) -> NativeValue {
+/// Most decimals a {:.N} placeholder may ask for
+const MAX_FORMAT_PRECISION: usize = 100;
 
-    //fixme: check vec is equal to arg count
+fn format_placeholder(heap: &Heap, spec: &str, value: &NativeValue) -> Result<String, NativeError> {
+    if spec.is_empty() {
+        return Ok(to_string(heap, value));
+    }
+    let precision = spec.strip_prefix(":.")
+        .and_then(|digits| digits.parse::<usize>().ok())
+        .ok_or_else(|| NativeError::new(&format!("Invalid placeholder {{{}}} in format template.", spec)))?;
+    if precision > MAX_FORMAT_PRECISION {
+        return Err(NativeError::new(&format!("Precision of format placeholder {{{}}} is above {}.", spec, MAX_FORMAT_PRECISION)));
+    }
+    return match value {
+        NativeValue::Number(n) => Ok(format!("{:.*}", precision, n)),
+        _ => Err(NativeError::new(&format!("Invalid type for format placeholder {{{}}}, number expected.", spec))),
+    };
+}
+
+/// Number written in the string, nil when it isn't one
+pub fn parse_number_native(_heap: &Heap, _arg_count: usize, arguments: Vec<NativeValue>) -> NativeResult {
+    check_arity("parseNumber", 1, &arguments)?;
+    let text = string_argument("parseNumber", "text", &arguments, 0)?;
+    return match text.trim().parse::<f64>() {
+        // Rust also parses "inf" and "NaN", which aren't number literals
+        Ok(n) if n.is_finite() => Ok(NativeValue::Number(n)),
+        _ => Ok(NativeValue::Nil()),
+    };
+}
+
+/// Number of items in a list or map or characters in a string
+pub fn len_native(heap: &Heap, _arg_count: usize, arguments: Vec<NativeValue>) -> NativeResult {
+    check_arity("len", 1, &arguments)?;
+    return match &arguments[0] {
+        NativeValue::Object(Object::ListIndex(idx)) => Ok(NativeValue::Number(heap.get_list(*idx).items.len() as f64)),
+        NativeValue::Object(Object::MapIndex(idx)) => Ok(NativeValue::Number(heap.get_map(*idx).entries.len() as f64)),
+        NativeValue::List(items) => Ok(NativeValue::Number(items.len() as f64)),
+        NativeValue::Map(entries) => Ok(NativeValue::Number(entries.len() as f64)),
+        NativeValue::String(s) => Ok(NativeValue::Number(s.chars().count() as f64)),
+        _ => Err(NativeError::new("Invalid type for len, list, map or string expected."))
+    };
+}
 
-    let path = match arguments.get(0).unwrap() {
-        NativeValue::String(str) => { str }
-        _ => { panic!("Invalid type for path, string expected.") } // replace with error
+/// Type name of a value, instances report their class name
+pub fn type_native(heap: &Heap, _arg_count: usize, arguments: Vec<NativeValue>) -> NativeResult {
+    check_arity("type", 1, &arguments)?;
+    let type_name = match &arguments[0] {
+        NativeValue::String(_) => "string".to_string(),
+        NativeValue::Number(_) => "number".to_string(),
+        NativeValue::Boolean(_) => "bool".to_string(),
+        NativeValue::Nil() => "nil".to_string(),
+        NativeValue::List(_) => "list".to_string(),
+        NativeValue::Map(_) => "map".to_string(),
+        NativeValue::Object(object) => object_type_name(heap, object),
     };
+    return Ok(NativeValue::String(type_name));
+}
+
+/// Keys of a map as a list, in insertion order
+pub fn keys_native(heap: &Heap, _arg_count: usize, arguments: Vec<NativeValue>) -> NativeResult {
+    check_arity("keys", 1, &arguments)?;
+    return match arguments.into_iter().next().unwrap() {
+        NativeValue::Object(Object::MapIndex(idx)) => {
+            Ok(NativeValue::List(heap.get_map(idx).entries.iter().map(|(key, _)| to_native(heap, *key)).collect()))
+        }
+        NativeValue::Map(entries) => Ok(NativeValue::List(entries.into_iter().map(|(key, _)| key).collect())),
+        _ => Err(NativeError::new("Invalid type for keys, map expected."))
+    };
+}
 
-    let content = match arguments.get(1).unwrap() {
-        NativeValue::String(str) => { str }
-        _ => {
-            panic!("Invalid type for content, string expected"); // replace with error
+/// Name of the class of an instance, or of the class itself
+pub fn class_name_native(heap: &Heap, _arg_count: usize, arguments: Vec<NativeValue>) -> NativeResult {
+    check_arity("className", 1, &arguments)?;
+    return match &arguments[0] {
+        NativeValue::Object(Object::InstanceIndex(idx)) => {
+            let class_idx = heap.get_instance(*idx).class_idx;
+            Ok(NativeValue::String(heap.get_class(class_idx).name.clone()))
         }
+        NativeValue::Object(Object::ClassIndex(idx)) => Ok(NativeValue::String(heap.get_class(*idx).name.clone())),
+        _ => Err(NativeError::new("Invalid type for className, instance or class expected."))
     };
+}
 
-    append_file(path, content);
+/// Names of the fields of an instance, in the order they were added
+pub fn fields_native(heap: &Heap, _arg_count: usize, arguments: Vec<NativeValue>) -> NativeResult {
+    check_arity("fields", 1, &arguments)?;
+    return match &arguments[0] {
+        NativeValue::Object(Object::InstanceIndex(idx)) => {
+            let names = heap.get_instance(*idx).field_names(&heap.shapes);
+            Ok(NativeValue::List(names.iter()
+                .map(|name| NativeValue::String(heap.get_string(*name).clone()))
+                .collect()))
+        }
+        _ => Err(NativeError::new("Invalid type for fields, instance expected."))
+    };
+}
 
-    return NativeValue::Boolean(true);
+/// Sorted names of the methods of a class, including inherited ones. An
+/// instance gives the methods of its class
+pub fn methods_native(heap: &Heap, _arg_count: usize, arguments: Vec<NativeValue>) -> NativeResult {
+    check_arity("methods", 1, &arguments)?;
+    let class_idx = match &arguments[0] {
+        NativeValue::Object(Object::ClassIndex(idx)) => *idx,
+        NativeValue::Object(Object::InstanceIndex(idx)) => heap.get_instance(*idx).class_idx,
+        _ => return Err(NativeError::new("Invalid type for methods, class or instance expected."))
+    };
+    let mut names: Vec<String> = heap.get_class(class_idx).methods.keys()
+        .map(|name| heap.get_string(*name).clone())
+        .collect();
+    names.sort();
+    return Ok(NativeValue::List(names.into_iter().map(NativeValue::String).collect()));
 }
 
-fn write_file(path: &str, content: &str) {
-    let mut f = File::create(path).unwrap();
+/// Whether the instance has a field with the name, methods don't count
+pub fn has_field_native(heap: &Heap, _arg_count: usize, arguments: Vec<NativeValue>) -> NativeResult {
+    check_arity("hasField", 2, &arguments)?;
+    let name = string_argument("hasField", "name", &arguments, 1)?;
+    return match &arguments[0] {
+        NativeValue::Object(Object::InstanceIndex(idx)) => {
+            let field = heap.string_key(name)
+                .and_then(|key| heap.get_instance(*idx).get_field(&heap.shapes, key));
+            Ok(NativeValue::Boolean(field.is_some()))
+        }
+        _ => Err(NativeError::new("Invalid type for hasField, instance expected."))
+    };
+}
+
+/// Copy of a list, map or instance holding the same values. With deep set to
+/// true the lists, maps and instances in it are copied too, map keys are
+/// kept as they are. Other values are returned unchanged
+pub fn clone_native(heap: &mut Heap, arguments: Vec<Value>) -> Result<Value, NativeError> {
+    if arguments.is_empty() || arguments.len() > 2 {
+        return Err(NativeError::new(&format!("clone expects 1 or 2 argument(s) but got {}.", arguments.len())));
+    }
+    let deep = match arguments.get(1) {
+        None => false,
+        Some(deep) if deep.is_boolean() => deep.as_boolean(),
+        Some(_) => return Err(NativeError::new("Invalid type for clone deep, true or false expected.")),
+    };
+    if !deep {
+        return Ok(shallow_clone(heap, arguments[0]));
+    }
+    return Ok(deep_clone(heap, arguments[0], &mut FnvHashMap::default()));
+}
+
+/// debugStack([asString]), the caller's call frames with the values on the
+/// stack. Printed to the script output, or returned when asString is true
+pub fn debug_stack_native(vm: &mut VM, arguments: Vec<Value>) -> Result<Value, NativeError> {
+    if arguments.len() > 1 {
+        return Err(NativeError::new(&format!("debugStack expects 0 or 1 argument(s) but got {}.", arguments.len())));
+    }
+    let as_string = match arguments.first() {
+        None => false,
+        Some(as_string) if as_string.is_boolean() => as_string.as_boolean(),
+        Some(_) => return Err(NativeError::new("Invalid type for debugStack asString, true or false expected.")),
+    };
+    // Leave out the native and its arguments
+    let dump = vm.debug_stack(vm.stack_top - arguments.len() - 1);
+    if as_string {
+        return Ok(Value::object(Object::string(vm.heap.alloc_string(dump))));
+    }
+    let _ = write!(vm.output, "{}", dump);
+    return Ok(Value::nil());
+}
+
+/// load(path), compiling another script and running it in the current global
+/// environment, so the functions, classes and variables it defines can be
+/// used once it returns. Relative paths resolve against the directory of the
+/// script calling it. Returns nil
+pub fn load_native(vm: &mut VM, arguments: Vec<Value>) -> Result<Value, NativeError> {
+    if arguments.len() != 1 {
+        return Err(NativeError::new(&format!("load expects 1 argument(s) but got {}.", arguments.len())));
+    }
+    if !arguments[0].is_string_hash() {
+        return Err(NativeError::new("Invalid type for load path, string expected."));
+    }
+    let path = vm.heap.get_string(arguments[0].as_string_hash()).clone();
+    let script_path = match vm.script_dir() {
+        Some(dir) => dir.join(&path),
+        None => PathBuf::from(&path),
+    };
+    let contents = fs::read(&script_path)
+        .map_err(|error| NativeError::new(&format!("Unable to load {}: {}", path, error)))?;
+    let func_main_idx = vm.compile_script(&contents)
+        .map_err(|errors| NativeError::new(&format!("Unable to load {}: {}", path, errors)))?;
+    // Stack traces name the file rather than main
+    {
+        let mut function = vm.heap.get_mut_function(func_main_idx);
+        function.name = path;
+        function.script_path = Some(script_path);
+    }
+    vm.call_script_from_native(func_main_idx, arguments.len())?;
+    return Ok(Value::nil());
+}
+
+fn shallow_clone(heap: &mut Heap, value: Value) -> Value {
+    let object = match value {
+        Value::Obj(object) => object,
+        _ => return value,
+    };
+    return match object {
+        Object::ListIndex(idx) => {
+            let list = List::new(heap.get_list(idx).items.clone());
+            Value::Obj(Object::ListIndex(heap.alloc_list(list)))
+        }
+        Object::MapIndex(idx) => {
+            let map = heap.get_map(idx).clone();
+            Value::Obj(Object::MapIndex(heap.alloc_map(map)))
+        }
+        Object::InstanceIndex(idx) => {
+            let instance = heap.get_instance(idx).clone();
+            Value::Obj(Object::InstanceIndex(heap.alloc_instance(instance)))
+        }
+        _ => value,
+    };
+}
+
+/// Copy of the value and everything in it, `copies` maps the objects copied
+/// so far to their copy so shared and cyclic references are kept
+fn deep_clone(heap: &mut Heap, value: Value, copies: &mut FnvHashMap<Object, Value>) -> Value {
+    let object = match value {
+        Value::Obj(object @ (Object::ListIndex(_) | Object::MapIndex(_) | Object::InstanceIndex(_))) => object,
+        _ => return value,
+    };
+    if let Some(copy) = copies.get(&object) {
+        return *copy;
+    }
+    let copy = shallow_clone(heap, value);
+    copies.insert(object, copy);
+    match copy {
+        Value::Obj(Object::ListIndex(idx)) => {
+            let items = heap.get_list(idx).items.clone();
+            let items = items.into_iter().map(|item| deep_clone(heap, item, copies)).collect();
+            heap.get_mut_list(idx).items = items;
+        }
+        Value::Obj(Object::MapIndex(idx)) => {
+            let entries = heap.get_map(idx).entries.clone();
+            for (position, (_, value)) in entries.into_iter().enumerate() {
+                let value = deep_clone(heap, value, copies);
+                heap.get_mut_map(idx).entries[position].1 = value;
+            }
+        }
+        Value::Obj(Object::InstanceIndex(idx)) => {
+            let slots = heap.get_instance(idx).slots.clone();
+            let slots = slots.into_iter().map(|slot| deep_clone(heap, slot, copies)).collect();
+            let fields = heap.get_instance(idx).fields.clone();
+            let fields = fields.into_iter().map(|(name, field)| (name, deep_clone(heap, field, copies))).collect();
+            let mut instance = heap.get_mut_instance(idx);
+            instance.slots = slots;
+            instance.fields = fields;
+        }
+        _ => {}
+    }
+    return copy;
+}
+
+/// Seconds since the Unix epoch
+pub fn clock_native(_heap: &Heap, _arg_count: usize, _arguments: Vec<NativeValue>) -> NativeResult {
+    let start = SystemTime::now();
+    let since_the_epoch = start.duration_since(UNIX_EPOCH)
+        .map_err(|_| NativeError::new("System clock is set before the Unix epoch."))?;
+    return Ok(NativeValue::Number(since_the_epoch.as_secs_f64()))
+}
+
+/// Line read from stdin without its line ending, after printing the optional
+/// prompt. Returns nil at the end of the input
+pub fn input_native(_heap: &Heap, _arg_count: usize, arguments: Vec<NativeValue>) -> NativeResult {
+    if arguments.len() > 1 {
+        return Err(NativeError::new(&format!("input expects 0 or 1 argument(s) but got {}.", arguments.len())));
+    }
+    if !arguments.is_empty() {
+        let prompt = string_argument("input", "prompt", &arguments, 0)?;
+        print!("{}", prompt);
+        io::stdout().flush()
+            .map_err(|error| NativeError::new(&format!("Unable to write the prompt: {}", error)))?;
+    }
+    let mut line = String::new();
+    let read = io::stdin().lock().read_line(&mut line)
+        .map_err(|error| NativeError::new(&format!("Unable to read input: {}", error)))?;
+    if read == 0 {
+        return Ok(NativeValue::Nil());
+    }
+    let len = line.trim_end_matches(['\n', '\r']).len();
+    line.truncate(len);
+    return Ok(NativeValue::String(line));
+}
+
+/// Start of the monotonic clock, the first time it is read
+static MONOTONIC_START: OnceLock<Instant> = OnceLock::new();
+
+/// Milliseconds on a clock that never goes backwards, for timing script sections
+pub fn monotonic_millis_native(_heap: &Heap, _arg_count: usize, arguments: Vec<NativeValue>) -> NativeResult {
+    check_arity("monotonicMillis", 0, &arguments)?;
+    let elapsed = MONOTONIC_START.get_or_init(Instant::now).elapsed();
+    return Ok(NativeValue::Number(elapsed.as_secs_f64() * 1000.0));
+}
+
+/// Nanoseconds on a clock that never goes backwards, for timing script sections
+pub fn monotonic_nanos_native(_heap: &Heap, _arg_count: usize, arguments: Vec<NativeValue>) -> NativeResult {
+    check_arity("monotonicNanos", 0, &arguments)?;
+    let elapsed = MONOTONIC_START.get_or_init(Instant::now).elapsed();
+    return Ok(NativeValue::Number(elapsed.as_nanos() as f64));
+}
+
+/// Heap usage as a map, for watching memory from inside a script
+pub fn mem_stats_native(heap: &Heap, _arg_count: usize, _arguments: Vec<NativeValue>) -> NativeResult {
+    let stats = heap.stats();
+    let fields = [
+        ("bytesAllocated", stats.bytes_allocated),
+        ("nextGC", stats.next_gc),
+        ("strings", stats.strings),
+        ("functions", stats.functions),
+        ("natives", stats.native_fns),
+        ("closures", stats.closures),
+        ("classes", stats.classes),
+        ("instances", stats.instances),
+        ("boundMethods", stats.bound_methods),
+        ("lists", stats.lists),
+        ("maps", stats.maps),
+        ("userData", stats.user_data),
+    ];
+    return Ok(NativeValue::Map(fields.iter()
+        .map(|(name, count)| (NativeValue::String(name.to_string()), NativeValue::Number(*count as f64)))
+        .collect()));
+}
+
+/// Write the text to the file, replacing what it had
+pub fn write_file_native(_heap: &Heap, _arg_count: usize, arguments: Vec<NativeValue>) -> NativeResult {
+    check_arity("writeFile", 2, &arguments)?;
+    let path = string_argument("writeFile", "path", &arguments, 0)?;
+    let content = string_argument("writeFile", "content", &arguments, 1)?;
+    write_file(path, content)
+        .map_err(|error| NativeError::new(&format!("Unable to write {}: {}", path, error)))?;
+    return Ok(NativeValue::Boolean(true));
+}
+
+pub fn append_file_native(_heap: &Heap, _arg_count: usize, arguments: Vec<NativeValue>) -> NativeResult {
+    check_arity("appendFile", 2, &arguments)?;
+    let path = string_argument("appendFile", "path", &arguments, 0)?;
+    let content = string_argument("appendFile", "content", &arguments, 1)?;
+    append_file(path, content)
+        .map_err(|error| NativeError::new(&format!("Unable to append to {}: {}", path, error)))?;
+    return Ok(NativeValue::Boolean(true));
+}
+
+/// Whole content of a text file
+pub fn read_file_native(_heap: &Heap, _arg_count: usize, arguments: Vec<NativeValue>) -> NativeResult {
+    check_arity("readFile", 1, &arguments)?;
+    let path = string_argument("readFile", "path", &arguments, 0)?;
+    let content = fs::read_to_string(path)
+        .map_err(|error| NativeError::new(&format!("Unable to read {}: {}", path, error)))?;
+    return Ok(NativeValue::String(content));
+}
+
+/// Is there a file or directory at the path?
+pub fn file_exists_native(_heap: &Heap, _arg_count: usize, arguments: Vec<NativeValue>) -> NativeResult {
+    check_arity("fileExists", 1, &arguments)?;
+    let path = string_argument("fileExists", "path", &arguments, 0)?;
+    return Ok(NativeValue::Boolean(Path::new(path).exists()));
+}
+
+pub fn delete_file_native(_heap: &Heap, _arg_count: usize, arguments: Vec<NativeValue>) -> NativeResult {
+    check_arity("deleteFile", 1, &arguments)?;
+    let path = string_argument("deleteFile", "path", &arguments, 0)?;
+    fs::remove_file(path)
+        .map_err(|error| NativeError::new(&format!("Unable to delete {}: {}", path, error)))?;
+    return Ok(NativeValue::Boolean(true));
+}
+
+/// Names of the entries in a directory, sorted
+pub fn list_dir_native(_heap: &Heap, _arg_count: usize, arguments: Vec<NativeValue>) -> NativeResult {
+    check_arity("listDir", 1, &arguments)?;
+    let path = string_argument("listDir", "path", &arguments, 0)?;
+    let list_error = |error: io::Error| NativeError::new(&format!("Unable to list {}: {}", path, error));
+    let mut names = vec![];
+    for entry in fs::read_dir(path).map_err(list_error)? {
+        let entry = entry.map_err(list_error)?;
+        names.push(entry.file_name().to_string_lossy().to_string());
+    }
+    names.sort();
+    return Ok(NativeValue::List(names.into_iter().map(NativeValue::String).collect()));
+}
+
+/// Create a directory along with any missing parents
+pub fn mkdir_native(_heap: &Heap, _arg_count: usize, arguments: Vec<NativeValue>) -> NativeResult {
+    check_arity("mkdir", 1, &arguments)?;
+    let path = string_argument("mkdir", "path", &arguments, 0)?;
+    fs::create_dir_all(path)
+        .map_err(|error| NativeError::new(&format!("Unable to create {}: {}", path, error)))?;
+    return Ok(NativeValue::Boolean(true));
+}
+
+/// GET the url, returning a map with the response status and body
+pub fn http_get_native(_heap: &Heap, _arg_count: usize, arguments: Vec<NativeValue>) -> NativeResult {
+    check_arity("httpGet", 1, &arguments)?;
+    let url = string_argument("httpGet", "url", &arguments, 0)?;
+    return http_request("GET", url, None, &[]);
+}
+
+/// POST the body to the url with an optional map of headers, returning a map
+/// with the response status and body
+pub fn http_post_native(heap: &Heap, _arg_count: usize, arguments: Vec<NativeValue>) -> NativeResult {
+    if arguments.len() != 2 && arguments.len() != 3 {
+        return Err(NativeError::new(&format!("httpPost expects 2 or 3 argument(s) but got {}.", arguments.len())));
+    }
+    let url = string_argument("httpPost", "url", &arguments, 0)?;
+    let body = string_argument("httpPost", "body", &arguments, 1)?;
+    let mut headers = vec![];
+    let copied = match arguments.get(2) {
+        Some(NativeValue::Object(object @ Object::MapIndex(_))) => Some(copy_value(heap, Value::Obj(*object))),
+        _ => None,
+    };
+    match copied.as_ref().or(arguments.get(2)) {
+        None | Some(NativeValue::Nil()) => {}
+        Some(NativeValue::Map(entries)) => {
+            for (name, value) in entries {
+                match (name, value) {
+                    (NativeValue::String(name), NativeValue::String(value)) => headers.push((name.as_str(), value.as_str())),
+                    _ => return Err(NativeError::new("Invalid type for httpPost headers, map of strings expected.")),
+                }
+            }
+        }
+        _ => return Err(NativeError::new("Invalid type for httpPost headers, map of strings expected.")),
+    }
+    return http_request("POST", url, Some(body), &headers);
+}
+
+/// Error statuses are returned like any other, only failing to get a
+/// response at all is an error
+#[cfg(feature = "http")]
+fn http_request(method: &str, url: &str, body: Option<&str>, headers: &[(&str, &str)]) -> NativeResult {
+    let mut request = ureq::request(method, url);
+    for (name, value) in headers {
+        request = request.set(name, value);
+    }
+    let result = match body {
+        Some(body) => request.send_string(body),
+        None => request.call(),
+    };
+    let response = match result {
+        Ok(response) => response,
+        Err(ureq::Error::Status(_, response)) => response,
+        Err(error) => return Err(NativeError::new(&format!("Unable to {} {}: {}", method, url, error))),
+    };
+    let status = response.status();
+    let body = response.into_string()
+        .map_err(|error| NativeError::new(&format!("Unable to read the response from {}: {}", url, error)))?;
+    return Ok(NativeValue::Map(vec![
+        (NativeValue::String("status".to_string()), NativeValue::Number(status as f64)),
+        (NativeValue::String("body".to_string()), NativeValue::String(body)),
+    ]));
+}
+
+#[cfg(not(feature = "http"))]
+fn http_request(_method: &str, _url: &str, _body: Option<&str>, _headers: &[(&str, &str)]) -> NativeResult {
+    return Err(NativeError::new("HTTP requests need kscript to be built with the http feature."));
+}
+
+fn write_file(path: &str, content: &str) -> io::Result<()> {
+    let mut f = File::create(path)?;
     let lines = content.split("\\n");
     for line in lines {
-        writeln!(&mut f, "{}", line).unwrap();
+        writeln!(&mut f, "{}", line)?;
     }
+    return Ok(());
 }
 
-fn append_file(path: &str, content: &str) {
-    let mut f = OpenOptions::new().write(true).create(true).append(true).open(path).unwrap();
+fn append_file(path: &str, content: &str) -> io::Result<()> {
+    let mut f = OpenOptions::new().create(true).append(true).open(path)?;
     let lines = content.split("\\n");
     for line in lines {
-        writeln!(&mut f, "{}", line).unwrap();
+        writeln!(&mut f, "{}", line)?;
     }
-}
\ No newline at end of file
+    return Ok(());
+}
@@ -1,8 +1,8 @@
 use std::fmt;
-use crate::Object::{ClassIndex, ClosureIndex, FunctionIndex, InstanceIndex, NativeFnIndex};
+use crate::Object::{BoundMethodIndex, ClassIndex, ClosureIndex, FunctionIndex, InstanceIndex, ListIndex, MapIndex, NativeFnIndex, UserDataIndex};
 use crate::object::Object::StringHash;
 
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
 pub enum Object {
     StringHash(u32),                // StringHash is a pseudo 'pointer' to the string in the heap via the hash key
     FunctionIndex(usize),           // Function index is a pseudo 'pointer to a function in the heap via index number
@@ -10,6 +10,10 @@ pub enum Object {
     ClosureIndex(usize),            // Closure index is a pseudo 'pointer' to a closure object in the heap via  index number
     ClassIndex(usize),              // Class index is a pseudo pointer to the class object in the heap via index number.
     InstanceIndex(usize),           // Class instance index is a pseudo pointer to the class instance object in the heap via index number.
+    BoundMethodIndex(usize),        // Bound method index is a pseudo pointer to a method bound to its receiver in the heap via index number.
+    ListIndex(usize),               // List index is a pseudo pointer to the list object in the heap via index number.
+    MapIndex(usize),                // Map index is a pseudo pointer to the map object in the heap via index number.
+    UserDataIndex(usize),           // User data index is a pseudo pointer to a host value in the heap via index number.
 }
 
 impl Object {
@@ -21,8 +25,12 @@ impl Object {
     }
     pub fn native_fn(idx: usize) -> Self { NativeFnIndex(idx) }
     pub fn closure(idx: usize) -> Self {ClosureIndex(idx) }
-    pub fn Class(idx: usize) -> Self { ClassIndex(idx) }
-    pub fn Instance(idx: usize) -> Self { InstanceIndex(idx) }
+    pub fn class(idx: usize) -> Self { ClassIndex(idx) }
+    pub fn instance(idx: usize) -> Self { InstanceIndex(idx) }
+    pub fn bound_method(idx: usize) -> Self { BoundMethodIndex(idx) }
+    pub fn list(idx: usize) -> Self { ListIndex(idx) }
+    pub fn map(idx: usize) -> Self { MapIndex(idx) }
+    pub fn user_data(idx: usize) -> Self { UserDataIndex(idx) }
 
     pub fn as_string_hash(&self) ->u32 {
         return *if let StringHash(ob) = self { ob } else {
@@ -60,59 +68,68 @@ impl Object {
         };
     }
 
+    pub fn as_bound_method_index(&self) ->usize {
+        return *if let BoundMethodIndex(ob) = self { ob } else {
+            panic!("Not a bound method")
+        };
+    }
+
+    pub fn as_list_index(&self) ->usize {
+        return *if let ListIndex(ob) = self { ob } else {
+            panic!("Not a list")
+        };
+    }
+
+    pub fn as_map_index(&self) ->usize {
+        return *if let MapIndex(ob) = self { ob } else {
+            panic!("Not a map")
+        };
+    }
+
+    pub fn as_user_data_index(&self) ->usize {
+        return *if let UserDataIndex(ob) = self { ob } else {
+            panic!("Not user data")
+        };
+    }
+
 
     pub fn is_string_hash(&self) ->bool {
-        return match self {
-            StringHash(_) => { true }
-            _ => { false }
-        }
+        return matches!(self, StringHash(_));
     }
 
     pub fn is_function_index(&self) ->bool {
-        return match self {
-            FunctionIndex(_) => { true }
-            _ => { false }
-        }
+        return matches!(self, FunctionIndex(_));
     }
     pub fn is_nativefn_index(&self) ->bool {
-        return match self {
-            NativeFnIndex(_) => { true }
-            _ => { false }
-        }
+        return matches!(self, NativeFnIndex(_));
     }
 
     pub fn is_closure_index(&self) -> bool {
-        return match self {
-            ClosureIndex(_) => { true }
-            _ => false
-        }
+        return matches!(self, ClosureIndex(_));
     }
 
     pub fn is_class_index(&self) -> bool {
-        return match self {
-            ClassIndex(_) => { true }
-            _ => false
-        }
+        return matches!(self, ClassIndex(_));
     }
 
     pub fn is_instance_index(&self) -> bool {
-        return match self {
-            InstanceIndex(_) => { true }
-            _ => false
-        }
+        return matches!(self, InstanceIndex(_));
     }
-}
 
-impl PartialEq for Object {
-    fn eq(&self, other: &Self) -> bool {
-        match (&self, &other) {
-            (StringHash(a), StringHash(b)) => a == b,
-            (FunctionIndex(a), FunctionIndex(b)) => a == b,
-            (ClosureIndex(a), ClosureIndex(b)) => a == b,
-            (ClassIndex(a), ClassIndex(b)) => a == b,
-            (InstanceIndex(a), InstanceIndex(b)) => a == b,
-            _ => false
-        }
+    pub fn is_bound_method_index(&self) -> bool {
+        return matches!(self, BoundMethodIndex(_));
+    }
+
+    pub fn is_list_index(&self) -> bool {
+        return matches!(self, ListIndex(_));
+    }
+
+    pub fn is_map_index(&self) -> bool {
+        return matches!(self, MapIndex(_));
+    }
+
+    pub fn is_user_data_index(&self) -> bool {
+        return matches!(self, UserDataIndex(_));
     }
 }
 
@@ -137,6 +154,18 @@ impl fmt::Display for Object {
             InstanceIndex(idx) => {
                 write!(f, "Instance index {}", idx)
             }
+            BoundMethodIndex(idx) => {
+                write!(f, "Bound method index {}", idx)
+            }
+            ListIndex(idx) => {
+                write!(f, "List index {}", idx)
+            }
+            MapIndex(idx) => {
+                write!(f, "Map index {}", idx)
+            }
+            UserDataIndex(idx) => {
+                write!(f, "User data index {}", idx)
+            }
         }
     }
 }
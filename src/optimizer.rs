@@ -0,0 +1,244 @@
+use crate::{Chunk, Heap, Opcode};
+
+/// Decoded instruction, jumps refer to the instruction they land on by index
+#[derive(Clone)]
+struct Instruction {
+    opcode: Opcode,
+    /// Operand bytes, without the jump offset for jumps
+    operands: Vec<u8>,
+    line: usize,
+    /// Index of the instruction a jump lands on, the instruction count for
+    /// the end of the chunk
+    target: Option<usize>,
+}
+
+/// Peephole pass over a compiled chunk. Collapses jumps to jumps, fuses a
+/// Not before a JumpIfFalse into a JumpIfTrue, drops values pushed only to
+/// be popped and merges consecutive pops into PopN
+pub fn optimize(chunk: &Chunk, heap: &Heap) -> Chunk {
+    let mut instructions = decode(chunk, heap);
+    collapse_jump_chains(&mut instructions);
+    loop {
+        let before = instructions.len();
+        instructions = fuse_not_jump(instructions);
+        instructions = remove_push_pop(instructions);
+        instructions = merge_pops(instructions);
+        if instructions.len() == before {
+            break;
+        }
+    }
+    let mut optimized = encode(&instructions);
+    optimized.share_constants(chunk);
+    return optimized;
+}
+
+/// Does the instruction end with a 16 bit jump offset?
+fn is_jump(opcode: Opcode) -> bool {
+    return matches!(opcode, Opcode::Jump | Opcode::JumpIfFalse | Opcode::JumpIfTrue | Opcode::JumpIfNotNil
+        | Opcode::Loop | Opcode::PushHandler | Opcode::ForIter);
+}
+
+/// Bytes of operands, besides a jump offset. `wide` holds the upper bits of
+/// the constant index set by a preceding Wide instruction
+fn operand_len(chunk: &Chunk, heap: &Heap, offset: usize, opcode: Opcode, wide: usize) -> usize {
+    return match opcode {
+        Opcode::Constant | Opcode::GetLocal | Opcode::GetGlobal | Opcode::DefineGlobal | Opcode::DefineDeclaration
+        | Opcode::DefineConstGlobal | Opcode::SetLocal | Opcode::SetGlobal | Opcode::GetUpvalue
+        | Opcode::SetUpvalue | Opcode::Call | Opcode::Class | Opcode::SetProperty | Opcode::GetProperty
+        | Opcode::Method | Opcode::AbstractMethod | Opcode::ClassConstant | Opcode::GetSuper | Opcode::BuildList | Opcode::BuildMap | Opcode::SliceFrom
+        | Opcode::PopN | Opcode::ForIter => 1,
+        Opcode::GetLocalLong | Opcode::SetLocalLong | Opcode::GetUpvalueLong | Opcode::SetUpvalueLong | Opcode::CallLong | Opcode::Invoke
+        | Opcode::SuperInvoke | Opcode::CallNamed | Opcode::MatchList | Opcode::Wide => 2,
+        Opcode::ConstantLong => 3,
+        Opcode::Closure | Opcode::ClosureLong => {
+            let constant = wide | chunk.code[offset + 1] as usize;
+            let func_idx = chunk.constants[constant].as_function_index();
+            let index_len = if matches!(opcode, Opcode::ClosureLong) { 2 } else { 1 };
+            1 + heap.get_function(func_idx).upvalue_count * (1 + index_len)
+        }
+        _ => 0,
+    };
+}
+
+fn decode(chunk: &Chunk, heap: &Heap) -> Vec<Instruction> {
+    let mut instructions = vec![];
+    let mut offsets = vec![];
+    let mut jump_offsets = vec![];
+    let mut offset = 0;
+    let mut wide = 0;
+    while offset < chunk.code.len() {
+        let opcode = Opcode::from_byte(chunk.code[offset]).expect("compiled code only holds valid opcodes");
+        let len = operand_len(chunk, heap, offset, opcode, wide);
+        let operands = chunk.code[offset + 1..offset + 1 + len].to_vec();
+        wide = if matches!(opcode, Opcode::Wide) { ((operands[0] as usize) << 16) | (operands[1] as usize) << 8 } else { 0 };
+        let mut next = offset + 1 + len;
+        if is_jump(opcode) {
+            let jump = ((chunk.code[next] as usize) << 8) | chunk.code[next + 1] as usize;
+            next += 2;
+            jump_offsets.push(if matches!(opcode, Opcode::Loop) { next - jump } else { next + jump });
+        } else {
+            jump_offsets.push(usize::MAX);
+        }
+        offsets.push(offset);
+        instructions.push(Instruction { opcode, operands, line: chunk.line_for_offset(offset), target: None });
+        offset = next;
+    }
+    offsets.push(chunk.code.len());
+    for (instruction, jump_offset) in instructions.iter_mut().zip(jump_offsets) {
+        if jump_offset != usize::MAX {
+            instruction.target = Some(offsets.binary_search(&jump_offset).expect("Jump into the middle of an instruction"));
+        }
+    }
+    return instructions;
+}
+
+fn encode(instructions: &[Instruction]) -> Chunk {
+    let mut offsets = vec![];
+    let mut offset = 0;
+    for instruction in instructions {
+        offsets.push(offset);
+        offset += 1 + instruction.operands.len() + if is_jump(instruction.opcode) { 2 } else { 0 };
+    }
+    offsets.push(offset);
+
+    let mut chunk = Chunk::new();
+    for (i, instruction) in instructions.iter().enumerate() {
+        chunk.code(instruction.opcode.byte(), instruction.line);
+        for operand in &instruction.operands {
+            chunk.code(*operand, instruction.line);
+        }
+        if let Some(target) = instruction.target {
+            let next = offsets[i + 1];
+            // Removing instructions only ever shortens jumps
+            let jump = if matches!(instruction.opcode, Opcode::Loop) { next - offsets[target] } else { offsets[target] - next };
+            chunk.code(((jump >> 8) & 0xff) as u8, instruction.line);
+            chunk.code((jump & 0xff) as u8, instruction.line);
+        }
+    }
+    return chunk;
+}
+
+/// Instructions some jump lands on
+fn jump_targets(instructions: &[Instruction]) -> Vec<bool> {
+    let mut targets = vec![false; instructions.len() + 1];
+    for instruction in instructions {
+        if let Some(target) = instruction.target {
+            targets[target] = true;
+        }
+    }
+    return targets;
+}
+
+/// Drop the instructions that aren't kept. Jumps to a dropped instruction
+/// land on the next kept one instead
+fn retain(instructions: Vec<Instruction>, keep: &[bool]) -> Vec<Instruction> {
+    let mut new_indexes = vec![0; instructions.len() + 1];
+    let mut kept = 0;
+    for i in 0..instructions.len() {
+        new_indexes[i] = kept;
+        if keep[i] {
+            kept += 1;
+        }
+    }
+    new_indexes[instructions.len()] = kept;
+    return instructions.into_iter().enumerate()
+        .filter(|(i, _)| keep[*i])
+        .map(|(_, mut instruction)| {
+            instruction.target = instruction.target.map(|target| new_indexes[target]);
+            instruction
+        })
+        .collect();
+}
+
+/// Point jumps that land on an unconditional forward jump at its target
+fn collapse_jump_chains(instructions: &mut [Instruction]) {
+    for i in 0..instructions.len() {
+        if !matches!(instructions[i].opcode, Opcode::Jump | Opcode::JumpIfFalse | Opcode::JumpIfTrue | Opcode::JumpIfNotNil) {
+            continue;
+        }
+        // Bounded in case the jumps form a cycle
+        for _ in 0..instructions.len() {
+            let target = instructions[i].target.unwrap();
+            match instructions.get(target) {
+                Some(next) if matches!(next.opcode, Opcode::Jump) && next.target != Some(target) => {
+                    instructions[i].target = next.target;
+                }
+                _ => break,
+            }
+        }
+    }
+}
+
+/// Not followed by JumpIfFalse becomes JumpIfTrue when the condition is popped
+/// on both paths, so nothing sees that it isn't negated anymore
+fn fuse_not_jump(mut instructions: Vec<Instruction>) -> Vec<Instruction> {
+    let targets = jump_targets(&instructions);
+    let mut keep = vec![true; instructions.len()];
+    for i in 0..instructions.len().saturating_sub(2) {
+        if !matches!(instructions[i].opcode, Opcode::Not) || !matches!(instructions[i + 1].opcode, Opcode::JumpIfFalse)
+            || targets[i + 1] || !keep[i] {
+            continue;
+        }
+        let target = instructions[i + 1].target.unwrap();
+        let pops_after = matches!(instructions[i + 2].opcode, Opcode::Pop);
+        let pops_at_target = instructions.get(target).is_some_and(|it| matches!(it.opcode, Opcode::Pop));
+        if pops_after && pops_at_target {
+            keep[i] = false;
+            instructions[i + 1].opcode = Opcode::JumpIfTrue;
+        }
+    }
+    return retain(instructions, &keep);
+}
+
+/// A value pushed without side effects and popped right away does nothing
+fn remove_push_pop(instructions: Vec<Instruction>) -> Vec<Instruction> {
+    let targets = jump_targets(&instructions);
+    let mut keep = vec![true; instructions.len()];
+    let mut i = 0;
+    while i + 1 < instructions.len() {
+        let pure_push = matches!(instructions[i].opcode, Opcode::Constant | Opcode::ConstantLong | Opcode::Nil
+            | Opcode::True | Opcode::False | Opcode::GetLocal | Opcode::GetLocalLong | Opcode::GetUpvalue | Opcode::Dup);
+        if pure_push && matches!(instructions[i + 1].opcode, Opcode::Pop) && !targets[i + 1] {
+            keep[i] = false;
+            keep[i + 1] = false;
+            i += 2;
+        } else {
+            i += 1;
+        }
+    }
+    return retain(instructions, &keep);
+}
+
+/// Runs of Pop become a single PopN
+fn merge_pops(mut instructions: Vec<Instruction>) -> Vec<Instruction> {
+    let targets = jump_targets(&instructions);
+    let mut keep = vec![true; instructions.len()];
+    let mut run_start: Option<usize> = None;
+    for i in 0..instructions.len() {
+        let count = match instructions[i].opcode {
+            Opcode::Pop => 1,
+            Opcode::PopN => instructions[i].operands[0] as usize,
+            _ => {
+                run_start = None;
+                continue;
+            }
+        };
+        match run_start {
+            Some(start) if !targets[i] && pop_count(&instructions[start]) + count <= u8::MAX as usize => {
+                let total = pop_count(&instructions[start]) + count;
+                instructions[start].opcode = Opcode::PopN;
+                instructions[start].operands = vec![total as u8];
+                keep[i] = false;
+            }
+            _ => run_start = Some(i),
+        }
+    }
+    return retain(instructions, &keep);
+}
+
+fn pop_count(instruction: &Instruction) -> usize {
+    return match instruction.opcode {
+        Opcode::PopN => instruction.operands[0] as usize,
+        _ => 1,
+    };
+}
@@ -0,0 +1,154 @@
+use std::io;
+use std::io::{BufRead, Write};
+use std::mem;
+use std::sync::atomic::Ordering;
+
+use crate::{Heap, Parser, Scanner};
+use crate::compiler::Disassemble;
+use crate::vm::{RunResult, VM};
+
+/// Interactive KScript console that can be embedded by a host application.
+///
+/// Lines are read from `input`; prompts, printed values and runtime errors
+/// are written to the output handed to the VM.
+pub struct Repl<R: BufRead> {
+    pub vm: VM,
+    input: R,
+    prompt: String,
+    /// Prompt for the lines continuing an unfinished statement
+    continuation_prompt: String,
+    /// Print execution metrics after every evaluation
+    pub print_metrics: bool,
+    /// Print the bytecode compiled from every evaluation
+    pub disassemble: Disassemble,
+}
+
+impl<R: BufRead> Repl<R> {
+    pub fn new(input: R, output: Box<dyn Write>) -> Self {
+        let mut vm = VM::new();
+        vm.init();
+        vm.output = output;
+        vm.hot_redefinition = true;
+        Repl {
+            vm,
+            input,
+            prompt: "> ".to_string(),
+            continuation_prompt: "... ".to_string(),
+            print_metrics: false,
+            disassemble: Disassemble::None,
+        }
+    }
+
+    /// Replace the default "> " prompt
+    pub fn with_prompt(mut self, prompt: &str) -> Self {
+        self.prompt = prompt.to_string();
+        self
+    }
+
+    /// Read and evaluate lines until `exit` or the end of the input.
+    /// Lines are collected until their brackets, strings and block comments
+    /// are closed, so definitions can span several lines
+    pub fn run(&mut self) -> io::Result<()> {
+        let mut source = String::new();
+        loop {
+            let prompt = if source.is_empty() { &self.prompt } else { &self.continuation_prompt };
+            write!(self.vm.output, "{}", prompt)?;
+            self.vm.output.flush()?;
+            let mut line = String::new();
+            if self.input.read_line(&mut line)? == 0 {
+                if !source.is_empty() {
+                    // Report what is missing from the unfinished input
+                    self.eval(&source);
+                }
+                return Ok(());
+            }
+            if source.is_empty() {
+                if line.trim() == "" {
+                    continue;
+                }
+                else if line.trim() == "exit" {
+                    writeln!(self.vm.output, "Good bye!\n")?;
+                    return Ok(());
+                }
+            }
+            source.push_str(&line);
+            if is_incomplete(&source) {
+                continue;
+            }
+            self.eval(&source);
+            source.clear();
+            if self.print_metrics {
+                let metrics = self.vm.metrics();
+                writeln!(self.vm.output, "{}", metrics)?;
+            }
+            self.vm.reset_stack();
+        }
+    }
+
+    /// Compile and execute a piece of source code.
+    /// Returns None when the source did not compile.
+    pub fn eval(&mut self, source: &String) -> Option<RunResult> {
+        let mut scanner = Scanner::new(source);
+        scanner.print_errors = false;
+
+        // transfer heap ownership of heap in VM to the parser
+        let mut heap_to_parser = Heap::new();
+        mem::swap(&mut self.vm.heap, &mut heap_to_parser);
+
+        let mut parser = Parser::new(heap_to_parser, scanner);
+        parser.disassemble = self.disassemble.clone();
+        parser.warn_unused_globals = false;
+        parser.source = source.as_str().into();
+        let func_main_idx = parser.compile();
+
+        // transfer heap ownership of back to vm
+        mem::swap(&mut parser.heap, &mut self.vm.heap);
+
+        if parser.had_error {
+            return None;
+        }
+        // A Ctrl-C pressed while waiting for input shouldn't stop this evaluation
+        self.vm.interrupt_flag.store(false, Ordering::Relaxed);
+        return Some(self.vm.execute_function(func_main_idx));
+    }
+}
+
+/// Does the source end inside brackets, a string or a block comment?
+fn is_incomplete(source: &str) -> bool {
+    let mut depth: i32 = 0;
+    let mut comment_depth = 0;
+    let mut in_string = false;
+    let mut chars = source.chars().peekable();
+    while let Some(c) = chars.next() {
+        if in_string {
+            in_string = c != '"';
+            continue;
+        }
+        if comment_depth > 0 {
+            if c == '*' && chars.peek() == Some(&'/') {
+                chars.next();
+                comment_depth -= 1;
+            } else if c == '/' && chars.peek() == Some(&'*') {
+                chars.next();
+                comment_depth += 1;
+            }
+            continue;
+        }
+        match c {
+            '"' => in_string = true,
+            '/' if chars.peek() == Some(&'/') => {
+                while chars.peek().is_some_and(|c| *c != '\n') {
+                    chars.next();
+                }
+            }
+            '/' if chars.peek() == Some(&'*') => {
+                chars.next();
+                comment_depth += 1;
+            }
+            '(' | '[' | '{' => depth += 1,
+            ')' | ']' | '}' => depth -= 1,
+            _ => {}
+        }
+    }
+    return depth > 0 || comment_depth > 0 || in_string;
+}
@@ -1,28 +1,57 @@
-use std::collections::HashMap;
-use substring::Substring;
+use std::collections::VecDeque;
+use std::rc::Rc;
+use fnv::FnvHashMap;
 use crate::token::{Token, TokenType};
 
-///
+/// Turns KScript source into tokens
 pub struct Scanner {
     pub source: String,
-    pub tokens: Vec<Token>,
+    /// Characters of the source, positions below index into it
+    chars: Vec<char>,
+    /// Tokens scanned but not handed out yet
+    pub tokens: VecDeque<Token>,
+    /// The Eof token has been handed out
+    reached_end: bool,
     pub start: usize,
     pub current: usize,
+    /// Current line, counted from 1 as errors show it
     pub line: usize,
-    pub is_block_comment: bool,
-    pub keywords: HashMap<String, TokenType>,
+    /// Index of the first character of the current line
+    pub line_start: usize,
+    /// Column the current lexeme starts at
+    pub start_column: usize,
+    pub had_error: bool,
+    /// Messages of the errors reported so far
+    pub errors: Vec<String>,
+    /// Print errors to stderr as they are reported
+    pub print_errors: bool,
+    /// Collect the comments in `comments` instead of dropping them
+    pub keep_comments: bool,
+    /// Comment tokens in source order, the lexeme is the whole comment
+    pub comments: Vec<Token>,
+    pub keywords: FnvHashMap<String, TokenType>,
+    /// Interned lexemes and literals shared by all tokens
+    pub symbols: FnvHashMap<String, Rc<str>>,
 }
 
 impl Scanner {
     pub fn new(source: &String) -> Self {
         Scanner {
             source: source.to_string(),
-            tokens: Vec::new(),
+            chars: source.chars().collect(),
+            tokens: VecDeque::new(),
+            reached_end: false,
             start: 0,
             current: 0,
-            line: 0,
-            is_block_comment: false,
-            keywords: HashMap::from([
+            line: 1,
+            line_start: 0,
+            start_column: 0,
+            had_error: false,
+            errors: vec![],
+            print_errors: true,
+            keep_comments: false,
+            comments: vec![],
+            keywords: FnvHashMap::from_iter([
                 ("and".to_string(), TokenType::And),
                 ("class".to_string(), TokenType::Class),
                 ("false".to_string(), TokenType::False),
@@ -37,45 +66,68 @@ impl Scanner {
                 ("this".to_string(), TokenType::This),
                 ("true".to_string(), TokenType::True),
                 ("var".to_string(), TokenType::Var),
+                ("const".to_string(), TokenType::Const),
+                ("match".to_string(), TokenType::Match),
+                ("try".to_string(), TokenType::Try),
+                ("catch".to_string(), TokenType::Catch),
+                ("throw".to_string(), TokenType::Throw),
                 ("while".to_string(), TokenType::While),
                 ("extend".to_string(), TokenType::Extend),
+                ("abstract".to_string(), TokenType::Abstract),
                 ("return".to_string(), TokenType::Return)
             ]),
+            symbols: FnvHashMap::default(),
         }
     }
 
+    /// Scan the whole source, leaving out the Error tokens. The errors are
+    /// in `errors`
     pub fn scan_tokens(&mut self) -> Vec<Token> {
-        while !self.is_at_end() {
-            // Beginning of next lexeme
-            self.start = self.current;
-            self.scan_token();
-        }
-        self.tokens.push(Token::new(TokenType::Eof, "".to_string(), "".to_string(), self.line));
-        self.tokens.to_vec()
+        return self.by_ref().filter(|token| token.token_type != TokenType::Error).collect();
     }
 
     fn scan_token(&mut self) {
         let c = self.advance();
-        if self.is_block_comment {
-            if c == '*' && self._match(&'/') {
-                self.is_block_comment = false;
-            }
-            return; // Ignore processing rest of the token in block comment mode
-        }
         match c {
             '(' => { self.add_token(&TokenType::LeftParen) }
             ')' => { self.add_token(&TokenType::RightParen) }
             '{' => { self.add_token(&TokenType::LeftBrace) }
             '}' => { self.add_token(&TokenType::RightBrace) }
+            '[' => { self.add_token(&TokenType::LeftBracket) }
+            ']' => { self.add_token(&TokenType::RightBracket) }
             ',' => { self.add_token(&TokenType::Comma) }
-            '.' => { self.add_token(&TokenType::Dot) }
+            ':' => { self.add_token(&TokenType::Colon) }
+            '?' => {
+                if self._match(&'?') {
+                    self.add_token(&TokenType::QuestionQuestion)
+                } else {
+                    self.error(self.line, self.start_column, "".to_string(), "Expect '?' after '?'.".to_string());
+                }
+            }
+            '.' => {
+                if self.peek() == '.' && self.peek_next() == '.' {
+                    self.advance();
+                    self.advance();
+                    self.add_token(&TokenType::Ellipsis)
+                } else {
+                    self.add_token(&TokenType::Dot)
+                }
+            }
             '-' => {
-                let is_match = self._match(&'=');
-                self.add_token(&if is_match  { TokenType::MinusEqual } else { TokenType::Minus})
+                if self._match(&'-') {
+                    self.add_token(&TokenType::MinusMinus)
+                } else {
+                    let is_match = self._match(&'=');
+                    self.add_token(&if is_match  { TokenType::MinusEqual } else { TokenType::Minus})
+                }
             }
             '+' => {
-                let is_match = self._match(&'=');
-                self.add_token(&if is_match  { TokenType::PlusEqual } else { TokenType::Plus})
+                if self._match(&'+') {
+                    self.add_token(&TokenType::PlusPlus)
+                } else {
+                    let is_match = self._match(&'=');
+                    self.add_token(&if is_match  { TokenType::PlusEqual } else { TokenType::Plus})
+                }
             }
             ';' => { self.add_token(&TokenType::Semicolon) }
             '*' => { self.add_token(&TokenType::Star) }
@@ -84,8 +136,12 @@ impl Scanner {
                 self.add_token(&if is_match { TokenType::BangEqual } else { TokenType::Bang })
             }
             '=' => {
-                let is_match = self._match(&'=');
-                self.add_token(&if is_match { TokenType::EqualEqual } else { TokenType::Equal })
+                if self._match(&'>') {
+                    self.add_token(&TokenType::FatArrow)
+                } else {
+                    let is_match = self._match(&'=');
+                    self.add_token(&if is_match { TokenType::EqualEqual } else { TokenType::Equal })
+                }
             }
             '<' => {
                 let is_match = self._match(&'=');
@@ -102,10 +158,11 @@ impl Scanner {
                     while self.peek() != '\n' && !self.is_at_end() {
                         self.advance();
                     }
+                    self.add_comment(self.line);
                 } else if is_match_star {
-                    self.is_block_comment = true;
-                    while self.peek() != '*' && self.peek_next() != '/' && !self.is_at_end() {
-                        self.advance();
+                    let opening_line = self.line;
+                    if self.block_comment() {
+                        self.add_comment(opening_line);
                     }
                 } else {
                     self.add_token(&TokenType::Slash)
@@ -113,7 +170,7 @@ impl Scanner {
             }
             |' '| '\r' |'\t' => { /* ignore me */ }
             '\n' => {
-                self.line = self.line + 1
+                self.new_line();
             }
             '"' => {
                 self.string()
@@ -131,14 +188,55 @@ impl Scanner {
                 }  else if self.is_alpha(c) {
                     self.identifier();
                 } else {
-                    self.error(self.line, "".to_string(), "Unexpected character .".to_string());
+                    self.error(self.line, self.start_column, "".to_string(), "Unexpected character .".to_string());
                 }
             }
         }
     }
 
-    fn error(&self, line: usize, location: String, message: String) {
-        eprintln!("[line {0} ] Error {1} : {2}", line, location, message );
+    fn error(&mut self, line: usize, column: usize, location: String, message: String) {
+        self.had_error = true;
+        let mut error = format!("[line {0} ] Error {1} : {2}", line, location, message);
+        error.push_str(&source_snippet(&self.source, line, column, 1));
+        if self.print_errors {
+            eprintln!("{}", error);
+        }
+        self.tokens.push_back(Token::new(TokenType::Error, message.as_str().into(), error.as_str().into(), line, column));
+        self.errors.push(error);
+    }
+
+    /// Skip a block comment, the opening `/*` has been consumed. Comments nest,
+    /// so each inner `/*` needs its own `*/`.
+    ///
+    /// Returns false when the comment isn't closed
+    fn block_comment(&mut self) -> bool {
+        let opening_line = self.line;
+        let opening_column = self.start_column;
+        let mut depth = 1;
+        while depth > 0 {
+            if self.is_at_end() {
+                self.error(opening_line, opening_column, "".to_string(), "Unterminated block comment.".to_string());
+                return false;
+            }
+            let c = self.advance();
+            if c == '/' && self._match(&'*') {
+                depth += 1;
+            } else if c == '*' && self._match(&'/') {
+                depth -= 1;
+            } else if c == '\n' {
+                self.new_line();
+            }
+        }
+        return true;
+    }
+
+    /// Keep the comment just skipped if comments are kept
+    fn add_comment(&mut self, line: usize) {
+        if self.keep_comments {
+            let text = self.text(self.start, self.current);
+            let text: Rc<str> = text.trim_end().into();
+            self.comments.push(Token::new(TokenType::Comment, text, "".into(), line, self.start_column));
+        }
     }
 
     fn number(&mut self) {
@@ -153,24 +251,18 @@ impl Scanner {
             }
         }
         self.add_token_literal(&TokenType::Number,
-                               &self.source.substring(self.start, self.current).to_string());
+                               &self.text(self.start, self.current));
     }
 
     fn identifier(&mut self) {
         while self.is_alpha_numeric(self.peek()) {
             self.advance();
         }
-        let text = self.source.substring(self.start, self.current).to_string();
-        let token_type: TokenType;
-        let optional_token_type = self.keywords.get(&text);
-        match optional_token_type {
-            Some(p) => {
-                token_type = *p;
-            }
-            None => {
-                token_type = TokenType::Identifier;
-            }
-        }
+        let text = self.text(self.start, self.current);
+        let token_type = match self.keywords.get(&text) {
+            Some(p) => *p,
+            None => TokenType::Identifier,
+        };
         self.add_token(&token_type);
     }
 
@@ -179,70 +271,131 @@ impl Scanner {
     }
 
     fn is_alpha(&self, c: char) -> bool {
-        return (c >= 'a' && c <= 'z') || (c >= 'A' && c <= 'Z') || c == '_';
+        return c.is_ascii_alphabetic() || c == '_';
     }
 
     fn is_at_end(&self) -> bool {
-        return self.current >= self.source.len();
+        return self.current >= self.chars.len();
     }
 
     fn advance(&mut self) -> char {
-        let result = self.source.chars().nth(self.current).unwrap();
-        self.current = self.current + 1;
+        let result = self.chars[self.current];
+        self.current += 1;
         return result;
     }
 
     fn peek(&self) -> char {
-        if self.is_at_end() {
-            return char::default();
-        }
-        return self.source.chars().nth(self.current).unwrap();
+        return *self.chars.get(self.current).unwrap_or(&char::default());
     }
 
     fn peek_next(&self) -> char {
-        if self.current + 1 >= self.source.len() {
-            return char::default();
-        }
-        return self.source.chars().nth(self.current + 1).unwrap();
+        return *self.chars.get(self.current + 1).unwrap_or(&char::default());
+    }
+
+    /// Source text between the two character positions
+    fn text(&self, start: usize, end: usize) -> String {
+        return self.chars[start..end].iter().collect();
     }
 
     fn _match(&mut self, expected: &char) -> bool {
         if self.is_at_end() {
             return false;
         }
-        if self.source.chars().nth(self.current).unwrap() != *expected {
+        if self.chars[self.current] != *expected {
             return false;
         }
-        self.current = self.current + 1;
+        self.current += 1;
         return true;
     }
 
-    fn add_token_literal(&mut self, token: &TokenType, literal: &String) {
-        let text = self.source.substring(self.start, self.current).to_string();
-        self.tokens.push(Token::new(*token, text, literal.to_string(), self.line));
+    fn add_token_literal(&mut self, token: &TokenType, literal: &str) {
+        let text = self.text(self.start, self.current);
+        let text = Self::intern(&mut self.symbols, &text);
+        let literal = Self::intern(&mut self.symbols, literal);
+        self.tokens.push_back(Token::new(*token, text, literal, self.line, self.start_column));
+    }
+
+    /// Return the shared copy of the given text, allocating it on first use
+    fn intern(symbols: &mut FnvHashMap<String, Rc<str>>, text: &str) -> Rc<str> {
+        if let Some(symbol) = symbols.get(text) {
+            return Rc::clone(symbol);
+        }
+        let symbol: Rc<str> = Rc::from(text);
+        symbols.insert(text.to_string(), Rc::clone(&symbol));
+        return symbol;
     }
 
     fn add_token(&mut self, token: &TokenType) {
-        self.add_token_literal(token, &"".to_string());
+        self.add_token_literal(token, "");
     }
 
     fn is_digit(&self, c: char) -> bool {
-        return c >= '0' && c <= '9';
+        return c.is_ascii_digit();
+    }
+
+    /// Count the newline just consumed
+    fn new_line(&mut self) {
+        self.line += 1;
+        self.line_start = self.current;
     }
 
     fn string(&mut self) {
+        let opening_line = self.line;
+        let opening_column = self.start_column;
         while self.peek() != '"' && !self.is_at_end() {
-            if self.peek() == '\n' {
-                self.line = self.line + 1
+            if self.advance() == '\n' {
+                self.new_line();
             }
-            self.advance();
         }
         if self.is_at_end() {
-            self.error(self.line, "".to_string(),"Unterminated string.".to_string());
+            self.error(opening_line, opening_column, "".to_string(),"Unterminated string.".to_string());
             return;
         }
         self.advance(); // closing "
-        let value = self.source.substring(self.start + 1, self.current - 1).to_string();
+        let value = self.text(self.start + 1, self.current - 1);
         self.add_token_literal(&TokenType::String, &value);
     }
 }
+
+/// Tokens are scanned as they are asked for, ending with Eof. A scan error
+/// comes out as an Error token with the message as its lexeme, and as its
+/// literal formatted with the location and source snippet
+impl Iterator for Scanner {
+    type Item = Token;
+
+    fn next(&mut self) -> Option<Token> {
+        while self.tokens.is_empty() && !self.is_at_end() {
+            // Beginning of next lexeme
+            self.start = self.current;
+            self.start_column = self.start - self.line_start;
+            self.scan_token();
+        }
+        if let Some(token) = self.tokens.pop_front() {
+            return Some(token);
+        }
+        if self.reached_end {
+            return None;
+        }
+        self.reached_end = true;
+        let empty = Self::intern(&mut self.symbols, "");
+        let column = self.current - self.line_start;
+        return Some(Token::new(TokenType::Eof, Rc::clone(&empty), empty, self.line, column));
+    }
+}
+
+/// The given line of the source with carets under the `width` characters
+/// starting at the column, to show below a diagnostic. Empty when the line
+/// isn't in the source
+pub fn source_snippet(source: &str, line: usize, column: usize, width: usize) -> String {
+    let text = match source.lines().nth(line.wrapping_sub(1)) {
+        Some(text) => text,
+        None => return "".to_string(),
+    };
+    // Keep tabs so the carets line up however the terminal renders them
+    let indent: String = text.chars().take(column)
+        .map(|c| if c == '\t' { '\t' } else { ' ' })
+        .collect();
+    let available = text.chars().count().saturating_sub(column);
+    let carets = "^".repeat(width.min(available).max(1));
+    return format!("\n    {}\n    {}{}", text, indent, carets);
+}
@@ -0,0 +1,84 @@
+use fnv::FnvHashMap;
+
+/// Fields an instance keeps in slots, more go to its fields map
+pub const MAX_SLOTS: usize = 64;
+
+/// Layout shared by the instances that got the same fields in the same
+/// order, giving each field a fixed slot
+pub struct Shape {
+    /// Slot of each field by name hash
+    pub slots: FnvHashMap<u32, usize>,
+    /// Field names in slot order
+    pub names: Vec<u32>,
+    /// Shape an instance moves to when it gets another field
+    transitions: FnvHashMap<u32, usize>,
+}
+
+/// Every shape created so far, they live as long as the heap. Shape 0 is the
+/// empty one new instances start with
+pub struct Shapes {
+    shapes: Vec<Shape>,
+}
+
+impl Shapes {
+    pub fn new() -> Self {
+        let empty = Shape { slots: FnvHashMap::default(), names: vec![], transitions: FnvHashMap::default() };
+        Shapes { shapes: vec![empty] }
+    }
+
+    pub fn get(&self, shape: usize) -> &Shape {
+        return &self.shapes[shape];
+    }
+
+    /// Slot of the field in instances of the shape
+    #[inline(always)]
+    pub fn slot(&self, shape: usize, name: u32) -> Option<usize> {
+        return self.shapes[shape].slots.get(&name).copied();
+    }
+
+    /// Shape of an instance of the given shape once the field is added,
+    /// the field goes in the next slot
+    pub fn add_field(&mut self, shape: usize, name: u32) -> usize {
+        if let Some(next) = self.shapes[shape].transitions.get(&name) {
+            return *next;
+        }
+        let mut slots = self.shapes[shape].slots.clone();
+        let mut names = self.shapes[shape].names.clone();
+        slots.insert(name, names.len());
+        names.push(name);
+        let next = self.shapes.len();
+        self.shapes.push(Shape { slots, names, transitions: FnvHashMap::default() });
+        self.shapes[shape].transitions.insert(name, next);
+        return next;
+    }
+
+    pub fn len(&self) -> usize {
+        return self.shapes.len();
+    }
+
+    /// Never true, the empty shape is always there
+    pub fn is_empty(&self) -> bool {
+        return self.shapes.is_empty();
+    }
+}
+
+impl Default for Shapes {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// What a property instruction found the last time it ran: instances of
+/// `shape` keep the field in `slot`, and setting it moves them to
+/// `next_shape`, which differs when the field was added
+#[derive(Copy, Clone)]
+pub struct PropertyCache {
+    pub shape: usize,
+    pub slot: usize,
+    pub next_shape: usize,
+}
+
+impl PropertyCache {
+    /// Matches no instance
+    pub const EMPTY: PropertyCache = PropertyCache { shape: usize::MAX, slot: 0, next_shape: usize::MAX };
+}
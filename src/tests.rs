@@ -1,7 +1,19 @@
-use std::{fs, mem, thread, time};
+use std::{fs, io, mem, thread, time};
+use std::any::Any;
+use std::cell::RefCell;
 use std::fmt::Error;
-use crate::{Heap, Parser, RunResult, Scanner, VM};
-use crate::nativefn::{clock_native, NativeFn, NativeValue};
+use std::io::{Cursor, Write};
+use std::rc::Rc;
+use std::sync::atomic::Ordering;
+use crate::{Chunk, Diagnostic, Heap, Interpreter, KError, Object, Opcode, Parser, RunResult, Scanner, Value, VM};
+use serial_test::serial;
+use crate::nativefn::{clock_native, Capabilities, NativeError, NativeFn, NativeValue};
+use crate::repl::Repl;
+use crate::{bytecode, debug};
+use crate::scanner::source_snippet;
+use crate::diagnostic::Severity;
+use crate::formatter::{format_source, unified_diff};
+use crate::ffi::{parse_signature, CType, Signature};
 
 /////////////////////////////////////////////////////////////////////
 // Tests
@@ -10,16 +22,17 @@ use crate::nativefn::{clock_native, NativeFn, NativeValue};
 #[test]
 #[serial]
 fn test_clock_native() {
-    let time1 = clock_native(0, vec![]);
+    let heap = Heap::new();
+    let time1 = clock_native(&heap, 0, vec![]);
     let clock: NativeFn = clock_native;
     thread::sleep(time::Duration::from_millis(1000));
-    let time2 = clock(0, vec![]);
+    let time2 = clock(&heap, 0, vec![]);
     let time1 = match time1 {
-        NativeValue::Number(n) => n,
+        Ok(NativeValue::Number(n)) => n,
         _=> {panic!("Expected a number.")}
     };
     let time2 = match time2 {
-        NativeValue::Number(n) => n,
+        Ok(NativeValue::Number(n)) => n,
         _=> {panic!("Expected a number.")}
     };
     assert!(time2-time1 > 0.0);
@@ -481,6 +494,29 @@ fn test_stringify() {
     }
 }
 
+#[test]
+#[serial]
+fn test_print_list_and_map_containing_themselves() {
+    let buffer = SharedBuffer::default();
+    let mut interpreter = Interpreter::new();
+    interpreter.vm.output = Box::new(buffer.clone());
+    let source = r#"
+        var l = [1];
+        l[0] = l;
+        var m = {};
+        m["a"] = m;
+        m["l"] = [l, l];
+        print l;
+        print m;
+        var text = str(m) + " " + str([l]);
+    "#;
+    let func_main_idx = interpreter.compile(source).unwrap();
+    interpreter.run(func_main_idx).unwrap();
+    assert_eq!("[[...]]\n{\"a\": {...}, \"l\": [[[...]], [[...]]]}\n", buffer.contents());
+    let text = interpreter.eval("text").unwrap();
+    assert_eq!("{\"a\": {...}, \"l\": [[[...]], [[...]]]} [[[...]]]", interpreter.display(text));
+}
+
 #[test]
 #[serial]
 fn test_closure() {
@@ -667,7 +703,7 @@ fn test_class_instance_this_outside_method() {
     let code = r#"
         this; // this is illegal
     "#.to_string();
-    run_code(&code);
+    let _ = run_code(&code);
 }
 
 #[test]
@@ -781,34 +817,3458 @@ fn test_class_inheritance_child_can_use_super() {
 }
 
 
+#[test]
+#[serial]
+fn test_hot_redefinition_of_function() {
+    let code = r#"
+        fun greet() {
+          return "old";
+        }
+        var callback = greet;
+        fun greet() {
+          return "new";
+        }
+        var _result = callback();
+    "#.to_string();
+    let output = run_code_with(&code, |vm| vm.hot_redefinition = true);
+    match output {
+        Ok(str) => assert_eq!("new", str),
+        Err(_) => panic!("Failed")
+    }
+}
 
-// todo: garbage collection tests
+#[test]
+#[serial]
+fn test_hot_redefinition_of_class() {
+    let code = r#"
+        class Foo {
+          name() {
+            return "old";
+          }
+        }
+        var foo = Foo();
+        class Foo {
+          name() {
+            return "new";
+          }
+        }
+        var _result = foo.name();
+    "#.to_string();
+    let output = run_code_with(&code, |vm| vm.hot_redefinition = true);
+    match output {
+        Ok(str) => assert_eq!("new", str),
+        Err(_) => panic!("Failed")
+    }
+}
 
-/////////////////////////////////////////////////////////////////////
-// Helper functions
-/////////////////////////////////////////////////////////////////////
+#[test]
+#[serial]
+fn test_hot_redefinition_of_class_reaches_subclasses() {
+    let code = r#"
+        class Base {
+          const KIND = "old";
+          name() { return "old"; }
+          size() { return 1; }
+        }
+        class Child extend Base {
+          size() { return 2; }
+        }
+        class GrandChild extend Child {}
+        var child = Child();
+        class Base {
+          const KIND = "new";
+          name() { return "new"; }
+          size() { return 10; }
+          extra() { return "extra"; }
+        }
+        var _result = child.name() + " " + str(child.size()) + " " + GrandChild().name() + " "
+          + GrandChild().extra() + " " + Child.KIND + " " + str(GrandChild().size());
+        // Inheriting from its own subclass leaves the class with what the subclass has of its own
+        class Base extend GrandChild {
+          const KIND = "cycle";
+        }
+        _result = _result + " " + GrandChild.KIND;
+    "#.to_string();
+    let output = run_code_with(&code, |vm| vm.hot_redefinition = true);
+    match output {
+        Ok(str) => assert_eq!("new 2 new extra new 2 cycle", str),
+        Err(_) => panic!("Failed")
+    }
+}
 
-/// Helper for testing single expression
-fn run_expr(code: &String) ->Result<String, Error> {
-    let wrapped_code = format!("writeFile(\"result.txt\", str({}));", code);
-    // println!("{}", wrapped_code);
-    return execute(&wrapped_code);
+#[test]
+#[serial]
+fn test_hot_redefinition_leaves_var_rebinding_alone() {
+    let code = r#"
+        fun one() { return 1; }
+        fun two() { return 2; }
+        class A { name() { return "a"; } }
+        class B { name() { return "b"; } }
+        var x = one;
+        var x = two;
+        var y = A;
+        var y = B;
+        var _result = str(one()) + str(x()) + A().name() + y().name();
+    "#.to_string();
+    let output = run_code_with(&code, |vm| vm.hot_redefinition = true);
+    match output {
+        Ok(str) => assert_eq!("12ab", str),
+        Err(_) => panic!("Failed")
+    }
 }
 
-/// Helper for testing multiline code
-fn run_code(code: &String) ->Result<String, Error> {
-    let wrapped_code = format!("{}\nwriteFile(\"result.txt\", str(_result));", code);
-    // println!("{}", wrapped_code);
-    return execute(&wrapped_code);
+#[test]
+#[serial]
+fn test_garbage_collection_keeps_reachable_strings() {
+    let code = r#"
+        class Greeter {
+          greet(name) {
+            return "Hi " + name;
+          }
+        }
+        var greeter = Greeter();
+        var greeting = "";
+        for (var i = 0; i < 200; i = i + 1) {
+          greeting = greeter.greet("Foo");
+        }
+        var _result = greeting;
+    "#.to_string();
+    let output = run_code_with(&code, |vm| {
+        vm.heap.next_gc = 0;
+        vm.gc_check_interval = 1;
+    });
+    match output {
+        Ok(str) => assert_eq!("Hi Foo", str),
+        Err(_) => panic!("Failed")
+    }
 }
 
-/// Interpret and execute the code
-fn execute(code: &String) ->Result<String, Error>  {
-    let mut vm = VM::new();
-    vm.init();
+#[test]
+#[serial]
+#[should_panic(expected = "VM failed to execute.")]
+fn test_heap_limit_out_of_memory() {
+    let code = r#"
+        var s = "a";
+        for (var i = 0; i < 30; i = i + 1) {
+          s = s + s;
+        }
+        var _result = s;
+    "#.to_string();
+    let _ = run_code_with(&code, |vm| vm.heap.max_bytes = 1024 * 1024);
+}
 
-    // Scanning step
-    let mut scanner = Scanner::new(&code);
+#[test]
+#[serial]
+fn test_metrics_count_calls_and_allocations() {
+    let code = r#"
+        class Foo {}
+        fun make() {
+          return Foo();
+        }
+        make();
+        make();
+    "#.to_string();
+    let vm = compile_and_run(&code);
+    let metrics = vm.metrics();
+    assert_eq!(3, metrics.calls); // main and make twice, Foo has no initializer to call
+    assert_eq!(2, metrics.allocations.instances);
+    assert!(metrics.instructions > 0);
+    assert_eq!(2, metrics.peak_call_depth);
+}
+
+#[test]
+#[serial]
+fn test_repl_writes_prompt_and_output() {
+    let buffer = SharedBuffer::default();
+    let input = Cursor::new("print 1 + 2;\nexit\n");
+    let mut repl = Repl::new(input, Box::new(buffer.clone())).with_prompt("ks> ");
+    repl.run().unwrap();
+    let output = buffer.contents();
+    assert!(output.starts_with("ks> "));
+    assert!(output.contains("3\n"));
+    assert!(output.ends_with("ks> Good bye!\n\n"));
+}
+#[test]
+#[serial]
+fn test_class_inheritance_super_with_arguments() {
+    let code = r#"
+        class A {
+           add(a, b) {
+             return a + b;
+           }
+        }
+        class B extend A {
+           add(a, b) {
+             return 1 + super.add(a, b);
+           }
+        }
+        var _result = B().add(10, 20);
+    "#.to_string();
+    let output = run_code(&code);
+    match output {
+        Ok(str) => assert_eq!("31", str),
+        Err(_) => panic!("Failed")
+    }
+}
+
+#[test]
+#[serial]
+fn test_class_inheritance_super_method_as_value() {
+    let code = r#"
+        class A {
+           name() {
+             return "A:" + this.id;
+           }
+        }
+        class B extend A {
+           init() {
+             this.id = "b";
+           }
+           name() {
+             var parent = super.name;
+             return "B>" + parent();
+           }
+        }
+        var _result = B().name();
+    "#.to_string();
+    let output = run_code(&code);
+    match output {
+        Ok(str) => assert_eq!("B>A:b", str),
+        Err(_) => panic!("Failed")
+    }
+}
+
+#[test]
+#[serial]
+fn test_class_inheritance_super_inside_closure() {
+    let code = r#"
+        class A {
+           value() {
+             return 100;
+           }
+        }
+        class B extend A {
+           value() {
+             fun inner() {
+               return super.value() + 1;
+             }
+             return inner();
+           }
+        }
+        var _result = B().value();
+    "#.to_string();
+    let output = run_code(&code);
+    match output {
+        Ok(str) => assert_eq!("101", str),
+        Err(_) => panic!("Failed")
+    }
+}
+
+#[test]
+#[serial]
+fn test_class_instance_method_as_value() {
+    let code = r#"
+        class Foo {
+          init() {
+            this.name = "foo";
+          }
+          getName() {
+            return this.name;
+          }
+        }
+        var getter = Foo().getName;
+        var _result = getter();
+    "#.to_string();
+    let output = run_code(&code);
+    match output {
+        Ok(str) => assert_eq!("foo", str),
+        Err(_) => panic!("Failed")
+    }
+}
+#[test]
+#[serial]
+fn test_list_literal_and_index() {
+    let code = r#"
+        var xs = [10, 20, 30];
+        var _result = xs[0] + xs[2];
+    "#.to_string();
+    let output = run_code(&code);
+    match output {
+        Ok(str) => assert_eq!("40", str),
+        Err(_) => panic!("Failed")
+    }
+}
+
+#[test]
+#[serial]
+fn test_list_index_assignment() {
+    let code = r#"
+        var xs = ["a", "b"];
+        xs[1] = "c";
+        var _result = xs;
+    "#.to_string();
+    let output = run_code(&code);
+    match output {
+        Ok(str) => assert_eq!("[\"a\", \"c\"]", str),
+        Err(_) => panic!("Failed")
+    }
+}
+
+#[test]
+#[serial]
+fn test_list_len_and_nesting() {
+    let code = r#"
+        var xs = [[1, 2], [], [3]];
+        var total = 0;
+        for (var i = 0; i < len(xs); i += 1) {
+          total += len(xs[i]);
+        }
+        var _result = total;
+    "#.to_string();
+    let output = run_code(&code);
+    match output {
+        Ok(str) => assert_eq!("3", str),
+        Err(_) => panic!("Failed")
+    }
+}
+
+#[test]
+#[serial]
+#[should_panic(expected = "VM failed to execute.")]
+fn test_list_index_out_of_range() {
+    let code = r#"
+        var xs = [1];
+        var _result = xs[1];
+    "#.to_string();
+    let _ = run_code(&code);
+}
+
+#[test]
+#[serial]
+fn test_map_literal_and_index() {
+    let code = r#"
+        var scores = {"a": 1, "b": 2};
+        scores["c"] = scores["a"] + scores["b"];
+        scores["a"] = 10;
+        var _result = scores;
+    "#.to_string();
+    let output = run_code(&code);
+    match output {
+        Ok(str) => assert_eq!("{\"a\": 10, \"b\": 2, \"c\": 3}", str),
+        Err(_) => panic!("Failed")
+    }
+}
+
+#[test]
+#[serial]
+fn test_map_missing_key_is_nil() {
+    let code = r#"
+        var m = {};
+        var _result = m["missing"] == nil and len(m) == 0;
+    "#.to_string();
+    let output = run_code(&code);
+    match output {
+        Ok(str) => assert_eq!("true", str),
+        Err(_) => panic!("Failed")
+    }
+}
+
+#[test]
+#[serial]
+fn test_map_keys_iteration() {
+    let code = r#"
+        var m = {1: "one", true: "yes", nil: "none"};
+        var names = keys(m);
+        var total = "";
+        for (var i = 0; i < len(names); i += 1) {
+          total = total + m[names[i]];
+        }
+        var _result = total;
+    "#.to_string();
+    let output = run_code(&code);
+    match output {
+        Ok(str) => assert_eq!("oneyesnone", str),
+        Err(_) => panic!("Failed")
+    }
+}
+
+#[test]
+#[serial]
+fn test_garbage_collection_keeps_map_entries() {
+    let code = r#"
+        var m = {};
+        for (var i = 0; i < 100; i = i + 1) {
+          m["k" + str(i)] = "v" + str(i);
+        }
+        var _result = m["k42"];
+    "#.to_string();
+    let output = run_code_with(&code, |vm| {
+        vm.heap.next_gc = 0;
+        vm.gc_check_interval = 1;
+    });
+    match output {
+        Ok(str) => assert_eq!("v42", str),
+        Err(_) => panic!("Failed")
+    }
+}
+
+#[test]
+#[serial]
+fn test_variadic_function_collects_rest() {
+    let code = r#"
+        fun count(first, ...rest) {
+          return str(first) + ":" + str(len(rest));
+        }
+        var _result = count(1) + " " + count(1, 2, 3);
+    "#.to_string();
+    let output = run_code(&code);
+    match output {
+        Ok(str) => assert_eq!("1:0 1:2", str),
+        Err(_) => panic!("Failed")
+    }
+}
+
+#[test]
+#[serial]
+fn test_variadic_method() {
+    let code = r#"
+        class Logger {
+          join(...parts) {
+            var text = "";
+            for (var i = 0; i < len(parts); i += 1) {
+              text = text + parts[i];
+            }
+            return text;
+          }
+        }
+        var _result = Logger().join("a", "b", "c");
+    "#.to_string();
+    let output = run_code(&code);
+    match output {
+        Ok(str) => assert_eq!("abc", str),
+        Err(_) => panic!("Failed")
+    }
+}
+
+#[test]
+#[serial]
+#[should_panic(expected = "VM failed to execute.")]
+fn test_variadic_function_missing_required_argument() {
+    let code = r#"
+        fun log(fmt, ...args) {}
+        log();
+    "#.to_string();
+    let _ = run_code(&code);
+}
+
+#[test]
+#[serial]
+fn test_const_declarations() {
+    let code = r#"
+        const greeting = "hi";
+        fun make() {
+          const name = "foo";
+          fun inner() { return greeting + " " + name; }
+          return inner;
+        }
+        var _result = make()();
+    "#.to_string();
+    let output = run_code(&code);
+    match output {
+        Ok(str) => assert_eq!("hi foo", str),
+        Err(_) => panic!("Failed")
+    }
+}
+
+#[test]
+#[serial]
+#[should_panic]
+fn test_const_local_reassignment() {
+    let code = r#"
+        {
+          const limit = 1;
+          limit += 1;
+        }
+    "#.to_string();
+    let _ = run_code(&code);
+}
+
+#[test]
+#[serial]
+#[should_panic(expected = "VM failed to execute.")]
+fn test_const_global_reassignment() {
+    let code = r#"
+        const limit = 1;
+        limit = 2;
+    "#.to_string();
+    let _ = run_code(&code);
+}
+
+#[test]
+#[serial]
+fn test_for_in_list() {
+    let code = r#"
+        var total = 0;
+        for (var x in [1, 2, 3]) {
+          total += x;
+        }
+        var _result = total;
+    "#.to_string();
+    let output = run_code(&code);
+    match output {
+        Ok(str) => assert_eq!("6", str),
+        Err(_) => panic!("Failed")
+    }
+}
+
+#[test]
+#[serial]
+fn test_for_in_map_keys_inside_function() {
+    let code = r#"
+        fun describe(m) {
+          var text = "";
+          for (key in m) text = text + key + "=" + str(m[key]) + ";";
+          return text;
+        }
+        var _result = describe({"a": 1, "b": 2});
+    "#.to_string();
+    let output = run_code(&code);
+    match output {
+        Ok(str) => assert_eq!("a=1;b=2;", str),
+        Err(_) => panic!("Failed")
+    }
+}
+
+#[test]
+#[serial]
+#[should_panic(expected = "VM failed to execute.")]
+fn test_for_in_non_collection() {
+    let code = r#"
+        for (var x in 10) {}
+    "#.to_string();
+    let _ = run_code(&code);
+}
+
+#[test]
+#[serial]
+fn test_try_catch_thrown_value() {
+    let code = r#"
+        fun fail(reason) {
+          throw "failed: " + reason;
+        }
+        var _result = "";
+        try {
+          fail("disk");
+          _result = "unreachable";
+        } catch (e) {
+          _result = e;
+        }
+    "#.to_string();
+    let output = run_code(&code);
+    match output {
+        Ok(str) => assert_eq!("failed: disk", str),
+        Err(_) => panic!("Failed")
+    }
+}
+
+#[test]
+#[serial]
+fn test_try_catch_runtime_error() {
+    let code = r#"
+        fun lookup(xs, i) {
+          var total = 0;
+          try {
+            return xs[i];
+          } catch (e) {
+            return e;
+          }
+        }
+        var _result = str(lookup([1, 2], 1)) + " " + lookup([1, 2], 5);
+    "#.to_string();
+    let output = run_code(&code);
+    match output {
+        Ok(str) => assert_eq!("2 List index 5 out of range for length 2.", str),
+        Err(_) => panic!("Failed")
+    }
+}
+
+#[test]
+#[serial]
+fn test_try_catch_nested_rethrow() {
+    let code = r#"
+        var log = "";
+        try {
+          try {
+            throw 1;
+          } catch (e) {
+            log = log + "inner" + str(e);
+            throw e + 1;
+          }
+        } catch (e) {
+          log = log + " outer" + str(e);
+        }
+        var _result = log;
+    "#.to_string();
+    let output = run_code(&code);
+    match output {
+        Ok(str) => assert_eq!("inner1 outer2", str),
+        Err(_) => panic!("Failed")
+    }
+}
+
+#[test]
+#[serial]
+#[should_panic(expected = "VM failed to execute.")]
+fn test_uncaught_throw() {
+    let code = r#"
+        throw "boom";
+    "#.to_string();
+    let _ = run_code(&code);
+}
+
+#[test]
+#[serial]
+fn test_increment_decrement_variables() {
+    let code = r#"
+        var a = 5;
+        var before = a++;
+        var after = ++a;
+        fun count() {
+          var n = 10;
+          n--;
+          return --n;
+        }
+        var _result = str(before) + " " + str(after) + " " + str(a) + " " + str(count());
+    "#.to_string();
+    let output = run_code(&code);
+    match output {
+        Ok(str) => assert_eq!("5 7 7 8", str),
+        Err(_) => panic!("Failed")
+    }
+}
+
+#[test]
+#[serial]
+fn test_increment_fields() {
+    let code = r#"
+        class Counter {
+          init() { this.hits = 0; }
+          hit() { return this.hits++; }
+        }
+        var c = Counter();
+        c.hit();
+        c.hit();
+        var bumped = ++c.hits;
+        var _result = str(bumped) + " " + str(c.hits--) + " " + str(c.hits);
+    "#.to_string();
+    let output = run_code(&code);
+    match output {
+        Ok(str) => assert_eq!("3 3 2", str),
+        Err(_) => panic!("Failed")
+    }
+}
+
+#[test]
+#[serial]
+fn test_increment_in_loop_header() {
+    let code = r#"
+        var total = 0;
+        for (var i = 0; i < 5; i++) {
+          total += i;
+        }
+        var _result = total;
+    "#.to_string();
+    let output = run_code(&code);
+    match output {
+        Ok(str) => assert_eq!("10", str),
+        Err(_) => panic!("Failed")
+    }
+}
+
+#[test]
+#[serial]
+fn test_nil_coalescing() {
+    let code = r#"
+        var options = {"name": "kscript", "debug": false};
+        var name = options["name"] ?? "default";
+        var port = options["port"] ?? 8080;
+        var debug = options["debug"] ?? true;
+        var _result = name + " " + str(port) + " " + str(debug) + " " + str(nil ?? nil ?? 1);
+    "#.to_string();
+    let output = run_code(&code);
+    match output {
+        Ok(str) => assert_eq!("kscript 8080 false 1", str),
+        Err(_) => panic!("Failed")
+    }
+}
+
+#[test]
+#[serial]
+fn test_string_number_concatenation() {
+    let code = r#"
+        var _result = "count: " + 3 + ", " + 2.5 + " " + (1 + 2) + "!";
+    "#.to_string();
+    let output = run_code(&code);
+    match output {
+        Ok(str) => assert_eq!("count: 3, 2.5 3!", str),
+        Err(_) => panic!("Failed")
+    }
+}
+
+#[test]
+#[serial]
+#[should_panic(expected = "VM failed to execute.")]
+fn test_string_bool_concatenation() {
+    let code = r#"
+        var _result = "flag: " + true;
+    "#.to_string();
+    let _ = run_code(&code);
+}
+
+#[test]
+#[serial]
+fn test_truthiness() {
+    let code = r#"
+        var text = "";
+        if (nil) text = text + "a";
+        if (0) text = text + "b";
+        if ("") text = text + "c";
+        if (!nil) text = text + "d";
+        var m = {};
+        while (m["missing"]) text = text + "e";
+        var _result = text + " " + str(nil or "fallback") + " " + str(1 and "last");
+    "#.to_string();
+    let output = run_code(&code);
+    match output {
+        Ok(str) => assert_eq!("bcd fallback last", str),
+        Err(_) => panic!("Failed")
+    }
+}
+
+#[test]
+#[serial]
+fn test_to_string_hook_in_concatenation() {
+    let code = r#"
+        class Point {
+          init(x, y) { this.x = x; this.y = y; }
+          toString() { return "(" + this.x + ", " + this.y + ")"; }
+        }
+        class Origin extend Point {
+          init() { this.x = 0; this.y = 0; }
+        }
+        var _result = "p=" + Point(1, 2) + " o=" + Origin();
+    "#.to_string();
+    let output = run_code(&code);
+    match output {
+        Ok(str) => assert_eq!("p=(1, 2) o=(0, 0)", str),
+        Err(_) => panic!("Failed")
+    }
+}
+
+#[test]
+#[serial]
+fn test_to_string_hook_in_print() {
+    let buffer = SharedBuffer::default();
+    let input = Cursor::new(concat!(
+        "class Foo { toString() { return \"custom foo\"; } } class Bar {} print Foo(); print Bar();\n",
+        "exit\n"));
+    let mut repl = Repl::new(input, Box::new(buffer.clone())).with_prompt("");
+    repl.run().unwrap();
+    let output = buffer.contents();
+    assert!(output.contains("custom foo\n<Bar instance>\n"));
+}
+
+#[test]
+#[serial]
+fn test_to_string_hook_error_is_catchable() {
+    let code = r#"
+        class Bad {
+          toString() { throw "no text"; }
+        }
+        var _result = "";
+        try {
+          _result = "value " + Bad();
+        } catch (e) {
+          _result = "caught " + e;
+        }
+    "#.to_string();
+    let output = run_code(&code);
+    match output {
+        Ok(str) => assert_eq!("caught no text", str),
+        Err(_) => panic!("Failed")
+    }
+}
+
+#[test]
+#[serial]
+fn test_type_builtin() {
+    let code = r#"
+        class Foo {}
+        fun f() {}
+        var values = [1, "a", true, nil, [], {}, f, clock, Foo, Foo()];
+        var names = "";
+        for (var v in values) names = names + type(v) + " ";
+        var _result = names;
+    "#.to_string();
+    let output = run_code(&code);
+    match output {
+        Ok(str) => assert_eq!("number string bool nil list map function function class Foo", str),
+        Err(_) => panic!("Failed")
+    }
+}
+
+#[test]
+#[serial]
+fn test_spread_arguments() {
+    let code = r#"
+        fun add3(a, b, c) { return a + b + c; }
+        var args = [2, 3];
+        var pair = [10, 20];
+        var _result = str(add3(1, ...args)) + " " + str(add3(...pair, [5][0])) + " " + str(len(...[[1, 2]]));
+    "#.to_string();
+    let output = run_code(&code);
+    match output {
+        Ok(str) => assert_eq!("6 35 2", str),
+        Err(_) => panic!("Failed")
+    }
+}
+
+#[test]
+#[serial]
+fn test_spread_arguments_to_methods() {
+    let code = r#"
+        class Base {
+          sum(...xs) {
+            var total = 0;
+            for (var x in xs) total += x;
+            return total;
+          }
+        }
+        class Derived extend Base {
+          sum(...xs) { return 100 + super.sum(...xs, 1); }
+        }
+        var _result = Derived().sum(...[1, 2, 3]);
+    "#.to_string();
+    let output = run_code(&code);
+    match output {
+        Ok(str) => assert_eq!("107", str),
+        Err(_) => panic!("Failed")
+    }
+}
+
+#[test]
+#[serial]
+fn test_named_arguments() {
+    let code = r#"
+        fun window(title, width, height) {
+          return title + " " + width + "x" + height;
+        }
+        class Box {
+          init(width, height) { this.area = width * height; }
+          scale(by, offset) { return this.area * by + offset; }
+        }
+        var b = Box(height: 2, width: 3);
+        var _result = window("main", height: 600, width: 800) + " " + b.area + " " + b.scale(offset: 1, by: 10);
+    "#.to_string();
+    let output = run_code(&code);
+    match output {
+        Ok(str) => assert_eq!("main 800x600 6 61", str),
+        Err(_) => panic!("Failed")
+    }
+}
+
+#[test]
+#[serial]
+#[should_panic(expected = "VM failed to execute.")]
+fn test_named_arguments_unknown_parameter() {
+    let code = r#"
+        fun area(width, height) { return width * height; }
+        area(width: 1, depth: 2);
+    "#.to_string();
+    let _ = run_code(&code);
+}
+
+#[test]
+#[serial]
+#[should_panic(expected = "VM failed to execute.")]
+fn test_named_arguments_missing_parameter() {
+    let code = r#"
+        fun area(width, height) { return width * height; }
+        area(width: 1);
+    "#.to_string();
+    let _ = run_code(&code);
+}
+
+#[test]
+#[serial]
+fn test_match_literals_and_guards() {
+    let code = r#"
+        fun describe(n) {
+          return match (n) {
+            0 => "zero",
+            -1 => "minus one",
+            "hi" => "greeting",
+            x if x > 100 => "big " + x,
+            _ => "other"
+          };
+        }
+        var _result = describe(0) + "," + describe(-1) + "," + describe("hi") + "," + describe(101) + "," + describe(5);
+    "#.to_string();
+    let output = run_code(&code);
+    match output {
+        Ok(str) => assert_eq!("zero,minus one,greeting,big 101,other", str),
+        Err(_) => panic!("Failed")
+    }
+}
+
+#[test]
+#[serial]
+fn test_match_destructures_lists_and_instances() {
+    let code = r#"
+        class Point {
+          init(x, y) { this.x = x; this.y = y; }
+        }
+        fun shape(value) {
+          return match (value) {
+            [] => "empty",
+            [a, b] => "pair " + (a + b),
+            [first, ...rest] => "first " + first + " then " + len(rest),
+            Point { x: 0, y } => "on y axis at " + y,
+            Point { x, y } => "point " + x + "," + y,
+          };
+        }
+        var offset = 1;
+        var _result = shape([]) + "; " + shape([1, 2]) + "; " + shape([7, 8, 9]) + "; "
+          + shape(Point(0, 5)) + "; " + shape(Point(2, 3)) + "; " + (offset + match ([offset]) { [n] => n });
+    "#.to_string();
+    let output = run_code(&code);
+    match output {
+        Ok(str) => assert_eq!("empty; pair 3; first 7 then 2; on y axis at 5; point 2,3; 2", str),
+        Err(_) => panic!("Failed")
+    }
+}
+
+#[test]
+#[serial]
+#[should_panic(expected = "VM failed to execute.")]
+fn test_match_without_matching_arm() {
+    let code = r#"
+        var _result = match (3) { 1 => "one" };
+    "#.to_string();
+    let _ = run_code(&code);
+}
+
+#[test]
+#[serial]
+fn test_loop_closures_capture_each_iteration() {
+    let code = r#"
+        var getters = [nil, nil, nil];
+        for (var i = 0; i < 3; i++) {
+          fun get() { return i; }
+          getters[i] = get;
+        }
+        var names = {};
+        for (var name in ["a", "b"]) {
+          fun get() { return name; }
+          names[name] = get;
+        }
+        var _result = str(getters[2]()) + str(getters[1]()) + str(getters[0]()) + names["b"]() + names["a"]();
+    "#.to_string();
+    let output = run_code(&code);
+    match output {
+        Ok(str) => assert_eq!("210ba", str),
+        Err(_) => panic!("Failed")
+    }
+}
+
+#[test]
+#[serial]
+fn test_loop_body_changes_reach_increment() {
+    let code = r#"
+        var visited = "";
+        for (var i = 0; i < 10; i++) {
+          visited = visited + i;
+          i += 2;
+        }
+        var _result = visited;
+    "#.to_string();
+    let output = run_code(&code);
+    match output {
+        Ok(str) => assert_eq!("0369", str),
+        Err(_) => panic!("Failed")
+    }
+}
+
+#[test]
+#[serial]
+fn test_and_or_return_deciding_operand() {
+    let code = r#"
+        var input = nil;
+        var name = input or "default";
+        var zero = 0 or 5;
+        var missing = nil and "never";
+        var both = "a" and "b";
+        var _result = name + " " + zero + " " + str(missing) + " " + both + " " + str(false or nil);
+    "#.to_string();
+    let output = run_code(&code);
+    match output {
+        Ok(str) => assert_eq!("default 0 nil b nil", str),
+        Err(_) => panic!("Failed")
+    }
+}
+
+#[test]
+#[serial]
+fn test_block_comments_nest() {
+    let code = r#"
+        /* a comment with * and / inside
+           /* and a nested comment */
+           var _result = "hidden";
+        */
+        var _result = /* inline */ "visible" /**/;
+    "#.to_string();
+    let output = run_code(&code);
+    match output {
+        Ok(str) => assert_eq!("visible", str),
+        Err(_) => panic!("Failed")
+    }
+}
+
+#[test]
+#[serial]
+fn test_unterminated_block_comment_is_an_error() {
+    let code = "var a = 1;\n/* outer /* inner */\nvar b = 2;".to_string();
+    let mut scanner = Scanner::new(&code);
+    let tokens = scanner.scan_tokens();
+    assert!(scanner.had_error);
+    assert_eq!(6, tokens.len());
+}
+
+#[test]
+#[serial]
+#[should_panic(expected = "VM failed to execute.")]
+fn test_unbounded_recursion_overflows() {
+    let code = r#"
+        fun forever(n) {
+          return forever(n + 1);
+        }
+        forever(0);
+    "#.to_string();
+    compile_and_run(&code);
+}
+
+#[test]
+#[serial]
+fn test_stack_overflow_is_catchable() {
+    let code = r#"
+        fun depth(n) {
+          return depth(n + 1);
+        }
+        var _result = "";
+        try {
+          depth(0);
+        } catch (e) {
+          _result = e;
+        }
+    "#.to_string();
+    let output = run_code(&code);
+    match output {
+        Ok(str) => assert_eq!("Stack overflow calling depth at depth 1024.", str),
+        Err(_) => panic!("Failed")
+    }
+}
+
+#[test]
+#[serial]
+fn test_nested_runs_overflow_is_catchable() {
+    let code = r#"
+        class A {
+          init(n) { this.n = n; }
+          eq(other) {
+            if (this.n == 0) { return true; }
+            return A(this.n - 1) == A(other.n - 1);
+          }
+          toString() {
+            if (this.n == 0) { return "a"; }
+            return "" + A(this.n - 1);
+          }
+        }
+        var _result = str(A(5) == A(5)) + A(3);
+        try {
+          A(1000) == A(1000);
+        } catch (e) {
+          _result = _result + "|" + e;
+        }
+        try {
+          print A(1000);
+        } catch (e) {
+          _result = _result + "|" + e;
+        }
+    "#.to_string();
+    let output = run_code(&code);
+    match output {
+        Ok(str) => assert_eq!("truea|Stack overflow calling eq at depth 65.|Stack overflow calling toString at depth 65.", str),
+        Err(_) => panic!("Failed")
+    }
+}
+
+#[test]
+#[serial]
+fn test_deep_recursion_grows_stack() {
+    let code = r#"
+        fun sum(n) {
+          if (n == 0) return 0;
+          return n + sum(n - 1);
+        }
+        var _result = sum(900);
+    "#.to_string();
+    let output = run_code(&code);
+    match output {
+        Ok(str) => assert_eq!("405450", str),
+        Err(_) => panic!("Failed")
+    }
+}
+
+#[test]
+#[serial]
+#[should_panic(expected = "VM failed to execute.")]
+fn test_configured_stack_size_overflows() {
+    let code = r#"
+        fun sum(n) {
+          if (n == 0) return 0;
+          return n + sum(n - 1);
+        }
+        var _result = sum(100);
+    "#.to_string();
+    let _ = run_code_with(&code, |vm| vm.max_stack = 64);
+}
+
+#[test]
+#[serial]
+fn test_runtime_error_prints_stack_trace() {
+    let buffer = SharedBuffer::default();
+    let mut repl = Repl::new(Cursor::new(""), Box::new(buffer.clone()));
+    let code = "fun inner() {\n  return 1 + nil;\n}\nfun outer() {\n  return inner();\n}\nouter();\n".to_string();
+    repl.eval(&code);
+    let output = buffer.contents();
+    let inner = output.find("at fn inner (line 2)").unwrap();
+    let outer = output.find("at fn outer (line 5)").unwrap();
+    let script = output.find("at script (line 7)").unwrap();
+    assert!(inner < outer && outer < script);
+}
+
+#[test]
+#[serial]
+fn test_more_than_256_constants() {
+    let numbers: Vec<String> = (0..600).map(|n| n.to_string()).collect();
+    let code = format!("fun total() {{ return {}; }}\nvar _result = total();", numbers.join(" + "));
+    let output = run_code(&code);
+    match output {
+        Ok(str) => assert_eq!("179700", str),
+        Err(_) => panic!("Failed")
+    }
+}
+
+#[test]
+#[serial]
+fn test_names_and_functions_after_more_than_256_constants() {
+    let numbers: Vec<String> = (0..300).map(|n| n.to_string()).collect();
+    let code = format!(r#"
+        var total = {};
+        var after = 1;
+        class Point {{
+          init(x) {{ this.x = x; }}
+          double() {{ return this.x * 2; }}
+        }}
+        fun add(a) {{ return a + after; }}
+        var point = Point(total);
+        point.y = 2;
+        var _result = add(point.double()) + point.y;
+    "#, numbers.join(" + "));
+    let output = run_code(&code);
+    match output {
+        Ok(str) => assert_eq!("89703", str),
+        Err(_) => panic!("Failed")
+    }
+}
+
+#[test]
+#[serial]
+fn test_more_than_256_locals_and_arguments() {
+    let declarations: Vec<String> = (1..300).map(|n| format!("var v{} = v{} + 1;", n, n - 1)).collect();
+    let arguments: Vec<String> = (0..300).map(|n| format!("v{}", n)).collect();
+    let code = format!(r#"
+        fun count(...items) {{
+          return len(items) + items[299];
+        }}
+        fun locals() {{
+          var v0 = 0;
+          {}
+          v299 = v299 + v1;
+          return count({});
+        }}
+        var _result = locals();
+    "#, declarations.join("\n"), arguments.join(", "));
+    let output = run_code(&code);
+    match output {
+        Ok(str) => assert_eq!("600", str),
+        Err(_) => panic!("Failed")
+    }
+}
+
+#[test]
+#[serial]
+fn test_invoke_skips_bound_method_allocation() {
+    let code = r#"
+        class Counter {
+          init() { this.count = 0; }
+          add(n) { this.count = this.count + n; return this; }
+        }
+        var counter = Counter();
+        for (var i = 0; i < 10; i++) {
+          counter.add(i).add(1);
+        }
+        var total = counter.count;
+    "#.to_string();
+    let vm = compile_and_run(&code);
+    assert_eq!(0, vm.metrics().allocations.bound_methods);
+}
+
+#[test]
+#[serial]
+fn test_incremental_collection_keeps_values_stored_while_marking() {
+    let code = r#"
+        class Box {
+          init() { this.value = nil; }
+        }
+        var box = Box();
+        var scores = {};
+        fun counter() {
+          var text = "";
+          fun next() {
+            text = text + "x";
+            return text;
+          }
+          return next;
+        }
+        var next = counter();
+        for (var i = 0; i < 300; i++) {
+          box.value = "box " + str(i);
+          scores["s" + str(i)] = "score " + str(i);
+          next();
+        }
+        var _result = box.value + "|" + scores["s297"] + "|" + str(len(next()));
+    "#.to_string();
+    let output = run_code_with(&code, |vm| {
+        vm.heap.next_gc = 0;
+        vm.gc_check_interval = 1;
+        vm.gc_step_budget = 1;
+    });
+    match output {
+        Ok(str) => assert_eq!("box 299|score 297|301", str),
+        Err(_) => panic!("Failed")
+    }
+}
+
+#[test]
+#[serial]
+fn test_incremental_collection_keeps_methods_defined_while_marking() {
+    let methods: String = (0..60).map(|i| format!("m{}() {{ return base + {}; }}\n", i, i)).collect();
+    let calls: Vec<String> = (0..60).map(|i| format!("sub.m{}()", i)).collect();
+    let code = format!(r#"
+        var total = 0;
+        for (var round = 0; round < 1000; round++) {{
+          var base = round;
+          class Many {{
+            {}
+          }}
+          class Sub extend Many {{}}
+          var sub = Sub();
+          total = total + {};
+        }}
+        var _result = total;
+    "#, methods, calls.join(" + "));
+    // Small steps keep marking in progress while the classes get their
+    // methods, which a full collection under stress never does
+    let output = run_code_with(&code, |vm| {
+        vm.heap.next_gc = 0;
+        vm.gc_check_interval = 1;
+        vm.gc_step_budget = 64;
+    });
+    match output {
+        // 60 * (0 + ... + 999) + 1000 * (0 + ... + 59)
+        Ok(str) => assert_eq!("31740000", str),
+        Err(_) => panic!("Failed")
+    }
+}
+
+#[test]
+#[serial]
+fn test_garbage_collection_frees_objects_and_keeps_handles() {
+    let code = r#"
+        class Point {
+          init(x) { this.x = x; }
+        }
+        var keep = {};
+        var last = nil;
+        for (var i = 0; i < 200; i++) {
+          var temp = Point(i);
+          var pair = [temp, {"x": i}];
+          if (i == 50 or i == 150) {
+            keep[i] = pair;
+          }
+          last = temp;
+        }
+        // Grow the heap past the next collection threshold
+        var padding = "a";
+        for (var i = 0; i < 21; i++) {
+          padding = padding + padding;
+        }
+        var total = keep[50][0].x + keep[150][1]["x"] + last.x;
+    "#.to_string();
+    let vm = compile_and_run_with(&code, |vm| {
+        vm.heap.next_gc = 0;
+        vm.gc_check_interval = 1;
+    });
+    let total = vm.globals.values()
+        .find(|value| value.is_number() && value.as_number() == 399.0);
+    assert!(total.is_some());
+    assert!(vm.heap.instances.len() < vm.heap.allocations.instances);
+    assert!(vm.heap.maps.len() < vm.heap.allocations.maps);
+}
+
+#[test]
+#[serial]
+fn test_repeated_evals_leave_the_heap_as_it_was() {
+    let mut interpreter = Interpreter::new();
+    interpreter.eval("fun add(a, b) { return a + b; }").unwrap();
+    interpreter.eval("add(1, 2)").unwrap();
+    interpreter.vm.collect_garbage();
+    let before = interpreter.vm.heap.stats();
+    for i in 0..300 {
+        interpreter.eval(&format!("add({}, 1)", i)).unwrap();
+        interpreter.eval("{ fun local() { return 1; } local(); }").unwrap();
+    }
+    interpreter.vm.collect_garbage();
+    let after = interpreter.vm.heap.stats();
+    assert_eq!(before.functions, after.functions);
+    assert_eq!(before.closures, after.closures);
+    assert_eq!(before.bytes_allocated, after.bytes_allocated);
+}
+
+#[test]
+#[serial]
+fn test_garbage_collection_keeps_captured_and_nested_constants() {
+    let code = r#"
+        fun outer() {
+          fun inner() {
+            return "nested constant";
+          }
+          return inner;
+        }
+        fun make(prefix) {
+          var label = prefix + " label";
+          fun read() { return label; }
+          var padding = "a";
+          for (var i = 0; i < 21; i++) {
+            padding = padding + padding;
+          }
+          return read;
+        }
+        var read = make("captured");
+        var padding = "b";
+        for (var i = 0; i < 22; i++) {
+          padding = padding + padding;
+        }
+        var _result = read() + ", " + outer()();
+    "#.to_string();
+    let output = run_code_with(&code, |vm| {
+        vm.heap.next_gc = 0;
+        vm.gc_check_interval = 1;
+        vm.gc_step_budget = 1;
+    });
+    match output {
+        Ok(str) => assert_eq!("captured label, nested constant", str),
+        Err(_) => panic!("Failed")
+    }
+}
+
+#[test]
+#[serial]
+fn test_gc_stress_closures() {
+    let code = r#"
+        fun counter(start) {
+          var count = start;
+          fun next() {
+            count = count + 1;
+            return "n" + count;
+          }
+          return next;
+        }
+        var a = counter(0);
+        var b = counter(10);
+        var _result = a() + a() + b() + a();
+    "#.to_string();
+    match run_code_stressed(&code) {
+        Ok(str) => assert_eq!("n1n2n11n3", str),
+        Err(_) => panic!("Failed")
+    }
+}
+
+#[test]
+#[serial]
+fn test_gc_stress_classes() {
+    let code = r#"
+        class Node {
+          init(value, next) {
+            this.value = value;
+            this.next = next;
+          }
+          toString() { return "<" + this.value + ">"; }
+        }
+        class Named extend Node {
+          toString() { return "named " + super.toString(); }
+        }
+        var head = nil;
+        for (var i = 0; i < 20; i++) {
+          head = Named("node " + i, head);
+        }
+        var _result = "" + head + head.next.next;
+    "#.to_string();
+    match run_code_stressed(&code) {
+        Ok(str) => assert_eq!("named <node 19>named <node 17>", str),
+        Err(_) => panic!("Failed")
+    }
+}
+
+#[test]
+#[serial]
+fn test_gc_stress_lists_and_maps() {
+    let code = r#"
+        var xs = {};
+        var names = {};
+        for (var i = 0; i < 20; i++) {
+          xs[i] = ["item " + i, {"index": "i" + i}];
+          names["key " + i] = "value " + i;
+        }
+        var _result = xs[7][0] + " " + xs[19][1]["index"] + " " + names["key 3"] + " " + str(len(keys(names)));
+    "#.to_string();
+    match run_code_stressed(&code) {
+        Ok(str) => assert_eq!("item 7 i19 value 3 20", str),
+        Err(_) => panic!("Failed")
+    }
+}
+
+#[test]
+#[serial]
+fn test_gc_stress_exceptions_and_match() {
+    let code = r#"
+        fun fail(name) {
+          throw "failed " + name;
+        }
+        var caught = "";
+        for (var i = 0; i < 5; i++) {
+          try { fail("step " + i); } catch (e) { caught = e; }
+        }
+        var _result = match (["head " + caught, "tail"]) {
+          [first, ...rest] => first + " " + str(rest),
+          _ => "no match"
+        };
+    "#.to_string();
+    match run_code_stressed(&code) {
+        Ok(str) => assert_eq!("head failed step 4 [\"tail\"]", str),
+        Err(_) => panic!("Failed")
+    }
+}
+
+#[test]
+#[serial]
+fn test_mem_stats_native() {
+    let code = r#"
+        class Point {}
+        var points = [Point(), Point()];
+        var stats = memStats();
+        var _result = str(stats["instances"]) + " " + str(stats["lists"]) + " " + str(stats["bytesAllocated"] > 0) + " " + str(stats["nextGC"] > stats["bytesAllocated"]);
+    "#.to_string();
+    match run_code(&code) {
+        // The args global is a list as well
+        Ok(str) => assert_eq!("2 2 true true", str),
+        Err(_) => panic!("Failed")
+    }
+}
+
+#[test]
+#[serial]
+fn test_heap_stats_count_live_objects() {
+    let code = r#"
+        class Point {}
+        var p = Point();
+        var xs = [1, 2];
+        var m = {"a": 1};
+    "#.to_string();
+    let vm = compile_and_run(&code);
+    let stats = vm.heap.stats();
+    assert_eq!(1, stats.classes);
+    assert_eq!(1, stats.instances);
+    // xs and the args global
+    assert_eq!(2, stats.lists);
+    assert_eq!(1, stats.maps);
+    assert_eq!(vm.heap.bytes_allocated, stats.bytes_allocated);
+    assert_eq!(vm.heap.next_gc, stats.next_gc);
+}
+
+#[test]
+#[serial]
+fn test_bytecode_round_trip() {
+    let code = r#"
+        class Greeter {
+          init(name) { this.name = name; }
+          greet(punctuation) { return "hello " + this.name + punctuation; }
+        }
+        fun twice(f, ...args) {
+          return f(...args) + f(...args);
+        }
+        fun make(n) {
+          fun add(x) { return x + n; }
+          return add;
+        }
+        var _result = twice(make(1.5), 2) + " " + Greeter("kbc").greet("!");
+        writeFile("result.txt", str(_result));
+    "#.to_string();
+    let mut scanner = Scanner::new(&code);
+    let tokens = scanner.scan_tokens();
+    let mut parser = Parser::new(Heap::new(), tokens);
+    let func_main_idx = parser.compile();
+    let bytes = bytecode::serialize(&parser.heap, func_main_idx);
+    assert!(bytecode::is_bytecode(&bytes));
+
+    let mut vm = VM::new();
+    vm.init();
+    let func_main_idx = bytecode::deserialize(&bytes, &mut vm.heap).unwrap();
+    match vm.execute_function(func_main_idx) {
+        RunResult::Ok => {}
+        _ => panic!("VM failed to execute.")
+    }
+    let contents = fs::read_to_string("result.txt").unwrap();
+    assert_eq!("7 hello kbc!", contents.trim());
+}
+
+#[test]
+#[serial]
+fn test_bytecode_rejects_truncated_file() {
+    let code = "print 1 + 2;".to_string();
+    let mut scanner = Scanner::new(&code);
+    let tokens = scanner.scan_tokens();
+    let mut parser = Parser::new(Heap::new(), tokens);
+    let func_main_idx = parser.compile();
+    let bytes = bytecode::serialize(&parser.heap, func_main_idx);
+
+    let mut heap = Heap::new();
+    let error = bytecode::deserialize(&bytes[..bytes.len() - 1], &mut heap).err();
+    assert_eq!(Some("Unexpected end of bytecode file.".to_string()), error);
+    let error = bytecode::deserialize(b"print 1;", &mut heap).err();
+    assert_eq!(Some("Not a compiled KScript file.".to_string()), error);
+    for len in 0..bytes.len() {
+        assert!(bytecode::deserialize(&bytes[..len], &mut heap).is_err());
+    }
+}
+
+#[test]
+#[serial]
+fn test_bytecode_rejects_corrupted_code() {
+    let mut parser = Parser::new(Heap::new(), Scanner::new(&"print 1;".to_string()).scan_tokens());
+    let func_main_idx = parser.compile();
+    let header = bytecode::serialize(&parser.heap, func_main_idx)[..5].to_vec();
+    // A file with only a main function, holding the code and number constants
+    let file = |code: &[u8], constants: &[f64]| {
+        let mut bytes = header.clone();
+        bytes.extend_from_slice(&1u32.to_le_bytes());
+        bytes.extend_from_slice(&4u32.to_le_bytes());
+        bytes.extend_from_slice(b"main");
+        bytes.extend_from_slice(&[0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+        bytes.extend_from_slice(&(code.len() as u32).to_le_bytes());
+        bytes.extend_from_slice(code);
+        bytes.extend_from_slice(&0u32.to_le_bytes());
+        bytes.extend_from_slice(&(constants.len() as u32).to_le_bytes());
+        for constant in constants {
+            bytes.push(3);
+            bytes.extend_from_slice(&constant.to_le_bytes());
+        }
+        return bytes;
+    };
+    let nil = Opcode::Nil.byte();
+    let ret = Opcode::Return.byte();
+    let cases: Vec<(Vec<u8>, &str)> = vec![
+        (vec![], "code doesn't end with a return."),
+        (vec![Opcode::Constant.byte(), 1, Opcode::Print.byte(), nil, ret], "constant 1 out of range."),
+        (vec![Opcode::GetGlobal.byte(), 0, Opcode::Print.byte(), nil, ret], "constant 0 isn't a name."),
+        (vec![200, nil, ret], "unknown opcode 200 at 0."),
+        (vec![nil, ret, Opcode::Constant.byte()], "instruction cut short at the end of the code."),
+        (vec![Opcode::Jump.byte(), 0, 16, nil, ret], "jump at 0 doesn't land on an instruction."),
+        (vec![Opcode::Jump.byte(), 0, 1, Opcode::Constant.byte(), 0, nil, ret], "jump at 0 doesn't land on an instruction."),
+        (vec![Opcode::Loop.byte(), 0, 9, nil, ret], "jump at 0 doesn't land on an instruction."),
+        (vec![Opcode::GetUpvalue.byte(), 0, nil, ret], "upvalue 0 out of range."),
+        (vec![Opcode::Wide.byte(), 0, 0, Opcode::Constant.byte(), 0, nil, ret], "wide operand at 0 before an instruction without a constant."),
+        (vec![nil, Opcode::Print.byte()], "code doesn't end with a return."),
+    ];
+    let mut interpreter = Interpreter::new();
+    for (code, message) in cases {
+        match interpreter.load_bytecode(&file(&code, &[1.0])) {
+            Err(KError::Bytecode(error)) => assert_eq!(format!("Invalid code in main: {}", message), error),
+            _ => panic!("Expected {} for {:?}", message, code),
+        }
+    }
+    // The checked code still runs
+    let func_main_idx = interpreter.load_bytecode(&file(&[Opcode::Constant.byte(), 0, Opcode::Print.byte(), nil, ret], &[1.0])).unwrap();
+    assert!(interpreter.run(func_main_idx).is_ok());
+}
+
+#[test]
+#[serial]
+fn test_sandbox_disables_file_natives() {
+    let buffer = SharedBuffer::default();
+    let input = Cursor::new(concat!(
+        "try { writeFile(\"sandbox.txt\", \"escaped\"); } catch (e) { print e; }\n",
+        "print type(clock());\n",
+        "exit\n"));
+    let mut repl = Repl::new(input, Box::new(buffer.clone())).with_prompt("");
+    repl.vm.capabilities = Capabilities::none();
+    repl.run().unwrap();
+    let output = buffer.contents();
+    assert!(output.contains("writeFile is not available, file system access is disabled.\nnumber\n"));
+    assert!(fs::metadata("sandbox.txt").is_err());
+}
+
+#[test]
+#[serial]
+#[should_panic(expected = "VM failed to execute.")]
+fn test_sandbox_native_error_without_handler() {
+    let code = "appendFile(\"sandbox.txt\", \"escaped\");".to_string();
+    compile_and_run_with(&code, |vm| vm.capabilities = Capabilities::none());
+}
+
+#[test]
+#[serial]
+fn test_instruction_budget_stops_runaway_loop() {
+    let buffer = SharedBuffer::default();
+    let mut repl = Repl::new(Cursor::new(""), Box::new(buffer.clone()));
+    repl.vm.max_instructions = Some(10_000);
+    let code = r#"
+        try {
+          while (true) {}
+        } catch (e) {
+          print "caught";
+        }
+    "#.to_string();
+    assert_eq!(Some(RunResult::BudgetExceeded), repl.eval(&code));
+    let output = buffer.contents();
+    assert!(output.contains("Instruction budget exceeded."));
+    assert!(!output.contains("caught"));
+}
+
+#[test]
+#[serial]
+fn test_instruction_budget_stops_nested_method_run() {
+    let buffer = SharedBuffer::default();
+    let mut repl = Repl::new(Cursor::new(""), Box::new(buffer.clone()));
+    repl.vm.max_instructions = Some(10_000);
+    let code = r#"
+        class Forever {
+          toString() { while (true) {} }
+        }
+        try { print Forever(); } catch (e) { print "caught"; }
+    "#.to_string();
+    assert_eq!(Some(RunResult::BudgetExceeded), repl.eval(&code));
+    assert!(!buffer.contents().contains("caught"));
+
+    repl.vm.reset_stack();
+    let code = "var n = 0; for (var i = 0; i < 10; i++) { n = n + i; } print n;".to_string();
+    assert_eq!(Some(RunResult::Ok), repl.eval(&code));
+    assert!(buffer.contents().ends_with("45\n"));
+}
+
+#[test]
+#[serial]
+fn test_timeout_stops_runaway_loop() {
+    let buffer = SharedBuffer::default();
+    let mut repl = Repl::new(Cursor::new(""), Box::new(buffer.clone()));
+    repl.vm.timeout = Some(time::Duration::from_millis(50));
+    let code = "try { while (true) {} } catch (e) { print \"caught\"; }".to_string();
+    let start = time::Instant::now();
+    assert_eq!(Some(RunResult::Timeout), repl.eval(&code));
+    assert!(start.elapsed() < time::Duration::from_secs(5));
+    let output = buffer.contents();
+    assert!(output.contains("Execution timed out."));
+    assert!(!output.contains("caught"));
+}
+
+#[test]
+#[serial]
+fn test_interrupt_flag_stops_running_script() {
+    let buffer = SharedBuffer::default();
+    let mut repl = Repl::new(Cursor::new(""), Box::new(buffer.clone()));
+    let interrupt_flag = repl.vm.interrupt_flag.clone();
+    let interrupter = thread::spawn(move || {
+        thread::sleep(time::Duration::from_millis(50));
+        interrupt_flag.store(true, Ordering::Relaxed);
+    });
+    let code = "try { while (true) {} } catch (e) { print \"caught\"; }".to_string();
+    assert_eq!(Some(RunResult::Interrupted), repl.eval(&code));
+    interrupter.join().unwrap();
+    let output = buffer.contents();
+    assert!(output.contains("Interrupted."));
+    assert!(!output.contains("caught"));
+}
+
+#[test]
+#[serial]
+fn test_repl_reads_continuation_lines() {
+    let buffer = SharedBuffer::default();
+    let input = Cursor::new(concat!(
+        "{\n",
+        "  fun add(a, b) {\n",
+        "    return a + b; // }\n",
+        "  }\n",
+        "  /* ( */ print add(1,\n",
+        "    2);\n",
+        "}\n",
+        "print \"(\";\n",
+        "exit\n"));
+    let mut repl = Repl::new(input, Box::new(buffer.clone()));
+    repl.run().unwrap();
+    let output = buffer.contents();
+    assert!(output.starts_with("> ... ... ... ... ... ... 3\n> (\n> Good bye!"));
+}
+
+#[test]
+#[serial]
+fn test_repl_keeps_state_between_inputs() {
+    let buffer = SharedBuffer::default();
+    let input = Cursor::new(concat!(
+        "var x = 1;\n",
+        "fun inc(n) { return n + x; }\n",
+        "class Point { init(v) { this.v = v; } toString() { return \"Point \" + this.v; } }\n",
+        "var p = Point(inc(41));\n",
+        "print missing;\n",
+        "x = 2;\n",
+        "print p; print inc(1);\n",
+        "exit\n"));
+    let mut repl = Repl::new(input, Box::new(buffer.clone())).with_prompt("");
+    repl.vm.heap.stress = true;
+    repl.run().unwrap();
+    let output = buffer.contents();
+    assert!(output.contains("Undefined variable missing"));
+    assert!(output.contains("Point 42\n3\n"));
+}
+
+#[test]
+#[serial]
+fn test_interpreter_eval_keeps_state() {
+    let mut interpreter = Interpreter::new();
+    let value = interpreter.eval("var xs = [1, 2];").unwrap();
+    assert!(value.is_nil());
+    interpreter.eval("fun total(list) { var sum = 0; for (var x in list) { sum = sum + x; } return sum; }").unwrap();
+    let value = interpreter.eval("total(xs) * 10").unwrap();
+    assert_eq!(30.0, value.as_number());
+    let value = interpreter.eval("[\"a\", total(xs)];").unwrap();
+    assert_eq!("[\"a\", 3]", interpreter.display(value));
+}
+
+#[test]
+#[serial]
+fn test_interpreter_reports_errors() {
+    let mut interpreter = Interpreter::new();
+    match interpreter.eval("var = 1;") {
+        Err(KError::Compile(errors)) => assert_eq!(vec!["[line 1] Error at '=': Expect a variable name.\n    var = 1;\n        ^".to_string()], errors),
+        _ => panic!("Expected a compile error.")
+    }
+    match interpreter.eval("missing + 1") {
+        Err(KError::Runtime(message)) => assert_eq!("Undefined variable missing", message),
+        _ => panic!("Expected a runtime error.")
+    }
+    interpreter.vm.max_instructions = Some(100);
+    assert!(matches!(interpreter.eval("while (true) {}"), Err(KError::BudgetExceeded)));
+    interpreter.vm.max_instructions = None;
+    assert_eq!(2.0, interpreter.eval("1 + 1").unwrap().as_number());
+    assert!(matches!(interpreter.run_file("missing.ks"), Err(KError::Io(_))));
+}
+
+#[test]
+#[serial]
+fn test_native_errors_are_catchable() {
+    let code = r#"
+        var errors = [];
+        try { len(1); } catch (e) { errors = [e]; }
+        try { str(); } catch (e) { errors = [errors[0], e]; }
+        try { writeFile(".", "content"); } catch (e) { errors = [errors[0], errors[1], type(e)]; }
+        var _result = errors;
+    "#.to_string();
+    match run_code(&code) {
+        Ok(str) => assert_eq!("[\"Invalid type for len, list, map or string expected.\", \"str expects 1 argument(s) but got 0.\", \"string\"]", str),
+        Err(_) => panic!("Failed")
+    }
+}
+
+#[test]
+#[serial]
+#[should_panic(expected = "VM failed to execute.")]
+fn test_native_error_without_handler() {
+    let code = "keys([1, 2]);".to_string();
+    compile_and_run(&code);
+}
+
+#[test]
+#[serial]
+fn test_natives_pass_objects_by_handle() {
+    let code = r#"
+        class Point { init(x) { this.x = x; } }
+        fun seven() { return 7; }
+        var p = Point(3);
+        var m = {};
+        m[p] = 1;
+        m[seven] = 2;
+        var ks = keys(m);
+        var _result = str(ks[0].x) + " " + str(ks[0] == p) + " " + str(ks[1]()) + " " + str(ks);
+    "#.to_string();
+    match run_code(&code) {
+        Ok(str) => assert_eq!("3 true 7 [<Point>, <function>]", str),
+        Err(_) => panic!("Failed")
+    }
+}
+
+#[test]
+#[serial]
+fn test_natives_pass_lists_and_maps_by_handle() {
+    // Copying the arguments would visit the shared list 2^40 times
+    let code = r#"
+        var shared = [1];
+        for (var i = 0; i < 40; i++) {
+          shared = [shared, shared];
+        }
+        var m = {"a": shared, shared: 2};
+        var ks = keys(m);
+        var _result = str(len(shared)) + " " + str(len(m)) + " " + type(shared) + " " + type(m)
+          + " " + str(ks[1] == shared) + " " + str(keys({"x": [1, [2]], 3: {}}));
+    "#.to_string();
+    match run_code(&code) {
+        Ok(str) => assert_eq!("2 2 list map true [\"x\", 3]", str),
+        Err(_) => panic!("Failed")
+    }
+}
+
+#[test]
+#[serial]
+fn test_script_args() {
+    let mut interpreter = Interpreter::new();
+    assert_eq!(0.0, interpreter.eval("len(args)").unwrap().as_number());
+    interpreter.vm.set_args(&["input.txt".to_string(), "--verbose".to_string()]);
+    let value = interpreter.eval("args[1] + \" \" + str(len(args))").unwrap();
+    assert_eq!("--verbose 2", interpreter.display(value));
+}
+
+#[test]
+#[serial]
+fn test_input_argument_errors() {
+    let code = r#"
+        var errors = [];
+        try { input(1); } catch (e) { errors = [e]; }
+        try { input("a", "b"); } catch (e) { errors = [errors[0], e]; }
+        var _result = errors;
+    "#.to_string();
+    match run_code(&code) {
+        Ok(str) => assert_eq!("[\"Invalid type for input prompt, string expected.\", \"input expects 0 or 1 argument(s) but got 2.\"]", str),
+        Err(_) => panic!("Failed")
+    }
+}
+
+#[test]
+#[serial]
+fn test_file_system_natives() {
+    let code = r#"
+        mkdir("test_fs_natives/sub");
+        writeFile("test_fs_natives/a.txt", "hello");
+        var before = fileExists("test_fs_natives/a.txt");
+        var content = readFile("test_fs_natives/a.txt");
+        var names = listDir("test_fs_natives");
+        deleteFile("test_fs_natives/a.txt");
+        var error = nil;
+        try { readFile("test_fs_natives/a.txt"); } catch (e) { error = type(e); }
+        var _result = str(before) + " " + content + str(names) + " " + str(fileExists("test_fs_natives/a.txt")) + " " + error;
+    "#.to_string();
+    let output = run_code(&code);
+    let _ = std::fs::remove_dir_all("test_fs_natives");
+    match output {
+        Ok(str) => assert_eq!("true hello\n[\"a.txt\", \"sub\"] false string", str),
+        Err(_) => panic!("Failed")
+    }
+}
+
+#[test]
+#[serial]
+#[cfg(feature = "http")]
+fn test_http_natives() {
+    use std::io::{BufRead, BufReader, Read};
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    // Answer two requests, echoing the method and the posted body
+    let server = thread::spawn(move || {
+        for _ in 0..2 {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream);
+            let mut request_line = String::new();
+            reader.read_line(&mut request_line).unwrap();
+            let mut content_length = 0;
+            loop {
+                let mut header = String::new();
+                reader.read_line(&mut header).unwrap();
+                if header.trim().is_empty() {
+                    break;
+                }
+                if let Some(len) = header.to_lowercase().strip_prefix("content-length:") {
+                    content_length = len.trim().parse().unwrap();
+                }
+            }
+            let mut body = vec![0u8; content_length];
+            reader.read_exact(&mut body).unwrap();
+            let method = request_line.split(' ').next().unwrap().to_string();
+            let reply = format!("{} {}", method, String::from_utf8(body).unwrap());
+            let status = if method == "GET" { "200 OK" } else { "404 Not Found" };
+            let response = format!("HTTP/1.1 {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}", status, reply.len(), reply);
+            reader.get_mut().write_all(response.as_bytes()).unwrap();
+        }
+    });
+    let code = format!(r#"
+        var got = httpGet("http://127.0.0.1:{0}/");
+        var posted = httpPost("http://127.0.0.1:{0}/", "hello", {{"Content-Type": "text/plain"}});
+        var _result = str(got["status"]) + " " + got["body"] + "|" + str(posted["status"]) + " " + posted["body"];
+    "#, port);
+    let output = run_code(&code);
+    server.join().unwrap();
+    match output {
+        Ok(str) => assert_eq!("200 GET |404 POST hello", str),
+        Err(_) => panic!("Failed")
+    }
+}
+
+#[test]
+#[serial]
+fn test_assert_native() {
+    run_asserts(r#"
+        var xs = [1, 2, 3];
+        assert(len(xs) == 3, "three items");
+        assert(xs[0]);
+        var message = nil;
+        try { assert(xs[0] == 2, "first is " + str(xs[0])); } catch (e) { message = e; }
+        assert(message == "Assertion failed: first is 1 (line 6)", message);
+    "#);
+}
+
+#[test]
+#[serial]
+#[should_panic(expected = "Assertion failed (line 2)")]
+fn test_failing_assert_stops_the_script() {
+    run_asserts("var ok = true;\nassert(nil);\nok = false;");
+}
+
+#[test]
+#[serial]
+fn test_format_native() {
+    run_asserts(r#"
+        assert(format("x={} y={:.2}", 1, 2.345) == "x=1 y=2.35");
+        assert(format("{} has {} items: {}", "cart", 2, [3, 4]) == "cart has 2 items: [3, 4]");
+        assert(format("{{}} {:.0}%", 99.6) == "{} 100%");
+        var error = nil;
+        try { format("{} {}", 1); } catch (e) { error = e; }
+        assert(error == "Too few arguments for the format template.", error);
+        try { format("{}", 1, 2); } catch (e) { error = e; }
+        assert(error == "Too many arguments for the format template.", error);
+        try { format("{:.2}", "a"); } catch (e) { error = e; }
+        assert(error == "Invalid type for format placeholder {:.2}, number expected.", error);
+        assert(len(format("{:.100}", 1)) == 102);
+        try { format("{:.4294967296}", 1); } catch (e) { error = e; }
+        assert(error == "Precision of format placeholder {:.4294967296} is above 100.", error);
+    "#);
+}
+
+#[test]
+#[serial]
+fn test_parse_number_native() {
+    run_asserts(r#"
+        assert(parseNumber("42") == 42);
+        assert(parseNumber(" -1.5 ") == -1.5);
+        assert(parseNumber("1e3") == 1000);
+        assert(parseNumber(str(0.25)) == 0.25);
+        assert(parseNumber("12abc") == nil);
+        assert(parseNumber("") == nil);
+        assert(parseNumber("inf") == nil);
+    "#);
+}
+
+#[test]
+#[serial]
+fn test_monotonic_timers() {
+    run_asserts(r#"
+        var startMillis = monotonicMillis();
+        var startNanos = monotonicNanos();
+        var total = 0;
+        for (var i = 0; i < 1000; i = i + 1) total = total + i;
+        assert(monotonicNanos() > startNanos);
+        assert(monotonicMillis() >= startMillis);
+    "#);
+}
+
+#[test]
+#[serial]
+fn test_unreachable_code_is_dropped() {
+    let code = r#"
+        fun f(x) {
+          if (x) {
+            return 1;
+            print "dead";
+          }
+          return 2;
+        }
+        throw f(true);
+        print "dead too";
+        f(false);
+    "#.to_string();
+    let mut scanner = Scanner::new(&code);
+    let tokens = scanner.scan_tokens();
+    let mut parser = Parser::new(Heap::new(), tokens);
+    parser.print_errors = false;
+    let func_main_idx = parser.compile();
+    assert!(!parser.had_error);
+    assert_eq!(vec![
+        "[line 5] Warning at 'print': Unreachable code.".to_string(),
+        "[line 10] Warning at 'print': Unreachable code.".to_string(),
+    ], parser.warnings);
+    // Main ends with the throw and the implicit return
+    let code = &parser.heap.get_function(func_main_idx).chunk.code;
+    assert_eq!(&[Opcode::Throw.byte(), Opcode::Nil.byte(), Opcode::Return.byte()], &code[code.len() - 3..]);
+}
+
+#[test]
+#[serial]
+fn test_peephole_merges_pops_and_drops_unused_values() {
+    let code = compile_main_code("{ var a = 1; var b = 2; 3; }", true);
+    assert_eq!(vec![
+        Opcode::Constant.byte(), 0,
+        Opcode::Constant.byte(), 1,
+        Opcode::PopN.byte(), 2,
+        Opcode::Nil.byte(), Opcode::Return.byte(),
+    ], code);
+}
+
+#[test]
+#[serial]
+fn test_peephole_fuses_not_into_jump_if_true() {
+    let source = "var i = 0; while (!(i > 3)) i = i + 1;";
+    let optimized = compile_main_code(source, true);
+    let unoptimized = compile_main_code(source, false);
+    assert!(!optimized.contains(&Opcode::Not.byte()));
+    assert!(optimized.contains(&Opcode::JumpIfTrue.byte()));
+    assert_eq!(unoptimized.len() - 1, optimized.len());
+    run_asserts(r#"
+        var i = 0;
+        while (!(i > 3)) i = i + 1;
+        assert(i == 4);
+        // The negated value is the result of and, so it can't be fused
+        assert((!nil and 3) == 3);
+        assert((!1 and 3) == false);
+        fun pick(a, b) {
+          if (!a) { if (!b) return "neither"; else return "b"; } else return "a";
+        }
+        assert(pick(false, false) == "neither");
+        assert(pick(false, true) == "b");
+        assert(pick(true, false) == "a");
+    "#);
+}
+
+#[test]
+#[serial]
+fn test_compile_reports_every_error() {
+    let mut interpreter = Interpreter::new();
+    let source = "var = 1;\nprint (;\nprint 1 = 2;\nclass { }\nprint \"ok\";";
+    match interpreter.compile(source) {
+        Err(KError::Compile(errors)) => assert_eq!(vec![
+            "[line 1] Error at '=': Expect a variable name.\n    var = 1;\n        ^".to_string(),
+            "[line 2] Error at ';': Expect expression\n    print (;\n           ^".to_string(),
+            "[line 3] Error at '=': Invalid assignment target.\n    print 1 = 2;\n            ^".to_string(),
+            "[line 4] Error at '{': Expect a class name.\n    class { }\n          ^".to_string(),
+        ], errors),
+        _ => panic!("Expected compile errors"),
+    }
+}
+
+#[test]
+#[serial]
+fn test_malformed_input_does_not_crash_the_compiler() {
+    let mut interpreter = Interpreter::new();
+    for source in ["(", "1 = 2;", "( print = in", "1 fun _ throw (", "else fun } ; ("] {
+        assert!(matches!(interpreter.compile(source), Err(KError::Compile(_))), "{}", source);
+    }
+    for source in ["", "// only a comment"] {
+        assert!(interpreter.compile(source).is_ok(), "{}", source);
+    }
+}
+
+#[test]
+#[serial]
+fn test_unused_declarations_are_reported() {
+    let source = "fun used(a, _b) { var c = a; var d = 1; return c; }\nfun unused() { }\nclass Empty { }\nvar _ignored = used(1, 2);\nfor (var i = 0; i < 2; i = i + 1) { print i; }";
+    let mut interpreter = Interpreter::new();
+    assert!(interpreter.compile(source).is_ok());
+
+    interpreter.deny_warnings = true;
+    match interpreter.compile(source) {
+        Err(KError::Compile(errors)) => assert_eq!(vec![
+            "[line 1] Warning at 'd': Unused variable 'd'.\n    fun used(a, _b) { var c = a; var d = 1; return c; }\n                                     ^".to_string(),
+            "[line 2] Warning at 'unused': Unused function 'unused'.\n    fun unused() { }\n        ^^^^^^".to_string(),
+            "[line 3] Warning at 'Empty': Unused class 'Empty'.\n    class Empty { }\n          ^^^^^".to_string(),
+        ], errors),
+        _ => panic!("Expected unused declarations to be errors"),
+    }
+}
+
+#[test]
+#[serial]
+fn test_errors_point_at_the_token() {
+    let mut interpreter = Interpreter::new();
+    // Scan errors are reported along with the errors of the parser
+    match interpreter.compile("var ok = 1;\n\tprint ok +;\nvar s = \"a\" @;") {
+        Err(KError::Compile(errors)) => assert_eq!(vec![
+            "[line 2] Error at ';': Expect expression\n    \tprint ok +;\n    \t          ^".to_string(),
+            "[line 3 ] Error  : Unexpected character .\n    var s = \"a\" @;\n                ^".to_string(),
+        ], errors),
+        _ => panic!("Expected compile errors"),
+    }
+    assert_eq!("", source_snippet("one line", 3, 0, 1));
+    assert_eq!("\n    end\n       ^", source_snippet("end", 1, 3, 1));
+}
+
+#[test]
+#[serial]
+#[cfg(feature = "line-tracking")]
+fn test_chunk_lines_are_run_length_encoded() {
+    let mut chunk = Chunk::new();
+    for line in [1, 1, 1, 2, 2, 5] {
+        chunk.code(Opcode::Nil.byte(), line);
+    }
+    assert_eq!(vec![(1, 3), (2, 2), (5, 1)], chunk.lines);
+    assert_eq!(1, chunk.line_for_offset(2));
+    assert_eq!(2, chunk.line_for_offset(3));
+    assert_eq!(5, chunk.line_for_offset(5));
+    assert_eq!(0, chunk.line_for_offset(6));
+
+    chunk.truncate(4);
+    assert_eq!(vec![(1, 3), (2, 1)], chunk.lines);
+    chunk.code(Opcode::Nil.byte(), 2);
+    assert_eq!(vec![(1, 3), (2, 2)], chunk.lines);
+}
+
+#[test]
+#[serial]
+fn test_chunk_constants_are_deduplicated() {
+    let mut chunk = Chunk::new();
+    let mut heap = Heap::new();
+    let name = Value::object(Object::string(heap.alloc_string("name".to_string())));
+    assert_eq!(0, chunk.add_constants(name));
+    assert_eq!(1, chunk.add_constants(Value::number(0.0)));
+    assert_eq!(2, chunk.add_constants(Value::number(-0.0)));
+    assert_eq!(0, chunk.add_constants(name));
+    assert_eq!(1, chunk.add_constants(Value::number(0.0)));
+    assert_eq!(3, chunk.add_constants(Value::nil()));
+    assert_eq!(4, chunk.constants.len());
+}
+
+#[test]
+#[serial]
+fn test_or_jumps_when_true() {
+    let code = compile_main_code("var a = nil; var b = a or 2;", false);
+    assert!(code.contains(&Opcode::JumpIfTrue.byte()));
+    assert!(!code.contains(&Opcode::JumpIfFalse.byte()));
+    assert!(!code.contains(&Opcode::Jump.byte()));
+    run_asserts(r#"
+        assert((nil or 2) == 2);
+        assert((false or nil) == nil);
+        assert((1 or 2) == 1);
+        var calls = 0;
+        fun touch() { calls = calls + 1; return true; }
+        assert((true or touch()) == true);
+        assert(calls == 0);
+        assert((false or false or touch()) == true);
+        assert(calls == 1);
+    "#);
+}
+
+#[test]
+#[serial]
+fn test_closed_scopes_free_their_local_slots() {
+    let source = "{ var a = 1; print a; } { var b = 2; print b; } for (var i in [1]) print i; for (var j in [1]) print j;";
+    let code = compile_main_code(source, false);
+    let slots: Vec<u8> = code.windows(2)
+        .filter(|pair| pair[0] == Opcode::GetLocal.byte())
+        .map(|pair| pair[1])
+        .collect();
+    // Both blocks use slot 1, both loops the same slots above their hidden sequence and index
+    assert_eq!(vec![1, 1, 3, 4, 3, 4], slots);
+}
+
+#[test]
+#[serial]
+fn test_closures_capture_past_slot_255() {
+    // Every local of outer is captured by inner, and the last ones again by
+    // innermost through upvalue indexes past 255
+    let count = 300;
+    let declarations: String = (0..count).map(|i| format!("var v{} = 1;", i)).collect();
+    let sum: Vec<String> = (0..count).map(|i| format!("v{}", i)).collect();
+    let source = format!(r#"
+        fun outer() {{
+          {}
+          fun inner() {{
+            v299 = v299 + 1;
+            fun innermost() {{ return v299 + v298; }}
+            return [{}, innermost()];
+          }}
+          return inner;
+        }}
+        var result = outer()();
+        assert(result[0] == {});
+        assert(result[1] == 3);
+    "#, declarations, sum.join(" + "), count + 1);
+    run_asserts(&source);
+}
+
+#[test]
+#[serial]
+fn test_scanner_handles_multi_byte_characters() {
+    let source = "var s = \"héllo ✓\"; // ünïcode\nprint s;".to_string();
+    let tokens = Scanner::new(&source).scan_tokens();
+    let lexemes: Vec<&str> = tokens.iter().map(|token| &*token.lexeme).collect();
+    assert_eq!(vec!["var", "s", "=", "\"héllo ✓\"", ";", "print", "s", ";", ""], lexemes);
+    assert_eq!("héllo ✓", &*tokens[3].literal);
+    assert_eq!(6, tokens[6].column);
+    run_asserts(r#"
+        var greeting = "日本" + "語";
+        assert(greeting == "日本語");
+        assert(len(greeting) == 3);
+    "#);
+}
+
+#[test]
+#[serial]
+fn test_parser_reads_tokens_from_the_scanner() {
+    let mut scanner = Scanner::new(&"fun add(a, b) { return a + b; } print add(1, 2);".to_string());
+    scanner.print_errors = false;
+    let mut parser = Parser::new(Heap::new(), scanner);
+    parser.print_errors = false;
+    parser.compile();
+    assert!(!parser.had_error);
+
+    let mut scanner = Scanner::new(&"var a = 1 #;\nprint a;".to_string());
+    scanner.print_errors = false;
+    let mut parser = Parser::new(Heap::new(), scanner);
+    parser.print_errors = false;
+    parser.compile();
+    assert!(parser.had_error);
+    assert_eq!(1, parser.errors.len());
+    assert!(parser.errors[0].contains("Unexpected character"));
+}
+
+#[test]
+#[serial]
+fn test_undefined_globals_are_reported() {
+    let source = "fun show(x) { pritn(x); pritn(x); return len(x) + later + readFile; }\nvar later = 1;\nshow(args);";
+    let mut interpreter = Interpreter::new();
+    interpreter.deny_warnings = true;
+    assert!(interpreter.compile(source).is_ok());
+
+    interpreter.warn_undefined_globals = true;
+    match interpreter.compile(source) {
+        Err(KError::Compile(errors)) => {
+            assert_eq!(1, errors.len());
+            assert!(errors[0].starts_with("[line 1] Warning at 'pritn': Undefined variable 'pritn'."));
+        }
+        _ => panic!("Expected pritn to be undefined"),
+    }
+
+    // Globals from earlier evaluations count as defined
+    interpreter.eval("fun pritn(x) { } pritn(1);").unwrap();
+    assert!(interpreter.compile(source).is_ok());
+}
+
+#[test]
+#[serial]
+fn test_syntax_tree_emits_the_single_pass_code() {
+    let source = r#"
+        const limit = 3;
+        var total = 0;
+        fun add(a, b, ...rest) { return a + b + len(rest); }
+        fun counter() {
+          var count = 0;
+          fun next() { count += 1; return count; }
+          return next;
+        }
+        class Shape {
+          init(name) { this.name = name; this.sides = 0; }
+          describe() { return this.name ?? "shape"; }
+        }
+        class Square extend Shape {
+          init() { super.init("square"); ++this.sides; this.sides++; }
+          describe() { return "a " + super.describe(); }
+        }
+        class Sized { const UNIT = "cm"; abstract size(unit, ...rest); const MAX = limit * 2; }
+        for (var i = 0; i < limit; i = i + 1) { total = total + add(i, 1); }
+        for (var item in [1, 2, 3]) { if (item == 2 and total > 0 or !false) total -= item; else { total++; } }
+        while (total > 100) { --total; }
+        try { throw "oops"; } catch (error) { print error; }
+        var point = {"x": 1, "y": -2};
+        point["x"] = point["y"];
+        print add(...[1, 2], 3) + add(a: 1, b: 2);
+        print match ([1, 2, 3]) {
+          [1, ...others] if len(others) > 1 => others,
+          Square { sides: 4 } => "square",
+          -1 => "minus one",
+          _ => nil
+        };
+        print counter()() + Square().describe();
+    "#;
+    assert_eq!(compile_functions(source, true), compile_functions(source, false));
+}
+
+#[test]
+#[serial]
+fn test_check_syntax_reports_positioned_errors() {
+    let source = "var a = 1;\nprint a +;\nvar s = \"open";
+    assert_eq!(vec![
+        Diagnostic::new(Severity::Error, 2, 9, "Expect expression"),
+        Diagnostic::new(Severity::Error, 3, 8, "Unterminated string."),
+        Diagnostic::new(Severity::Error, 3, 13, "Expect expression"),
+    ], Interpreter::check_syntax(source));
+
+    // Only the syntax is checked
+    assert!(Interpreter::check_syntax("const a = 1; a = 2; var unused;").is_empty());
+
+    let diagnostic = Diagnostic::new(Severity::Warning, 3, 4, "Say \"hi\"\n");
+    assert_eq!(r#"{"file": "dir\\a.ks", "line": 3, "column": 4, "severity": "warning", "message": "Say \"hi\"\n"}"#,
+               diagnostic.to_json("dir\\a.ks"));
+}
+
+#[test]
+#[serial]
+fn test_format_source() {
+    let source = r#"// header comment
+
+/* block
+   comment */
+var   x=1;   // trailing
+var m = {
+  // first key
+  "a": [1,2,
+        3],
+  "b": match (x) {1=>"one", _ => "other"} // after b
+};
+
+
+fun f(a,b,...rest){return a+b;}
+class A extend B{
+  // a method
+  go(){ super.go(); }
+
+  stop(){}
+}
+if(x>1)print x;else if(x<0){print -x;}else print - -x;
+try{throw "e";}catch(e){
+  print e;
+  // end of handler
+}
+var r = match ([1,2]) {
+  [a, ...others] if a > 0 => others,
+  A { x, y: -1 } => "p",
+  -2 => nil
+};
+for(;;){}
+while(true) { x++; --m.a; m.b--; }
+print f(...[1], 2) + f(1, b: "n");
+// tail comment
+"#;
+    let expected = r#"// header comment
+
+/* block
+   comment */
+var x = 1; // trailing
+var m = {
+    // first key
+    "a": [1, 2, 3],
+    "b": match (x) { 1 => "one", _ => "other" } // after b
+};
+
+fun f(a, b, ...rest) {
+    return a + b;
+}
+class A extend B {
+    // a method
+    go() {
+        super.go();
+    }
+
+    stop() {}
+}
+if (x > 1) print x;
+else if (x < 0) {
+    print -x;
+} else print - -x;
+try {
+    throw "e";
+} catch (e) {
+    print e;
+    // end of handler
+}
+var r = match ([1, 2]) {
+    [a, ...others] if a > 0 => others,
+    A { x, y: -1 } => "p",
+    -2 => nil
+};
+for (;;) {}
+while (true) {
+    x++;
+    --m.a;
+    m.b--;
+}
+print f(...[1], 2) + f(1, b: "n");
+// tail comment
+"#;
+    assert_eq!(expected, format_source(source).unwrap());
+    assert_eq!(expected, format_source(expected).unwrap());
+    assert_eq!(compile_functions(source, false), compile_functions(expected, false));
+
+    // Reformatting the example scripts changes nothing but the layout
+    for entry in fs::read_dir("script").unwrap() {
+        let source = fs::read_to_string(entry.unwrap().path()).unwrap();
+        let formatted = format_source(&source).unwrap();
+        assert_eq!(compile_functions(&source, false), compile_functions(&formatted, false));
+        assert_eq!(formatted, format_source(&formatted).unwrap());
+    }
+
+    assert!(format_source("print (1;").unwrap_err()[0].contains("Expect ')' after expression."));
+}
+
+#[test]
+#[serial]
+fn test_unified_diff() {
+    let old = "a\nb\nc\nd\ne\nf\ng\nh\ni\nj\nk\nl\n";
+    let new = "a\nB\nc\nd\ne\nf\ng\nh\ni\nj\nk\nl\nm\n";
+    assert_eq!("--- old\n+++ new\n\
+                @@ -1,5 +1,5 @@\n a\n-b\n+B\n c\n d\n e\n\
+                @@ -10,3 +10,4 @@\n j\n k\n l\n+m\n",
+               unified_diff(old, new, "old", "new"));
+    assert_eq!("", unified_diff(old, old, "old", "new"));
+}
+
+#[test]
+#[serial]
+fn test_token_rows() {
+    let rows: Vec<String> = Scanner::new(&"x += \"a b\"; ?".to_string()).map(|token| debug::token_row(&token)).collect();
+    assert_eq!(vec![
+        "    1 |    0 | Identifier       | \"x\"",
+        "    1 |    2 | PlusEqual        | \"+=\"",
+        "    1 |    5 | String           | \"\\\"a b\\\"\"",
+        "    1 |   10 | Semicolon        | \";\"",
+        "    1 |   12 | Error            | \"Expect '?' after '?'.\"",
+        "    1 |   13 | Eof              | \"\"",
+    ], rows);
+}
+
+#[test]
+#[serial]
+fn test_function_listing() {
+    let source = r#"
+fun add(a) {
+  fun inner(b) { return a + b; }
+  return inner;
+}
+class P { go(x) { return x; } }
+print add(1)(2) + P().go(3);"#.trim_start();
+    let expected = "\
+main
+Loc  | Line  | Instruction          | Const  | Values
+   0 |     4 | op_closure     1    <fn add>
+   2 |     4 | op_define_declaration |      0 | add
+   4 |     5 | op_class             |      2 | P
+   6 |     5 | op_define_declaration |      2 | P
+   8 |     5 | op_get_global        |      2 | P
+  10 |     5 | op_closure     4     <fn go>
+  12 |     5 | op_method            |      3 | go
+  14 |     5 | op_pop
+  15 |     6 | op_get_global        |      0 | add
+  17 |     6 | op_constant          |      5 | 1
+  19 |     6 | op_call              |      1 |
+  21 |     6 | op_constant          |      6 | 2
+  23 |     6 | op_call              |      1 |
+  25 |     6 | op_get_global        |      2 | P
+  27 |     6 | op_call              |      0 |
+  29 |     6 | op_constant          |      7 | 3
+  31 |     6 | op_invoke            |      1 |    3 go
+  34 |     6 | op_add
+  35 |     6 | op_print
+  36 |     6 | op_nil
+  37 |     6 | op_return
+
+add
+Loc  | Line  | Instruction          | Const  | Values
+   0 |     2 | op_closure     0  <fn inner>
+   2           | local 1
+   4 |     3 | op_get_local         |      2 |
+   6 |     3 | op_return
+   7 |     4 | op_nil
+   8 |     4 | op_return
+
+inner
+Loc  | Line  | Instruction          | Const  | Values
+   0 |     2 | op_get_upvalue       |      0 |
+   2 |     2 | op_get_local         |      1 |
+   4 |     2 | op_add
+   5 |     2 | op_return
+   6 |     2 | op_nil
+   7 |     2 | op_return
+
+go
+Loc  | Line  | Instruction          | Const  | Values
+   0 |     5 | op_get_local         |      1 |
+   2 |     5 | op_return
+   3 |     5 | op_nil
+   4 |     5 | op_return
+";
+    assert_eq!(expected, Interpreter::new().listing(source).unwrap());
+}
+
+#[test]
+#[serial]
+fn test_instance_shapes() {
+    run_asserts(r#"
+        class Point {
+            init(x, y) { this.x = x; this.y = y; }
+            sum() { return this.x + this.y; }
+        }
+        class Other {}
+        fun getX(p) { return p.x; }
+        var points = [Point(1, 2), Point(3, 4)];
+        var other = Other();
+        other.y = 10;
+        other.x = 20;
+        // Same call site sees instances of different shapes
+        var total = 0;
+        for (var i = 0; i < 3; i++) {
+            total = total + getX(points[0]) + getX(other) + getX(points[1]);
+        }
+        assert(total == 72, str(total));
+        points[0].x = 5;
+        assert(points[0].sum() == 7);
+        // A field shadows the method of the same name
+        fun field() { return "field"; }
+        points[1].sum = field;
+        assert(points[1].sum() == "field");
+        assert(points[0].sum() == 7);
+    "#);
+
+    let vm = compile_and_run(&r#"
+        class Point { init(x, y) { this.x = x; this.y = y; } }
+        var a = Point(1, 2);
+        var b = Point(3, 4);
+        var c = Point(5, 6);
+        c.z = 7;
+    "#.to_string());
+    let instances: Vec<_> = vm.heap.instances.live_indexes().map(|idx| vm.heap.get_instance(idx).shape).collect();
+    assert_eq!(3, instances.len());
+    assert_eq!(instances[0], instances[1]);
+    assert_ne!(instances[0], instances[2]);
+
+    // Fields past the slots spill into the map and stay readable
+    let mut code = "class Bag {}\nvar bag = Bag();\n".to_string();
+    for i in 0..100 {
+        code.push_str(&format!("bag.f{} = {};\n", i, i));
+    }
+    code.push_str("bag.f3 = 33;\nbag.f90 = 99;\nvar _result = bag.f0 + bag.f3 + bag.f63 + bag.f64 + bag.f90 + bag.f99;");
+    assert_eq!("358", run_code_stressed(&code).unwrap());
+}
+
+#[test]
+#[serial]
+fn test_introspection_natives() {
+    run_asserts(r#"
+        class Animal {
+            init(name) { this.name = name; }
+            speak() { return "..."; }
+        }
+        class Dog extend Animal {
+            speak() { return "Woof"; }
+            fetch() { return "ball"; }
+        }
+        var dog = Dog("rex");
+        dog.age = 3;
+        assert(className(dog) == "Dog");
+        assert(className(Animal) == "Animal");
+        var names = fields(dog);
+        assert(len(names) == 2 and names[0] == "name" and names[1] == "age", str(names));
+        assert(len(fields(Animal("cat"))) == 1);
+        var dogMethods = methods(Dog);
+        assert(len(dogMethods) == 3, str(dogMethods));
+        assert(dogMethods[0] == "fetch" and dogMethods[1] == "init" and dogMethods[2] == "speak");
+        assert(str(methods(dog)) == str(dogMethods));
+        assert(hasField(dog, "age"));
+        assert(!hasField(dog, "speak"));
+        assert(!hasField(dog, "color"));
+
+        var error = nil;
+        try { fields(1); } catch (e) { error = e; }
+        assert(error == "Invalid type for fields, instance expected.", error);
+        try { className("x"); } catch (e) { error = e; }
+        assert(error == "Invalid type for className, instance or class expected.", error);
+    "#);
+}
+
+#[test]
+#[serial]
+fn test_eq_method() {
+    run_asserts(r#"
+        class Point {
+            init(x, y) { this.x = x; this.y = y; }
+            eq(other) { return type(other) == "Point" and this.x == other.x and this.y == other.y; }
+        }
+        class Plain {}
+        assert(Point(1, 2) == Point(1, 2));
+        assert(Point(1, 2) != Point(2, 1));
+        assert(Point(1, 2) != 3);
+        var plain = Plain();
+        assert(plain == plain);
+        assert(Plain() != Plain());
+        var calls = 0;
+        for (var i = 0; i < 3; i++) {
+            if (Point(i, i) == Point(1, 1)) calls = calls + 1;
+        }
+        assert(calls == 1);
+
+        class Broken { eq(other) { return 1; } }
+        var error = nil;
+        try { Broken() == Broken(); } catch (e) { error = e; }
+        assert(error == "eq must return true or false.", error);
+        class Throwing { eq(other) { throw "no comparing"; } }
+        try { Throwing() == 1; } catch (e) { error = e; }
+        assert(error == "no comparing", error);
+    "#);
+}
+
+#[test]
+#[serial]
+fn test_hash_method_map_keys() {
+    run_asserts(r#"
+        var hashCalls = 0;
+        class Point {
+            init(x, y) { this.x = x; this.y = y; }
+            hash() { hashCalls = hashCalls + 1; return this.x * 31 + this.y; }
+            eq(other) { return type(other) == "Point" and this.x == other.x and this.y == other.y; }
+        }
+        // Every key hashes the same, so eq tells them apart
+        class Clash {
+            init(id) { this.id = id; }
+            hash() { return 1; }
+            eq(other) { return this.id == other.id; }
+        }
+        class Plain {}
+
+        var grid = {Point(0, 0): "origin"};
+        grid[Point(1, 2)] = "a";
+        grid[Point(1, 2)] = "b";
+        assert(len(grid) == 2, str(len(grid)));
+        assert(grid[Point(0, 0)] == "origin");
+        assert(grid[Point(1, 2)] == "b");
+        assert(grid[Point(2, 1)] == nil);
+        assert(hashCalls == 6, str(hashCalls));
+
+        var clashes = {};
+        for (var i = 0; i < 5; i++) clashes[Clash(i)] = i;
+        assert(len(clashes) == 5);
+        assert(clashes[Clash(3)] == 3);
+
+        var plain = Plain();
+        var byIdentity = {plain: 1};
+        assert(byIdentity[plain] == 1);
+        assert(byIdentity[Plain()] == nil);
+
+        class BadHash { hash() { return "h"; } }
+        var error = nil;
+        try { grid[BadHash()] = 1; } catch (e) { error = e; }
+        assert(error == "hash must return a number.", error);
+    "#);
+
+    // Collections while hash and eq run keep the map and its keys alive
+    let code = r#"
+        class Key {
+            init(n) { this.n = n; }
+            hash() { var garbage = [this.n, "x" + str(this.n)]; return len(garbage); }
+            eq(other) { var garbage = {"n": other.n}; return this.n == other.n; }
+        }
+        var map = {Key(1): 1, Key(2): 2};
+        for (var i = 0; i < 20; i++) map[Key(i)] = i * 2;
+        var _result = str(len(map)) + " " + str(map[Key(7)]);
+    "#.to_string();
+    assert_eq!("20 14", run_code_stressed(&code).unwrap());
+}
+
+#[test]
+#[serial]
+fn test_abstract_methods() {
+    run_asserts(r#"
+        class Shape {
+            abstract area();
+            abstract name(verbose);
+            describe() { return this.name(false) + " " + str(this.area()); }
+        }
+        class Square extend Shape {
+            init(side) { this.side = side; }
+            area() { return this.side * this.side; }
+            name(verbose) { return "square"; }
+        }
+        class Unnamed extend Shape {
+            area() { return 0; }
+        }
+        assert(Square(3).describe() == "square 9");
+        assert(str(methods(Shape)) == str(["describe"]));
+
+        // Through a parameter the compiler can't tell which class it gets
+        fun make(cls) { return cls(); }
+        var error = nil;
+        try { make(Shape); } catch (e) { error = e; }
+        assert(error == "Cannot instantiate abstract class Shape, missing methods: area, name.", error);
+        try { make(Unnamed); } catch (e) { error = e; }
+        assert(error == "Cannot instantiate abstract class Unnamed, missing methods: name.", error);
+    "#);
+
+    let source = "class Shape { abstract area(); }\nclass Circle extend Shape {}\nclass Dot extend Shape { area() { return 0; } }\nDot();\nvar c = Circle();";
+    for single_pass in [false, true] {
+        let mut interpreter = Interpreter::new();
+        interpreter.single_pass = single_pass;
+        match interpreter.compile(source) {
+            Err(KError::Compile(errors)) => assert_eq!(vec![
+                "[line 5] Error at 'Circle': Cannot instantiate abstract class Circle, missing methods: area.\n    var c = Circle();\n            ^^^^^^".to_string(),
+            ], errors, "single pass: {}", single_pass),
+            _ => panic!("Expected compile errors, single pass: {}", single_pass),
+        }
+    }
+    let mut interpreter = Interpreter::new();
+    match interpreter.compile("class Shape { abstract init(); }") {
+        Err(KError::Compile(errors)) => assert!(errors[0].contains("An initializer can't be abstract."), "{:?}", errors),
+        _ => panic!("Expected compile errors"),
+    }
+    let formatted = format_source("class Shape {\n    // Area in square units\n    abstract area(unit);\n\n    abstract  name( ) ; // Shown to users\n}\n").unwrap();
+    assert_eq!("class Shape {\n    // Area in square units\n    abstract area(unit);\n\n    abstract name(); // Shown to users\n}\n", formatted);
+}
+
+#[test]
+#[serial]
+fn test_class_constants() {
+    run_asserts(r#"
+        fun double(x) { return x * 2; }
+        class Circle {
+            const PI = 3.14159;
+            init(r) { this.r = r; }
+            const TAU = Circle.PI * 2;
+            const scale = double;
+            area() { return Circle.PI * this.r * this.r; }
+        }
+        class Ring extend Circle {}
+        assert(Circle.PI == 3.14159);
+        assert(Circle.TAU == 6.28318);
+        assert(Circle(2).area() == 12.56636);
+        assert(Ring.PI == Circle.PI);
+        assert(Circle.scale(4) == 8);
+        var error = nil;
+        try { print Circle.E; } catch (e) { error = e; }
+        assert(error == "Undefined constant 'E'", error);
+    "#);
+    let formatted = format_source("class Circle{const PI=3.14;\n\n  area(){return Circle.PI;}\n  const E = 2.71; // Euler\n}\n").unwrap();
+    assert_eq!("class Circle {\n    const PI = 3.14;\n\n    area() {\n        return Circle.PI;\n    }\n    const E = 2.71; // Euler\n}\n", formatted);
+}
+
+#[test]
+#[serial]
+fn test_super_init_chaining() {
+    run_asserts(r#"
+        class Animal {
+            init(name) { this.name = name; this.legs = 4; }
+        }
+        class Dog extend Animal {
+            init(name) { super.init(name); this.sound = "woof"; }
+        }
+        class Puppy extend Dog {}
+        var puppy = Puppy("rex");
+        assert(puppy.name == "rex" and puppy.legs == 4 and puppy.sound == "woof");
+    "#);
+
+    let source = r#"
+        class Animal { init(name) { this.name = name; } }
+        class Dog extend Animal { init() { this.name = "dog"; } }
+        class Cat extend Animal { init() { fun later() { super.init("cat"); } later(); } }
+        class Puppy extend Dog { init() { super.init(); } }
+        class Named extend Animal {}
+        class Stray extend Named { init() {} }
+        class Quiet { init() {} }
+        class Mute extend Quiet { init() {} }
+        print Cat().name + Puppy().name + Stray().name + Mute();
+    "#;
+    for single_pass in [false, true] {
+        let mut parser = Parser::new(Heap::new(), Scanner::new(&source.to_string()));
+        parser.print_errors = false;
+        parser.single_pass = single_pass;
+        parser.compile();
+        assert!(!parser.had_error, "{:?}", parser.errors);
+        assert_eq!(vec![
+            "[line 3] Warning at 'init': init never calls super.init, the init of Animal takes 1 argument(s).".to_string(),
+            "[line 7] Warning at 'init': init never calls super.init, the init of Named takes 1 argument(s).".to_string(),
+        ], parser.warnings, "single pass: {}", single_pass);
+    }
+}
+
+
+#[test]
+#[serial]
+fn test_clone_native() {
+    run_asserts(r#"
+        class Point {
+            init(x, y) { this.x = x; this.y = y; }
+            eq(other) { return this.x == other.x and this.y == other.y; }
+            hash() { return this.x; }
+        }
+        var p = Point(1, [2]);
+        var q = clone(p);
+        assert(type(q) == "Point");
+        q.x = 5;
+        assert(p.x == 1);
+        q.y[0] = 3;
+        assert(p.y[0] == 3);
+        var r = clone(p, true);
+        r.y[0] = 4;
+        assert(p.y[0] == 3);
+
+        var xs = [1, [2]];
+        var ys = clone(xs);
+        ys[0] = 9;
+        assert(xs[0] == 1 and ys[1] == xs[1]);
+        var zs = clone(xs, true);
+        assert(zs[1] != xs[1] and zs[1][0] == 2);
+
+        var shared = [0];
+        var pair = [shared, shared];
+        pair[1][0] = pair;
+        var copy = clone(pair, true);
+        assert(copy[0] == copy[1] and copy[0] != shared);
+        assert(copy[0][0] == copy);
+
+        var grid = {Point(1, 2): "a"};
+        var grid2 = clone(grid, true);
+        grid2[Point(1, 2)] = "b";
+        assert(grid[Point(1, 2)] == "a" and grid2[Point(1, 2)] == "b");
+        assert(len(grid2) == 1);
+
+        assert(clone(1) == 1 and clone("s") == "s" and clone(nil) == nil);
+        var error = nil;
+        try { clone(xs, 1); } catch (e) { error = e; }
+        assert(error == "Invalid type for clone deep, true or false expected.", error);
+    "#);
+
+    let code = r#"
+        var tree = [];
+        for (var i = 0; i < 20; i++) tree = [tree, {"n": i, "s": "x" + str(i)}];
+        var copy = clone(tree, true);
+        var n = 0;
+        while (len(copy) > 0) { n = n + copy[1]["n"]; copy = copy[0]; }
+        var _result = str(n);
+    "#.to_string();
+    match run_code_stressed(&code) {
+        Ok(str) => assert_eq!("190", str),
+        Err(_) => panic!("Failed")
+    }
+}
+
+#[test]
+#[serial]
+fn test_method_missing() {
+    run_asserts(r#"
+        class Recorder {
+            init() { this.calls = ""; }
+            record() { return this.calls; }
+            methodMissing(name, args) {
+                this.calls = this.calls + name + " ";
+                return args;
+            }
+        }
+        var recorder = Recorder();
+        var args = recorder.move(1, "up");
+        assert(len(args) == 2 and args[0] == 1 and args[1] == "up", str(args));
+        assert(len(recorder.stop()) == 0);
+        assert(recorder.record() == "move stop ", recorder.calls);
+        // Reading a property isn't a call, so it doesn't reach methodMissing
+        var error = nil;
+        try { recorder.speed; } catch (e) { error = e; }
+        assert(error == "Undefined property 'speed'", error);
+        assert(recorder.calls == "move stop ", recorder.calls);
+
+        class Proxy {
+            init(target) { this.target = target; }
+            methodMissing(name, args) {
+                if (name == "area") return this.target.area();
+                throw "No " + name;
+            }
+        }
+        class Square {
+            init(side) { this.side = side; }
+            area() { return this.side * this.side; }
+        }
+        class LoggedProxy extend Proxy {
+            area() { return super.area() + 1; }
+        }
+        assert(Proxy(Square(3)).area() == 9);
+        assert(LoggedProxy(Square(3)).area() == 10);
+        error = nil;
+        try { Proxy(Square(3)).perimeter(); } catch (e) { error = e; }
+        assert(error == "No perimeter", error);
+
+        class Plain {}
+        try { Plain().missing(); } catch (e) { error = e; }
+        assert(error == "Undefined property 'missing'", error);
+    "#);
+}
+
+#[test]
+#[serial]
+fn test_ffi_signatures() {
+    assert_eq!(Ok(Signature { ret: CType::Double, params: vec![CType::Double, CType::Int] }), parse_signature("double(double, int)"));
+    assert_eq!(Ok(Signature { ret: CType::Long, params: vec![] }), parse_signature("long(void)"));
+    assert_eq!(Ok(Signature { ret: CType::Void, params: vec![] }), parse_signature("void()"));
+    assert_eq!(Err("Unknown type 'char' in ffiCall signature.".to_string()), parse_signature("int(char)"));
+    assert_eq!(Err("Invalid ffiCall signature 'int', expected a form like double(double, int).".to_string()), parse_signature("int"));
+    assert!(parse_signature("int(int, int, int, int, int, int, int)").is_err());
+    assert!(parse_signature("int(double, double, double, double, double, double, double, double, double)").is_err());
+
+    let mut interpreter = Interpreter::new();
+    interpreter.vm.output = Box::new(io::sink());
+    interpreter.vm.capabilities = Capabilities::none();
+    match interpreter.eval(r#"loadLibrary("libm.so.6");"#) {
+        Err(KError::Runtime(message)) => assert_eq!("loadLibrary is not available, foreign function access is disabled.", message),
+        _ => panic!("loadLibrary ran in the sandbox"),
+    }
+}
+
+#[test]
+#[serial]
+#[cfg(all(feature = "ffi", target_os = "linux", any(target_arch = "x86_64", target_arch = "aarch64")))]
+fn test_ffi_call() {
+    run_asserts(r#"
+        var libm = loadLibrary("libm.so.6");
+        var libc = loadLibrary("libc.so.6");
+        assert(ffiCall(libm, "cos", "double(double)", [0]) == 1);
+        assert(ffiCall(libm, "pow", "double(double, double)", [2, 10]) == 1024);
+        assert(ffiCall(libm, "ldexp", "double(double, int)", [3, 4]) == 48);
+        assert(ffiCall(libc, "abs", "int(int)", [-7]) == 7);
+        assert(ffiCall(libc, "strlen", "long(string)", ["hello"]) == 5);
+        assert(ffiCall(libc, "atoi", "int(string)", ["-42"]) == -42);
+        assert(ffiCall(libc, "strchr", "string(string, int)", ["key=value", 61]) == "=value");
+        assert(ffiCall(libc, "strchr", "string(string, int)", ["key", 61]) == nil);
+
+        var error = nil;
+        try { ffiCall(libc, "no_such_function_here", "void()", []); } catch (e) { error = e; }
+        assert(error != nil);
+    "#);
+}
+
+#[test]
+#[serial]
+fn test_user_data() {
+    struct Counter {
+        count: f64,
+        dropped: Rc<RefCell<bool>>,
+    }
+    impl Drop for Counter {
+        fn drop(&mut self) {
+            *self.dropped.borrow_mut() = true;
+        }
+    }
+    fn add(_heap: &mut Heap, data: &mut dyn Any, args: Vec<Value>) -> Result<Value, NativeError> {
+        let counter = data.downcast_mut::<Counter>().unwrap();
+        match args.as_slice() {
+            [Value::Number(n)] => counter.count += n,
+            _ => return Err(NativeError::new("add expects a number.")),
+        }
+        return Ok(Value::number(counter.count));
+    }
+    fn label(heap: &mut Heap, data: &mut dyn Any, _args: Vec<Value>) -> Result<Value, NativeError> {
+        let counter = data.downcast_ref::<Counter>().unwrap();
+        let hash = heap.alloc_string(format!("count {}", counter.count));
+        return Ok(Value::object(Object::string(hash)));
+    }
+
+    let dropped = Rc::new(RefCell::new(false));
+    let mut interpreter = Interpreter::new();
+    interpreter.vm.heap.stress = true;
+    let counter_type = interpreter.vm.register_user_type("Counter", &[("add", add), ("label", label)]);
+    let counter = interpreter.vm.new_user_data(counter_type, Box::new(Counter { count: 0.0, dropped: dropped.clone() }));
+    interpreter.vm.set_global("counter", counter);
+    interpreter.eval(r#"
+        counter.add(2);
+        assert(counter.add(3) == 5);
+        assert(counter.label() == "count 5");
+        assert(type(counter) == "Counter");
+        assert(str(counter) == "<Counter>", str(counter));
+        var error = nil;
+        try { counter.add("x"); } catch (e) { error = e; }
+        assert(error == "add expects a number.", error);
+        try { counter.reset(); } catch (e) { error = e; }
+        assert(error == "Undefined property 'reset'", error);
+        var same = counter;
+        assert(same == counter);
+    "#).unwrap();
+    assert_eq!(Some(5.0), interpreter.vm.heap.get_user_data(counter.as_user_data_index()).get::<Counter>().map(|c| c.count));
+    assert!(!*dropped.borrow());
+
+    interpreter.eval("counter = nil; same = nil; var garbage = [1];").unwrap();
+    assert!(*dropped.borrow());
+}
+
+#[test]
+#[serial]
+#[cfg(feature = "json")]
+fn test_json_values() {
+    let mut interpreter = Interpreter::new();
+    interpreter.vm.heap.stress = true;
+    let config: serde_json::Value = serde_json::from_str(r#"{"name": "demo", "size": 3, "tags": ["a", "b"], "debug": false, "extra": null}"#).unwrap();
+    let config = Value::from_json(&config, &mut interpreter.vm.heap);
+    interpreter.vm.set_global("config", config);
+    interpreter.eval(r#"
+        assert(config["name"] == "demo" and config["size"] == 3);
+        assert(len(config["tags"]) == 2 and config["tags"][1] == "b");
+        assert(config["debug"] == false and config["extra"] == nil);
+        assert(str(keys(config)) == str(["name", "size", "tags", "debug", "extra"]));
+        class Result {
+            init(total) { this.total = total; this.items = [1, 2.5]; this.meta = {"ok": true}; }
+        }
+    "#).unwrap();
+    let result = interpreter.eval("Result(config[\"size\"] * 2)").unwrap();
+    let json = result.to_json(&interpreter.vm.heap).unwrap();
+    assert_eq!(r#"{"total":6,"items":[1,2.5],"meta":{"ok":true}}"#, json.to_string());
+
+    interpreter.eval("var xs = [1]; xs[0] = xs; var ys = [0];").unwrap();
+    let cyclic = interpreter.eval("xs").unwrap();
+    assert_eq!(Err("Value contains itself and can't be converted to JSON.".to_string()), cyclic.to_json(&interpreter.vm.heap));
+    let shared = interpreter.eval("[ys, ys]").unwrap();
+    assert_eq!("[[0],[0]]", shared.to_json(&interpreter.vm.heap).unwrap().to_string());
+    let numbers = interpreter.eval("{1: 2}").unwrap();
+    assert_eq!(Err("Only maps with string keys can be converted to JSON.".to_string()), numbers.to_json(&interpreter.vm.heap));
+    let function = interpreter.eval("clock").unwrap();
+    assert!(function.to_json(&interpreter.vm.heap).is_err());
+}
+
+#[test]
+#[serial]
+fn test_semantic_tokens() {
+    use crate::highlight::{semantic_tokens, SemanticKind::*};
+    let source = "// greet\nfun greet(name) {\n  print \"hi \" + name; /* two\n lines */ return 1.5;\n}\nvar s = \"a\nb\";";
+    let spans: Vec<(usize, usize, usize, &str)> = semantic_tokens(source).iter()
+        .map(|token| (token.line, token.column, token.length, token.kind.name()))
+        .collect();
+    assert_eq!(vec![
+        (1, 0, 8, "comment"),
+        (2, 0, 3, "keyword"), (2, 4, 5, "identifier"), (2, 10, 4, "identifier"),
+        (3, 2, 5, "keyword"), (3, 8, 5, "string"), (3, 16, 4, "identifier"), (3, 22, 6, "comment"),
+        (4, 0, 9, "comment"), (4, 10, 6, "keyword"), (4, 17, 3, "number"),
+        (6, 0, 3, "keyword"), (6, 4, 1, "identifier"), (6, 8, 2, "string"), (7, 0, 2, "string"),
+    ], spans);
+    assert_eq!(Identifier, semantic_tokens("x")[0].kind);
+
+    // Rejected text is skipped, the rest is still classified
+    let spans = semantic_tokens("var a = 1 ? 2; \"open");
+    assert_eq!(vec![Keyword, Identifier, Number, Number], spans.iter().map(|token| token.kind).collect::<Vec<_>>());
+}
+#[test]
+#[serial]
+fn test_opcode_from_byte() {
+    for byte in 0..=u8::MAX {
+        if let Some(opcode) = Opcode::from_byte(byte) {
+            assert_eq!(byte, opcode.byte());
+        } else {
+            assert!(byte as usize >= 66, "{}", byte);
+        }
+    }
+    assert!(Opcode::from_byte(65).is_some());
+}
+
+#[test]
+#[serial]
+fn test_too_much_nesting() {
+    let nested = |open: &str, inner: &str, close: &str, depth: usize| {
+        format!("{}{}{}", open.repeat(depth), inner, close.repeat(depth))
+    };
+    for single_pass in [false, true] {
+        let mut interpreter = Interpreter::new();
+        interpreter.single_pass = single_pass;
+        for source in [
+            format!("print {};", nested("(", "1", ")", 500)),
+            format!("var x = {};", nested("[", "", "]", 500)),
+            nested("{", "", "}", 500),
+            format!("print {};", nested("!", "true", "", 500)),
+            nested("if (true) ", "print 1;", "", 500),
+            nested("fun f() {", "", "}", 500),
+            nested("class A { f() {", "", "}}", 500),
+            format!("print match (1) {{ {} => 1 }};", nested("[", "_", "]", 500)),
+        ] {
+            match interpreter.compile(&source) {
+                Err(KError::Compile(errors)) => assert!(errors[0].contains("Too much nesting."), "{}", errors[0]),
+                _ => panic!("Expected a compile error"),
+            }
+        }
+        let source = format!("var x = {};", nested("(", "1", ")", 150));
+        let func_main_idx = interpreter.compile(&source).unwrap();
+        interpreter.run(func_main_idx).unwrap();
+    }
+}
+
+#[cfg(feature = "fuzzing")]
+#[test]
+#[serial]
+fn test_fuzz_compile_and_run() {
+    use crate::fuzz::fuzz_compile_and_run;
+    assert!(fuzz_compile_and_run(b"print 1 + 2;").is_ok());
+    assert!(matches!(fuzz_compile_and_run(b"print (;"), Err(KError::Compile(_))));
+    assert!(matches!(fuzz_compile_and_run(b"while (true) {}"), Err(KError::BudgetExceeded)));
+    assert!(matches!(fuzz_compile_and_run(b"readFile(\"x\");"), Err(KError::Runtime(_))));
+    assert!(fuzz_compile_and_run(&[0xff, 0xfe, b'(', 0]).is_err());
+}
+#[cfg(all(feature = "logging", feature = "json"))]
+#[test]
+#[serial]
+fn test_log_events() {
+    use std::sync::{Arc, Mutex};
+    let buffer = Arc::new(Mutex::new(vec![]));
+    let writer = buffer.clone();
+    let subscriber = tracing_subscriber::fmt()
+        .json()
+        .with_span_list(true)
+        .with_env_filter("kscript_rust=debug")
+        .with_writer(move || SharedLog(writer.clone()))
+        .finish();
+    tracing::subscriber::with_default(subscriber, || {
+        let mut interpreter = Interpreter::new();
+        interpreter.vm.heap.stress = true;
+        let func_main_idx = interpreter.compile("var s = str(1) + str(2);").unwrap();
+        interpreter.run(func_main_idx).unwrap();
+    });
+
+    let output = String::from_utf8(buffer.lock().unwrap().clone()).unwrap();
+    let events: Vec<serde_json::Value> = output.lines().map(|line| serde_json::from_str(line).unwrap()).collect();
+    let spans_of = |message: &str| -> Vec<String> {
+        let event = events.iter().find(|event| event["fields"]["message"] == message).expect(message);
+        return event["spans"].as_array().unwrap().iter().map(|span| span["name"].as_str().unwrap().to_string()).collect();
+    };
+    assert_eq!(vec!["compile"], spans_of("compiled"));
+    assert_eq!(vec!["execute"], spans_of("executed"));
+    assert_eq!(vec!["execute", "gc"], spans_of("freed memory"));
+    assert!(events.iter().all(|event| event["level"] == "DEBUG"));
+}
+#[cfg(feature = "json")]
+#[test]
+#[serial]
+fn test_heap_dump() {
+    let code = r#"
+        class Node { init(value) { this.value = value; this.next = nil; } }
+        var head = Node(1);
+        head.next = Node(2);
+        var garbage = [1, 2, 3];
+        garbage = nil;
+        assert(heapDump("heap_dump.json"));
+    "#.to_string();
+    let mut vm = compile_and_run(&code);
+    let written: serde_json::Value = serde_json::from_str(&fs::read_to_string("heap_dump.json").unwrap()).unwrap();
+    fs::remove_file("heap_dump.json").unwrap();
+    let dump: serde_json::Value = serde_json::from_str(&vm.heap_dump()).unwrap();
+    assert_eq!(written["globals"], dump["globals"]);
+
+    let objects = dump["objects"].as_array().unwrap();
+    let find = |id: &serde_json::Value| objects.iter().find(|object| &object["id"] == id).unwrap();
+    let head = find(&dump["globals"]["head"]);
+    assert_eq!("instance", head["type"]);
+    assert_eq!("Node", head["class"]);
+    assert!(head["size"].as_u64().unwrap() > 0);
+    let next = head["references"].as_array().unwrap().iter()
+        .find(|id| id.as_str().unwrap().starts_with("instance:"))
+        .unwrap();
+    assert_eq!("Node", find(next)["class"]);
+    assert!(find(&dump["globals"]["Node"])["references"].as_array().unwrap().iter().any(|id| find(id)["name"] == "init"));
+    // The list dropped from garbage was collected before the dump
+    assert!(objects.iter().all(|object| object["type"] != "list" || object["length"] != 3));
+    assert!(objects.iter().any(|object| object["type"] == "string" && object["value"] == "value"));
+
+    let code = "var result; try { heapDump(\"heap_dump.json\"); } catch (e) { result = e; }".to_string();
+    let vm = compile_and_run_with(&code, |vm| vm.capabilities = Capabilities::none());
+    let result = vm.globals.iter().find(|(name, _)| vm.heap.get_string(**name) == "result").unwrap().1;
+    assert_eq!("heapDump is not available, file system access is disabled.", vm.heap.get_string(result.as_string_hash()));
+}
+#[test]
+#[serial]
+fn test_debug_stack_native() {
+    let buffer = SharedBuffer::default();
+    let mut interpreter = Interpreter::new();
+    interpreter.vm.output = Box::new(buffer.clone());
+    let source = concat!(
+        "class Greeter {\n",
+        "  greet(name) {\n",
+        "    var message = \"hi \" + name;\n",
+        "    debugStack();\n",
+        "    return message;\n",
+        "  }\n",
+        "}\n",
+        "fun outer(n) {\n",
+        "  var pair = [n, \"x\"];\n",
+        "  print len(pair);\n",
+        "  return Greeter().greet(\"bob\");\n",
+        "}\n",
+        "outer(3);\n",
+        "var dump = debugStack(true);\n");
+    let func_main_idx = interpreter.compile(source).unwrap();
+    interpreter.run(func_main_idx).unwrap();
+    assert_eq!(concat!(
+        "2\n",
+        "at fn greet (line 4)\n",
+        "  [4] <Greeter instance>\n",
+        "  [5] \"bob\"\n",
+        "  [6] \"hi bob\"\n",
+        "at fn outer (line 11)\n",
+        "  [1] <fn outer>\n",
+        "  [2] 3\n",
+        "  [3] [3, \"x\"]\n",
+        "at script (line 13)\n",
+        "  [0] <fn main>\n"), buffer.contents());
+
+    let dump = interpreter.eval("dump").unwrap();
+    assert_eq!("at script (line 14)\n  [0] <fn main>\n", interpreter.display(dump));
+    match interpreter.eval("debugStack(1);") {
+        Err(KError::Runtime(message)) => assert_eq!("Invalid type for debugStack asString, true or false expected.", message),
+        _ => panic!("Expected a runtime error"),
+    }
+}
+#[test]
+#[serial]
+fn test_load_native() {
+    fs::write("load_lib.ks", "fun double(n) { return n * 2; }\nvar loads = loads + 1;\n").unwrap();
+    fs::write("load_throw.ks", "throw \"from load_throw\";\n").unwrap();
+    fs::write("load_bad.ks", "var = ;\n").unwrap();
+    let mut interpreter = Interpreter::new();
+    interpreter.eval("var loads = 0;").unwrap();
+    interpreter.eval("load(\"load_lib.ks\"); load(\"load_lib.ks\");").unwrap();
+    let value = interpreter.eval("double(loads)").unwrap();
+    assert_eq!("4", interpreter.display(value));
+
+    interpreter.eval("var caught; try { load(\"load_throw.ks\"); } catch (e) { caught = e; }").unwrap();
+    let caught = interpreter.eval("caught").unwrap();
+    assert_eq!("from load_throw", interpreter.display(caught));
+    let result = interpreter.eval("load(\"load_bad.ks\");");
+    for path in ["load_lib.ks", "load_throw.ks", "load_bad.ks"] {
+        fs::remove_file(path).unwrap();
+    }
+    match result {
+        Err(KError::Runtime(message)) => assert!(message.starts_with("Unable to load load_bad.ks: [line 1] Error at '=': Expect a variable name."), "{}", message),
+        _ => panic!("Expected a runtime error"),
+    }
+
+    let mut interpreter = Interpreter::new();
+    interpreter.vm.capabilities = Capabilities::none();
+    match interpreter.eval("load(\"load_lib.ks\");") {
+        Err(KError::Runtime(message)) => assert_eq!("load is not available, file system access is disabled.", message),
+        _ => panic!("Expected a runtime error"),
+    }
+}
+
+#[test]
+#[serial]
+fn test_load_resolves_paths_next_to_the_loading_script() {
+    fs::create_dir_all("test_load_dir/lib").unwrap();
+    fs::write("test_load_dir/main.ks", "load(\"lib/lib.ks\");\nvar total = double(base);\n").unwrap();
+    fs::write("test_load_dir/lib/lib.ks", "load(\"util.ks\");\nfun double(n) { return n * 2; }\n").unwrap();
+    fs::write("test_load_dir/lib/util.ks", "var base = 21;\n").unwrap();
+    // Run from the crate directory, not the one holding the scripts
+    let mut interpreter = Interpreter::new();
+    let result = interpreter.run_file("test_load_dir/main.ks");
+    let _ = fs::remove_dir_all("test_load_dir");
+    result.unwrap();
+    let total = interpreter.eval("total").unwrap();
+    assert_eq!("42", interpreter.display(total));
+}
+
+#[test]
+#[serial]
+fn test_strings_with_colliding_hashes_stay_distinct() {
+    // Both strings hash to the same 32 bits
+    let code = r#"
+        class Pair {}
+        var a = "k70659";
+        var b = "k516982";
+        var m = {};
+        m[a] = 1;
+        m[b] = 2;
+        var p = Pair();
+        p.k516982 = 3;
+        var _result = str(a == b) + " " + a + " " + b + " " + str(m[a]) + " " + str(m[b])
+          + " " + str(len(m)) + " " + str(hasField(p, a)) + " " + str(hasField(p, b));
+    "#.to_string();
+    match run_code(&code) {
+        Ok(str) => assert_eq!("false k70659 k516982 1 2 2 false true", str),
+        Err(_) => panic!("Failed")
+    }
+    // Freeing the first string mustn't lose the second
+    let mut interpreter = Interpreter::new();
+    interpreter.eval("var b = \"k516982\"; { var a = \"k70659\"; }").unwrap();
+    interpreter.vm.collect_garbage();
+    let value = interpreter.eval("b == \"k516982\"").unwrap();
+    assert!(value.as_boolean());
+}
+/////////////////////////////////////////////////////////////////////
+// Helper functions
+/////////////////////////////////////////////////////////////////////
+
+/// Writer that keeps everything written to it for later inspection
+#[derive(Clone, Default)]
+struct SharedBuffer(Rc<RefCell<Vec<u8>>>);
+
+impl SharedBuffer {
+    fn contents(&self) -> String {
+        String::from_utf8(self.0.borrow().clone()).unwrap()
+    }
+}
+
+impl Write for SharedBuffer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.borrow_mut().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Writer appending to a buffer that can be shared across threads, as log
+/// subscribers require
+#[cfg(feature = "logging")]
+struct SharedLog(std::sync::Arc<std::sync::Mutex<Vec<u8>>>);
+
+#[cfg(feature = "logging")]
+impl Write for SharedLog {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Helper for testing single expression
+fn run_expr(code: &String) ->Result<String, Error> {
+    let wrapped_code = format!("writeFile(\"result.txt\", str({}));", code);
+    // println!("{}", wrapped_code);
+    return execute(&wrapped_code);
+}
+
+/// Helper for testing multiline code
+fn run_code(code: &String) ->Result<String, Error> {
+    let wrapped_code = format!("{}\nwriteFile(\"result.txt\", str(_result));", code);
+    // println!("{}", wrapped_code);
+    return execute(&wrapped_code);
+}
+
+/// Helper for testing multiline code against a configured VM
+fn run_code_with(code: &String, configure: fn(&mut VM)) ->Result<String, Error> {
+    let wrapped_code = format!("{}\nwriteFile(\"result.txt\", str(_result));", code);
+    return execute_with(&wrapped_code, configure);
+}
+
+/// Helper for testing multiline code with a collection after every allocation
+fn run_code_stressed(code: &String) ->Result<String, Error> {
+    return run_code_with(code, |vm| vm.heap.stress = true);
+}
+
+/// Interpret and execute the code
+fn execute(code: &String) ->Result<String, Error>  {
+    return execute_with(code, |_| {});
+}
+
+/// Compile and run the code, returning the VM for inspection
+fn compile_and_run(code: &String) -> VM {
+    return compile_and_run_with(code, |_| {});
+}
+
+/// Compile and run the code against a configured VM, returning it for inspection
+fn compile_and_run_with(code: &String, configure: fn(&mut VM)) -> VM {
+    let mut vm = VM::new();
+    vm.init();
+    configure(&mut vm);
+    let mut scanner = Scanner::new(code);
+    let tokens = scanner.scan_tokens();
+    let mut heap_to_parser = Heap::new();
+    mem::swap(&mut vm.heap, &mut heap_to_parser);
+    let mut parser = Parser::new(heap_to_parser, tokens);
+    parser.compile();
+    mem::swap(&mut parser.heap, &mut vm.heap, );
+    if parser.had_error {
+        panic!("Parsing failed with error.");
+    }
+    match vm.execute() {
+        RunResult::Ok => vm,
+        _ => panic!("VM failed to execute.")
+    }
+}
+
+/// Name and code of every function compiled from the source, in the order
+/// they were allocated
+fn compile_functions(source: &str, single_pass: bool) -> Vec<(String, Vec<u8>)> {
+    let mut parser = Parser::new(Heap::new(), Scanner::new(&source.to_string()));
+    parser.single_pass = single_pass;
+    parser.compile();
+    assert!(!parser.had_error, "{:?}", parser.errors);
+    return parser.heap.functions.live_indexes()
+        .map(|idx| {
+            let function = parser.heap.get_function(idx);
+            (function.name.to_string(), function.chunk.code.clone())
+        })
+        .collect();
+}
+
+/// Code of the main function compiled from the source
+fn compile_main_code(source: &str, optimize: bool) -> Vec<u8> {
+    let mut scanner = Scanner::new(&source.to_string());
+    let tokens = scanner.scan_tokens();
+    let mut parser = Parser::new(Heap::new(), tokens);
+    parser.optimize = optimize;
+    let func_main_idx = parser.compile();
+    assert!(!parser.had_error);
+    return parser.heap.get_function(func_main_idx).chunk.code.clone();
+}
+
+/// Run a script that checks itself with assert, failing the test with the
+/// assertion message
+fn run_asserts(code: &str) {
+    if let Err(error) = Interpreter::new().eval(code) {
+        panic!("{}", error);
+    }
+}
+
+/// Interpret and execute the code after letting the caller configure the VM
+fn execute_with(code: &String, configure: fn(&mut VM)) ->Result<String, Error>  {
+    let mut vm = VM::new();
+    vm.init();
+    configure(&mut vm);
+
+    // Scanning step
+    let mut scanner = Scanner::new(code);
     let tokens = scanner.scan_tokens();
 
     // transfer heap ownership to parser
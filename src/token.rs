@@ -1,37 +1,37 @@
 use std::fmt;
+use std::rc::Rc;
 
+/// Lexeme and literal text are interned by the scanner, so cloning a
+/// token only bumps reference counts.
+#[derive(Clone)]
 pub struct Token {
     pub token_type: TokenType,
-    pub lexeme: String,
-    pub literal: String,
+    pub lexeme: Rc<str>,
+    pub literal: Rc<str>,
     pub line: usize,
-}
-
-impl Clone for Token {
-    fn clone(&self) -> Self {
-        Token::new(self.token_type,
-                   self.lexeme.to_string(),
-                   self.literal.to_string(),
-                   self.line)
-    }
+    /// Characters between the start of the line and the token
+    pub column: usize,
 }
 
 impl Token {
     pub fn new(token_type: TokenType,
-               lexeme: String,
-               literal: String,
-               line: usize ) -> Token {
+               lexeme: Rc<str>,
+               literal: Rc<str>,
+               line: usize,
+               column: usize) -> Token {
         Token {
             token_type,
             lexeme,
             literal,
-            line
+            line,
+            column
         }
     }
-    pub fn to_string(&self)->String {
-        return [self.token_type.to_string(),
-            self.lexeme.to_string(),
-            self.literal.to_string()].join(", ")
+}
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}, {}, {}", self.token_type, self.lexeme, self.literal)
     }
 }
 
@@ -44,8 +44,12 @@ pub enum TokenType {
     RightParen,
     LeftBrace,
     RightBrace,
+    LeftBracket,
+    RightBracket,
     Comma,
+    Colon,
     Dot,
+    Ellipsis,
     Minus,
     Plus,
     Semicolon,
@@ -61,7 +65,10 @@ pub enum TokenType {
     Less,
     LessEqual,
     PlusEqual,
+    PlusPlus,
+    QuestionQuestion,
     MinusEqual,
+    MinusMinus,
     // Identifiers
     Identifier,
     String,
@@ -82,9 +89,18 @@ pub enum TokenType {
     This,
     True,
     Var,
+    Const,
+    Match,
+    FatArrow,
+    Try,
+    Catch,
+    Throw,
     While,
     Error,
     Extend,
+    Abstract,
+    /// Only in Scanner::comments, the parser never sees one
+    Comment,
     Eof
 }
 impl fmt::Display for TokenType {
@@ -94,8 +110,12 @@ impl fmt::Display for TokenType {
             TokenType::RightParen => write!(f, "RightParen"),
             TokenType::LeftBrace => write!(f, "LeftBrace"),
             TokenType::RightBrace => write!(f, "RightBrace"),
+            TokenType::LeftBracket => write!(f, "LeftBracket"),
+            TokenType::RightBracket => write!(f, "RightBracket"),
             TokenType::Comma => write!(f, "Comma"),
+            TokenType::Colon => write!(f, "Colon"),
             TokenType::Dot => write!(f, "Dot"),
+            TokenType::Ellipsis => write!(f, "Ellipsis"),
             TokenType::Minus => write!(f, "Minus"),
             TokenType::Plus => write!(f, "Plus"),
             TokenType::Semicolon => write!(f, "Semicolon"),
@@ -110,22 +130,40 @@ impl fmt::Display for TokenType {
             TokenType::Less => write!(f, "Less"),
             TokenType::LessEqual => write!(f, "LessEqual"),
             TokenType::PlusEqual => write!(f, "PlusEqual"),
+            TokenType::PlusPlus => write!(f, "PlusPlus"),
+            TokenType::QuestionQuestion => write!(f, "QuestionQuestion"),
+            TokenType::MinusEqual => write!(f, "MinusEqual"),
+            TokenType::MinusMinus => write!(f, "MinusMinus"),
             TokenType::Identifier => write!(f, "Identifier"),
             TokenType::String => write!(f, "String"),
             TokenType::Number => write!(f, "Number"),
             TokenType::And => write!(f, "And"),
             TokenType::Class => write!(f, "Class"),
             TokenType::Else => write!(f, "Else"),
+            TokenType::False => write!(f, "False"),
+            TokenType::Fun => write!(f, "Fun"),
+            TokenType::For => write!(f, "For"),
+            TokenType::If => write!(f, "If"),
+            TokenType::Nil => write!(f, "Nil"),
+            TokenType::Or => write!(f, "Or"),
+            TokenType::Print => write!(f, "Print"),
+            TokenType::Return => write!(f, "Return"),
+            TokenType::Super => write!(f, "Super"),
             TokenType::This => write!(f, "This"),
             TokenType::True => write!(f, "True"),
             TokenType::Var => write!(f, "Var"),
+            TokenType::Const => write!(f, "Const"),
+            TokenType::Match => write!(f, "Match"),
+            TokenType::FatArrow => write!(f, "FatArrow"),
+            TokenType::Try => write!(f, "Try"),
+            TokenType::Catch => write!(f, "Catch"),
+            TokenType::Throw => write!(f, "Throw"),
             TokenType::While => write!(f, "While"),
-            TokenType::Print => write!(f, "Print"),
-            TokenType::Return => write!(f, "Return"),
-            TokenType::Fun => write!(f, "Fun"),
-            TokenType::Fun => write!(f, "Extend"),
+            TokenType::Error => write!(f, "Error"),
+            TokenType::Extend => write!(f, "Extend"),
+            TokenType::Abstract => write!(f, "Abstract"),
+            TokenType::Comment => write!(f, "Comment"),
             TokenType::Eof => write!(f, "Eof"),
-            _ =>  write!(f, "TOKEN"),
         }
     }
 }
@@ -0,0 +1,40 @@
+use std::any::Any;
+
+use fnv::FnvHashMap;
+
+use crate::heap::Heap;
+use crate::nativefn::NativeError;
+use crate::Value;
+
+/// Method of a host type, called with the heap, the host value and the
+/// arguments of the call
+pub type UserMethod = fn(&mut Heap, &mut dyn Any, Vec<Value>) -> Result<Value, NativeError>;
+
+/// Host type registered through VM::register_user_type, its values show up in
+/// scripts under its name and only have the methods given
+pub struct UserType {
+    pub name: String,
+    /// Methods by name
+    pub methods: FnvHashMap<String, UserMethod>,
+}
+
+/// Host value owned by the heap, dropped when scripts no longer reach it
+pub struct UserData {
+    pub type_idx: usize,
+    pub data: Box<dyn Any>,
+}
+
+impl UserData {
+    pub fn new(type_idx: usize, data: Box<dyn Any>) -> Self {
+        UserData { type_idx, data }
+    }
+
+    /// The host value, when it is a T
+    pub fn get<T: 'static>(&self) -> Option<&T> {
+        return self.data.downcast_ref::<T>();
+    }
+
+    pub fn get_mut<T: 'static>(&mut self) -> Option<&mut T> {
+        return self.data.downcast_mut::<T>();
+    }
+}
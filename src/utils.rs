@@ -1,15 +1,15 @@
-use std::collections::hash_map::DefaultHasher;
 use std::hash::{Hash, Hasher};
-use std::io;
+use fnv::FnvHasher;
+
+/// Clocks for natives and VM timing. std's panic on wasm32-unknown-unknown,
+/// where web-time reads the browser's instead
+#[cfg(not(target_arch = "wasm32"))]
+pub use std::time::{Instant, SystemTime, UNIX_EPOCH};
+#[cfg(target_arch = "wasm32")]
+pub use web_time::{Instant, SystemTime, UNIX_EPOCH};
 
 pub fn hash_string(t: &String) -> u32 {
-    let mut s = DefaultHasher::new();
+    let mut s = FnvHasher::default();
     t.hash(&mut s);
     s.finish() as u32
 }
-
-pub fn read_line() -> io::Result<String> {
-    let mut buffer = String::new();
-    io::stdin().read_line(&mut buffer)?;
-    Ok(buffer)
-}
\ No newline at end of file
@@ -37,6 +37,15 @@ impl Value {
         };
     }
 
+    /// nil and false are falsey, every other value is truthy
+    pub fn is_falsey(&self) ->bool {
+        return match self {
+            Nil() => { true }
+            Bool(b) => { !b }
+            _ => { false }
+        }
+    }
+
     pub fn as_object(&self) ->&Object {
         return if let Obj(ob) = self { ob } else {
             panic!("Not an object")
@@ -79,25 +88,44 @@ impl Value {
         };
     }
 
+    pub fn as_bound_method_index(&self) ->usize {
+        return if let Obj(ob) = self { ob.as_bound_method_index() } else {
+            panic!("Not a bound method")
+        };
+    }
+
+    pub fn as_list_index(&self) ->usize {
+        return if let Obj(ob) = self { ob.as_list_index() } else {
+            panic!("Not a list")
+        };
+    }
+
+    pub fn as_map_index(&self) ->usize {
+        return if let Obj(ob) = self { ob.as_map_index() } else {
+            panic!("Not a map")
+        };
+    }
+
+    pub fn as_user_data_index(&self) ->usize {
+        return if let Obj(ob) = self { ob.as_user_data_index() } else {
+            panic!("Not user data")
+        };
+    }
+
     pub fn is_number(&self) ->bool {
-        return match self {
-            Number(_) => { true }
-            _ => { false }
-        }
+        return matches!(self, Number(_));
+    }
+
+    pub fn is_nil(&self) ->bool {
+        return matches!(self, Nil());
     }
 
     pub fn is_object(&self) ->bool {
-        return match self {
-            Obj(_) => { true }
-            _ => { false }
-        }
+        return matches!(self, Obj(_));
     }
 
     pub fn is_boolean(&self) ->bool {
-        return match self {
-            Bool(_) => { true }
-            _ => { false }
-        }
+        return matches!(self, Bool(_));
     }
 
     pub fn is_string_hash(&self) -> bool {
@@ -138,6 +166,34 @@ impl Value {
             _ => { false }
         }
     }
+
+    pub fn is_bound_method_index(&self) -> bool {
+        return match self {
+            Obj(obj) => {obj.is_bound_method_index()}
+            _ => { false }
+        }
+    }
+
+    pub fn is_list_index(&self) -> bool {
+        return match self {
+            Obj(obj) => {obj.is_list_index()}
+            _ => { false }
+        }
+    }
+
+    pub fn is_map_index(&self) -> bool {
+        return match self {
+            Obj(obj) => {obj.is_map_index()}
+            _ => { false }
+        }
+    }
+
+    pub fn is_user_data_index(&self) -> bool {
+        return match self {
+            Obj(obj) => {obj.is_user_data_index()}
+            _ => { false }
+        }
+    }
 }
 
 impl PartialEq for Value {
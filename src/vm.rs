@@ -1,36 +1,74 @@
+use std::any::Any;
 use std::borrow::{Borrow};
 use std::cell::RefCell;
+use std::io;
+use std::io::Write;
+use std::mem;
+use std::path::{Path, PathBuf};
 use std::rc::Rc;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
 use colored::Colorize;
-use fnv::{ FnvHashMap};
+use fnv::{FnvHashMap, FnvHashSet};
+use tracing::{debug, debug_span, info_span, trace};
 
-use crate::{Heap, Object, Opcode, Value};
-use crate::callframe::CallFrame;
-use crate::class::{Class, Instance};
+use crate::{bytecode, Heap, Object, Opcode, Parser, Scanner, Value};
+use crate::callframe::{CallFrame, Handler};
+use crate::debug::constant_text;
+use crate::class::{abstract_class_message, BoundMethod, Class, Instance};
 use crate::closure::{Closure, ObjUpvalue};
 use crate::function::Function;
-use crate::nativefn::{append_file_native, clock_native, NativeFn, NativeValue, str_native, write_file_native};
+use crate::shape::PropertyCache;
+use crate::list::List;
+use crate::map::{KeyPosition, Map, number_bits};
+use crate::metrics::Metrics;
+use crate::nativefn::{append_file_native, assert_native, Capabilities, Capability, class_name_native, clock_native, clone_native, debug_stack_native, delete_file_native, fields_native, file_exists_native, format_native, has_field_native, http_get_native, http_post_native, input_native, keys_native, len_native, list_dir_native, load_native, mem_stats_native, methods_native, mkdir_native, monotonic_millis_native, monotonic_nanos_native, parse_number_native, type_native, Native, NativeError, NativeFn, NativeValue, read_file_native, str_native, to_native, write_file_native};
+use crate::ffi::{ffi_call_native, load_library_native};
+use crate::heapdump::heap_dump_native;
+use crate::userdata::{UserData, UserMethod, UserType};
+use crate::utils::Instant;
 
 const CHECK_GC_INTERVAL: usize =  5000;
-const MAX_CALLSTACK: usize = 256;
-const MAX_VALUE_STACK: usize = 256;
-const DEBUG: bool = true;
-
-#[cfg(debug_assertions)]
-macro_rules! log {
-    ($( $args:expr ),*) => { /*println!( $( $args ),* );*/  }
-}
-
-// Non-debug version
-#[cfg(not(debug_assertions))]
-macro_rules! log {
-    ($( $args:expr ),*) => {()}
-}
+/// Default number of values traced per incremental marking step
+const GC_STEP_BUDGET: usize = 1000;
+const MAX_CALLSTACK: usize = 1024;
+/// Limit of runs nested inside instructions, such as toString and eq calls.
+/// Each takes native stack, which runs out long before MAX_CALLSTACK frames
+const MAX_NESTED_RUNS: usize = 64;
+/// Default limit of value stack slots, the stack grows on demand up to it
+const MAX_VALUE_STACK: usize = 16 * 1024;
+/// Slots allocated up front, enough for most scripts without growing
+const INITIAL_VALUE_STACK: usize = 256;
+
+/// Natives that are rarely used and only registered on first lookup
+const LAZY_NATIVES: [(&str, Native, Capability); 13] = [
+    ("writeFile", Native::Converted(write_file_native), Capability::Fs),
+    ("appendFile", Native::Converted(append_file_native), Capability::Fs),
+    ("readFile", Native::Converted(read_file_native), Capability::Fs),
+    ("fileExists", Native::Converted(file_exists_native), Capability::Fs),
+    ("deleteFile", Native::Converted(delete_file_native), Capability::Fs),
+    ("listDir", Native::Converted(list_dir_native), Capability::Fs),
+    ("mkdir", Native::Converted(mkdir_native), Capability::Fs),
+    ("heapDump", Native::Vm(heap_dump_native), Capability::Fs),
+    ("load", Native::Vm(load_native), Capability::Fs),
+    ("httpGet", Native::Converted(http_get_native), Capability::Net),
+    ("httpPost", Native::Converted(http_post_native), Capability::Net),
+    ("loadLibrary", Native::Converted(load_library_native), Capability::Ffi),
+    ("ffiCall", Native::Converted(ffi_call_native), Capability::Ffi),
+];
 
 /// Enum for run result
+#[derive(Copy, Clone, PartialEq, Debug)]
 pub enum RunResult {
     Ok,
     RuntimeError,
+    /// Stopped after executing max_instructions instructions
+    BudgetExceeded,
+    /// Stopped after running longer than the timeout
+    Timeout,
+    /// Stopped because interrupt_flag was set, e.g. by Ctrl-C
+    Interrupted,
 }
 
 // fixme: Too many conversion e.g usize,
@@ -42,27 +80,132 @@ pub struct VM {
     pub stack: Vec<Value>,                                  // Hold computation values
     pub callstack: Vec<CallFrame>,                          // List of call frames
     pub globals: FnvHashMap<u32, Value>,
+    /// Globals declared with const, they can't be assigned or redefined
+    pub const_globals: FnvHashSet<u32>,
     pub heap: Heap,                                         // For memory management (using Rust Box construct)
     pub curr_func_idx: usize,                               // For caching current function pointer
     pub open_upvalues: Option<Rc<RefCell<ObjUpvalue>>>,      // For tracking open upvalues
     pub stack_top: usize,
+    /// Slot offset of the current call frame, cached for local variable access
+    frame_base: usize,
+    /// Bytecode of the current function, cached so reading an operand skips the heap lookup
+    code: *const u8,
     pub init_string_hash: u32,
+    pub to_string_hash: u32,
+    pub eq_hash: u32,
+    pub hash_method_hash: u32,
+    pub method_missing_hash: u32,
+    /// Call stack depth at which the current run loop returns, non zero while
+    /// the VM runs a method such as toString from inside an instruction
+    base_depth: usize,
+    /// Runs in progress inside instructions, see MAX_NESTED_RUNS
+    nested_runs: usize,
+    /// Upper bits of the next constant operand, set by a Wide instruction
+    wide_operand: usize,
+    /// Number of instructions executed between garbage collection checks
+    pub gc_check_interval: usize,
+    /// Values traced per check while a collection is marking incrementally
+    pub gc_step_budget: usize,
+    /// Is a collection in progress? Marking is spread over many checks
+    gc_marking: bool,
+    /// Values found reachable so far, those before gc_traced have been traced (black)
+    /// and the rest are waiting to be traced (gray)
+    gc_marked: Vec<Value>,
+    gc_traced: usize,
+    /// Objects already traced in the current collection
+    gc_visited: FnvHashSet<Object>,
+    /// Allocation count at the last stress collection
+    gc_stress_allocations: usize,
+    /// Closures shared by functions without upvalues, keyed by function index
+    pub closure_cache: FnvHashMap<usize, usize>,
+    /// Redeclaring a global function or class updates the existing object in
+    /// place, assigning one with var only rebinds the name
+    pub hot_redefinition: bool,
+    /// Host access allowed to natives, see init_with()
+    pub capabilities: Capabilities,
+    /// Execution counters, see metrics()
+    pub metrics: Metrics,
+    /// Destination for print statements and runtime errors
+    pub output: Box<dyn Write>,
+    /// Value stack slots a call may start beyond before raising a stack overflow
+    pub max_stack: usize,
+    /// Stop a run after this many instructions, so runaway loops can't hang the host
+    pub max_instructions: Option<u64>,
+    /// Instruction count at which the current run exceeds max_instructions
+    instruction_deadline: u64,
+    /// Wall clock time a run may take, checked every gc_check_interval instructions
+    pub timeout: Option<Duration>,
+    /// When the current run exceeds the timeout
+    deadline: Option<Instant>,
+    /// Set from another thread or a signal handler to stop the current run,
+    /// polled every gc_check_interval instructions. A native blocked in a call
+    /// such as input or httpGet only sees it once the call returns
+    pub interrupt_flag: Arc<AtomicBool>,
+    /// Why the current run was stopped by a host limit, nested runs pass it outwards
+    halted: Option<RunResult>,
+    /// Catch blocks of the try statements currently executing, innermost last
+    pub handlers: Vec<Handler>,
+    /// Value being thrown while unwinding to a handler
+    thrown: Option<Value>,
+    /// Value returned by the main function of the last run, kept alive until the next run
+    pub last_result: Value,
+    /// Message of the runtime error that ended the last run
+    pub last_error: Option<String>,
     // pub _profile_duration: Duration                      // For testing
 }
 
+impl Default for VM {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl VM {
     /// Default constructor
     pub fn new() ->Self {
         VM {
             ip: 0,
-            stack: vec![Value::Nil();256],
-            callstack: Vec::with_capacity(256),
+            stack: Vec::with_capacity(INITIAL_VALUE_STACK),
+            callstack: Vec::with_capacity(MAX_CALLSTACK),
             globals: FnvHashMap::default(),
+            const_globals: FnvHashSet::default(),
             heap: Heap::new(),
             curr_func_idx: 0,
             open_upvalues: None,
             stack_top: 0,
-            init_string_hash: 0
+            frame_base: 0,
+            code: std::ptr::null(),
+            init_string_hash: 0,
+            to_string_hash: 0,
+            eq_hash: 0,
+            hash_method_hash: 0,
+            method_missing_hash: 0,
+            base_depth: 0,
+            nested_runs: 0,
+            wide_operand: 0,
+            gc_check_interval: CHECK_GC_INTERVAL,
+            gc_step_budget: GC_STEP_BUDGET,
+            gc_marking: false,
+            gc_marked: vec![],
+            gc_traced: 0,
+            gc_visited: FnvHashSet::default(),
+            gc_stress_allocations: 0,
+            closure_cache: FnvHashMap::default(),
+            hot_redefinition: false,
+            capabilities: Capabilities::default(),
+            metrics: Metrics::default(),
+            output: Box::new(io::stdout()),
+            max_stack: MAX_VALUE_STACK,
+            max_instructions: None,
+            instruction_deadline: u64::MAX,
+            timeout: None,
+            deadline: None,
+            interrupt_flag: Arc::new(AtomicBool::new(false)),
+            halted: None,
+            handlers: vec![],
+            thrown: None,
+            last_result: Value::nil(),
+            last_error: None,
             // _profile_duration: Default::default()
         }
     }
@@ -72,26 +215,155 @@ impl VM {
         self.ip = 0;
         self.stack.clear();
         self.globals.clear();
+        self.const_globals.clear();
         self.heap.clear();
+        self.closure_cache.clear();
+        self.handlers.clear();
+        self.abort_collection();
         self.curr_func_idx = 0;
         self.open_upvalues = None;
         self.stack_top = 0;
     }
 
     pub fn init(&mut self) {
+        self.init_with(Capabilities::default());
+    }
+
+    /// Init, only making natives available when their host access is allowed.
+    /// Scripts using a disallowed native get a runtime error
+    pub fn init_with(&mut self, capabilities: Capabilities) {
+        self.capabilities = capabilities;
         self.define_native("clock", clock_native);
-        self.define_native("writeFile", write_file_native);
-        self.define_native("appendFile", append_file_native);
         self.define_native("str", str_native);
+        self.define_native("len", len_native);
+        self.define_native("keys", keys_native);
+        self.define_native("type", type_native);
+        self.define_native("className", class_name_native);
+        self.define_native("fields", fields_native);
+        self.define_native("methods", methods_native);
+        self.define_native("hasField", has_field_native);
+        self.define_native_as("clone", Native::Raw(clone_native));
+        self.define_native_as("debugStack", Native::Vm(debug_stack_native));
+        self.define_native("memStats", mem_stats_native);
+        self.define_native("input", input_native);
+        self.define_native("assert", assert_native);
+        self.define_native("format", format_native);
+        self.define_native("parseNumber", parse_number_native);
+        self.define_native("monotonicMillis", monotonic_millis_native);
+        self.define_native("monotonicNanos", monotonic_nanos_native);
+        self.set_args(&[]);
         self.init_string_hash = self.heap.alloc_string("init".to_string());
+        self.to_string_hash = self.heap.alloc_string("toString".to_string());
+        self.eq_hash = self.heap.alloc_string("eq".to_string());
+        self.hash_method_hash = self.heap.alloc_string("hash".to_string());
+        self.method_missing_hash = self.heap.alloc_string("methodMissing".to_string());
+    }
+
+    /// Expose the arguments to scripts as the `args` global, a list of strings
+    pub fn set_args(&mut self, args: &[String]) {
+        let items = args.iter()
+            .map(|arg| Value::object(Object::string(self.heap.alloc_string(arg.to_string()))))
+            .collect();
+        let list_idx = self.heap.alloc_list(List::new(items));
+        let name_hash = self.heap.alloc_string("args".to_string());
+        self.globals.insert(name_hash, Value::object(Object::list(list_idx)));
+    }
+
+    /// Define a global for scripts to use, such as a value from new_user_data
+    pub fn set_global(&mut self, name: &str, value: Value) {
+        let name_hash = self.heap.alloc_string(name.to_string());
+        self.shade(value);
+        self.globals.insert(name_hash, value);
+    }
+
+    /// Register a host type whose values scripts see under the name and call
+    /// the methods of. Returns the type for new_user_data
+    pub fn register_user_type(&mut self, name: &str, methods: &[(&str, UserMethod)]) -> usize {
+        let methods = methods.iter()
+            .map(|(method_name, method)| (method_name.to_string(), *method))
+            .collect();
+        self.heap.user_types.push(UserType { name: name.to_string(), methods });
+        return self.heap.user_types.len() - 1;
     }
 
-    /// Report run time error
+    /// Hand a host value of a registered type to scripts. The heap owns it
+    /// from now on and drops it once scripts can't reach it
+    pub fn new_user_data(&mut self, type_idx: usize, data: Box<dyn Any>) -> Value {
+        let user_data_idx = self.heap.alloc_user_data(UserData::new(type_idx, data));
+        return Value::object(Object::user_data(user_data_idx));
+    }
+
+    /// Report run time error, or raise it as a string to the innermost try
+    /// statement when one is active
     pub fn runtime_error(&mut self, message: &str) {
-        println!("{} {}", "Runtime Error".bold().red(), message.bold().yellow());
+        if !self.handlers.is_empty() {
+            let hash = self.heap.alloc_string(message.to_string());
+            self.thrown = Some(Value::Obj(Object::StringHash(hash)));
+            return;
+        }
+        self.last_error = Some(message.to_string());
+        let _ = writeln!(self.output, "{} {}", "Runtime Error".bold().red(), message.bold().yellow());
+        for line in self.stack_trace() {
+            let _ = writeln!(self.output, "  {}", line);
+        }
         self.reset_stack();
     }
 
+    /// Stop the run for a limit set by the host. Unlike runtime errors this
+    /// can't be caught by try statements
+    fn halt(&mut self, reason: RunResult, message: &str) -> RunResult {
+        self.handlers.clear();
+        self.runtime_error(message);
+        self.halted = Some(reason);
+        return reason;
+    }
+
+    /// One `at fn name (line N)` entry per active call, innermost first
+    fn stack_trace(&self) -> Vec<String> {
+        let depth = self.callstack.len();
+        return self.callstack.iter().enumerate().rev().map(|(i, frame)| {
+            // Saved ips point past the call instruction, the current one past the failing instruction
+            let ip = if i == depth - 1 { self.ip } else { frame.ip };
+            let function = self.heap.get_function(self.heap.get_closure(frame.closure_idx).func_idx);
+            let line = function.chunk.line_for_offset(ip.saturating_sub(1));
+            if i == 0 {
+                format!("at script (line {})", line)
+            } else {
+                format!("at fn {} (line {})", function.name, line)
+            }
+        }).collect();
+    }
+
+    /// The call frames innermost first, each followed by the stack slots it
+    /// holds, for debugStack. Slots from stack_top on are left out
+    pub(crate) fn debug_stack(&self, stack_top: usize) -> String {
+        let mut out = String::new();
+        let mut end = stack_top;
+        for (frame, location) in self.callstack.iter().rev().zip(self.stack_trace()) {
+            out.push_str(&format!("{}\n", location));
+            for slot in frame.slot_offset..end.max(frame.slot_offset) {
+                let value = self.stack[slot];
+                let text = match value {
+                    Value::Obj(Object::StringHash(hash)) => format!("{:?}", self.heap.get_string(hash)),
+                    // Named, where print only shows their heap index
+                    Value::Obj(Object::ClosureIndex(_) | Object::FunctionIndex(_) | Object::ClassIndex(_)
+                               | Object::NativeFnIndex(_) | Object::BoundMethodIndex(_)) => constant_text(value, &self.heap),
+                    _ => self.format_value(value),
+                };
+                out.push_str(&format!("  [{}] {}\n", slot, text));
+            }
+            end = frame.slot_offset;
+        }
+        return out;
+    }
+
+    /// Source line of the instruction being executed
+    fn current_line(&self) -> usize {
+        let frame = self.callstack.last().unwrap();
+        let function = self.heap.get_function(self.heap.get_closure(frame.closure_idx).func_idx);
+        return function.chunk.line_for_offset(self.ip.saturating_sub(1));
+    }
+
     /// Entry point to execute the virtual machine
     ///
     /// # Precondition
@@ -99,28 +371,181 @@ impl VM {
     /// 2. The heap must contain results from the parsing phase.
     ///    eg String objects, Function objects, etc..
     pub fn execute(&mut self) -> RunResult {
-        let func_main_idx = 0;  // Main function is always 0
+        return self.execute_function(0); // Main function is always 0 when compiled from source
+    }
+
+    /// Run the given function as the script's main
+    pub fn execute_function(&mut self, func_main_idx: usize) -> RunResult {
+        let _span = info_span!("execute", function = %self.heap.get_function(func_main_idx).name).entered();
         self.push(Value::object(Object::function(func_main_idx)));
         let upvalue_count = self.heap.get_function(func_main_idx).upvalue_count;
         let closure_idx = self.new_closure(func_main_idx, upvalue_count);
         self.fpop(); // Pop the function
         self.push(Value::Obj(Object::ClosureIndex(closure_idx)));
         self.call(closure_idx,0);
-        return self.run();
+        self.halted = None;
+        self.last_result = Value::nil();
+        self.last_error = None;
+        self.instruction_deadline = match self.max_instructions {
+            Some(limit) => self.metrics.instructions.saturating_add(limit),
+            None => u64::MAX,
+        };
+        let start = Instant::now();
+        self.deadline = self.timeout.map(|timeout| start + timeout);
+        let result = self.run_with_handlers();
+        let elapsed = start.elapsed();
+        self.metrics.elapsed += elapsed;
+        debug!(result = ?result, ?elapsed, instructions = self.metrics.instructions, "executed");
+        return result;
+    }
+
+    /// Run, resuming at the catch block whenever a throw reaches a handler
+    fn run_with_handlers(&mut self) -> RunResult {
+        loop {
+            match self.run() {
+                RunResult::RuntimeError if self.unwind_to_handler() => continue,
+                result => return self.halted.unwrap_or(result)
+            }
+        }
+    }
+
+    /// Run a method to completion from inside an instruction and return its
+    /// result, or None after a runtime error
+    fn call_method_now(&mut self, receiver: Value, closure_idx: usize, args: &[Value]) -> Option<Value> {
+        if self.nested_runs >= MAX_NESTED_RUNS {
+            let name = {
+                let func_idx = self.heap.get_closure(closure_idx).func_idx;
+                self.heap.get_function(func_idx).name.to_string()
+            };
+            let message = format!("Stack overflow calling {} at depth {}.", name, self.callstack.len());
+            self.runtime_error(&message);
+            return None;
+        }
+        let saved_base_depth = self.base_depth;
+        self.callstack.last_mut().unwrap().ip = self.ip;
+        self.push(receiver);
+        for arg in args {
+            self.push(*arg);
+        }
+        if !self.call(closure_idx, args.len()) {
+            return None;
+        }
+        self.base_depth = self.callstack.len() - 1;
+        self.nested_runs += 1;
+        let result = self.run_with_handlers();
+        self.nested_runs -= 1;
+        self.base_depth = saved_base_depth;
+        if !matches!(result, RunResult::Ok) {
+            return None;
+        }
+        // The caller's frame is innermost again and holds the ip saved above
+        self.load_frame();
+        return Some(self.pop());
     }
 
-    /// Push value on to the stack
+    /// Compile a script, source or bytecode, onto the heap for load. Globals
+    /// it defines go in with the ones already here.
+    ///
+    /// Returns the index of the main function or the compile errors
+    pub(crate) fn compile_script(&mut self, contents: &[u8]) -> Result<usize, String> {
+        if bytecode::is_bytecode(contents) {
+            return bytecode::deserialize(contents, &mut self.heap);
+        }
+        let source = String::from_utf8_lossy(contents);
+        let mut scanner = Scanner::new(&source.to_string());
+        scanner.print_errors = false;
+
+        // The parser owns the heap while compiling
+        let mut heap_to_parser = Heap::new();
+        mem::swap(&mut self.heap, &mut heap_to_parser);
+
+        let mut parser = Parser::new(heap_to_parser, scanner);
+        parser.print_errors = false;
+        parser.warn_unused_globals = false;
+        parser.source = source.as_ref().into();
+        let func_main_idx = parser.compile();
+
+        mem::swap(&mut parser.heap, &mut self.heap);
+
+        if parser.had_error {
+            return Err(parser.errors.join("\n"));
+        }
+        return Ok(func_main_idx);
+    }
+
+    /// Directory of the innermost running script that was read from a file,
+    /// None for scripts compiled from a string
+    pub(crate) fn script_dir(&self) -> Option<PathBuf> {
+        return self.callstack.iter().rev()
+            .find_map(|frame| {
+                let function = self.heap.get_function(self.heap.get_closure(frame.closure_idx).func_idx);
+                return function.script_path.as_deref().map(|path| path.parent().map_or_else(PathBuf::new, Path::to_path_buf));
+            });
+    }
+
+    /// Call the main function of a compiled script in place of the native
+    /// being called, its nil return becomes the native's result
+    pub(crate) fn call_script_from_native(&mut self, func_main_idx: usize, arg_count: usize) -> Result<(), NativeError> {
+        if self.callstack.len() >= MAX_CALLSTACK || self.stack_top >= self.max_stack {
+            return Err(NativeError::new(&format!("Stack overflow calling main at depth {}.", self.callstack.len())));
+        }
+        // The script's closure takes the native's slot, the arguments are dropped
+        self.stack_top -= arg_count + 1;
+        let upvalue_count = self.heap.get_function(func_main_idx).upvalue_count;
+        let closure_idx = self.new_closure(func_main_idx, upvalue_count);
+        self.push(Value::Obj(Object::ClosureIndex(closure_idx)));
+        self.call(closure_idx, 0);
+        return Ok(());
+    }
+
+    /// Resume at the innermost catch block with the thrown value on top of
+    /// the stack, discarding the frames and values above its try statement.
+    /// Returns false when nothing was thrown to a handler.
+    fn unwind_to_handler(&mut self) -> bool {
+        // Handlers outside a nested run are left for the outer run loop
+        if self.handlers.last().is_none_or(|handler| handler.frame_depth <= self.base_depth) {
+            return false;
+        }
+        let thrown = match self.thrown.take() {
+            Some(thrown) => thrown,
+            None => return false
+        };
+        let handler = self.handlers.pop().unwrap();
+        self.close_upvalues(handler.stack_top);
+        self.callstack.truncate(handler.frame_depth);
+        self.stack_top = handler.stack_top;
+        self.push(thrown);
+        self.callstack.last_mut().unwrap().ip = handler.catch_ip;
+        return true;
+    }
+
+    /// Snapshot of the execution counters, including heap allocations
+    pub fn metrics(&self) -> Metrics {
+        let mut metrics = self.metrics;
+        metrics.allocations = self.heap.allocations;
+        return metrics;
+    }
+
+    /// Push value on to the stack, growing it when every slot is in use
     #[inline(always)]
     fn push(&mut self, value: Value) {
-        self.stack[self.stack_top] = value;
+        if self.stack_top == self.stack.len() {
+            self.stack.push(value);
+        } else {
+            self.stack[self.stack_top] = value;
+        }
         self.stack_top += 1;
+        if self.stack_top > self.metrics.peak_stack_depth {
+            self.metrics.peak_stack_depth = self.stack_top;
+        }
     }
 
     /// Pop value from the stack
     #[inline(always)]
     fn pop(&mut self)->Value {
         self.stack_top -= 1;
-        return self.stack.get(self.stack_top).unwrap().clone();
+        // Value is Copy, so a plain indexed read is enough
+        return self.stack[self.stack_top];
     }
 
     /// Fast pop without returning value
@@ -132,116 +557,285 @@ impl VM {
     /// Run the VM
     fn run(&mut self)-> RunResult {
 
-        let main_frame = self.callstack.last().unwrap();
-
-        let mut ip_counter = 0;
-        self.ip = main_frame.ip;
-        self.curr_func_idx = self.heap.get_closure(main_frame.closure_idx).func_idx;
+        // Count down instead of taking a modulo on every instruction
+        let mut gc_countdown = 0;
+        self.load_frame();
 
         // The VM run loop
         loop {
-            log!("LINE: {}", self.ip);
-            log!("CALL STACK {:?}", &self.stack);
-
             let byte = self.read_byte();
+            self.metrics.instructions += 1;
+            if self.metrics.instructions > self.instruction_deadline {
+                return self.halt(RunResult::BudgetExceeded, "Instruction budget exceeded.");
+            }
 
-            // Convert byte to opcode
-            let opcode: Opcode = unsafe { std::mem::transmute(byte) };
+            let opcode = match Opcode::from_byte(byte) {
+                Some(opcode) => opcode,
+                None => {
+                    self.runtime_error(&format!("Invalid opcode {}.", byte));
+                    return RunResult::RuntimeError;
+                }
+            };
+            // An event per instruction is only affordable in debug builds
+            #[cfg(debug_assertions)]
+            trace!(ip = self.ip - 1, opcode = ?opcode, stack = self.stack_top, "dispatch");
 
             match opcode {
                 Opcode::Constant => {
-                    log!("OP CONSTANT");
                     let constant = self.read_constant();
                     self.push(constant);
                 }
+                Opcode::Wide => {
+                    self.wide_operand = (self.read_short() as usize) << 8;
+                }
+                Opcode::ConstantLong => {
+                    let constant = self.read_constant_long();
+                    self.push(constant);
+                }
                 Opcode::Nil => {
-                    log!("OP NIL");
                     self.push(Value::nil());
                 }
                 Opcode::True => {
-                    log!("OP TRUE");
                     self.push(Value::bool(true));
                 }
                 Opcode::False => {
-                    log!("OP FALSE");
                     self.push(Value::bool(false));
                 }
                 Opcode::Pop => {
-                    log!("OP POP");
                     self.fpop();
                 }
+                Opcode::PopN => {
+                    let count = self.read_byte() as usize;
+                    self.stack_top -= count;
+                }
                 Opcode::DefineGlobal => {
-                    log!("OP DEFINE GLOBAL VAR");
                     let str = self.read_string();
                     let str_hash = str.as_string_hash();
+                    if !self.check_not_const(str_hash, "redefine") {
+                        return RunResult::RuntimeError;
+                    }
+                    self.globals.insert(str_hash, *self.peek(0));
+                    self.fpop();
+                }
+                Opcode::DefineDeclaration => {
+                    let str_hash = self.read_string().as_string_hash();
+                    if !self.check_not_const(str_hash, "redefine") {
+                        return RunResult::RuntimeError;
+                    }
                     let value = *self.peek(0);
-                    self.globals.insert(str_hash, value );
+                    let existing = self.globals.get(&str_hash).copied();
+                    let redefined = match existing {
+                        Some(existing) if self.hot_redefinition => self.redefine_in_place(existing, value),
+                        _ => false
+                    };
+                    if !redefined {
+                        self.globals.insert(str_hash, value );
+                    }
+                    self.fpop();
+                }
+                Opcode::DefineConstGlobal => {
+                    let str_hash = self.read_string().as_string_hash();
+                    if !self.check_not_const(str_hash, "redefine") {
+                        return RunResult::RuntimeError;
+                    }
+                    self.globals.insert(str_hash, *self.peek(0));
+                    self.const_globals.insert(str_hash);
                     self.fpop();
                 }
                 Opcode::GetGlobal => {
-                    log!("OP GET GLOBAL VAR");
                     let str = self.read_string();
                     let str_hash = str.as_string_hash();
-                    let option_value = self.globals.get(&str_hash);
+                    let option_value = match self.globals.get(&str_hash) {
+                        None => {
+                            if let Some(message) = self.disallowed_native(str_hash) {
+                                self.runtime_error(&message);
+                                return RunResult::RuntimeError
+                            }
+                            self.define_lazy_native(str_hash)
+                        }
+                        Some(content) => Some(*content)
+                    };
                     let value = match option_value {
                         None => {
                             let message = format!("Undefined variable {}",
                                     self.heap.get_string(str_hash));
-                            self.runtime_error(&*message);
+                            self.runtime_error(&message);
                             return RunResult::RuntimeError
                         }
-                        Some(content) => (*content)
+                        Some(content) => content
                     };
                     self.push(value);
                 }
                 Opcode::SetGlobal => {
-                    log!("OP SET GLOBAL");
                     let str = self.read_string();
                     let str_hash = str.as_string_hash();
-                    if self.globals.get(&str_hash).is_none() {
+                    if !self.globals.contains_key(&str_hash) {
                         let message = format!("Undefined variable {}", self.heap.get_string(str_hash));
                         self.runtime_error(&message);
                         return RunResult::RuntimeError;
+                    } else if !self.check_not_const(str_hash, "assign to") {
+                        return RunResult::RuntimeError;
                     } else {
                         self.globals.insert(str_hash, *self.peek(0));
                     }
                 }
                 Opcode::GetLocal => {
-                    log!("OP GET LOCAL");
                     let slot = self.read_byte() as usize;
-                    log!("SLOT: {}", slot);
-                    let slot_offset = self.callstack.last().unwrap().slot_offset;
-                    log!("SLOT INDEX: {}", slot_offset);
+                    let slot_offset = self.frame_base;
                     let value = self.stack[slot + slot_offset];
-                    log!("Value: {}", value);
                     self.push(value);
                 }
                 Opcode::SetLocal => {
-                    log!("OP SET CONSTANT");
                     let slot = self.read_byte() as usize;
-                    let slot_offset = self.callstack.last().unwrap().slot_offset;
+                    let slot_offset = self.frame_base;
+                    self.stack[slot + slot_offset] = *self.peek(0);
+                }
+                Opcode::GetLocalLong => {
+                    let slot = self.read_short() as usize;
+                    let slot_offset = self.frame_base;
+                    let value = self.stack[slot + slot_offset];
+                    self.push(value);
+                }
+                Opcode::SetLocalLong => {
+                    let slot = self.read_short() as usize;
+                    let slot_offset = self.frame_base;
                     self.stack[slot + slot_offset] = *self.peek(0);
                 }
                 Opcode::GetUpvalue => {
-                    log!("OP GET UPVALUE");
-                    let slot = self.read_byte();
+                    let slot = self.read_byte() as usize;
+                    let closure_idx = self.callstack.last().unwrap().closure_idx;
+                    let value = self.resolve_upvalue_location(slot, closure_idx);
+                    self.push(value);
+                }
+                Opcode::GetUpvalueLong => {
+                    let slot = self.read_short() as usize;
                     let closure_idx = self.callstack.last().unwrap().closure_idx;
                     let value = self.resolve_upvalue_location(slot, closure_idx);
                     self.push(value);
                 }
                 Opcode::SetUpvalue => {
-                    log!("OP SET UPVALUE");
-                    let slot = self.read_byte();
+                    let slot = self.read_byte() as usize;
+                    let closure_idx = self.callstack.last().unwrap().closure_idx;
+                    self.set_upvalue_location(slot, closure_idx);
+                }
+                Opcode::SetUpvalueLong => {
+                    let slot = self.read_short() as usize;
                     let closure_idx = self.callstack.last().unwrap().closure_idx;
                     self.set_upvalue_location(slot, closure_idx);
                 }
                 Opcode::GetProperty => {
+                    if self.peek(0).is_class_index() {
+                        let class_idx = self.peek(0).as_class_index();
+                        let name_hash = self.read_string().as_string_hash();
+                        let constant = self.heap.get_class(class_idx).constants.get(&name_hash).copied();
+                        match constant {
+                            Some(value) => {
+                                self.fpop(); // class
+                                self.push(value);
+                            }
+                            None => {
+                                let message = format!("Undefined constant '{}'", self.heap.get_string(name_hash));
+                                self.runtime_error(&message);
+                                return RunResult::RuntimeError;
+                            }
+                        }
+                        continue;
+                    }
+                    if !self.peek(0).is_instance_index() {
+                        self.runtime_error("Only instances have properties.");
+                        return RunResult::RuntimeError;
+                    }
                     let instance_idx = self.peek(0).as_instance_index();
+                    let offset = self.ip - 1;
                     let field_name_hash = self.read_string().as_string_hash();
-                    if self.heap.get_instance(instance_idx).fields.contains_key(&field_name_hash) {
-                        let value = self.heap.get_instance(instance_idx).fields.get(&field_name_hash).unwrap().clone();
+                    if let Some(value) = self.get_field(offset, instance_idx, field_name_hash) {
                         self.fpop(); // instance
                         self.push(value);
+                    } else {
+                        let class_idx = self.heap.get_instance(instance_idx).class_idx;
+                        if !self.bind_method(class_idx, field_name_hash) {
+                            return RunResult::RuntimeError;
+                        }
+                    }
+                }
+                Opcode::BuildList => {
+                    let count = self.read_byte() as usize;
+                    let items = self.stack[self.stack_top - count..self.stack_top].to_vec();
+                    self.stack_top -= count;
+                    let list_idx = self.heap.alloc_list(List::new(items));
+                    self.push(Value::object(Object::list(list_idx)));
+                }
+                Opcode::BuildMap => {
+                    let count = self.read_byte() as usize;
+                    let start = self.stack_top - count * 2;
+                    let map_idx = self.heap.alloc_map(Map::new());
+                    // Keep the map reachable while hash and eq methods run
+                    self.push(Value::object(Object::map(map_idx)));
+                    for slot in (start..start + count * 2).step_by(2) {
+                        let (key, value) = (self.stack[slot], self.stack[slot + 1]);
+                        let position = match self.find_key(map_idx, key) {
+                            Some(position) => position,
+                            None => return RunResult::RuntimeError
+                        };
+                        self.shade(key);
+                        self.shade(value);
+                        self.heap.get_mut_map(map_idx).set_at(position, key, value);
+                    }
+                    self.stack_top = start;
+                    self.push(Value::object(Object::map(map_idx)));
+                }
+                Opcode::GetIndex => {
+                    let index = *self.peek(0);
+                    let target = *self.peek(1);
+                    if target.is_map_index() {
+                        // Missing keys read as nil
+                        let value = match self.find_key(target.as_map_index(), index) {
+                            Some(KeyPosition::Entry(position)) => self.heap.get_map(target.as_map_index()).entries[position].1,
+                            Some(KeyPosition::Vacant(_)) => Value::nil(),
+                            None => return RunResult::RuntimeError
+                        };
+                        self.stack_top -= 2;
+                        self.push(value);
+                        continue;
+                    }
+                    self.stack_top -= 2;
+                    let position = match self.list_position(target, index) {
+                        Some(position) => position,
+                        None => return RunResult::RuntimeError
+                    };
+                    let value = self.heap.get_list(target.as_list_index()).items[position];
+                    self.push(value);
+                }
+                Opcode::SetIndex => {
+                    let value = *self.peek(0);
+                    let index = *self.peek(1);
+                    let target = *self.peek(2);
+                    if target.is_map_index() {
+                        let position = match self.find_key(target.as_map_index(), index) {
+                            Some(position) => position,
+                            None => return RunResult::RuntimeError
+                        };
+                        self.shade(index);
+                        self.shade(value);
+                        self.heap.get_mut_map(target.as_map_index()).set_at(position, index, value);
+                        self.stack_top -= 3;
+                        self.push(value);
+                        continue;
+                    }
+                    self.stack_top -= 3;
+                    let position = match self.list_position(target, index) {
+                        Some(position) => position,
+                        None => return RunResult::RuntimeError
+                    };
+                    self.shade(value);
+                    self.heap.get_mut_list(target.as_list_index()).items[position] = value;
+                    self.push(value);
+                }
+                Opcode::GetSuper => {
+                    let method_name_hash = self.read_string().as_string_hash();
+                    let superclass_idx = self.pop().as_class_index();
+                    if !self.bind_method(superclass_idx, method_name_hash) {
+                        return RunResult::RuntimeError;
                     }
                 }
                 Opcode::SetProperty => {
@@ -250,20 +844,24 @@ impl VM {
                         return RunResult::RuntimeError;
                     }
                     let instance_idx = self.peek(1).as_instance_index();
+                    let offset = self.ip - 1;
                     let field_name_hash = self.read_string().as_string_hash();
-                    self.heap.get_mut_instance(instance_idx).fields.insert(field_name_hash, *self.peek(0) );
+                    self.shade(*self.peek(0));
+                    self.set_field(offset, instance_idx, field_name_hash, *self.peek(0));
                     let value = self.pop();
                     self.fpop(); // instance
                     self.push(value)
                 }
                 Opcode::Equal => {
-                    log!("OP EQUAL");
                     let b = self.pop();
                     let a = self.pop();
-                    self.push(Value::bool(a == b))
+                    let equal = match self.values_equal(a, b) {
+                        Some(equal) => equal,
+                        None => return RunResult::RuntimeError
+                    };
+                    self.push(Value::bool(equal))
                 }
                 Opcode::Add => {
-                    log!("OP ADD");
                     // fixme: refactor this to use self.bin_ops(..)
                     let b = *self.peek(0);
                     let a = *self.peek(1);
@@ -271,15 +869,16 @@ impl VM {
                         self.fpop();
                         self.fpop();
                         self.push(Value::number(a.as_number() + b.as_number()));
-                    } else if Self::is_both_string(&a, &b) {
-                        let str_b = self.heap.get_string(a.as_string_hash());
-                        let str_a = self.heap.get_string(b.as_string_hash());
-
-                        // Due to ownership rule, this is the easiest way to merge
-                        // two borrowed strings
-                        let mut merged = String::with_capacity(str_a.len() + str_b.len());
-                        merged.push_str(&str_b);
-                        merged.push_str(&str_a);
+                    } else if Self::is_concatenable(&a, &b) {
+                        // A number or instance next to a string is converted to its text
+                        let mut merged = match self.stringify(a) {
+                            Some(text) => text,
+                            None => return RunResult::RuntimeError
+                        };
+                        match self.stringify(b) {
+                            Some(text) => merged.push_str(&text),
+                            None => return RunResult::RuntimeError
+                        }
 
                         let hash = self.heap.alloc_string(merged);
 
@@ -289,42 +888,36 @@ impl VM {
                         self.push(Value::object(Object::string(hash)));
                     }
                     else {
-                        self.runtime_error("Operands must be numbers or two strings");
+                        self.runtime_error("Operands must be numbers or strings");
                         return RunResult::RuntimeError
                     }
                 }
                 Opcode::Multiply => {
-                    log!("OP MUL");
                     if !self.bin_ops(|a, b| a * b) {
                         return RunResult::RuntimeError
                     }
                 }
                 Opcode::Divide => {
-                    log!("OP DIV");
                     if !self.bin_ops(|a, b| a / b) {
                         return RunResult::RuntimeError
                     }
                 }
                 Opcode::Subtract => {
-                    log!("OP SUBS");
                     if !self.bin_ops(|a, b| a - b) {
                         return RunResult::RuntimeError
                     }
                 }
                 Opcode::Less => {
-                    log!("OP LESS");
                     if !self.bin_cmp(|a, b| a < b) {
                         return RunResult::RuntimeError
                     }
                 }
                 Opcode::Greater => {
-                    log!("OP GREATER");
                     if !self.bin_cmp(|a, b| a > b) {
                         return RunResult::RuntimeError
                     }
                 }
                 Opcode::Negate => {
-                    log!("OP NEGATE");
                     let value = self.pop();
                     if value.is_number() {
                         self.push(Value::number(-value.as_number()));
@@ -334,59 +927,180 @@ impl VM {
                     }
                 }
                 Opcode::Not => {
-                    log!("OP NOT");
                     let value = self.pop();
-                    if value.is_boolean() {
-                        self.push(Value::bool(!value.as_boolean()));
-                    } else {
-                        self.runtime_error("Operand must be a boolean.");
-                        return RunResult::RuntimeError
-                    }
+                    self.push(Value::bool(value.is_falsey()));
                 }
                 Opcode::Jump => {
-                    log!("OP JUMP");
                     let offset = self.read_short() as usize;
                     self.ip += offset;
                 }
                 Opcode::JumpIfFalse => {
-                    log!("OP JUMP IF FALSE");
                     let offset = self.read_short() as usize;
-                    let value = self.peek(0);
-                    if !value.as_boolean() {
+                    if self.peek(0).is_falsey() {
+                        self.ip += offset
+                    }
+                }
+                Opcode::JumpIfTrue => {
+                    let offset = self.read_short() as usize;
+                    if !self.peek(0).is_falsey() {
+                        self.ip += offset
+                    }
+                }
+                Opcode::JumpIfNotNil => {
+                    let offset = self.read_short() as usize;
+                    if !self.peek(0).is_nil() {
                         self.ip += offset
                     }
                 }
                 Opcode::Loop => {
-                    log!("OP LOOP");
                     let offset = self.read_short() as usize;
                     self.ip -= offset;
                 }
-                Opcode::Call => {
-                    log!("OP CALL");
-                    let arg_count = self.read_byte() as usize;
+                Opcode::Dup => {
+                    let value = *self.peek(0);
+                    self.push(value);
+                }
+                Opcode::PushHandler => {
+                    let offset = self.read_short() as usize;
+                    self.handlers.push(Handler {
+                        frame_depth: self.callstack.len(),
+                        stack_top: self.stack_top,
+                        catch_ip: self.ip + offset,
+                    });
+                }
+                Opcode::PopHandler => {
+                    self.handlers.pop();
+                }
+                Opcode::Throw => {
+                    let value = self.pop();
+                    if self.handlers.is_empty() {
+                        let message = format!("Uncaught exception {}", self.format_value(value));
+                        self.runtime_error(&message);
+                    } else {
+                        self.thrown = Some(value);
+                    }
+                    return RunResult::RuntimeError;
+                }
+                Opcode::ForIter => {
+                    let slot = self.read_byte() as usize;
+                    let offset = self.read_short() as usize;
+                    let seq_slot = self.frame_base + slot;
+                    let seq = self.stack[seq_slot];
+                    let position = self.stack[seq_slot + 1].as_number() as usize;
+                    let next = if seq.is_list_index() {
+                        self.heap.get_list(seq.as_list_index()).items.get(position).copied()
+                    } else if seq.is_map_index() {
+                        self.heap.get_map(seq.as_map_index()).entries.get(position).map(|entry| entry.0)
+                    } else {
+                        self.runtime_error("Can only iterate over lists and maps.");
+                        return RunResult::RuntimeError;
+                    };
+                    match next {
+                        Some(value) => {
+                            self.stack[seq_slot + 1] = Value::number((position + 1) as f64);
+                            self.stack[seq_slot + 2] = value;
+                        }
+                        None => self.ip += offset
+                    }
+                }
+                Opcode::Call | Opcode::CallLong => {
+                    let arg_count = if matches!(opcode, Opcode::Call) {
+                        self.read_byte() as usize
+                    } else {
+                        self.read_short() as usize
+                    };
                     let curr_callstack = self.callstack.len()-1;
                     // Store current ip
                     self.callstack.get_mut(curr_callstack).unwrap().ip = self.ip;
                     if !self.call_value(*self.peek(arg_count ), arg_count) {
                         return RunResult::RuntimeError;
                     }
-                    let curr_frame = self.callstack.last().unwrap();
-                    self.ip = curr_frame.ip;
-                    // Cached the function ptr from the current callstack
-                    self.curr_func_idx = self.heap.get_closure(curr_frame.closure_idx).func_idx;
+                    self.load_frame();
+                }
+                Opcode::ListAppend => {
+                    let value = self.pop();
+                    let list_idx = self.peek(0).as_list_index();
+                    self.shade(value);
+                    self.heap.get_mut_list(list_idx).items.push(value);
+                }
+                Opcode::ListExtend => {
+                    let value = self.pop();
+                    if !value.is_list_index() {
+                        self.runtime_error("Only lists can be spread into arguments.");
+                        return RunResult::RuntimeError;
+                    }
+                    let list_idx = self.peek(0).as_list_index();
+                    self.shade(value);
+                    let items = self.heap.get_list(value.as_list_index()).items.clone();
+                    self.heap.get_mut_list(list_idx).items.extend(items);
+                }
+                Opcode::CallSpread => {
+                    let list_idx = self.pop().as_list_index();
+                    let items = self.heap.get_list(list_idx).items.clone();
+                    let arg_count = items.len();
+                    for item in items {
+                        self.push(item);
+                    }
+                    let curr_callstack = self.callstack.len()-1;
+                    // Store current ip
+                    self.callstack.get_mut(curr_callstack).unwrap().ip = self.ip;
+                    if !self.call_value(*self.peek(arg_count), arg_count) {
+                        return RunResult::RuntimeError;
+                    }
+                    self.load_frame();
+                }
+                Opcode::CallNamed => {
+                    let positional = self.read_byte() as usize;
+                    let named = self.read_byte() as usize;
+                    let curr_callstack = self.callstack.len()-1;
+                    // Store current ip
+                    self.callstack.get_mut(curr_callstack).unwrap().ip = self.ip;
+                    let arg_count = match self.bind_named_arguments(positional, named) {
+                        Some(arg_count) => arg_count,
+                        None => return RunResult::RuntimeError
+                    };
+                    if !self.call_value(*self.peek(arg_count), arg_count) {
+                        return RunResult::RuntimeError;
+                    }
+                    self.load_frame();
+                }
+                Opcode::MatchList => {
+                    let count = self.read_byte() as usize;
+                    let has_rest = self.read_byte() == 1;
+                    let value = self.pop();
+                    let is_match = value.is_list_index() && {
+                        let len = self.heap.get_list(value.as_list_index()).items.len();
+                        if has_rest { len >= count } else { len == count }
+                    };
+                    self.push(Value::bool(is_match));
+                }
+                Opcode::SliceFrom => {
+                    let start = self.read_byte() as usize;
+                    let list_idx = self.pop().as_list_index();
+                    let items = self.heap.get_list(list_idx).items[start..].to_vec();
+                    let slice_idx = self.heap.alloc_list(List::new(items));
+                    self.push(Value::object(Object::list(slice_idx)));
+                }
+                Opcode::IsInstance => {
+                    let class = self.pop();
+                    let value = self.pop();
+                    if !class.is_class_index() {
+                        self.runtime_error("Instance pattern must name a class.");
+                        return RunResult::RuntimeError;
+                    }
+                    let is_instance = value.is_instance_index() &&
+                        self.heap.get_instance(value.as_instance_index()).class_idx == class.as_class_index();
+                    self.push(Value::bool(is_instance));
                 }
                 Opcode::Print => {
-                    log!("OP PRINT");
                     let content = self.pop();
-                    if content.is_string_hash() {
-                        let hash = content.as_string_hash();
-                        println!("{}", self.heap.get_string(hash));
-                    } else {
-                        println!("{}", content);
-                    }
+                    let text = match self.stringify(content) {
+                        Some(text) => text,
+                        None => return RunResult::RuntimeError
+                    };
+                    let _ = writeln!(self.output, "{}", text);
                 }
                 Opcode::Invoke => {
-                    log!("OP INVOKE");
                     let method_name_hash = self.read_string().as_string_hash();
                     let arg_count = self.read_byte() as usize;
                     let curr_callstack = self.callstack.len()-1;
@@ -395,10 +1109,7 @@ impl VM {
                     if !self.invoke(method_name_hash, arg_count) {
                         return RunResult::RuntimeError
                     }
-                    let curr_frame = self.callstack.last().unwrap();
-                    self.ip = curr_frame.ip;
-                    // Cached the function ptr from the current callstack
-                    self.curr_func_idx = self.heap.get_closure(curr_frame.closure_idx).func_idx;
+                    self.load_frame();
                 }
                 Opcode::SuperInvoke => {
                     let method_name_hash = self.read_string().as_string_hash();
@@ -410,16 +1121,12 @@ impl VM {
                     if !self.invoke_from_class(superclass_idx, method_name_hash, arg_count) {
                         return RunResult::RuntimeError;
                     }
-                    let curr_frame = self.callstack.last().unwrap();
-                    self.ip = curr_frame.ip;
-                    // Cached the function ptr from the current callstack
-                    self.curr_func_idx = self.heap.get_closure(curr_frame.closure_idx).func_idx;
+                    self.load_frame();
 
                 }
-                Opcode::Closure => {
-                    log!("OP CLOSURE");
-                    let func_idx = self.read_constant().as_function_index();
-                    log!("FUNC: {}", self.heap.get_function(func_idx).name);
+                Opcode::Closure | Opcode::ClosureLong => {
+                    let is_wide = matches!(opcode, Opcode::ClosureLong);
+                    let func_idx = self.read_operand_constant().as_function_index();
                     let upvalue_count = self.heap.get_function(func_idx).upvalue_count;
                     let closure_idx = self.new_closure(func_idx, upvalue_count);
                     self.push(Value::object(Object::ClosureIndex(closure_idx)));
@@ -428,21 +1135,31 @@ impl VM {
                     let upvalues_count = self.heap.get_closure(closure_idx).upvalues.len();
                     for i in 0..upvalues_count {
                         let is_local = self.read_byte();
-                        let index = self.read_byte();
+                        let index = if is_wide { self.read_short() as usize } else { self.read_byte() as usize };
 
                         let curr_frame = self.callstack.last().unwrap();
+                        // Loaded bytecode could capture past the frame or the enclosing upvalues
+                        let in_bounds = if is_local == 1u8 {
+                            curr_frame.slot_offset + index < self.stack_top
+                        } else {
+                            index < self.heap.get_closure(curr_frame.closure_idx).upvalues.len()
+                        };
+                        if !in_bounds {
+                            self.runtime_error("Closure captures an upvalue out of range.");
+                            return RunResult::RuntimeError;
+                        }
                         if is_local == 1u8 {
                             // The upvalue is in local scope
                             let mut prev_upvalue: Option<Rc<RefCell<ObjUpvalue>>> = None;
                             let mut curr_upvalue = match &self.open_upvalues {
                                 None => { None }
-                                Some(it) => { Some(Rc::clone(&it)) }
+                                Some(it) => { Some(Rc::clone(it)) }
                             };
-                            let location = curr_frame.slot_offset + index as usize;
+                            let location = curr_frame.slot_offset + index;
                             // todo: Untested path
                             while Self::upvalue_location_is_greater_than(&curr_upvalue, &location) {
                                 // previous = current
-                                prev_upvalue = Some(Rc::clone(&curr_upvalue.as_ref().unwrap()));
+                                prev_upvalue = Some(Rc::clone(curr_upvalue.as_ref().unwrap()));
                                 // current = current -> next
                                 curr_upvalue = if Self::has_next_upvalue(&mut curr_upvalue) {
                                     Self::get_next_upvalue(&curr_upvalue)
@@ -454,20 +1171,15 @@ impl VM {
                             if Self::upvalue_location_match(&curr_upvalue, location) {
                                 self.heap.get_mut_closure(closure_idx).upvalues[i] = Rc::clone(&curr_upvalue.unwrap());
                             } else {
-                                let mut next_link: Option<Rc<RefCell<ObjUpvalue>>> = None;
-                                if curr_upvalue.is_some() {
-                                    next_link = Some( Rc::clone(&curr_upvalue.unwrap()));
-                                }
+                                let next_link: Option<Rc<RefCell<ObjUpvalue>>> = curr_upvalue.as_ref().map(Rc::clone);
                                 let created_upvalue = Rc::new(RefCell::new(
                                     ObjUpvalue::new(location, next_link )));
 
-                                if prev_upvalue.is_none() {
-                                    self.open_upvalues = Some(Rc::clone(&created_upvalue))
-                                } else {
+                                match &prev_upvalue {
+                                    None => self.open_upvalues = Some(Rc::clone(&created_upvalue)),
                                     // todo: Untested path
-                                    unsafe {
-                                        (*prev_upvalue.as_ref().unwrap().as_ptr()).next =
-                                            Some(Rc::clone(&created_upvalue));
+                                    Some(prev_upvalue) => unsafe {
+                                        (*prev_upvalue.as_ptr()).next = Some(Rc::clone(&created_upvalue));
                                     }
                                 }
                                 self.heap.get_mut_closure(closure_idx).upvalues[i] = Rc::clone(&created_upvalue);
@@ -476,7 +1188,7 @@ impl VM {
                             // The upvalue is in outer scope
                             let curr_frame_closure_idx = curr_frame.closure_idx;
                             self.heap.get_mut_closure(closure_idx).upvalues[i] = Rc::clone(
-                                &self.heap.get_mut_closure(curr_frame_closure_idx).upvalues[index as usize]);
+                                &self.heap.get_mut_closure(curr_frame_closure_idx).upvalues[index]);
                         }
                     }
                 }
@@ -485,82 +1197,149 @@ impl VM {
                     self.close_upvalues(self.stack_top-1);
                 }
                 Opcode::Class => {
-                    let str_hash = self.read_constant().as_string_hash();
+                    let str_hash = self.read_operand_constant().as_string_hash();
                     let class_name = self.heap.get_string(str_hash);
                     let class = Class::new(class_name.to_string());
                     let class_idx = self.heap.alloc_class(class);
                     self.push(Value::Obj(Object::ClassIndex(class_idx)));
                 }
                 Opcode::Inherit => {
-                    log!("OP INHERIT");
                     let superclass = self.peek(1);
                     if !superclass.is_class_index() {
                         self.runtime_error("Superclass must be a class.");
                         return RunResult::RuntimeError;
                     }
-                    let subclass = self.peek(0).as_class_index();
-                    let methods = self.heap.get_class(superclass.as_class_index()).methods.clone();
+                    let subclass_idx = self.peek(0).as_class_index();
+                    let superclass_idx = superclass.as_class_index();
+                    let before = self.class_before_change(subclass_idx);
+                    let (methods, abstract_methods, constants) = {
+                        let superclass = self.heap.get_class(superclass_idx);
+                        (superclass.methods.clone(), superclass.abstract_methods.clone(), superclass.constants.clone())
+                    };
+                    for value in methods.values().chain(constants.values()) {
+                        self.shade(*value);
+                    }
+                    let mut subclass = self.heap.get_mut_class(subclass_idx);
                     for (key, value) in methods.into_iter() {
-                        self.heap.get_mut_class(subclass).methods.insert(key, value);
+                        subclass.methods.insert(key, value);
                     }
+                    subclass.abstract_methods = abstract_methods;
+                    subclass.constants = constants;
+                    subclass.superclass = Some(superclass_idx);
+                    drop(subclass);
+                    self.update_subclasses(subclass_idx, before);
                     self.pop();
                 }
                 Opcode::Method => {
-                    log!("OP METHOD");
                     let string_hash = self.read_string().as_string_hash();
                     self.define_method(string_hash);
                 }
+                Opcode::ClassConstant => {
+                    let string_hash = self.read_string().as_string_hash();
+                    let value = self.pop();
+                    self.shade(value);
+                    let class_idx = self.peek(0).as_class_index();
+                    let before = self.class_before_change(class_idx);
+                    self.heap.get_mut_class(class_idx).constants.insert(string_hash, value);
+                    self.update_subclasses(class_idx, before);
+                }
+                Opcode::AbstractMethod => {
+                    let string_hash = self.read_string().as_string_hash();
+                    let class_idx = self.peek(0).as_class_index();
+                    let before = self.class_before_change(class_idx);
+                    let mut class = self.heap.get_mut_class(class_idx);
+                    // Declaring it again abstract drops an inherited method
+                    class.methods.remove(&string_hash);
+                    if !class.abstract_methods.contains(&string_hash) {
+                        class.abstract_methods.push(string_hash);
+                    }
+                    drop(class);
+                    self.update_subclasses(class_idx, before);
+                }
                 Opcode::Return => {
-                    log!("OP RETURN");
 
                     // Pop return value
                     let result = self.pop();
                     let frame_to_delete = self.callstack.pop().unwrap();
-                    if self.callstack.is_empty() {
-                         self.fpop(); // Pop main function
-                        // println!("profile duration is: {:?}", self._profile_duration);
+                    // Drop handlers of try statements the return jumped out of
+                    while self.handlers.last().is_some_and(|handler| handler.frame_depth > self.callstack.len()) {
+                        self.handlers.pop();
+                    }
+                    if self.callstack.len() == self.base_depth {
+                        if self.callstack.is_empty() {
+                            self.fpop(); // Pop main function
+                            self.last_result = result;
+                            // println!("profile duration is: {:?}", self._profile_duration);
+                            return RunResult::Ok
+                        }
+                        // End of a nested run, leave the result for call_method_now
+                        self.stack_top = frame_to_delete.slot_offset;
+                        self.close_upvalues(frame_to_delete.slot_offset);
+                        self.push(result);
                         return RunResult::Ok
                     }
 
                     // Discard call frame
-                    let stack_len = self.stack_top;
-                    for _ in frame_to_delete.slot_offset..stack_len {
-                        self.fpop(); // performance-tuning
-                    }
+                    self.stack_top = frame_to_delete.slot_offset;
                     self.close_upvalues(frame_to_delete.slot_offset);
 
                     // Push return value
                     self.push(result);
 
-                    // Load the correct ip;
-                    self.ip = self.callstack.last().unwrap().ip;
-                    // Cached the function ptr from the current callstack
-                    self.curr_func_idx = self.heap.get_closure(self.callstack.last().unwrap().closure_idx).func_idx;
+                    self.load_frame();
+                }
+            }
+
+            if self.heap.is_over_limit() {
+                // Give the collector one chance to get back under the limit
+                self.collect_garbage();
+                if self.heap.is_over_limit() {
+                    self.runtime_error("Out of memory.");
+                    return RunResult::RuntimeError;
                 }
             }
 
-            if ip_counter % CHECK_GC_INTERVAL == 0 {
-                self.try_run_garbage_collection();
+            if self.heap.stress {
+                self.stress_garbage_collection();
+            }
+
+            if gc_countdown == 0 {
+                if !self.heap.stress {
+                    self.try_run_garbage_collection();
+                }
+                if self.deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+                    return self.halt(RunResult::Timeout, "Execution timed out.");
+                }
+                if self.interrupt_flag.swap(false, Ordering::Relaxed) {
+                    return self.halt(RunResult::Interrupted, "Interrupted.");
+                }
+                gc_countdown = self.gc_check_interval;
             }
 
-            ip_counter += 1;
+            gc_countdown -= 1;
         }
 
     }
 
-    fn set_upvalue_location(&mut self, slot: u8, closure_idx: usize) {
-        self.heap.get_closure(closure_idx)
-            .upvalues[slot as usize]
-            .as_ref().borrow_mut()
-            .location = Some((self.stack_top - 1) as usize);
+    /// Assign the value on top of the stack to the variable an upvalue refers
+    /// to, on the stack while open or in the upvalue once closed
+    fn set_upvalue_location(&mut self, slot: usize, closure_idx: usize) {
+        let value = *self.peek(0);
+        self.shade(value);
+        let upvalue = Rc::clone(&self.heap.get_closure(closure_idx).upvalues[slot]);
+        let mut upvalue = upvalue.as_ref().borrow_mut();
+        match upvalue.location {
+            Some(location) if upvalue.closed.is_none() => self.stack[location] = value,
+            _ => upvalue.closed = Some(value)
+        }
     }
 
-    fn resolve_upvalue_location(&mut self, slot: u8, closure_idx: usize) -> Value {
+    fn resolve_upvalue_location(&mut self, slot: usize, closure_idx: usize) -> Value {
         let location = self.heap.get_closure(closure_idx)
-            .upvalues[slot as usize]
+            .upvalues[slot]
             .as_ref()
             .borrow_mut()
-            .resolve_value(&self);
+            .resolve_value(self);
         location
     }
 
@@ -579,7 +1358,7 @@ impl VM {
     }
 
     fn get_next_upvalue(upvalue: &Option<Rc<RefCell<ObjUpvalue>>>) -> Option<Rc<RefCell<ObjUpvalue>>> {
-        return Some(Rc::clone(&upvalue.as_ref().unwrap()              // unwrap option of RC without dropping
+        return Some(Rc::clone(upvalue.as_ref().unwrap()              // unwrap option of RC without dropping
                                             .as_ref().borrow().next         // access upvalue object inside refcell
                                             .as_ref().unwrap()));           // unwrap option of next without dropping
     }
@@ -592,74 +1371,188 @@ impl VM {
     }
 
     fn new_closure(&mut self, func_idx: usize, upvalue_count: usize) -> usize {
+        // A closure without upvalues carries no state of its own, so it can be shared
+        if upvalue_count == 0 {
+            if let Some(closure_idx) = self.closure_cache.get(&func_idx) {
+                return *closure_idx;
+            }
+        }
         let mut closure = Closure::new(func_idx);
         closure.init_upvalues(upvalue_count);
         let closure_idx = self.heap.alloc_closure(closure);
+        if upvalue_count == 0 {
+            self.closure_cache.insert(func_idx, closure_idx);
+        }
         closure_idx
     }
 
-    /// Run garbage collection if heap is ready for GC
+    /// Advance the incremental collector, starting a collection once the heap
+    /// is ready for one
     fn try_run_garbage_collection(&mut self) {
-        if self.heap.is_ready_for_garbage_collection() {
-            let mut marked_objects = vec![];
-            // fixme: mark class and instances
-            self.mark_roots(&mut marked_objects);
-            // fixme: trace references under class and instances
-            self.trace_references(&mut marked_objects);
-            self.heap.run_gc(marked_objects);
+        if !self.gc_marking {
+            if !self.heap.is_ready_for_garbage_collection() {
+                return;
+            }
+            self.start_marking();
         }
+        let start = Instant::now();
+        if self.trace_references(self.gc_step_budget) {
+            self.finish_collection();
+        }
+        self.metrics.gc_pause += start.elapsed();
     }
 
-    ///
-    fn trace_references(&mut self, roots: &mut Vec<Value>) {
-        for object in roots.clone() {
-            match object {
-                Value::Obj(object) => {
-                    match object {
-                        Object::ClosureIndex(idx) => {
-                            let func_dx = self.heap.get_closure(idx).func_idx;
-                            // Constants
-                            for val in &self.heap.functions[func_dx].borrow().chunk.constants {
-                                roots.push(val.clone());
-                            }
-                            // Function
-                            roots.push(Value::Obj(Object::FunctionIndex(func_dx)));
-                            // Upvalues that have been closed
-                            for val in &self.heap.get_closure(idx).upvalues {
-                                if !val.as_ref().borrow().is_null {
-                                    match val.as_ref().borrow().closed {
-                                        Some(it) => roots.push(it.clone()),
-                                        None => {}
-                                    }
-                                }
-                            }
-                        },
-                        Object::InstanceIndex(idx) => {
-                            let instance = self.heap.get_instance(idx);
-                            // Mark fields hash table
-                            roots.extend(instance.fields.values().cloned().collect::<Vec<Value>>());
-                            for str_hash in instance.fields.keys() {
-                                roots.push(Value::Obj(Object::StringHash(*str_hash)));
-                            }
-                        },
-                        Object::ClassIndex(idx) => {
-                            let class = self.heap.get_class(idx);
-                            // Mark methods hash table
-                            roots.extend(class.methods.values().cloned().collect::<Vec<Value>>());
-                            for str_hash in class.methods.keys() {
-                                roots.push(Value::Obj(Object::StringHash(*str_hash)));
-                            }
-                        }
-                        _ => {}
+    /// Collect in full whenever the last instruction allocated, so an object
+    /// missing from the roots is freed before anything can use it again
+    fn stress_garbage_collection(&mut self) {
+        let allocated = self.heap.allocations.total();
+        if allocated != self.gc_stress_allocations {
+            self.collect_garbage();
+            self.gc_stress_allocations = allocated;
+        }
+    }
+
+    /// Mark everything reachable from the roots and sweep the rest in one go
+    pub(crate) fn collect_garbage(&mut self) {
+        let start = Instant::now();
+        if !self.gc_marking {
+            self.start_marking();
+        }
+        self.finish_collection();
+        self.metrics.gc_pause += start.elapsed();
+    }
+
+    /// Begin a collection by shading the roots gray
+    fn start_marking(&mut self) {
+        trace!(bytes = self.heap.bytes_allocated, "gc marking started");
+        self.gc_marking = true;
+        let mut marked = mem::take(&mut self.gc_marked);
+        self.mark_roots(&mut marked);
+        self.gc_marked = marked;
+    }
+
+    /// Rescan the roots, which change without a barrier, trace whatever is
+    /// still gray and sweep
+    fn finish_collection(&mut self) {
+        let _span = debug_span!("gc", cycle = self.metrics.gc_cycles + 1).entered();
+        let mut marked = mem::take(&mut self.gc_marked);
+        self.mark_roots(&mut marked);
+        self.gc_marked = marked;
+        self.trace_references(usize::MAX);
+        self.heap.run_gc(mem::take(&mut self.gc_marked));
+        // The cache doesn't keep closures alive, forget the ones just freed
+        let closures = &self.heap.closures;
+        self.closure_cache.retain(|_, closure_idx| closures.is_live(*closure_idx));
+        self.gc_traced = 0;
+        self.gc_visited.clear();
+        self.gc_marking = false;
+        self.metrics.gc_cycles += 1;
+    }
+
+    /// Write barrier, a value stored into a heap object while marking is shaded
+    /// gray so an already traced object can't hide it from the collector
+    #[inline(always)]
+    fn shade(&mut self, value: Value) {
+        if self.gc_marking {
+            self.gc_marked.push(value);
+        }
+    }
+
+    /// Trace up to budget gray values, appending everything they reference.
+    /// Returns true once no gray values are left.
+    fn trace_references(&mut self, budget: usize) -> bool {
+        let mut roots = mem::take(&mut self.gc_marked);
+        let mut visited = mem::take(&mut self.gc_visited);
+        let mut next = self.gc_traced;
+        let mut traced = 0;
+        while next < roots.len() && traced < budget {
+            let value = roots[next];
+            next += 1;
+            traced += 1;
+            let object = match value {
+                Value::Obj(object) => object,
+                _ => continue
+            };
+            if object.is_string_hash() || !visited.insert(object) {
+                continue;
+            }
+            self.push_references(object, &mut roots);
+        }
+        let done = next == roots.len();
+        self.gc_traced = next;
+        self.gc_marked = roots;
+        self.gc_visited = visited;
+        return done;
+    }
+
+    /// Append the values the object refers to, which the collector traces
+    /// after it
+    #[inline]
+    pub(crate) fn push_references(&self, object: Object, references: &mut Vec<Value>) {
+        match object {
+            Object::ClosureIndex(idx) => {
+                let closure = self.heap.get_closure(idx);
+                // Function
+                references.push(Value::Obj(Object::FunctionIndex(closure.func_idx)));
+                // Captured variables, held by the upvalue once closed or still on the stack while open
+                for val in &closure.upvalues {
+                    let upvalue = val.as_ref().borrow();
+                    if upvalue.is_null {
+                        continue;
+                    }
+                    match (upvalue.closed, upvalue.location) {
+                        (Some(it), _) => references.push(it),
+                        (None, Some(location)) => references.push(self.stack[location]),
+                        (None, None) => {}
                     }
                 }
-                _ => {}
+            },
+            Object::FunctionIndex(idx) => {
+                // Constants, including nested functions
+                references.extend(self.heap.get_function(idx).chunk.constants.iter().copied());
+            },
+            Object::InstanceIndex(idx) => {
+                let instance = self.heap.get_instance(idx);
+                references.push(Value::Obj(Object::ClassIndex(instance.class_idx)));
+                references.extend(instance.field_values());
+                for str_hash in instance.field_names(&self.heap.shapes) {
+                    references.push(Value::Obj(Object::StringHash(str_hash)));
+                }
+            },
+            Object::ListIndex(idx) => {
+                references.extend(self.heap.get_list(idx).items.iter().copied());
+            },
+            Object::MapIndex(idx) => {
+                // Mark both keys and values
+                for (key, value) in &self.heap.get_map(idx).entries {
+                    references.push(*key);
+                    references.push(*value);
+                }
+            },
+            Object::BoundMethodIndex(idx) => {
+                let bound_method = self.heap.get_bound_method(idx);
+                references.push(bound_method.receiver);
+                references.push(Value::Obj(Object::ClosureIndex(bound_method.closure_idx)));
+            },
+            Object::ClassIndex(idx) => {
+                let class = self.heap.get_class(idx);
+                // Mark methods hash table
+                references.extend(class.methods.values().copied());
+                references.extend(class.constants.values().copied());
+                // Kept alive so that redefining it can still find its subclasses
+                if let Some(superclass) = class.superclass {
+                    references.push(Value::Obj(Object::ClassIndex(superclass)));
+                }
+                for str_hash in class.methods.keys().chain(class.abstract_methods.iter()).chain(class.constants.keys()) {
+                    references.push(Value::Obj(Object::StringHash(*str_hash)));
+                }
             }
+            _ => {}
         }
     }
 
-    ///
-    fn mark_roots(&mut self, roots: &mut Vec<Value>) {
+    /// Append the values the VM holds on to directly
+    pub(crate) fn mark_roots(&mut self, roots: &mut Vec<Value>) {
         roots.extend(self.stack.clone());
         // Mark hash table
         roots.extend(self.globals.values().cloned().collect::<Vec<Value>>());
@@ -670,11 +1563,30 @@ impl VM {
             roots.push(Value::Obj(Object::ClosureIndex(callframe.closure_idx)));
         }
         roots.push(Value::object(Object::StringHash(self.init_string_hash)));
+        roots.push(Value::object(Object::StringHash(self.to_string_hash)));
+        roots.push(Value::object(Object::StringHash(self.eq_hash)));
+        roots.push(Value::object(Object::StringHash(self.hash_method_hash)));
+        roots.push(Value::object(Object::StringHash(self.method_missing_hash)));
+        // Variables captured by open upvalues
+        let mut upvalue = self.open_upvalues.clone();
+        while let Some(current) = upvalue {
+            let current = current.as_ref().borrow();
+            if let Some(location) = current.location {
+                roots.push(self.stack[location]);
+            }
+            upvalue = current.next.clone();
+        }
+        if let Some(thrown) = self.thrown {
+            roots.push(thrown);
+        }
+        roots.push(self.last_result);
     }
 
-    /// Shortcut for checking both strings are string hash
-    fn is_both_string(a: &Value, b: &Value) -> bool {
-        a.is_string_hash() && b.is_string_hash()
+    /// Can the operands be joined as strings? At least one must be a string
+    /// and the other a string, a number or an instance
+    fn is_concatenable(a: &Value, b: &Value) -> bool {
+        let is_text = |value: &Value| value.is_string_hash() || value.is_number() || value.is_instance_index();
+        (a.is_string_hash() && is_text(b)) || (b.is_string_hash() && is_text(a))
     }
 
     /// Shortcut for checking both strings are string hash
@@ -684,14 +1596,85 @@ impl VM {
 
     /// Interpret byte
     fn read_byte(&mut self)->u8 {
+        // The code pointer is refreshed by load_frame whenever the frame changes
         unsafe {
-            // Because curr_function is a pointer, * is needed to deference it
-            let result = (*(self.curr_function())).chunk.code[self.ip];
+            let result = *self.code.add(self.ip);
             self.ip += 1;
             return result;
         }
     }
 
+    /// Resume the innermost call frame, caching its ip, function and slot offset
+    #[inline(always)]
+    fn load_frame(&mut self) {
+        let frame = self.callstack.last().unwrap();
+        self.ip = frame.ip;
+        self.frame_base = frame.slot_offset;
+        self.curr_func_idx = unsafe { (*self.heap.closures[frame.closure_idx].as_ptr()).func_idx };
+        self.code = unsafe { (*self.curr_function()).chunk.code.as_ptr() };
+    }
+
+    /// Field of the instance, found through the inline cache of the property
+    /// instruction at the offset when the instance has the shape it saw last
+    #[inline(always)]
+    fn get_field(&self, offset: usize, instance_idx: usize, name: u32) -> Option<Value> {
+        let cache = self.property_cache(offset);
+        let instance = self.heap.get_instance(instance_idx);
+        if instance.shape == cache.shape {
+            return Some(instance.slots[cache.slot]);
+        }
+        if let Some(slot) = self.heap.shapes.slot(instance.shape, name) {
+            self.set_property_cache(offset, PropertyCache { shape: instance.shape, slot, next_shape: instance.shape });
+            return Some(instance.slots[slot]);
+        }
+        return instance.fields.get(&name).copied();
+    }
+
+    /// Set or add a field of the instance, through the inline cache of the
+    /// property instruction at the offset like get_field
+    #[inline(always)]
+    fn set_field(&mut self, offset: usize, instance_idx: usize, name: u32, value: Value) {
+        let cache = self.property_cache(offset);
+        let mut instance = self.heap.instances[instance_idx].borrow_mut();
+        let shape = instance.shape;
+        if shape == cache.shape {
+            if cache.next_shape == shape {
+                instance.slots[cache.slot] = value;
+            } else {
+                instance.slots.push(value);
+                instance.shape = cache.next_shape;
+            }
+            return;
+        }
+        let slot_count = instance.slots.len();
+        instance.set_field(&mut self.heap.shapes, name, value);
+        let cache = if instance.shape != shape {
+            PropertyCache { shape, slot: slot_count, next_shape: instance.shape }
+        } else {
+            match self.heap.shapes.slot(shape, name) {
+                Some(slot) => PropertyCache { shape, slot, next_shape: shape },
+                // Kept in the fields map
+                None => return,
+            }
+        };
+        drop(instance);
+        self.set_property_cache(offset, cache);
+    }
+
+    #[inline(always)]
+    fn property_cache(&self, offset: usize) -> PropertyCache {
+        let function = unsafe { &*self.curr_function() };
+        return function.property_caches.get(offset).copied().unwrap_or(PropertyCache::EMPTY);
+    }
+
+    fn set_property_cache(&self, offset: usize, cache: PropertyCache) {
+        let function = unsafe { &mut *self.curr_function() };
+        if function.property_caches.is_empty() {
+            function.property_caches = vec![PropertyCache::EMPTY; function.chunk.code.len()];
+        }
+        function.property_caches[offset] = cache;
+    }
+
     /// Helper to get current function
     #[inline(always)]
     fn curr_function(&self) -> *mut Function {
@@ -703,9 +1686,9 @@ impl VM {
     fn read_short(&mut self)->u16 {
         // Unsafe due to use of ptr as performance optimization
         unsafe {
-            let byte1 = (*(self.curr_function())).chunk.code[self.ip] as u16;
-            let byte2 = (*(self.curr_function())).chunk.code[self.ip + 1] as u16;
-            let result = (byte1 << 8 | byte2) as u16;
+            let byte1 = *self.code.add(self.ip) as u16;
+            let byte2 = *self.code.add(self.ip + 1) as u16;
+            let result = byte1 << 8 | byte2;
             self.ip += 2;
             return result;
         }
@@ -716,20 +1699,39 @@ impl VM {
         // Unsafe due to use of ptr as performance optimization
         unsafe {
             let pos = self.read_byte() as usize;
-            let value = (*(self.curr_function())).chunk.constants[pos];
-            return value.clone();
+            let value = (&(*(self.curr_function())).chunk.constants)[pos];
+            return value;
+        }
+    }
+
+    /// Read a constant addressed by a 24 bit operand
+    fn read_constant_long(&mut self) -> Value {
+        let high = self.read_byte() as usize;
+        let pos = (high << 16) | self.read_short() as usize;
+        unsafe {
+            return (&(*(self.curr_function())).chunk.constants)[pos];
+        }
+    }
+
+    /// Read the constant a name or function operand indexes, along with the
+    /// upper bits a Wide instruction before it gave
+    fn read_operand_constant(&mut self) -> Value {
+        let pos = mem::take(&mut self.wide_operand) | self.read_byte() as usize;
+        unsafe {
+            return (&(*(self.curr_function())).chunk.constants)[pos];
         }
     }
 
     /// Interpret string
     fn read_string(&mut self) -> Object {
-        let value = self.read_constant();
-        return value.as_object().clone();
+        let value = self.read_operand_constant();
+        return *value.as_object();
     }
 
     /// Peek stack based on the last position
+    #[inline(always)]
     fn peek(&self, pos: usize) -> &Value {
-        return self.stack.get(self.stack_top-1-pos).unwrap();
+        return &self.stack[self.stack_top-1-pos];
     }
 
     /// Method to call a callable object. eg function, native function, instance method, etc..
@@ -741,12 +1743,23 @@ impl VM {
             return self.call(closure_idx, arg_count);
         } else if callee.is_class_index() {
             let class_idx = callee.as_class_index();
+            if !self.heap.get_class(class_idx).abstract_methods.is_empty() {
+                let message = {
+                    let class = self.heap.get_class(class_idx);
+                    let missing: Vec<&str> = class.abstract_methods.iter()
+                        .map(|name| self.heap.get_string(*name).as_str())
+                        .collect();
+                    abstract_class_message(&class.name, &missing)
+                };
+                self.runtime_error(&message);
+                return false;
+            }
             let instance_idx = self.heap.alloc_instance(Instance::new(class_idx));
             let stack_idx = self.stack_top as isize - (arg_count as isize) - 1;
             self.stack[stack_idx as usize] = Value::Obj(Object::InstanceIndex(instance_idx));
 
             if self.heap.get_class(class_idx).methods.contains_key(&self.init_string_hash) {
-                let initializer = self.heap.get_mut_class(class_idx).methods.get(&self.init_string_hash).unwrap().clone();
+                let initializer = *self.heap.get_mut_class(class_idx).methods.get(&self.init_string_hash).unwrap();
                 return self.call(initializer.as_closure_index(),arg_count);
             } else if arg_count != 0 {
                 let format = format!("Expect 0 arguments but got {}", arg_count);
@@ -758,25 +1771,138 @@ impl VM {
         } else if callee.is_nativefn_index() {
             let native_fn_idx = callee.as_nativefn_index();
             return self.call_native(arg_count, native_fn_idx);
+        } else if callee.is_bound_method_index() {
+            let (receiver, closure_idx) = {
+                let bound_method = self.heap.get_bound_method(callee.as_bound_method_index());
+                (bound_method.receiver, bound_method.closure_idx)
+            };
+            // The receiver takes the callee's slot so the method sees it as 'this'
+            self.stack[self.stack_top - arg_count - 1] = receiver;
+            return self.call(closure_idx, arg_count);
         }
 
         self.runtime_error("Can only call function and classes.");
         return false;
     }
 
-    ///
+    /// Rewrite positional and `name: value` arguments on the stack into the
+    /// callee's parameter order, returning the resulting argument count
+    fn bind_named_arguments(&mut self, positional: usize, named: usize) -> Option<usize> {
+        let base = self.stack_top - positional - named * 2;
+        let callee = self.stack[base - 1];
+        let closure_idx = if callee.is_closure_index() {
+            Some(callee.as_closure_index())
+        } else if callee.is_bound_method_index() {
+            Some(self.heap.get_bound_method(callee.as_bound_method_index()).closure_idx)
+        } else if callee.is_class_index() {
+            let class = self.heap.get_class(callee.as_class_index());
+            class.methods.get(&self.init_string_hash).map(|init| init.as_closure_index())
+        } else {
+            None
+        };
+        let closure_idx = match closure_idx {
+            Some(closure_idx) => closure_idx,
+            None => {
+                self.runtime_error("Can only use named arguments with functions, methods and initializers.");
+                return None;
+            }
+        };
+        let param_names = {
+            let func_idx = self.heap.get_closure(closure_idx).func_idx;
+            self.heap.get_function(func_idx).param_names.clone()
+        };
+
+        let mut slots: Vec<Option<Value>> = vec![None; param_names.len()];
+        let mut extras = vec![];
+        for i in 0..positional {
+            let value = self.stack[base + i];
+            match slots.get_mut(i) {
+                Some(slot) => *slot = Some(value),
+                None => extras.push(value)
+            }
+        }
+        for i in 0..named {
+            let name_hash = self.stack[base + positional + i * 2].as_string_hash();
+            let value = self.stack[base + positional + i * 2 + 1];
+            let name = self.heap.get_string(name_hash).to_string();
+            let message = match param_names.iter().position(|param| *param == name) {
+                Some(index) if slots[index].is_none() => {
+                    slots[index] = Some(value);
+                    continue;
+                }
+                Some(_) => format!("Argument '{}' given more than once.", name),
+                None => format!("Unknown parameter '{}'.", name)
+            };
+            self.runtime_error(&message);
+            return None;
+        }
+        if let Some(index) = slots.iter().position(|slot| slot.is_none()) {
+            let message = format!("Missing argument '{}'.", param_names[index]);
+            self.runtime_error(&message);
+            return None;
+        }
+
+        self.stack_top = base;
+        let arg_count = slots.len() + extras.len();
+        for value in slots.into_iter().flatten().chain(extras) {
+            self.push(value);
+        }
+        return Some(arg_count);
+    }
+
+    /// Call a native with the arguments on the stack converted for it
     fn call_native(&mut self, arg_count: usize, native_fn_idx: usize) ->bool {
+        let native = match *self.heap.get_nativefn(native_fn_idx) {
+            Native::Converted(native) => native,
+            Native::Raw(native) => return self.call_raw_native(arg_count, |vm, arguments| native(&mut vm.heap, arguments)),
+            Native::Vm(native) => return self.call_raw_native(arg_count, native),
+        };
         let mut native_values: Vec<NativeValue> = vec![];
         self.convert_args_to_native(arg_count, &mut native_values);
         self.fpop(); // pop function
-        let native = self.heap.get_nativefn(native_fn_idx);
-        let native_val: NativeValue = native(arg_count, native_values);
+        self.metrics.calls += 1;
+        let native_val: NativeValue = match native(&self.heap, arg_count, native_values) {
+            Ok(native_val) => native_val,
+            Err(error) if error.with_line => {
+                let message = format!("{} (line {})", error.message, self.current_line());
+                self.runtime_error(&message);
+                return false;
+            }
+            Err(error) => {
+                self.runtime_error(&error.message);
+                return false;
+            }
+        };
         let result = self.native_to_value(native_val);
         self.push(result);
         return true;
     }
 
-    ///
+    /// Call a native with the arguments as they are, which stay on the stack
+    /// until it returns so a collection can't free them
+    fn call_raw_native<F>(&mut self, arg_count: usize, native: F) -> bool
+        where F: FnOnce(&mut VM, Vec<Value>) -> Result<Value, NativeError> {
+        let arguments = self.stack[self.stack_top - arg_count..self.stack_top].to_vec();
+        self.metrics.calls += 1;
+        let depth = self.callstack.len();
+        let result = match native(self, arguments) {
+            Ok(result) => result,
+            Err(error) => {
+                self.runtime_error(&error.message);
+                return false;
+            }
+        };
+        // The native handed over to a script function, whose frame returns the result
+        if self.callstack.len() > depth {
+            return true;
+        }
+        // Arguments and the native itself
+        self.stack_top -= arg_count + 1;
+        self.push(result);
+        return true;
+    }
+
+    /// Value on the heap for what a native returned
     fn native_to_value(&mut self, native_val: NativeValue) -> Value {
         match native_val {
             NativeValue::String(s) => {
@@ -785,28 +1911,33 @@ impl VM {
             }
             NativeValue::Number(n) => Value::number(n),
             NativeValue::Boolean(b) => Value::Bool(b),
-            NativeValue::Nil() => Value::nil()
+            NativeValue::Nil() => Value::nil(),
+            NativeValue::List(items) => {
+                let items = items.into_iter().map(|item| self.native_to_value(item)).collect();
+                let list_idx = self.heap.alloc_list(List::new(items));
+                Value::Obj(Object::ListIndex(list_idx))
+            }
+            NativeValue::Map(entries) => {
+                let mut map = Map::new();
+                for (key, value) in entries {
+                    let key = self.native_to_value(key);
+                    let value = self.native_to_value(value);
+                    map.set(key, value);
+                }
+                let map_idx = self.heap.alloc_map(map);
+                Value::Obj(Object::MapIndex(map_idx))
+            }
+            NativeValue::Object(object) => Value::Obj(object),
         }
     }
 
-    ///
+    /// Pop the arguments of a native call off the stack, converted for it
     fn convert_args_to_native(&mut self, arg_count: usize, native_values: &mut Vec<NativeValue>) {
-        for _ in 0..arg_count {
-            let value = self.pop();
-            match value {
-                Value::Number(n) => native_values.push(NativeValue::Number(n)),
-                Value::Bool(b) => native_values.push(NativeValue::Boolean(b)),
-                Value::Nil() => native_values.push(NativeValue::Nil()),
-                Value::Obj(obj) => match obj {
-                        Object::StringHash(hash) => {
-                            let str = self.heap.get_string(hash).to_string();
-                            native_values.insert(0, NativeValue::String(str));
-                        }
-                        _ => { panic!("Function, NativeFn are not allowed as argument to native function") }
-                }
-
-            }
+        // Arguments sit on the stack in call order
+        for slot in self.stack_top - arg_count..self.stack_top {
+            native_values.push(to_native(&self.heap, self.stack[slot]));
         }
+        self.stack_top -= arg_count;
     }
 
     /// Insert the call into the call stack
@@ -815,30 +1946,192 @@ impl VM {
             closure_idx: usize,
             arg_count: usize) -> bool {
 
-        let arity = unsafe { // faster to use ptr
-            (*self.heap.functions[(*self.heap.closures[closure_idx].as_ptr()).func_idx].as_ptr()).arity
+        let (arity, is_variadic) = unsafe { // faster to use ptr
+            let function = &*self.heap.functions[(*self.heap.closures[closure_idx].as_ptr()).func_idx].as_ptr();
+            (function.arity, function.is_variadic)
         };
 
         // slower => let arity = self.heap.get_function(self.heap.get_closure(closure_idx).func_idx).arity;
 
-        if arg_count != arity {
+        let mut arg_count = arg_count;
+        if is_variadic {
+            if arg_count < arity {
+                let message = format!("Expected at least {} arguments but got {}", arity, arg_count);
+                self.runtime_error(&message);
+                return false;
+            }
+            // Collect the extra arguments into a list bound to the rest parameter
+            let rest = self.stack[self.stack_top - (arg_count - arity)..self.stack_top].to_vec();
+            self.stack_top -= arg_count - arity;
+            let list_idx = self.heap.alloc_list(List::new(rest));
+            self.push(Value::object(Object::list(list_idx)));
+            arg_count = arity + 1;
+        } else if arg_count != arity {
             let message = format!("Expected {} arguments but got {}", arity, arg_count);
             self.runtime_error(&message);
+            return false;
+        }
+
+        if self.callstack.len() >= MAX_CALLSTACK || self.stack_top >= self.max_stack {
+            let name = {
+                let func_idx = self.heap.get_closure(closure_idx).func_idx;
+                self.heap.get_function(func_idx).name.to_string()
+            };
+            let message = format!("Stack overflow calling {} at depth {}.", name, self.callstack.len());
+            self.runtime_error(&message);
+            return false;
         }
 
         let frame = CallFrame::new(closure_idx,
                                    self.stack_top - 1 - arg_count);
         self.callstack.push(frame);
+        self.metrics.calls += 1;
+        if self.callstack.len() > self.metrics.peak_call_depth {
+            self.metrics.peak_call_depth = self.callstack.len();
+        }
         return true;
     }
 
     fn define_native(&mut self, name: &str, native: NativeFn) {
+        self.define_native_as(name, Native::Converted(native));
+    }
+
+    fn define_native_as(&mut self, name: &str, native: Native) {
         let string_hash = self.heap.alloc_string(name.to_string());
         let native_fn_idx = self.heap.alloc_nativefn(native);
         self.globals.insert(string_hash, Value::Obj(Object::NativeFnIndex(native_fn_idx)));
     }
 
-    /// Reset the stack
+    /// Swap the body of an existing closure or class for the new definition so
+    /// that references already held elsewhere pick up the new behaviour.
+    /// Returns false when the two values are not of the same redefinable kind.
+    fn redefine_in_place(&mut self, existing: Value, value: Value) -> bool {
+        self.shade(value);
+        if existing.is_closure_index() && value.is_closure_index() {
+            let old_idx = existing.as_closure_index();
+            let new_idx = value.as_closure_index();
+            if old_idx == new_idx {
+                return true;
+            }
+            let (func_idx, upvalues) = {
+                let closure = self.heap.get_closure(new_idx);
+                (closure.func_idx, closure.upvalues.clone())
+            };
+            let mut closure = self.heap.get_mut_closure(old_idx);
+            closure.func_idx = func_idx;
+            closure.upvalues = upvalues;
+            // The old closure no longer belongs to the function it was cached for
+            self.closure_cache.retain(|_, cached| *cached != old_idx);
+            return true;
+        }
+        if existing.is_class_index() && value.is_class_index() {
+            let old_idx = existing.as_class_index();
+            let new_idx = value.as_class_index();
+            if old_idx == new_idx {
+                return true;
+            }
+            let new_class = self.heap.get_class(new_idx).clone();
+            let before = mem::replace(&mut *self.heap.get_mut_class(old_idx), new_class);
+            self.update_subclasses(old_idx, Some(before));
+            return true;
+        }
+        return false;
+    }
+
+    /// Copy of the class to compare with once it changed, taken only under hot
+    /// redefinition. Only then can a class still being defined have subclasses,
+    /// the ones of the definition it replaced
+    fn class_before_change(&self, class_idx: usize) -> Option<Class> {
+        if !self.hot_redefinition {
+            return None;
+        }
+        return Some(self.heap.get_class(class_idx).clone());
+    }
+
+    /// Give the subclasses of a changed class what it has now in place of
+    /// the methods, abstract methods and constants they inherited from what
+    /// it had before. What a subclass defines itself stays
+    fn update_subclasses(&mut self, class_idx: usize, before: Option<Class>) {
+        let Some(before) = before else {
+            return;
+        };
+        let mut pending = vec![(class_idx, before)];
+        // A redefinition can make a class inherit from its own subclass
+        let mut updated = vec![class_idx];
+        while let Some((class_idx, before)) = pending.pop() {
+            let subclasses: Vec<usize> = self.heap.classes.live_indexes()
+                .filter(|idx| self.heap.get_class(*idx).superclass == Some(class_idx) && !updated.contains(idx))
+                .collect();
+            let class = self.heap.get_class(class_idx).clone();
+            for value in class.methods.values().chain(class.constants.values()) {
+                self.shade(*value);
+            }
+            for subclass_idx in subclasses {
+                let mut subclass = self.heap.get_mut_class(subclass_idx);
+                let subclass_before = subclass.clone();
+                // Entries still holding what the class had are inherited ones
+                subclass.methods.retain(|name, method| before.methods.get(name) != Some(method));
+                subclass.constants.retain(|name, constant| before.constants.get(name) != Some(constant));
+                let own_abstract_methods: Vec<u32> = subclass.abstract_methods.iter().copied()
+                    .filter(|name| !before.abstract_methods.contains(name))
+                    .collect();
+                for (name, method) in &class.methods {
+                    if !own_abstract_methods.contains(name) && !subclass.methods.contains_key(name) {
+                        subclass.methods.insert(*name, *method);
+                    }
+                }
+                for (name, constant) in &class.constants {
+                    subclass.constants.entry(*name).or_insert(*constant);
+                }
+                let mut abstract_methods: Vec<u32> = class.abstract_methods.iter().copied()
+                    .filter(|name| !subclass.methods.contains_key(name) && !own_abstract_methods.contains(name))
+                    .collect();
+                abstract_methods.extend(own_abstract_methods);
+                subclass.abstract_methods = abstract_methods;
+                drop(subclass);
+                updated.push(subclass_idx);
+                pending.push((subclass_idx, subclass_before));
+            }
+        }
+    }
+
+    /// Names of the defined globals, along with the natives registered on
+    /// first use that the capabilities allow
+    pub fn global_names(&self) -> Vec<String> {
+        let mut names: Vec<String> = self.globals.keys()
+            .map(|hash| self.heap.get_string(*hash).to_string())
+            .collect();
+        for (name, _, capability) in LAZY_NATIVES {
+            if self.capabilities.allows(capability) {
+                names.push(name.to_string());
+            }
+        }
+        return names;
+    }
+
+    /// Error for a lazy native the capabilities don't allow
+    fn disallowed_native(&self, name_hash: u32) -> Option<String> {
+        for (name, _, capability) in LAZY_NATIVES {
+            if !self.capabilities.allows(capability) && self.heap.get_string(name_hash) == name {
+                return Some(format!("{} is not available, {} access is disabled.", name, capability));
+            }
+        }
+        return None;
+    }
+
+    /// Register a lazy native the first time its name is looked up
+    fn define_lazy_native(&mut self, name_hash: u32) -> Option<Value> {
+        for (name, native, capability) in LAZY_NATIVES {
+            if self.capabilities.allows(capability) && self.heap.get_string(name_hash) == name {
+                self.define_native_as(name, native);
+                return self.globals.get(&name_hash).copied();
+            }
+        }
+        return None;
+    }
+
+    /// Reset the stack and call frames after a run, globals and the heap are
+    /// kept so the REPL can keep using them
     pub fn reset_stack(&mut self) {
         self.stack.clear();
         self.stack_top = 0;
@@ -846,7 +2139,16 @@ impl VM {
         self.open_upvalues = None;
         self.curr_func_idx = 0;
         self.callstack.clear();
-        self.heap.clear();
+        self.handlers.clear();
+        self.abort_collection();
+    }
+
+    /// Forget an unfinished collection, its marks refer to a heap that was cleared
+    fn abort_collection(&mut self) {
+        self.gc_marking = false;
+        self.gc_marked.clear();
+        self.gc_traced = 0;
+        self.gc_visited.clear();
     }
 
     /// Convenience method for binary operations
@@ -884,7 +2186,8 @@ impl VM {
     fn close_upvalues(&mut self, frame_slot: usize) {
         while self.open_upvalues_location_greater_or_equal_to(&frame_slot) {
             let location = self.get_open_upvalues_location();
-            let value = self.stack.get(location).unwrap().clone();
+            let value = *self.stack.get(location).unwrap();
+            self.shade(value);
             self.close_upvalue(value);
             let next = if Self::has_next_upvalue(&mut self.open_upvalues) {
                 Self::get_next_upvalue(&self.open_upvalues)
@@ -908,7 +2211,7 @@ impl VM {
 
     fn open_upvalues_location_greater_or_equal_to(&mut self, frame_slot: &usize) -> bool {
         match self.open_upvalues.borrow() {
-            Some(it) => it.as_ref().borrow().location.as_ref().unwrap() >= &frame_slot,
+            Some(it) => it.as_ref().borrow().location.as_ref().unwrap() >= frame_slot,
             None => false
         }
     }
@@ -922,35 +2225,272 @@ impl VM {
     }
 
     fn define_method(&mut self, string_hash: u32) {
-        let method = self.peek(0);
+        let method = *self.peek(0);
+        self.shade(method);
         let class_idx = self.peek(1).as_class_index();
-        self.heap.get_mut_class(class_idx).methods.insert(string_hash, *method);
+        let before = self.class_before_change(class_idx);
+        let mut class = self.heap.get_mut_class(class_idx);
+        class.methods.insert(string_hash, method);
+        class.abstract_methods.retain(|name| *name != string_hash);
+        drop(class);
+        self.update_subclasses(class_idx, before);
         self.pop();
     }
 
     fn invoke(&mut self, method_name_hash: u32, arg_count: usize) -> bool {
-        let receiver = self.peek(arg_count);
-        if !receiver.is_instance_index() {
+        let receiver = *self.peek(arg_count);
+        if receiver.is_class_index() {
+            // Calls a constant holding a function, such as `Shape.make()`
+            let constant = self.heap.get_class(receiver.as_class_index()).constants.get(&method_name_hash).copied();
+            if let Some(value) = constant {
+                self.stack[self.stack_top - arg_count - 1] = value;
+                return self.call_value(value, arg_count);
+            }
+        }
+        if !receiver.is_instance_index() && !receiver.is_user_data_index() {
             self.runtime_error("Only instances have methods");
             return false;
         }
+        if receiver.is_user_data_index() {
+            return self.invoke_user_method(receiver.as_user_data_index(), method_name_hash, arg_count);
+        }
         let instance_idx = receiver.as_instance_index();
-        if self.heap.get_instance(instance_idx).fields.contains_key(&method_name_hash) {
-            let value = self.heap.get_instance(instance_idx).fields.get(&method_name_hash).unwrap().clone();
+        let field = self.heap.get_instance(instance_idx).get_field(&self.heap.shapes, method_name_hash);
+        if let Some(value) = field {
             self.stack[self.stack_top - arg_count - 1] = value;
             return self.call_value(value, arg_count);
         }
         let class_idx = self.heap.get_instance(instance_idx).class_idx;
         return self.invoke_from_class(class_idx, method_name_hash, arg_count);
     }
+    /// Report a runtime error when the global was declared const
+    fn check_not_const(&mut self, name_hash: u32, action: &str) -> bool {
+        if self.const_globals.contains(&name_hash) {
+            let message = format!("Can't {} constant {}", action, self.heap.get_string(name_hash));
+            self.runtime_error(&message);
+            return false;
+        }
+        return true;
+    }
+
+    /// Validate that target is a list and index a whole number inside it
+    fn list_position(&mut self, target: Value, index: Value) -> Option<usize> {
+        if !target.is_list_index() {
+            self.runtime_error("Only lists and maps can be indexed.");
+            return None;
+        }
+        if !index.is_number() || index.as_number().fract() != 0.0 {
+            self.runtime_error("List index must be a whole number.");
+            return None;
+        }
+        let position = index.as_number();
+        let len = self.heap.get_list(target.as_list_index()).items.len();
+        if position < 0.0 || position as usize >= len {
+            let message = format!("List index {} out of range for length {}.", position, len);
+            self.runtime_error(&message);
+            return None;
+        }
+        return Some(position as usize);
+    }
+
+    /// Text used by print for a value; strings inside lists are quoted
+    pub fn format_value(&self, value: Value) -> String {
+        return self.format_nested(value, &mut vec![]);
+    }
+
+    /// `enclosing` holds the lists and maps being printed around the value, a
+    /// list or map inside itself is printed as [...] or {...}
+    fn format_nested(&self, value: Value, enclosing: &mut Vec<Object>) -> String {
+        if value.is_string_hash() {
+            return self.heap.get_string(value.as_string_hash()).to_string();
+        }
+        if let Value::Obj(object) = value {
+            if enclosing.contains(&object) {
+                return if value.is_list_index() { "[...]".to_string() } else { "{...}".to_string() };
+            }
+        }
+        if value.is_list_index() {
+            let list = self.heap.get_list(value.as_list_index());
+            enclosing.push(Object::ListIndex(value.as_list_index()));
+            let items: Vec<String> = list.items.iter().map(|item| self.format_item(*item, enclosing)).collect();
+            enclosing.pop();
+            return format!("[{}]", items.join(", "));
+        }
+        if value.is_instance_index() {
+            let class_idx = self.heap.get_instance(value.as_instance_index()).class_idx;
+            return format!("<{} instance>", self.heap.get_class(class_idx).name);
+        }
+        if value.is_user_data_index() {
+            let type_idx = self.heap.get_user_data(value.as_user_data_index()).type_idx;
+            return format!("<{} userdata>", self.heap.user_types[type_idx].name);
+        }
+        if value.is_map_index() {
+            let map = self.heap.get_map(value.as_map_index());
+            enclosing.push(Object::MapIndex(value.as_map_index()));
+            let entries: Vec<String> = map.entries.iter().map(|(key, value)| {
+                format!("{}: {}", self.format_item(*key, enclosing), self.format_item(*value, enclosing))
+            }).collect();
+            enclosing.pop();
+            return format!("{{{}}}", entries.join(", "));
+        }
+        return value.to_string();
+    }
+
+    /// Text for print and string concatenation. Instances use the toString
+    /// method of their class when it defines one
+    fn stringify(&mut self, value: Value) -> Option<String> {
+        if value.is_instance_index() {
+            let class_idx = self.heap.get_instance(value.as_instance_index()).class_idx;
+            let method = self.heap.get_class(class_idx).methods.get(&self.to_string_hash).copied();
+            if let Some(method) = method {
+                let result = self.call_method_now(value, method.as_closure_index(), &[])?;
+                if !result.is_string_hash() {
+                    self.runtime_error("toString must return a string.");
+                    return None;
+                }
+                return Some(self.heap.get_string(result.as_string_hash()).to_string());
+            }
+        }
+        return Some(self.format_value(value));
+    }
+
+    /// Result of ==. An instance on the left whose class defines eq decides
+    /// through it, everything else compares by value or identity
+    fn values_equal(&mut self, a: Value, b: Value) -> Option<bool> {
+        if a.is_instance_index() {
+            let class_idx = self.heap.get_instance(a.as_instance_index()).class_idx;
+            let method = self.heap.get_class(class_idx).methods.get(&self.eq_hash).copied();
+            if let Some(method) = method {
+                let result = self.call_method_now(a, method.as_closure_index(), &[b])?;
+                if !result.is_boolean() {
+                    self.runtime_error("eq must return true or false.");
+                    return None;
+                }
+                return Some(result.as_boolean());
+            }
+        }
+        return Some(a == b);
+    }
+
+    /// Where the key is in the map. Instance keys whose class defines hash are
+    /// found by calling it and comparing the keys with the same hash through
+    /// ==, None after a runtime error in either
+    fn find_key(&mut self, map_idx: usize, key: Value) -> Option<KeyPosition> {
+        let method = if key.is_instance_index() {
+            let class_idx = self.heap.get_instance(key.as_instance_index()).class_idx;
+            self.heap.get_class(class_idx).methods.get(&self.hash_method_hash).copied()
+        } else {
+            None
+        };
+        let method = match method {
+            Some(method) => method,
+            None => return Some(match self.heap.get_map(map_idx).position(key) {
+                Some(position) => KeyPosition::Entry(position),
+                None => KeyPosition::Vacant(None),
+            })
+        };
+        let result = self.call_method_now(key, method.as_closure_index(), &[])?;
+        if !result.is_number() {
+            self.runtime_error("hash must return a number.");
+            return None;
+        }
+        let hash = number_bits(result.as_number());
+        let positions = self.heap.get_map(map_idx).hashed_positions(hash);
+        for position in positions {
+            let other = self.heap.get_map(map_idx).entries[position].0;
+            if self.values_equal(key, other)? {
+                return Some(KeyPosition::Entry(position));
+            }
+        }
+        return Some(KeyPosition::Vacant(Some(hash)));
+    }
+
+    /// Text for a value nested inside a list or map, strings are quoted
+    fn format_item(&self, value: Value, enclosing: &mut Vec<Object>) -> String {
+        if value.is_string_hash() {
+            return format!("\"{}\"", self.format_value(value));
+        }
+        return self.format_nested(value, enclosing);
+    }
+
+    /// Replace the instance on top of the stack with the named method of the
+    /// given class bound to that instance. Only calls go to methodMissing, a
+    /// bare read of a method the class lacks is an undefined property
+    fn bind_method(&mut self, class_idx: usize, method_name_hash: u32) -> bool {
+        let method = self.heap.get_class(class_idx).methods.get(&method_name_hash).copied();
+        let method = match method {
+            Some(method) => method,
+            None => {
+                let property = self.heap.get_string(method_name_hash);
+                let format = format!("Undefined property '{}'", &property);
+                self.runtime_error(&format);
+                return false;
+            }
+        };
+        let bound_method = BoundMethod::new(*self.peek(0), method.as_closure_index());
+        let bound_method_idx = self.heap.alloc_bound_method(bound_method);
+        self.fpop(); // instance
+        self.push(Value::object(Object::bound_method(bound_method_idx)));
+        return true;
+    }
+
     fn invoke_from_class(&mut self, class_idx: usize, method_name_hash: u32, arg_count: usize) -> bool {
+        if !self.heap.get_class(class_idx).methods.contains_key(&method_name_hash) && self.has_method_missing(class_idx) {
+            return self.invoke_method_missing(class_idx, method_name_hash, arg_count);
+        }
         if !self.heap.get_class(class_idx).methods.contains_key(&method_name_hash) {
             let property = self.heap.get_string(method_name_hash);
             let format = format!("Undefined property '{}'", &property);
             self.runtime_error(&format);
             return false;
         }
-        let method = self.heap.get_class(class_idx).methods.get(&method_name_hash).unwrap().clone();
+        let method = *self.heap.get_class(class_idx).methods.get(&method_name_hash).unwrap();
         return self.call(method.as_closure_index(), arg_count);
     }
+
+    /// Call a method of a host type with the arguments on the stack. The host
+    /// value is moved out of the heap for the call so the method can use both
+    fn invoke_user_method(&mut self, user_data_idx: usize, method_name_hash: u32, arg_count: usize) -> bool {
+        let type_idx = self.heap.get_user_data(user_data_idx).type_idx;
+        let method = match self.heap.user_types[type_idx].methods.get(self.heap.get_string(method_name_hash)) {
+            Some(method) => *method,
+            None => {
+                let format = format!("Undefined property '{}'", self.heap.get_string(method_name_hash));
+                self.runtime_error(&format);
+                return false;
+            }
+        };
+        let args = self.stack[self.stack_top - arg_count..self.stack_top].to_vec();
+        let mut data = mem::replace(&mut self.heap.get_mut_user_data(user_data_idx).data, Box::new(()));
+        self.metrics.calls += 1;
+        let result = method(&mut self.heap, data.as_mut(), args);
+        self.heap.get_mut_user_data(user_data_idx).data = data;
+        let result = match result {
+            Ok(result) => result,
+            Err(error) => {
+                self.runtime_error(&error.message);
+                return false;
+            }
+        };
+        // Arguments and the receiver
+        self.stack_top -= arg_count + 1;
+        self.push(result);
+        return true;
+    }
+
+    fn has_method_missing(&self, class_idx: usize) -> bool {
+        return self.heap.get_class(class_idx).methods.contains_key(&self.method_missing_hash);
+    }
+
+    /// Call methodMissing of the class in place of the missing method, with
+    /// its name and the arguments gathered in a list
+    fn invoke_method_missing(&mut self, class_idx: usize, method_name_hash: u32, arg_count: usize) -> bool {
+        let method_missing = self.heap.get_class(class_idx).methods[&self.method_missing_hash];
+        let items = self.stack[self.stack_top - arg_count..self.stack_top].to_vec();
+        self.stack_top -= arg_count;
+        let args = self.heap.alloc_list(List::new(items));
+        self.push(Value::object(Object::StringHash(method_name_hash)));
+        self.push(Value::object(Object::list(args)));
+        return self.call(method_missing.as_closure_index(), 2);
+    }
 }
@@ -0,0 +1,95 @@
+//! Browser entry points, exported through wasm-bindgen when built with the
+//! wasm feature.
+//!
+//! ```js
+//! import init, { evaluate, setPrintCallback } from "./pkg/kscript_rust.js";
+//! await init();
+//! setPrintCallback(line => console.log(line));
+//! evaluate("print 1 + 2;");
+//! ```
+use std::cell::RefCell;
+use std::io;
+use std::io::Write;
+
+use js_sys::Function;
+use wasm_bindgen::prelude::*;
+use wasm_bindgen::JsValue;
+
+use crate::nativefn::Capabilities;
+use crate::{semantic_tokens, Interpreter};
+
+thread_local! {
+    /// Called with every line a script prints
+    static PRINT_CALLBACK: RefCell<Option<Function>> = RefCell::new(None);
+}
+
+/// Set the function called with each line printed by scripts, print output is
+/// discarded until one is set
+#[wasm_bindgen(js_name = setPrintCallback)]
+pub fn set_print_callback(callback: Function) {
+    PRINT_CALLBACK.with(|print| *print.borrow_mut() = Some(callback));
+}
+
+/// Run the source in a fresh sandboxed interpreter, returning the value of a
+/// lone expression, "nil" for statements or the error message
+#[wasm_bindgen(js_name = evaluate)]
+pub fn eval(source: &str) -> String {
+    // The playground shows the text as it is, without terminal colors
+    colored::control::set_override(false);
+    let mut interpreter = Interpreter::new();
+    interpreter.vm.capabilities = Capabilities::none();
+    interpreter.vm.output = Box::new(PrintCallback { line: vec![] });
+    let result = match interpreter.eval(source) {
+        Ok(value) => interpreter.display(value),
+        Err(error) => error.to_string(),
+    };
+    let _ = interpreter.vm.output.flush();
+    return result;
+}
+
+/// Highlighting spans of the source, four numbers each: line, column, length
+/// and kind, 0 keyword, 1 string, 2 number, 3 identifier and 4 comment
+#[wasm_bindgen(js_name = semanticTokens)]
+pub fn semantic_tokens_flat(source: &str) -> Vec<u32> {
+    return semantic_tokens(source).iter()
+        .flat_map(|token| [token.line as u32, token.column as u32, token.length as u32, token.kind as u32])
+        .collect();
+}
+
+/// Print output handed to the print callback a line at a time
+struct PrintCallback {
+    /// Text after the last line ending
+    line: Vec<u8>,
+}
+
+impl PrintCallback {
+    fn send_line(&mut self) {
+        let line = String::from_utf8_lossy(&self.line).into_owned();
+        self.line.clear();
+        PRINT_CALLBACK.with(|print| {
+            if let Some(callback) = print.borrow().as_ref() {
+                let _ = callback.call1(&JsValue::NULL, &JsValue::from_str(&line));
+            }
+        });
+    }
+}
+
+impl Write for PrintCallback {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        for byte in buf {
+            if *byte == b'\n' {
+                self.send_line();
+            } else {
+                self.line.push(*byte);
+            }
+        }
+        return Ok(buf.len());
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if !self.line.is_empty() {
+            self.send_line();
+        }
+        return Ok(());
+    }
+}